@@ -179,13 +179,13 @@ fn main()
 fn gen_rust_bindings(out_file: &str, subsystems: &Vec<SubSystem>)
 {
     // Generate an array of syscalls sorted by const_idx
-    let mut syscall_list: Vec<SysCall> = Vec::new();
+    let mut syscall_list: Vec<(SysCall, &str)> = Vec::new();
     for subsystem in subsystems {
         for syscall in &subsystem.syscalls {
-            syscall_list.push(syscall.clone());
+            syscall_list.push((syscall.clone(), &subsystem.subsystem));
         }
     }
-    syscall_list.sort_by(|a, b| a.const_idx.unwrap().cmp(&b.const_idx.unwrap()));
+    syscall_list.sort_by(|(a, _), (b, _)| a.const_idx.unwrap().cmp(&b.const_idx.unwrap()));
 
     // Generate syscall constants in rust
     let mut file = File::create(out_file).unwrap();
@@ -218,20 +218,29 @@ fn gen_rust_bindings(out_file: &str, subsystems: &Vec<SubSystem>)
         "    pub const_idx: u16,\n",
         "    pub argc: usize,\n",
         "    pub has_ret: bool,\n",
+        "    pub arg_types: &'static [&'static str],\n",
+        "    pub ret_type: &'static str,\n",
+        "    pub permission: &'static str,\n",
+        "    pub subsystem: &'static str,\n",
         "}",
     )).unwrap();
     writeln!(&mut file).unwrap();
 
     writeln!(&mut file, "pub const SYSCALL_DESCS: [SysCallDesc; NUM_SYSCALLS] = [").unwrap();
-    for syscall in syscall_list {
+    for (syscall, subsystem) in syscall_list {
         let has_ret = syscall.returns.0 != "void";
+        let arg_types: Vec<String> = syscall.args.iter().map(|(arg_type, _)| format!("\"{}\"", arg_type)).collect();
         writeln!(
             &mut file,
-            "    SysCallDesc {{ name: \"{}\", const_idx: {}, argc: {}, has_ret: {} }},",
+            "    SysCallDesc {{ name: \"{}\", const_idx: {}, argc: {}, has_ret: {}, arg_types: &[{}], ret_type: \"{}\", permission: \"{}\", subsystem: \"{}\" }},",
             syscall.name,
             syscall.const_idx.unwrap(),
             syscall.args.len(),
             has_ret,
+            arg_types.join(", "),
+            syscall.returns.0,
+            syscall.permission,
+            subsystem,
         ).unwrap();
     }
     writeln!(&mut file, "];").unwrap();
@@ -344,6 +353,12 @@ fn gen_markdown(out_file: &str, subsystems: &Vec<SubSystem>)
                 writeln!(&mut file).unwrap();
             }
 
+            // If this syscall requires a specific permission
+            if syscall.permission != "default_allowed" {
+                writeln!(&mut file, "**Permission:** `{}`", syscall.permission).unwrap();
+                writeln!(&mut file).unwrap();
+            }
+
             // Add description comment if present
             if let Some(text) = &syscall.description {
                 writeln!(&mut file, "{}", text).unwrap();
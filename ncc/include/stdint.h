@@ -7,4 +7,7 @@
 #define uint32_t u32
 #define uint64_t u64
 
+// Largest value of size_t
+#define SIZE_MAX 0xFFFFFFFFFFFFFFFF
+
 #endif
@@ -1,6 +1,9 @@
 #ifndef __STDLIB_H__
 #define __STDLIB_H__
 
+#include <stdint.h>
+#include <uvm/syscalls.h>
+
 int abs(int n)
 {
     if (n < 0)
@@ -29,8 +32,9 @@ int rand()
 {
     __cur_rand__ = (0xf1357aea2e62a9c5 * __cur_rand__);
 
-    // Use the upper 32 bits of the state only
-    return (int)(__cur_rand__ >> 32);
+    // Use the upper 31 bits of the state only,
+    // so that the result is a non-negative int
+    return (int)(__cur_rand__ >> 33);
 }
 
 void srand(unsigned int seed)
@@ -40,10 +44,26 @@ void srand(unsigned int seed)
     __cur_rand__ = (seed << 1) + 1;
 }
 
-// TODO:
-//void* malloc(size_t size)
+// Simple bump allocator which grows the heap for each allocation
+// Returns null if the heap can't be grown to fit the allocation
+void* malloc(size_t size)
+{
+    // Keep allocations aligned to 8 bytes
+    size_t ptr = (vm_heap_size() + 7) & ~7;
+
+    // The end of the allocation must not wrap around
+    if (size > SIZE_MAX - ptr)
+        return (void*)0;
+
+    if (!vm_resize_heap(ptr + size))
+        return (void*)0;
 
-// TODO:
-//void free(void* ptr)
+    return (void*)ptr;
+}
+
+// TODO: memory is never reused for now
+void free(void* ptr)
+{
+}
 
 #endif
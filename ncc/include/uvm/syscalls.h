@@ -13,6 +13,26 @@
 // Fill a block of bytes in the heap with a given value.
 #define memset(__dst, __value, __num_bytes) asm (__dst, __value, __num_bytes) -> void { syscall memset; }
 
+// u64 vm_heap_size()
+// Get the current size of the heap in bytes.
+#define vm_heap_size() asm () -> u64 { syscall vm_heap_size; }
+
+// bool vm_resize_heap(u64 num_bytes)
+// Grow or shrink the heap to a given size in bytes. Newly added heap memory is zero-filled. Fails and leaves the heap unchanged if the requested size exceeds the maximum heap size allowed by the host.
+#define vm_resize_heap(__num_bytes) asm (__num_bytes) -> bool { syscall vm_resize_heap; }
+
+// u64 vm_code_size()
+// Get the current size of the code space in bytes.
+#define vm_code_size() asm () -> u64 { syscall vm_code_size; }
+
+// bool vm_grow_code(u64 num_bytes)
+// Grow the code space by a given number of bytes. The new code space is zero-filled, which corresponds to panic instructions. Fails and leaves the code space unchanged if the new size would exceed the maximum code size allowed by the host.
+#define vm_grow_code(__num_bytes) asm (__num_bytes) -> bool { syscall vm_grow_code; }
+
+// void vm_code_write(u64 dst_pc, const u8* src, u64 num_bytes)
+// Copy bytecode from the heap into the code space at a given address. The destination must be within the current code space.
+#define vm_code_write(__dst_pc, __src, __num_bytes) asm (__dst_pc, __src, __num_bytes) -> void { syscall vm_code_write; }
+
 // void print_i64(i64 val)
 // Print an i64 value to standard output.
 #define print_i64(__val) asm (__val) -> void { syscall print_i64; }
@@ -61,4 +81,32 @@
 // Register a callback for mouse button release events.
 #define window_on_mouseup(__window_id, __callback) asm (__window_id, __callback) -> void { syscall window_on_mouseup; }
 
+// u64 thread_spawn(void* fun, u64 arg)
+// Spawn a new thread which calls the function fun with arg as its only argument. The new thread gets its own stack and a copy of the heap of the calling thread. Spawned threads can't use the window subsystem and don't run an event loop, so their callbacks are never called.
+#define thread_spawn(__fun, __arg) asm (__fun, __arg) -> u64 { syscall thread_spawn; }
+
+// u64 thread_id()
+// Get the id of the current thread. The main thread has id 0.
+#define thread_id() asm () -> u64 { syscall thread_id; }
+
+// u64 thread_join(u64 thread_id)
+// Wait for a thread to finish and get the value returned by its function. Each thread can only be joined once. Joining a thread which faulted produces a fault in the calling thread.
+#define thread_join(__thread_id) asm (__thread_id) -> u64 { syscall thread_join; }
+
+// u64 chan_create()
+// Create a new channel and return its id. Channel ids are shared by all threads, so a channel created before spawning a thread can be used to communicate with it.
+#define chan_create() asm () -> u64 { syscall chan_create; }
+
+// void chan_send(u64 chan_id, const u8* buf, u64 num_bytes)
+// Send a message made of a copy of num_bytes bytes from buf. This never blocks. Messages are received in the order they were sent.
+#define chan_send(__chan_id, __buf, __num_bytes) asm (__chan_id, __buf, __num_bytes) -> void { syscall chan_send; }
+
+// u64 chan_recv(u64 chan_id, u8* buf, u64 buf_size)
+// Wait until a message is available on a channel, copy it into buf, and return its size in bytes. If the message is larger than buf_size, only the first buf_size bytes are copied.
+#define chan_recv(__chan_id, __buf, __buf_size) asm (__chan_id, __buf, __buf_size) -> u64 { syscall chan_recv; }
+
+// void chan_on_recv(u64 chan_id, u8* buf, u64 buf_size, void* callback)
+// Register a callback to be called by the event loop of the current thread for each message received on a channel. Each message is copied into buf as with chan_recv, and the callback is called with the message size as argument. This replaces any callback previously registered for this channel.
+#define chan_on_recv(__chan_id, __buf, __buf_size, __callback) asm (__chan_id, __buf, __buf_size, __callback) -> void { syscall chan_on_recv; }
+
 #endif
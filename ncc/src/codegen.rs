@@ -1,6 +1,7 @@
 use crate::ast::*;
 use crate::parsing::{ParseError};
 use crate::types::*;
+use std::cmp::max;
 
 #[derive(Default)]
 struct SymGen
@@ -156,7 +157,7 @@ impl Function
             out.push_str("push 0;\n");
         }
 
-        self.body.gen_code(&None, &None, sym, out)?;
+        self.body.gen_code(&self.ret_type, &None, &None, sym, out)?;
 
         // If the body needs a final return
         if self.needs_final_return() {
@@ -174,6 +175,7 @@ impl Stmt
 {
     fn gen_code(
         &self,
+        ret_type: &Type,
         break_label: &Option<String>,
         cont_label: &Option<String>,
         sym: &mut SymGen,
@@ -229,9 +231,13 @@ impl Stmt
                     out.push_str("push 0;\n");
                     out.push_str("ret;\n");
                 }
+                else if is_tail_call(expr, ret_type)
+                {
+                    gen_tail_call(expr, sym, out)?;
+                }
                 else
                 {
-                    expr.gen_code(sym, out)?;
+                    gen_expr_as(expr, ret_type, sym, out)?;
                     out.push_str("ret;\n");
                 }
             }
@@ -247,16 +253,16 @@ impl Stmt
                 if else_stmt.is_some() {
                     let join_label = sym.gen_sym("if_join");
 
-                    then_stmt.gen_code(break_label, cont_label, sym, out)?;
+                    then_stmt.gen_code(ret_type, break_label, cont_label, sym, out)?;
                     out.push_str(&format!("jmp {};\n", join_label));
 
                     out.push_str(&format!("{}:\n", false_label));
-                    else_stmt.as_ref().unwrap().gen_code(break_label, cont_label, sym, out)?;
+                    else_stmt.as_ref().unwrap().gen_code(ret_type, break_label, cont_label, sym, out)?;
                     out.push_str(&format!("{}:\n", join_label));
                 }
                 else
                 {
-                    then_stmt.gen_code(break_label, cont_label, sym, out)?;
+                    then_stmt.gen_code(ret_type, break_label, cont_label, sym, out)?;
                     out.push_str(&format!("{}:\n", false_label));
                 }
             }
@@ -270,6 +276,7 @@ impl Stmt
                 out.push_str(&format!("jz {};\n", break_label));
 
                 body_stmt.gen_code(
+                    ret_type,
                     &Some(break_label.clone()),
                     &Some(loop_label.clone()),
                     sym,
@@ -282,7 +289,7 @@ impl Stmt
 
             Stmt::For { init_stmt, test_expr, incr_expr, body_stmt } => {
                 if init_stmt.is_some() {
-                    init_stmt.as_ref().unwrap().gen_code(break_label, cont_label, sym, out)?;
+                    init_stmt.as_ref().unwrap().gen_code(ret_type, break_label, cont_label, sym, out)?;
                 }
 
                 let loop_label = sym.gen_sym("for_loop");
@@ -294,6 +301,7 @@ impl Stmt
                 out.push_str(&format!("jz {};\n", break_label));
 
                 body_stmt.gen_code(
+                    ret_type,
                     &Some(break_label.clone()),
                     &Some(cont_label.clone()),
                     sym,
@@ -310,7 +318,7 @@ impl Stmt
 
             Stmt::Block(stmts) => {
                 for stmt in stmts {
-                    stmt.gen_code(break_label, cont_label, sym, out)?;
+                    stmt.gen_code(ret_type, break_label, cont_label, sym, out)?;
                 }
             }
 
@@ -342,7 +350,7 @@ impl Expr
                         out.push_str(&format!("push {};\n", name));
                         match t {
                             Type::UInt(n) => out.push_str(&format!("load_u{};\n", n)),
-                            Type::Int(64) => out.push_str("load_u64;\n"),
+                            Type::Int(n) => out.push_str(&format!("load_u{};\n", n)),
                             Type::Pointer(_) => {}
                             Type::Fun { .. } => {}
                             Type::Array { .. } => {}
@@ -363,19 +371,16 @@ impl Expr
                 child.gen_code(sym, out)?;
 
                 match (&new_type, &child_type) {
-                    // Cast to a larger type
-                    (UInt(m), Int(n)) if m >= n => {},
-                    (Int(m), UInt(n)) if m >= n => {},
+                    (UInt(_) | Int(_), UInt(_) | Int(_)) => {
+                        gen_conv(&child_type, new_type, out);
+                    }
 
-                    (UInt(m), Int(n)) if m < n => {
-                        out.push_str(&format!("trunc_u{};\n", m));
-                    },
+                    (Pointer(_), Pointer(_)) => {},
 
-                    (Int(m), UInt(n)) if m < n => {
-                        out.push_str(&format!("trunc_u{};\n", m));
-                    },
+                    // Pointers and 64-bit integers have the same representation
+                    (Pointer(_), UInt(64) | Int(64)) => {},
+                    (UInt(64) | Int(64), Pointer(_)) => {},
 
-                    (Pointer(_), Pointer(_)) => {},
                     _ => todo!()
                 }
             }
@@ -383,6 +388,12 @@ impl Expr
             Expr::Unary { op, child } => {
                 child.gen_code(sym, out)?;
 
+                // Width of the integer operation to perform
+                let op_bits = match child.eval_type()? {
+                    Type::UInt(32) | Type::Int(32) => 32,
+                    _ => 64
+                };
+
                 match op {
                     UnOp::Deref => {
                         let ptr_type = child.eval_type()?;
@@ -394,17 +405,17 @@ impl Expr
                     UnOp::Minus => {
                         out.push_str(&format!("push 0;\n"));
                         out.push_str(&format!("swap;\n"));
-                        out.push_str(&format!("sub_u64;\n"));
+                        out.push_str(&format!("sub_u{};\n", op_bits));
                     }
 
                     UnOp::BitNot => {
-                        out.push_str("not_u64;\n");
+                        out.push_str(&format!("not_u{};\n", op_bits));
                     }
 
                     // Logical negation
                     UnOp::Not => {
                         out.push_str("push 0;\n");
-                        out.push_str("eq_u64;\n");
+                        out.push_str(&format!("eq_u{};\n", op_bits));
                     }
 
                     _ => todo!()
@@ -437,10 +448,10 @@ impl Expr
                 //callee.gen_code(out)?;
 
                 match callee.as_ref() {
-                    Expr::Ref(Decl::Fun { name, .. }) =>
+                    Expr::Ref(Decl::Fun { name, t: Type::Fun { param_types, .. } }) =>
                     {
-                        for arg in args {
-                            arg.gen_code(sym, out)?;
+                        for (arg, param_type) in args.iter().zip(param_types) {
+                            gen_expr_as(arg, param_type, sym, out)?;
                         }
 
                         out.push_str(&format!("call {}, {};\n", name, args.len()));
@@ -450,8 +461,14 @@ impl Expr
             }
 
             Expr::Asm { text, args, out_type } => {
+                // Arguments to asm expressions are passed as 64-bit values
                 for arg in args {
+                    let arg_type = arg.eval_type()?;
                     arg.gen_code(sym, out)?;
+
+                    if let Type::Int(_) = arg_type {
+                        gen_conv(&arg_type, &Type::Int(64), out);
+                    }
                 }
 
                 out.push_str(&text);
@@ -535,92 +552,126 @@ fn gen_bin_op(op: &BinOp, lhs: &Expr, rhs: &Expr, sym: &mut SymGen, out: &mut St
         return Ok(());
     }
 
+    let (lhs_type, rhs_type) = bin_operand_types(lhs, rhs)?;
+
+    // Pointer arithmetic
+    match (&lhs_type, &rhs_type, op) {
+        (Pointer(elem_type), UInt(_) | Int(_), Add) |
+        (Array { elem_type, .. }, UInt(_) | Int(_), Add) => {
+            lhs.gen_code(sym, out)?;
+            gen_expr_as(rhs, &UInt(64), sym, out)?;
+
+            let elem_sizeof = elem_type.sizeof();
+            out.push_str(&format!("push {};\n", elem_sizeof));
+            out.push_str("mul_u64;\n");
+            out.push_str("add_u64;\n");
+            return Ok(());
+        }
+
+        (Pointer(_), _, _) | (_, Pointer(_), _) |
+        (Array { .. }, _, _) | (_, Array { .. }, _) => {
+            lhs.gen_code(sym, out)?;
+            rhs.gen_code(sym, out)?;
+
+            match op {
+                Add => out.push_str("add_u64;\n"),
+                Sub => out.push_str("sub_u64;\n"),
+                Eq => out.push_str("eq_u64;\n"),
+                Ne => out.push_str("ne_u64;\n"),
+                Lt => out.push_str("lt_u64;\n"),
+                Le => out.push_str("le_u64;\n"),
+                Gt => out.push_str("gt_u64;\n"),
+                Ge => out.push_str("ge_u64;\n"),
+                _ => todo!("{:?}", op),
+            }
+
+            return Ok(());
+        }
+
+        _ => {}
+    }
+
+    // Operations on 32-bit integers use 32-bit instructions,
+    // everything else is widened to 64 bits
+    let op_type = match arith_type(&lhs_type, &rhs_type) {
+        Some(op_type) => op_type,
+        None => todo!("{:?} {} {}", op, lhs_type, rhs_type)
+    };
+
     lhs.gen_code(sym, out)?;
+    gen_conv(&lhs_type, &op_type, out);
     rhs.gen_code(sym, out)?;
+    gen_conv(&rhs_type, &op_type, out);
 
-    let lhs_type = lhs.eval_type()?;
-    let rhs_type = rhs.eval_type()?;
+    let (bits, sign) = match op_type {
+        Int(n) => (n, "i"),
+        UInt(n) => (n, "u"),
+        _ => unreachable!()
+    };
 
     match op {
         BitAnd => {
-            out.push_str("and_u64;\n");
+            out.push_str(&format!("and_u{};\n", bits));
         }
 
         BitOr => {
-            out.push_str("or_u64;\n");
+            out.push_str(&format!("or_u{};\n", bits));
         }
 
         BitXor => {
-            out.push_str("xor_u64;\n");
+            out.push_str(&format!("xor_u{};\n", bits));
         }
 
         LShift => {
-            out.push_str("lshift_u64;\n");
+            out.push_str(&format!("lshift_u{};\n", bits));
         }
 
         RShift => {
-            out.push_str("rshift_u64;\n");
+            out.push_str(&format!("rshift_{}{};\n", sign, bits));
         }
 
-        // For now we're ignoring the type
         Add => {
-            match (lhs_type, rhs_type) {
-                (Pointer(b), UInt(n)) | (Pointer(b), Int(n)) => {
-                    let elem_sizeof = b.sizeof();
-                    out.push_str(&format!("push {};\n", elem_sizeof));
-                    out.push_str("mul_u64;\n");
-                    out.push_str("add_u64;\n");
-                }
-
-                (Array{ elem_type , ..}, UInt(n)) | (Array{ elem_type , ..}, Int(n)) => {
-                    let elem_sizeof = elem_type.sizeof();
-                    out.push_str(&format!("push {};\n", elem_sizeof));
-                    out.push_str("mul_u64;\n");
-                    out.push_str("add_u64;\n");
-                }
-
-                _ => out.push_str("add_u64;\n")
-            }
+            out.push_str(&format!("add_u{};\n", bits));
         }
 
         Sub => {
-            out.push_str("sub_u64;\n");
+            out.push_str(&format!("sub_u{};\n", bits));
         }
 
         Mul => {
-            out.push_str("mul_u64;\n");
+            out.push_str(&format!("mul_u{};\n", bits));
         }
 
         Div => {
-            out.push_str("div_i64;\n");
+            out.push_str(&format!("div_{}{};\n", sign, bits));
         }
 
         Mod => {
-            out.push_str("mod_i64;\n");
+            out.push_str(&format!("mod_{}{};\n", sign, bits));
         }
 
         Eq => {
-            out.push_str("eq_u64;\n");
+            out.push_str(&format!("eq_u{};\n", bits));
         }
 
         Ne => {
-            out.push_str("ne_u64;\n");
+            out.push_str(&format!("ne_u{};\n", bits));
         }
 
         Lt => {
-            out.push_str("lt_i64;\n");
+            out.push_str(&format!("lt_{}{};\n", sign, bits));
         }
 
         Le => {
-            out.push_str("le_i64;\n");
+            out.push_str(&format!("le_{}{};\n", sign, bits));
         }
 
         Gt => {
-            out.push_str("gt_i64;\n");
+            out.push_str(&format!("gt_{}{};\n", sign, bits));
         }
 
         Ge => {
-            out.push_str("ge_i64;\n");
+            out.push_str(&format!("ge_{}{};\n", sign, bits));
         }
 
         _ => todo!("{:?}", op),
@@ -629,6 +680,112 @@ fn gen_bin_op(op: &BinOp, lhs: &Expr, rhs: &Expr, sym: &mut SymGen, out: &mut St
     Ok(())
 }
 
+/// Integer promotion: types narrower than int are promoted to int
+fn promote(t: &Type) -> Option<Type>
+{
+    match t {
+        Type::Int(n) | Type::UInt(n) if *n < 32 => Some(Type::Int(32)),
+        Type::Int(_) | Type::UInt(_) => Some(t.clone()),
+        _ => None
+    }
+}
+
+/// Type a binary operation on two integers is performed in, following
+/// the usual arithmetic conversions of C. With mixed signedness, the
+/// signed type is used only if it is strictly wider than the unsigned one.
+fn arith_type(lhs_type: &Type, rhs_type: &Type) -> Option<Type>
+{
+    use Type::*;
+
+    let op_type = match (promote(lhs_type)?, promote(rhs_type)?) {
+        (Int(m), Int(n)) => Int(max(m, n)),
+        (UInt(m), UInt(n)) => UInt(max(m, n)),
+        (Int(m), UInt(n)) | (UInt(n), Int(m)) if m > n => Int(m),
+        (Int(m), UInt(n)) | (UInt(n), Int(m)) => UInt(max(m, n)),
+        _ => unreachable!()
+    };
+
+    Some(op_type)
+}
+
+/// Generate code to convert an integer value from one type to another.
+/// Values with 32 bits or fewer are kept zero-extended in their stack slot,
+/// so narrowing truncates, and widening signed values sign-extends them.
+fn gen_conv(from_type: &Type, to_type: &Type, out: &mut String)
+{
+    use Type::*;
+
+    let from_bits = match from_type {
+        UInt(n) | Int(n) => *n,
+        _ => 64
+    };
+
+    let to_bits = match to_type {
+        UInt(n) | Int(n) => *n,
+        Pointer(_) => 64,
+        _ => return
+    };
+
+    if to_bits < from_bits {
+        out.push_str(&format!("trunc_u{};\n", to_bits));
+    }
+    else if to_bits > from_bits {
+        if let Int(n) = from_type {
+            out.push_str(&format!("sx_i{}_i{};\n", n, to_bits));
+        }
+    }
+}
+
+/// Generate code for an expression and convert its value to a given type
+fn gen_expr_as(expr: &Expr, to_type: &Type, sym: &mut SymGen, out: &mut String) -> Result<(), ParseError>
+{
+    // Integer constants can be truncated at compile time
+    if let (Expr::Int(v), Type::UInt(n) | Type::Int(n)) = (expr, to_type) {
+        if *n < 64 {
+            let mask = (1i128 << n) - 1;
+            out.push_str(&format!("push {};\n", v & mask));
+            return Ok(());
+        }
+    }
+
+    expr.gen_code(sym, out)?;
+    gen_conv(&expr.eval_type()?, to_type, out);
+    Ok(())
+}
+
+/// Check if a returned expression is a direct call to a function whose
+/// return value doesn't need to be converted, so that it can reuse the
+/// stack frame of the caller
+fn is_tail_call(expr: &Expr, ret_type: &Type) -> bool
+{
+    match expr {
+        Expr::Call { callee, .. } => match callee.as_ref() {
+            Expr::Ref(Decl::Fun { t: Type::Fun { ret_type: callee_ret, .. }, .. }) => callee_ret.eq(ret_type),
+            _ => false
+        }
+        _ => false
+    }
+}
+
+fn gen_tail_call(expr: &Expr, sym: &mut SymGen, out: &mut String) -> Result<(), ParseError>
+{
+    match expr {
+        Expr::Call { callee, args } => match callee.as_ref() {
+            Expr::Ref(Decl::Fun { name, t: Type::Fun { param_types, .. } }) => {
+                for (arg, param_type) in args.iter().zip(param_types) {
+                    gen_expr_as(arg, param_type, sym, out)?;
+                }
+
+                out.push_str(&format!("tail_call {}, {};\n", name, args.len()));
+            }
+            _ => unreachable!()
+        }
+        _ => unreachable!()
+    }
+
+    Ok(())
+}
+
 fn gen_assign(
     lhs: &Expr,
     rhs: &Expr,
@@ -645,13 +802,14 @@ fn gen_assign(
             match op {
                 UnOp::Deref => {
                     let ptr_type = child.eval_type()?;
-                    let elem_size = ptr_type.elem_type().sizeof();
+                    let elem_type = ptr_type.elem_type();
+                    let elem_size = elem_type.sizeof();
                     let elem_bits = elem_size * 8;
 
                     // If the output value is needed
                     if need_value {
                         // Evaluate the value expression
-                        rhs.gen_code(sym, out)?;
+                        gen_expr_as(rhs, &elem_type, sym, out)?;
 
                         // Evaluate the address expression
                         child.gen_code(sym, out)?;
@@ -664,7 +822,7 @@ fn gen_assign(
                         child.gen_code(sym, out)?;
 
                         // Evaluate the value expression
-                        rhs.gen_code(sym, out)?;
+                        gen_expr_as(rhs, &elem_type, sym, out)?;
                     }
 
                     // store (addr) (value)
@@ -676,13 +834,13 @@ fn gen_assign(
 
         Expr::Ref(decl) => {
             match decl {
-                Decl::Arg { idx, .. } => {
-                    rhs.gen_code(sym, out)?;
+                Decl::Arg { idx, t } => {
+                    gen_expr_as(rhs, t, sym, out)?;
                     if need_value { out.push_str("dup;\n"); }
                     out.push_str(&format!("set_arg {};\n", idx));
                 }
-                Decl::Local { idx, .. } => {
-                    rhs.gen_code(sym, out)?;
+                Decl::Local { idx, t } => {
+                    gen_expr_as(rhs, t, sym, out)?;
                     if need_value { out.push_str("dup;\n"); }
                     out.push_str(&format!("set_local {};\n", idx));
                 }
@@ -691,7 +849,7 @@ fn gen_assign(
                     // If the output value is needed
                     if need_value {
                         // Evaluate the value expression
-                        rhs.gen_code(sym, out)?;
+                        gen_expr_as(rhs, t, sym, out)?;
 
                         // Push the address
                         out.push_str(&format!("push {};\n", name));
@@ -704,12 +862,12 @@ fn gen_assign(
                         out.push_str(&format!("push {};\n", name));
 
                         // Evaluate the value expression
-                        rhs.gen_code(sym, out)?;
+                        gen_expr_as(rhs, t, sym, out)?;
                     }
 
                     match t {
                         Type::UInt(n) => out.push_str(&format!("store_u{};\n", n)),
-                        Type::Int(64) => out.push_str("store_u64;\n"),
+                        Type::Int(n) => out.push_str(&format!("store_u{};\n", n)),
                         _ => todo!()
                     }
                 }
@@ -804,12 +962,38 @@ mod tests
         gen_ok("i64 g = -77; void foo() { g = 1; }");
     }
 
+    #[test]
+    fn int32()
+    {
+        assert!(gen_ok("int foo(int a, int b) { return a + b; }").contains("add_u32;"));
+        assert!(gen_ok("int foo(int a) { return a + 1; }").contains("add_u32;"));
+        assert!(gen_ok("int foo(int a, int b) { return a / b; }").contains("div_i32;"));
+        assert!(gen_ok("u32 foo(u32 a, u32 b) { return a / b; }").contains("div_u32;"));
+        assert!(gen_ok("bool foo(int a, int b) { return a < b; }").contains("lt_i32;"));
+        assert!(gen_ok("bool foo(u32 a, u32 b) { return a < b; }").contains("lt_u32;"));
+        assert!(gen_ok("bool foo(u64 a, u64 b) { return a < b; }").contains("lt_u64;"));
+
+        // Widening and narrowing conversions
+        assert!(gen_ok("i64 foo(int a) { return a; }").contains("sx_i32_i64;"));
+        assert!(gen_ok("i64 foo(int a, i64 b) { return a + b; }").contains("sx_i32_i64;"));
+        assert!(gen_ok("int foo(i64 a) { return a; }").contains("trunc_u32;"));
+        gen_ok("i64 foo(int a) { return (i64)a; }");
+    }
+
     #[test]
     fn call_ret()
     {
         gen_ok("void foo() {} void bar() {}");
         gen_ok("void foo() {} void bar() { return foo(); } ");
         gen_ok("void print_i64(i64 v) {} void bar(u64 v) { print_i64(v); }");
+
+        // Calls in return position are tail calls
+        assert!(gen_ok("u64 foo(u64 n) { return n; } u64 bar(u64 n) { return foo(n + 1); }").contains("tail_call foo, 1;"));
+        assert!(gen_ok("u64 foo(u64 n) { if (n == 0) return 0; return foo(n - 1); }").contains("tail_call foo, 1;"));
+
+        // Not when the return value needs to be converted
+        assert!(!gen_ok("int foo() { return 1; } i64 bar() { return foo(); }").contains("tail_call"));
+        assert!(!gen_ok("u64 foo() { return 1; } u64 bar() { return foo() + 1; }").contains("tail_call"));
     }
 
     #[test]
@@ -18,14 +18,23 @@ fn compile_and_run(file_path: &str)
     let output = command.output().unwrap();
     assert!(output.status.success(), "compilation failed");
 
-    // Run the compiled program
-    let mut command = Command::new("cargo");
-    command.current_dir("../vm");
-    command.arg("run");
-    command.arg("../ncc/out.asm");
-    println!("{:?}", command);
-    let output = command.output().unwrap();
-    assert!(output.status.success(), "execution failed");
+    // Run the compiled program, with the interpreter and with the JIT
+    let mut outputs = Vec::new();
+    for jit in [false, true] {
+        let mut command = Command::new("cargo");
+        command.current_dir("../vm");
+        command.arg("run");
+        if jit {
+            command.arg("--");
+            command.arg("--jit");
+        }
+        command.arg("../ncc/out.asm");
+        println!("{:?}", command);
+        let output = command.output().unwrap();
+        assert!(output.status.success(), "execution failed");
+        outputs.push(output.stdout);
+    }
+    assert!(outputs[0] == outputs[1], "output differs with the JIT");
 }
 
 #[test]
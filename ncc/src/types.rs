@@ -39,12 +39,35 @@ fn assign_compat(lhs_type: &Type, rhs_type: &Type) -> bool
         // Assigning a function to a void pointer
         (Pointer(base_type), Fun { .. }) => base_type.eq(&Type::Void),
 
+        // Any pointer can be converted to and from a void pointer
+        (Pointer(lhs_base), Pointer(rhs_base)) if lhs_base.eq(&Type::Void) || rhs_base.eq(&Type::Void) => true,
+
         // TODO: we need to correctly handle signed vs unsigned, sign extension
 
         _ => lhs_type.eq(&rhs_type)
     }
 }
 
+/// Get the types of the operands of a binary operation.
+/// Integer literals combined with a 32-bit integer take on its type, as they
+/// would in C, so that `x + 1` remains a 32-bit operation when `x` is an int.
+pub fn bin_operand_types(lhs: &Expr, rhs: &Expr) -> Result<(Type, Type), ParseError>
+{
+    fn fits_32(v: i128) -> bool
+    {
+        i32::try_from(v).is_ok() || u32::try_from(v).is_ok()
+    }
+
+    let lhs_type = lhs.eval_type()?;
+    let rhs_type = rhs.eval_type()?;
+
+    match (lhs, rhs, &lhs_type, &rhs_type) {
+        (Expr::Int(v), _, _, Int(32) | UInt(32)) if fits_32(*v) => Ok((rhs_type.clone(), rhs_type)),
+        (_, Expr::Int(v), Int(32) | UInt(32), _) if fits_32(*v) => Ok((lhs_type.clone(), lhs_type)),
+        _ => Ok((lhs_type, rhs_type))
+    }
+}
+
 impl Unit
 {
     pub fn check_types(&mut self) -> Result<(), ParseError>
@@ -171,8 +194,14 @@ impl Expr
                 match (&new_type, &child_type) {
                     (UInt(m), Int(n)) => Ok(new_type.clone()),
                     (Int(m), UInt(n)) => Ok(new_type.clone()),
+                    (UInt(m), UInt(n)) => Ok(new_type.clone()),
+                    (Int(m), Int(n)) => Ok(new_type.clone()),
                     (Pointer(_), Pointer(_)) => Ok(new_type.clone()),
 
+                    // Casts between pointers and 64-bit integers
+                    (Pointer(_), UInt(64) | Int(64)) => Ok(new_type.clone()),
+                    (UInt(64) | Int(64), Pointer(_)) => Ok(new_type.clone()),
+
                     _ => ParseError::msg_only(&format!(
                         "cannot cast type {} into {}",
                         child_type,
@@ -203,8 +232,10 @@ impl Expr
             Expr::Binary { op, lhs, rhs } => {
                 use BinOp::*;
 
-                let lhs_type = lhs.eval_type()?;
-                let rhs_type = rhs.eval_type()?;
+                let (lhs_type, rhs_type) = match op {
+                    Assign | Comma => (lhs.eval_type()?, rhs.eval_type()?),
+                    _ => bin_operand_types(lhs, rhs)?
+                };
 
                 match op {
                     // TODO: we need to automatically insert type casting operations
@@ -0,0 +1,69 @@
+#include <assert.h>
+#include <stdint.h>
+
+int global_int = -5;
+unsigned int global_uint = 0xFFFFFFFF;
+
+int neg(int x)
+{
+    return -x;
+}
+
+long widen(int x)
+{
+    return x;
+}
+
+void main()
+{
+    // Unsigned 32-bit arithmetic wraps around
+    unsigned int u = 0xFFFFFFFF;
+    u = u + 1;
+    assert(u == 0);
+    u = u - 1;
+    assert(u == 0xFFFFFFFF);
+    assert(global_uint + 2 == 1);
+
+    // Signed 32-bit arithmetic
+    int a = -7;
+    int b = 2;
+    assert(a / b == -3);
+    assert(a % b == -1);
+    assert(a < b);
+    assert(a >> 1 == -4);
+    assert(neg(a) == 7);
+    assert(global_int + 5 == 0);
+
+    // Unsigned comparisons and division
+    unsigned int c = 0x80000000;
+    assert(c > 1);
+    assert(c / 2 == 0x40000000);
+    assert(c >> 31 == 1);
+
+    // Sign extension when widening to 64 bits
+    long w = widen(-1);
+    assert(w == -1);
+    assert(w < 0);
+    i64 s = a;
+    assert(s == -7);
+
+    // Truncation when narrowing to 32 bits
+    u64 big = 0x1_0000_0003;
+    unsigned int t = big;
+    assert(t == 3);
+    assert(((int)big) == 3);
+
+    // Mixed signedness: a wider signed type holds all the unsigned values
+    i64 m = -1;
+    unsigned int one = 1;
+    assert(m < one);
+
+    // Narrow types are promoted to int
+    int x = -1;
+    u8 y = 1;
+    assert(x < y);
+    assert(x + y == 0);
+
+    // Unsigned when the signed type isn't wider
+    assert(x > one);
+}
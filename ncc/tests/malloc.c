@@ -0,0 +1,38 @@
+#include <stdlib.h>
+#include <assert.h>
+
+void main()
+{
+    u64* a = (u64*)malloc(64);
+    assert(a != (u64*)0);
+    assert((((size_t)a) & 7) == 0);
+
+    // New memory is zero-filled
+    for (size_t i = 0; i < 8; ++i)
+        assert(a[i] == 0);
+
+    for (size_t i = 0; i < 8; ++i)
+        a[i] = i * i;
+
+    char* b = (char*)malloc(3);
+    u64* c = (u64*)malloc(8);
+    assert(((size_t)b) >= ((size_t)a) + 64);
+    assert(((size_t)c) >= ((size_t)b) + 3);
+    assert((((size_t)c) & 7) == 0);
+    *c = 1337;
+
+    for (size_t i = 0; i < 8; ++i)
+        assert(a[i] == i * i);
+    assert(*c == 1337);
+
+    // Sizes for which the end of the allocation wraps around
+    assert(malloc(SIZE_MAX) == (void*)0);
+    assert(malloc(SIZE_MAX - 7) == (void*)0);
+    assert(*c == 1337);
+
+    free(a);
+    free(b);
+    free(c);
+
+    exit(0);
+}
@@ -0,0 +1,33 @@
+#include <assert.h>
+
+// Runs in a single stack frame because of the tail call
+u64 sum(u64 n, u64 acc)
+{
+    if (n == 0)
+        return acc;
+
+    return sum(n - 1, acc + n);
+}
+
+bool is_odd(u64 n)
+{
+    if (n == 0)
+        return false;
+
+    return is_even(n - 1);
+}
+
+bool is_even(u64 n)
+{
+    if (n == 0)
+        return true;
+
+    return is_odd(n - 1);
+}
+
+void main()
+{
+    assert(sum(1000000, 0) == 500000500000);
+    assert(is_odd(77777));
+    assert(is_even(100000));
+}
@@ -52,7 +52,6 @@ LOOP_Y:
     add_u64; # Y * 800 + X
     push_u64 4;
     mul_u64; # (Y * 800 + X) * 4
-    dup;
 
     # Compute red color:
     # Y * 256 / 600
@@ -2,7 +2,8 @@ use std::fmt;
 use std::convert::{TryFrom};
 use std::collections::HashMap;
 use std::collections::HashSet;
-use crate::vm::{VM, MemBlock, Op};
+use crate::vm::{VM, MemBlock, Op, SymbolTable};
+use crate::sys::SysState;
 
 #[derive(Debug)]
 pub struct ParseError
@@ -281,6 +282,32 @@ impl Input
         return Ok(sign * val);
     }
 
+    /// Parse a floating-point literal, e.g. 1.5, -2e-3, inf or nan
+    fn parse_float(&mut self) -> Result<f64, ParseError>
+    {
+        let mut num_str = String::new();
+
+        loop
+        {
+            let ch = self.peek_ch();
+
+            if !ch.is_ascii_alphanumeric() && ch != '.' && ch != '-' && ch != '+' && ch != '_' {
+                break;
+            }
+
+            self.eat_ch();
+
+            if ch != '_' {
+                num_str.push(ch);
+            }
+        }
+
+        match num_str.parse::<f64>() {
+            Ok(val) => Ok(val),
+            Err(_) => self.parse_error("expected floating-point literal")
+        }
+    }
+
     /// Parse a string literal
     fn parse_str(&mut self) -> Result<String, ParseError>
     {
@@ -406,13 +433,35 @@ pub struct Assembler
     /// References to labels (name, position)
     label_refs: Vec<LabelRef>,
 
+    /// Labels that are called or have their address taken,
+    /// which we assume to be function entry points
+    fn_labels: HashSet<String>,
+
     /// Current section
     section: Section,
+
+    /// System state for the VM being assembled
+    sys_state: SysState,
+}
+
+impl Default for Assembler
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
 }
 
 impl Assembler
 {
     pub fn new() -> Self
+    {
+        Self::with_sys_state(SysState::new())
+    }
+
+    /// Create an assembler producing a VM with a given system state
+    /// The syscalls registered by the host can be called by name
+    pub fn with_sys_state(sys_state: SysState) -> Self
     {
         /*
         // Populate the available constants
@@ -432,6 +481,9 @@ impl Assembler
         for syscall in SYSCALL_DESCS {
             syscall_map.insert(syscall.name.to_string(), syscall.const_idx);
         }
+        for (name, const_idx) in sys_state.host_syscall_names() {
+            syscall_map.insert(name.to_string(), const_idx);
+        }
 
         Self {
             //const_map: const_map,
@@ -442,7 +494,9 @@ impl Assembler
             data: MemBlock::new(),
             label_defs: HashMap::default(),
             label_refs: Vec::default(),
+            fn_labels: HashSet::default(),
             section: Section::Code,
+            sys_state,
         }
     }
 
@@ -497,7 +551,16 @@ impl Assembler
             }
         }
 
-        Ok(VM::new(self.code, self.data, self.syscall_set))
+        // Keep the code labels for symbolic backtraces
+        // Labels are added in definition order
+        let mut code_labels: Vec<(&String, &LabelDef)> = self.label_defs.iter().filter(|(_, def)| def.section == Section::Code).collect();
+        code_labels.sort_by_key(|(_, def)| (def.line_no, def.col_no));
+        let mut symbols = SymbolTable::new();
+        for (name, def) in code_labels {
+            symbols.add_label(name, def.pos, self.fn_labels.contains(name));
+        }
+
+        Ok(VM::with_sys_state(self.sys_state, self.code, self.data, symbols))
     }
 
     pub fn parse_file(mut self, file_name: &str) -> Result<VM, ParseError>
@@ -789,6 +852,7 @@ impl Assembler
         match op_name.as_str() {
             "panic" => self.code.push_op(Op::panic),
             "nop" => self.code.push_op(Op::nop),
+            "breakpoint" => self.code.push_op(Op::breakpoint),
 
             "pop" => self.code.push_op(Op::pop),
             "dup" => self.code.push_op(Op::dup),
@@ -800,6 +864,8 @@ impl Assembler
                 self.code.push_u8(n);
             }
 
+            "get_argc" => self.code.push_op(Op::get_argc),
+
             "get_arg" => {
                 let idx: u8 = self.parse_int_arg(input)?;
                 self.code.push_op(Op::get_arg);
@@ -852,10 +918,26 @@ impl Assembler
                 self.code.push_u64(val);
             }
 
+            // Float literals are pushed as their bit pattern
+            "push_f32" => {
+                input.eat_ws()?;
+                let val = input.parse_float()? as f32;
+                self.code.push_op(Op::push_u32);
+                self.code.push_u32(val.to_bits());
+            }
+
+            "push_f64" => {
+                input.eat_ws()?;
+                let val = input.parse_float()?;
+                self.code.push_op(Op::push_u64);
+                self.code.push_u64(val.to_bits());
+            }
+
             // Push a pointer to a label
             "push_p32" => {
                 let label_name = input.parse_ident()?;
                 self.code.push_op(Op::push_u32);
+                self.fn_labels.insert(label_name.clone());
                 self.add_label_ref(input, label_name, LabelRefKind::Address32);
             }
 
@@ -870,20 +952,63 @@ impl Assembler
             "not_u64" => self.code.push_op(Op::not_u64),
             "lshift_u64" => self.code.push_op(Op::lshift_u64),
             "rshift_u64" => self.code.push_op(Op::rshift_u64),
+            "rshift_i64" => self.code.push_op(Op::rshift_i64),
 
             "add_u64" => self.code.push_op(Op::add_u64),
             "sub_u64" => self.code.push_op(Op::sub_u64),
             "mul_u64" => self.code.push_op(Op::mul_u64),
+            "div_u64" => self.code.push_op(Op::div_u64),
+            "mod_u64" => self.code.push_op(Op::mod_u64),
             "div_i64" => self.code.push_op(Op::div_i64),
             "mod_i64" => self.code.push_op(Op::mod_i64),
 
+            "add_u64_ovf" => self.code.push_op(Op::add_u64_ovf),
+            "sub_u64_ovf" => self.code.push_op(Op::sub_u64_ovf),
+            "mul_u64_ovf" => self.code.push_op(Op::mul_u64_ovf),
+            "add_i64_ovf" => self.code.push_op(Op::add_i64_ovf),
+            "sub_i64_ovf" => self.code.push_op(Op::sub_i64_ovf),
+            "mul_i64_ovf" => self.code.push_op(Op::mul_i64_ovf),
+            "mul_u64_wide" => self.code.push_op(Op::mul_u64_wide),
+            "mul_i64_wide" => self.code.push_op(Op::mul_i64_wide),
+
             "eq_u64" => self.code.push_op(Op::eq_u64),
             "ne_u64" => self.code.push_op(Op::ne_u64),
+            "lt_u64" => self.code.push_op(Op::lt_u64),
+            "le_u64" => self.code.push_op(Op::le_u64),
+            "gt_u64" => self.code.push_op(Op::gt_u64),
+            "ge_u64" => self.code.push_op(Op::ge_u64),
             "lt_i64" => self.code.push_op(Op::lt_i64),
             "le_i64" => self.code.push_op(Op::le_i64),
             "gt_i64" => self.code.push_op(Op::gt_i64),
             "ge_i64" => self.code.push_op(Op::ge_i64),
 
+            "and_u32" => self.code.push_op(Op::and_u32),
+            "or_u32" => self.code.push_op(Op::or_u32),
+            "xor_u32" => self.code.push_op(Op::xor_u32),
+            "not_u32" => self.code.push_op(Op::not_u32),
+            "lshift_u32" => self.code.push_op(Op::lshift_u32),
+            "rshift_u32" => self.code.push_op(Op::rshift_u32),
+            "rshift_i32" => self.code.push_op(Op::rshift_i32),
+
+            "add_u32" => self.code.push_op(Op::add_u32),
+            "sub_u32" => self.code.push_op(Op::sub_u32),
+            "mul_u32" => self.code.push_op(Op::mul_u32),
+            "div_u32" => self.code.push_op(Op::div_u32),
+            "mod_u32" => self.code.push_op(Op::mod_u32),
+            "div_i32" => self.code.push_op(Op::div_i32),
+            "mod_i32" => self.code.push_op(Op::mod_i32),
+
+            "eq_u32" => self.code.push_op(Op::eq_u32),
+            "ne_u32" => self.code.push_op(Op::ne_u32),
+            "lt_u32" => self.code.push_op(Op::lt_u32),
+            "le_u32" => self.code.push_op(Op::le_u32),
+            "gt_u32" => self.code.push_op(Op::gt_u32),
+            "ge_u32" => self.code.push_op(Op::ge_u32),
+            "lt_i32" => self.code.push_op(Op::lt_i32),
+            "le_i32" => self.code.push_op(Op::le_i32),
+            "gt_i32" => self.code.push_op(Op::gt_i32),
+            "ge_i32" => self.code.push_op(Op::ge_i32),
+
             "sx_i8_i32" => self.code.push_op(Op::sx_i8_i32),
             "sx_i8_i64" => self.code.push_op(Op::sx_i8_i64),
             "sx_i16_i32" => self.code.push_op(Op::sx_i16_i32),
@@ -894,6 +1019,37 @@ impl Assembler
             "trunc_u16" => self.code.push_op(Op::trunc_u16),
             "trunc_u32" => self.code.push_op(Op::trunc_u32),
 
+            "add_f32" => self.code.push_op(Op::add_f32),
+            "sub_f32" => self.code.push_op(Op::sub_f32),
+            "mul_f32" => self.code.push_op(Op::mul_f32),
+            "div_f32" => self.code.push_op(Op::div_f32),
+            "sqrt_f32" => self.code.push_op(Op::sqrt_f32),
+            "eq_f32" => self.code.push_op(Op::eq_f32),
+            "ne_f32" => self.code.push_op(Op::ne_f32),
+            "lt_f32" => self.code.push_op(Op::lt_f32),
+            "le_f32" => self.code.push_op(Op::le_f32),
+            "gt_f32" => self.code.push_op(Op::gt_f32),
+            "ge_f32" => self.code.push_op(Op::ge_f32),
+
+            "add_f64" => self.code.push_op(Op::add_f64),
+            "sub_f64" => self.code.push_op(Op::sub_f64),
+            "mul_f64" => self.code.push_op(Op::mul_f64),
+            "div_f64" => self.code.push_op(Op::div_f64),
+            "sqrt_f64" => self.code.push_op(Op::sqrt_f64),
+            "eq_f64" => self.code.push_op(Op::eq_f64),
+            "ne_f64" => self.code.push_op(Op::ne_f64),
+            "lt_f64" => self.code.push_op(Op::lt_f64),
+            "le_f64" => self.code.push_op(Op::le_f64),
+            "gt_f64" => self.code.push_op(Op::gt_f64),
+            "ge_f64" => self.code.push_op(Op::ge_f64),
+
+            "i32_to_f32" => self.code.push_op(Op::i32_to_f32),
+            "i64_to_f64" => self.code.push_op(Op::i64_to_f64),
+            "f32_to_i32" => self.code.push_op(Op::f32_to_i32),
+            "f64_to_i64" => self.code.push_op(Op::f64_to_i64),
+            "f32_to_f64" => self.code.push_op(Op::f32_to_f64),
+            "f64_to_f32" => self.code.push_op(Op::f64_to_f32),
+
             "load_u8" => self.code.push_op(Op::load_u8),
             "load_u16" => self.code.push_op(Op::load_u16),
             "load_u32" => self.code.push_op(Op::load_u32),
@@ -942,16 +1098,23 @@ impl Assembler
                 self.code.push_u16(syscall_idx);
             }
 
-            "call" => {
+            "call" | "tail_call" => {
                 let label_name = input.parse_ident()?;
                 input.expect_token(",")?;
                 let argc: u8 = self.parse_int_arg(input)?;
 
-                self.code.push_op(Op::call);
+                self.code.push_op(if op_name == "call" { Op::call } else { Op::tail_call });
+                self.fn_labels.insert(label_name.clone());
                 self.add_label_ref(input, label_name, LabelRefKind::Offset32(1));
                 self.code.push_u8(argc);
             }
 
+            "call_fp" => {
+                let argc: u8 = self.parse_int_arg(input)?;
+                self.code.push_op(Op::call_fp);
+                self.code.push_u8(argc);
+            }
+
             "ret" => self.code.push_op(Op::ret),
             "exit" => self.code.push_op(Op::exit),
 
@@ -1011,6 +1174,7 @@ impl Assembler
         // Assume that this must be a label reference
         let label_name = input.parse_ident()?;
         self.code.push_op(Op::push_u32);
+        self.fn_labels.insert(label_name.clone());
         self.add_label_ref(input, label_name, LabelRefKind::Address32);
 
         Ok(())
@@ -1040,7 +1204,8 @@ mod tests
     {
         dbg!(file_name);
         let asm = Assembler::new();
-        assert!(asm.parse_file(file_name).is_ok());
+        let vm = asm.parse_file(file_name).unwrap();
+        assert_eq!(vm.verify(), vec![]);
     }
 
     #[test]
@@ -1087,6 +1252,13 @@ mod tests
 
         // Callback label
         parse_ok("CB: ret; push_p32 CB; exit;");
+        parse_ok("CB: ret; push CB; call_fp 0; exit;");
+
+        // Function labels
+        parse_ok("call FN, 0; exit; FN: push 0; ret;");
+        parse_ok("push 1; call FN, 1; exit; FN: push 0; get_arg 0; tail_call FN, 1;");
+        parse_fails("tail_call FN 0; FN: push 0; ret;");
+        parse_fails("tail_call MISSING, 0;");
     }
 
     #[test]
@@ -1121,6 +1293,8 @@ mod tests
         parse_fails(". code");
         parse_fails("FOO: FOO: jmp FOO;");
         parse_fails("FOO: jmp BAR;");
+        parse_fails("push_f64 1.5.5;");
+        parse_fails("push_f32 x;");
         parse_fails("push_i8 555");
         parse_fails("push_i855;");
         parse_fails("push_i8 55; comment without hash");
@@ -0,0 +1,660 @@
+//! Interactive command-line debugger.
+//!
+//! Breakpoints are set by patching the one-byte `breakpoint` instruction
+//! into the code space. The debugger puts them in place before letting the
+//! program run, and puts back the original bytes whenever the program stops,
+//! so that the code it shows is always the program's own. They stay in
+//! place while the event loop runs, so that callbacks stop at them too.
+//! Single-stepping uses a fuel budget of one instruction.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use crate::vm::{VM, Op, MemBlock, ExitReason, SymbolTable};
+use crate::decode::decode_insn;
+use crate::sys::SysState;
+use crate::sys::constants::SYSCALL_DESCS;
+
+const HELP: &str = "\
+break <label|pc>     set a breakpoint (b)
+delete <label|pc>    remove a breakpoint (d)
+step                 execute one instruction (s)
+next                 execute one instruction, stepping over calls (n)
+continue             run until a breakpoint or the end of the program (c)
+stack                print the value stack
+frames               print the args and locals of each stack frame (bt)
+x <addr> [len]       hex dump a range of the heap
+dis [pc] [count]     disassemble around the pc, or from an address
+quit                 stop the program and exit (q)";
+
+/// Number of bytes dumped by x when no length is given
+const DEFAULT_DUMP_LEN: usize = 64;
+
+/// Number of instructions disassembled by dis when no count is given
+const DEFAULT_DIS_COUNT: usize = 8;
+
+/// Disassemble the instruction at a given address
+/// Returns its text and the address of the next instruction
+pub(crate) fn disasm(code: &MemBlock, symbols: &SymbolTable, sys_state: &SysState, pc: usize) -> (String, usize)
+{
+    let insn = decode_insn(code, pc);
+
+    // Push instructions are decoded as push_u64, but shown as written
+    let op = match insn.op {
+        Op::end_of_code => return ("<end of code>".to_string(), pc),
+        Op::invalid_op => return (format!(".u8 {}", insn.idx), insn.next_pc),
+        _ => {
+            let mut op_pc = pc;
+            Op::from_u8(code.read_pc::<u8>(&mut op_pc).unwrap()).unwrap()
+        }
+    };
+
+    if insn.op == Op::truncated {
+        return (format!("{:?} <truncated>", op), code.len());
+    }
+
+    let text = match op {
+        Op::getn | Op::get_arg | Op::set_arg | Op::get_local | Op::set_local | Op::call_fp => {
+            format!("{:?} {}", op, insn.idx)
+        }
+        Op::push_i8 => format!("push_i8 {}", insn.imm as i64),
+        Op::push_u32 | Op::push_u64 => format!("{:?} {}", op, insn.imm),
+        Op::jmp | Op::jz | Op::jnz => {
+            format!("{:?} {}", op, symbols.symbolize(insn.imm as usize))
+        }
+        Op::call | Op::tail_call => {
+            format!("{:?} {}, {}", op, symbols.symbolize(insn.imm as usize), insn.idx)
+        }
+        Op::syscall => {
+            let name = SYSCALL_DESCS.iter()
+                .map(|desc| (desc.name, desc.const_idx))
+                .chain(sys_state.host_syscall_names())
+                .find(|(_, const_idx)| *const_idx == insn.idx)
+                .map(|(name, _)| name.to_string());
+            format!("syscall {}", name.unwrap_or_else(|| insn.idx.to_string()))
+        }
+        _ => format!("{:?}", op)
+    };
+
+    (text, insn.next_pc)
+}
+
+/// Read a byte of the code space
+fn code_byte(vm: &VM, pc: usize) -> u8
+{
+    let mut pc = pc;
+    vm.code().read_pc(&mut pc).unwrap()
+}
+
+/// Address of the start of the function containing a code address
+fn fn_start(vm: &VM, pc: usize) -> usize
+{
+    match vm.symbols().lookup(pc) {
+        Some((_, offset)) => pc - offset,
+        None => 0
+    }
+}
+
+/// Check that a code address is the start of an instruction
+/// Instructions are decoded from the start of the function containing it
+pub(crate) fn is_insn_start(vm: &VM, pc: usize) -> bool
+{
+    if pc >= vm.code().len() {
+        return false;
+    }
+
+    let mut insn_pc = fn_start(vm, pc);
+    while insn_pc < pc {
+        insn_pc = disasm(vm.code(), vm.symbols(), &vm.sys_state, insn_pc).1;
+    }
+
+    insn_pc == pc
+}
+
+/// Parse a decimal or hexadecimal (0x) number
+pub(crate) fn parse_num(s: &str) -> Option<usize>
+{
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok()
+    }
+}
+
+/// Breakpoints set by a debugger, and execution control around them
+/// This is shared by the command-line debugger and the GDB stub
+#[derive(Default)]
+pub(crate) struct Breakpoints
+{
+    // Original byte at the address of each breakpoint
+    // The bytes are only replaced while the program isn't stopped
+    orig_bytes: BTreeMap<usize, u8>,
+
+    // True when the breakpoints are patched into the code
+    patched: bool,
+}
+
+impl Breakpoints
+{
+    pub fn add(&mut self, vm: &mut VM, pc: usize)
+    {
+        if self.orig_bytes.contains_key(&pc) {
+            return;
+        }
+
+        self.orig_bytes.insert(pc, code_byte(vm, pc));
+        if self.patched {
+            vm.write_code(pc, &[Op::breakpoint as u8]).unwrap();
+        }
+    }
+
+    /// Remove a breakpoint, returning false if there was none
+    pub fn remove(&mut self, vm: &mut VM, pc: usize) -> bool
+    {
+        match self.orig_bytes.remove(&pc) {
+            Some(byte) => {
+                if self.patched {
+                    vm.write_code(pc, &[byte]).unwrap();
+                }
+                true
+            }
+            None => false
+        }
+    }
+
+    pub fn contains(&self, pc: usize) -> bool
+    {
+        self.orig_bytes.contains_key(&pc)
+    }
+
+    /// Patch the breakpoints into the code, if they aren't already
+    fn insert_all(&mut self, vm: &mut VM)
+    {
+        if self.patched {
+            return;
+        }
+
+        for (pc, byte) in self.orig_bytes.iter_mut() {
+            *byte = code_byte(vm, *pc);
+            vm.write_code(*pc, &[Op::breakpoint as u8]).unwrap();
+        }
+        self.patched = true;
+    }
+
+    /// Put back the original bytes replaced by breakpoints
+    /// This is done whenever the program stops
+    pub fn remove_all(&mut self, vm: &mut VM)
+    {
+        if !self.patched {
+            return;
+        }
+
+        for (pc, byte) in self.orig_bytes.iter() {
+            vm.write_code(*pc, &[*byte]).unwrap();
+        }
+        self.patched = false;
+    }
+
+    /// Execute one instruction, or run the program until it stops, with
+    /// the breakpoints in place. The instruction at the pc is executed
+    /// even if there is a breakpoint there. If the call ends, the
+    /// breakpoints are left in place for the callbacks run after it.
+    fn run(&mut self, vm: &mut VM, single_step: bool) -> ExitReason
+    {
+        self.remove_all(vm);
+        let pc = vm.suspended_pc().unwrap();
+
+        // A breakpoint that is part of the program executes as a nop
+        let program_breakpoint = code_byte(vm, pc) == Op::breakpoint as u8;
+        if program_breakpoint {
+            vm.write_code(pc, &[Op::nop as u8]).unwrap();
+        }
+
+        // Execute the first instruction on its own, so that it
+        // doesn't hit the breakpoint that may be set there
+        vm.set_fuel(Some(1));
+        let mut exit_reason = vm.resume();
+
+        if program_breakpoint {
+            vm.write_code(pc, &[Op::breakpoint as u8]).unwrap();
+        }
+
+        if matches!(exit_reason, ExitReason::OutOfFuel) && !single_step {
+            self.insert_all(vm);
+            vm.set_fuel(None);
+            exit_reason = vm.resume();
+        }
+
+        if matches!(exit_reason, ExitReason::Breakpoint | ExitReason::OutOfFuel) {
+            self.remove_all(vm);
+        }
+        else
+        {
+            self.insert_all(vm);
+        }
+
+        vm.set_fuel(None);
+        exit_reason
+    }
+
+    /// Execute one instruction
+    pub fn step(&mut self, vm: &mut VM) -> ExitReason
+    {
+        self.run(vm, true)
+    }
+
+    /// Run until a breakpoint or the end of the call
+    pub fn cont(&mut self, vm: &mut VM) -> ExitReason
+    {
+        self.run(vm, false)
+    }
+}
+
+pub struct Debugger
+{
+    // Commands are read from stdin if there is no input
+    // The program can then read from stdin as well
+    input: Option<Box<dyn BufRead>>,
+    output: Box<dyn Write>,
+
+    breakpoints: Breakpoints,
+}
+
+impl Default for Debugger
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Debugger
+{
+    /// Create a debugger reading commands from stdin
+    pub fn new() -> Self
+    {
+        Self {
+            input: None,
+            output: Box::new(io::stdout()),
+            breakpoints: Breakpoints::default(),
+        }
+    }
+
+    /// Create a debugger with its own input and output
+    pub fn with_io(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self
+    {
+        Self {
+            input: Some(input),
+            output,
+            breakpoints: Breakpoints::default(),
+        }
+    }
+
+    /// Take control of a program that stopped at a breakpoint or ran out of
+    /// fuel, and run commands until it ends. Other exit reasons are returned
+    /// as they are. Returns None if the user quits.
+    pub fn stop(&mut self, vm: &mut VM, exit_reason: ExitReason) -> Option<ExitReason>
+    {
+        let mut exit_reason = exit_reason;
+
+        loop
+        {
+            if !matches!(exit_reason, ExitReason::Breakpoint | ExitReason::OutOfFuel) {
+                return Some(exit_reason);
+            }
+
+            // A callback may have stopped with the breakpoints in place
+            self.breakpoints.remove_all(vm);
+
+            let pc = vm.suspended_pc().unwrap();
+            let label = if matches!(exit_reason, ExitReason::Breakpoint) { "breakpoint" } else { "stopped" };
+            let (text, _) = disasm(vm.code(), vm.symbols(), &vm.sys_state, pc);
+            self.print(&format!("{} at {} (pc={}): {}", label, vm.symbols().symbolize(pc), pc, text));
+
+            exit_reason = self.run_commands(vm)?;
+        }
+    }
+
+    fn print(&mut self, text: &str)
+    {
+        writeln!(self.output, "{}", text).unwrap();
+    }
+
+    /// Read and run commands until one of them resumes the program
+    fn run_commands(&mut self, vm: &mut VM) -> Option<ExitReason>
+    {
+        loop
+        {
+            write!(self.output, "(uvm) ").unwrap();
+            self.output.flush().unwrap();
+
+            let mut line = String::new();
+            let num_read = match self.input.as_mut() {
+                Some(input) => input.read_line(&mut line),
+                None => io::stdin().read_line(&mut line)
+            };
+
+            if num_read.unwrap_or(0) == 0 {
+                return None;
+            }
+
+            let args: Vec<&str> = line.split_whitespace().collect();
+            if args.is_empty() {
+                continue;
+            }
+
+            match args[0] {
+                "break" | "b" | "delete" | "d" if args.len() == 2 => {
+                    let pc = match self.resolve(vm, args[1]) {
+                        Some(pc) => pc,
+                        None => {
+                            self.print(&format!("no label or code address {}", args[1]));
+                            continue;
+                        }
+                    };
+
+                    let sym = vm.symbols().symbolize(pc);
+                    if args[0].starts_with('b') {
+                        self.breakpoints.add(vm, pc);
+                        self.print(&format!("breakpoint set at {} (pc={})", sym, pc));
+                    }
+                    else if !self.breakpoints.remove(vm, pc) {
+                        self.print(&format!("no breakpoint at {}", sym));
+                    }
+                }
+
+                "step" | "s" => return Some(self.breakpoints.step(vm)),
+                "next" | "n" => return Some(self.next(vm)),
+                "continue" | "c" => return Some(self.breakpoints.cont(vm)),
+
+                "stack" => {
+                    let vals: Vec<String> = vm.stack_values().iter().map(|val| val.as_i64().to_string()).collect();
+                    self.print(&format!("stack: [{}]", vals.join(", ")));
+                }
+
+                "frames" | "bt" => {
+                    for (idx, frame) in vm.suspended_frames().iter().enumerate() {
+                        let args: Vec<String> = frame.args.iter().map(|val| val.as_i64().to_string()).collect();
+                        let locals: Vec<String> = frame.locals.iter().map(|val| val.as_i64().to_string()).collect();
+                        self.print(&format!("#{} {} (pc={})", idx, vm.symbols().symbolize(frame.pc), frame.pc));
+                        self.print(&format!("    args: [{}]", args.join(", ")));
+                        self.print(&format!("    locals: [{}]", locals.join(", ")));
+                    }
+                }
+
+                "x" if args.len() == 2 || args.len() == 3 => {
+                    let addr = parse_num(args[1]);
+                    let len = args.get(2).map_or(Some(DEFAULT_DUMP_LEN), |len| parse_num(len));
+                    match (addr, len) {
+                        (Some(addr), Some(len)) => self.dump(vm, addr, len),
+                        _ => self.print("usage: x <addr> [len]")
+                    }
+                }
+
+                "dis" if args.len() <= 3 => {
+                    let start = args.get(1).map(|pc| self.resolve(vm, pc));
+                    let count = args.get(2).map_or(Some(DEFAULT_DIS_COUNT), |count| parse_num(count));
+                    match (start, count) {
+                        (Some(None), _) | (_, None) => self.print("usage: dis [pc] [count]"),
+                        (Some(Some(start)), Some(count)) => self.disassemble(vm, start, count),
+                        (None, Some(count)) => {
+                            let pc = vm.suspended_pc().unwrap();
+                            self.disassemble_around(vm, pc, count);
+                        }
+                    }
+                }
+
+                "quit" | "q" => return None,
+                "help" | "h" => self.print(HELP),
+
+                _ => self.print("unknown command, type help for a list of commands")
+            }
+        }
+    }
+
+    /// Find the code address of a label or number
+    /// Addresses in the middle of an instruction are rejected
+    fn resolve(&self, vm: &VM, spec: &str) -> Option<usize>
+    {
+        let pc = parse_num(spec).or_else(|| vm.symbols().find(spec))?;
+
+        if !is_insn_start(vm, pc) {
+            return None;
+        }
+
+        Some(pc)
+    }
+
+    /// Step over call instructions, stopping when the call returns,
+    /// unless it reaches a breakpoint first
+    fn next(&mut self, vm: &mut VM) -> ExitReason
+    {
+        let pc = vm.suspended_pc().unwrap();
+        let op = Op::from_u8(code_byte(vm, pc));
+        if op != Some(Op::call) && op != Some(Op::call_fp) {
+            return self.breakpoints.step(vm);
+        }
+
+        let (_, ret_pc) = disasm(vm.code(), vm.symbols(), &vm.sys_state, pc);
+        let depth = vm.suspended_frames().len();
+        let had_breakpoint = self.breakpoints.contains(ret_pc);
+        self.breakpoints.add(vm, ret_pc);
+
+        let exit_reason = loop
+        {
+            let exit_reason = self.breakpoints.cont(vm);
+
+            // Keep going if the return address is reached by a recursive call
+            if matches!(exit_reason, ExitReason::Breakpoint) &&
+               vm.suspended_pc() == Some(ret_pc) &&
+               vm.suspended_frames().len() > depth {
+                continue;
+            }
+
+            break exit_reason;
+        };
+
+        if !had_breakpoint {
+            self.breakpoints.remove(vm, ret_pc);
+
+            // The temporary breakpoint is reported as a step
+            if matches!(exit_reason, ExitReason::Breakpoint) && vm.suspended_pc() == Some(ret_pc) {
+                return ExitReason::OutOfFuel;
+            }
+        }
+
+        exit_reason
+    }
+
+    /// Print a hex dump of a heap range, 16 bytes per line
+    fn dump(&mut self, vm: &mut VM, addr: usize, len: usize)
+    {
+        let bytes = match vm.get_heap_slice::<u8>(addr, len) {
+            Ok(bytes) => bytes.to_vec(),
+            Err(kind) => {
+                self.print(&format!("{}", kind));
+                return;
+            }
+        };
+
+        for (idx, chunk) in bytes.chunks(16).enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let text: String = chunk.iter().map(|byte| {
+                if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' }
+            }).collect();
+            self.print(&format!("{:08x}  {:<47}  |{}|", addr + idx * 16, hex.join(" "), text));
+        }
+    }
+
+    /// Disassemble a number of instructions from an address
+    fn disassemble(&mut self, vm: &VM, start: usize, count: usize)
+    {
+        let cur_pc = vm.suspended_pc();
+        let mut pc = start;
+
+        for _ in 0..count {
+            if pc >= vm.code().len() {
+                break;
+            }
+
+            let (text, next_pc) = disasm(vm.code(), vm.symbols(), &vm.sys_state, pc);
+            let marker = if Some(pc) == cur_pc { "=>" } else if self.breakpoints.contains(pc) { " *" } else { "  " };
+            self.print(&format!("{} {:6} {:<16} {}", marker, pc, vm.symbols().symbolize(pc), text));
+            pc = next_pc;
+        }
+    }
+
+    /// Disassemble the instructions before and after the pc
+    /// Instructions are decoded from the start of the function, since
+    /// bytecode can't be decoded backwards
+    fn disassemble_around(&mut self, vm: &VM, pc: usize, count: usize)
+    {
+        let mut pcs = Vec::new();
+        let mut insn_pc = fn_start(vm, pc);
+        while insn_pc < pc {
+            pcs.push(insn_pc);
+            insn_pc = disasm(vm.code(), vm.symbols(), &vm.sys_state, insn_pc).1;
+        }
+
+        // Show up to half of the instructions before the pc, unless the
+        // pc isn't on the instruction boundaries found
+        let start = if insn_pc == pc {
+            pcs.get(pcs.len().saturating_sub(count / 2)).copied().unwrap_or(pc)
+        }
+        else
+        {
+            pc
+        };
+
+        self.disassemble(vm, start, count);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::Cursor;
+    use crate::asm::Assembler;
+    use crate::test_util::SharedBuf;
+
+    /// Run a program under the debugger, stopping at the first instruction
+    /// Returns how the program ended, and the debugger output
+    fn debug(src: &str, commands: &str) -> (Option<ExitReason>, String)
+    {
+        let mut vm = Assembler::new().parse_str(src).unwrap();
+        let out = SharedBuf::default();
+        let mut debugger = Debugger::with_io(Box::new(Cursor::new(commands.to_string())), Box::new(out.clone()));
+
+        vm.set_fuel(Some(0));
+        let exit_reason = vm.call(0, &[]);
+        let exit_reason = debugger.stop(&mut vm, exit_reason);
+
+        let out = out.contents();
+        (exit_reason, out)
+    }
+
+    const FACT: &str = "push 3; call FACT, 1; exit; FACT: get_arg 0; push 1; le_i64; jz RECURSE; get_arg 0; ret; RECURSE: get_arg 0; push 1; sub_u64; call FACT, 1; get_arg 0; mul_u64; ret;";
+
+    fn exits_with(exit_reason: Option<ExitReason>, expected: u64) -> bool
+    {
+        matches!(exit_reason, Some(ExitReason::Exit(val)) if val.as_u64() == expected)
+    }
+
+    #[test]
+    fn test_breakpoints()
+    {
+        let (exit_reason, out) = debug(FACT, "b FACT\nc\nc\nbt\nstack\nd FACT\nc\n");
+        assert!(exits_with(exit_reason, 6));
+        assert!(out.contains("breakpoint set at FACT"));
+        assert!(out.contains("breakpoint at FACT (pc=9): get_arg 0"));
+        assert!(out.contains("#0 FACT (pc=9)\n    args: [2]\n    locals: []\n#1 FACT+0x16 (pc=31)\n    args: [3]"));
+        assert!(out.contains("stack: [3, 2]"));
+
+        // Breakpoints by address, and errors
+        let (exit_reason, out) = debug(FACT, "b 0x19\nb FOO\nb 0x1a\nd 0\nc\nstack\nq\n");
+        assert!(exit_reason.is_none());
+        assert!(out.contains("no label or code address FOO"));
+        assert!(out.contains("no label or code address 0x1a"));
+        assert!(out.contains("no breakpoint at 0x0"));
+        assert!(out.contains("breakpoint at FACT+0x10 (pc=25): call FACT, 1"));
+        assert!(out.contains("stack: [3, 2]"));
+
+        // Breakpoint instruction in the program
+        let (exit_reason, out) = debug("push 1; breakpoint; push 2; add_u64; exit;", "c\nc\n");
+        assert!(exits_with(exit_reason, 3));
+        assert!(out.contains("breakpoint at 0x1 (pc=1): breakpoint"));
+
+        // Breakpoints can't be set inside an instruction
+        let (exit_reason, out) = debug("push_0; pop; push_u32 5; exit;", "b 3\nc\n");
+        assert!(exits_with(exit_reason, 5));
+        assert!(out.contains("no label or code address 3"));
+    }
+
+    #[test]
+    fn test_callback_breakpoints()
+    {
+        let mut vm = Assembler::new().parse_str("push 0; ret; CB: push 7; ret;").unwrap();
+        let out = SharedBuf::default();
+        let commands = "b CB\nc\nc\nd CB\nc\n";
+        let mut debugger = Debugger::with_io(Box::new(Cursor::new(commands.to_string())), Box::new(out.clone()));
+
+        vm.set_fuel(Some(0));
+        let exit_reason = vm.call(0, &[]);
+        assert!(matches!(debugger.stop(&mut vm, exit_reason), Some(ExitReason::Return(_))));
+
+        // Callbacks run by the event loop stop at the breakpoint, every time
+        let cb = vm.symbols().find("CB").unwrap();
+        for _ in 0..2 {
+            let exit_reason = vm.call_event(cb as u64, &[]);
+            assert!(matches!(exit_reason, ExitReason::Breakpoint));
+            let exit_reason = debugger.stop(&mut vm, exit_reason);
+            assert!(matches!(exit_reason, Some(ExitReason::Return(val)) if val.as_u64() == 7));
+        }
+
+        let out = out.contents();
+        assert_eq!(out.matches("breakpoint at CB (pc=2): push_i8 7").count(), 2);
+
+        // Once deleted, the breakpoint is gone from the code
+        assert_eq!(code_byte(&vm, cb), Op::push_i8 as u8);
+        assert!(matches!(vm.call_event(cb as u64, &[]), ExitReason::Return(_)));
+    }
+
+    #[test]
+    fn test_step()
+    {
+        let src = "push 1; push 2; call ADD, 2; push 7; exit; ADD: get_arg 0; get_arg 1; add_u64; ret;";
+        let (exit_reason, out) = debug(src, "s\ns\nn\nstack\ns\nstack\nq\n");
+        assert!(exit_reason.is_none());
+        assert!(out.contains("stopped at 0x2 (pc=2): call ADD, 2\n(uvm) stopped at 0x8 (pc=8): push_i8 7"));
+        assert!(out.contains("stack: [3]\n"));
+        assert!(out.contains("stack: [3, 7]\n"));
+        assert!(!out.contains("stopped at ADD"));
+
+        // Stepping into a call, and stepping over a call with a breakpoint in it
+        let (exit_reason, out) = debug(src, "s\ns\ns\nstack\nc\n");
+        assert!(exits_with(exit_reason, 7));
+        assert!(out.contains("stopped at ADD (pc=11): get_arg 0\n(uvm) stack: [1, 2]"));
+        let (exit_reason, out) = debug(src, "b ADD\ns\ns\nn\nbt\nc\n");
+        assert!(exits_with(exit_reason, 7));
+        assert!(out.contains("breakpoint at ADD (pc=11)"));
+        assert!(out.contains("args: [1, 2]"));
+
+        // Stepping over a recursive call
+        let (exit_reason, out) = debug(FACT, "b RECURSE\nc\nd RECURSE\ns\ns\ns\nn\nbt\nc\n");
+        assert!(exits_with(exit_reason, 6));
+        assert!(out.contains("#0 FACT+0x16 (pc=31)\n    args: [3]\n    locals: [2]\n#1 0x8"));
+    }
+
+    #[test]
+    fn test_dump_dis()
+    {
+        let src = ".data; .stringz \"hi\"; .code; push 0; exit; FN: push 1; ret;";
+        let (exit_reason, out) = debug(src, "x 0 3\nx 100000 4\ndis\ndis FN 1\nfoo\nq\n");
+        assert!(exit_reason.is_none());
+        assert!(out.contains("00000000  68 69 00"));
+        assert!(out.contains("|hi.|"));
+        assert!(out.contains("past end of heap"));
+        assert!(out.contains("=>      0 0x0              push_0\n        1 0x1              exit\n        2 FN               push_1\n"));
+        assert!(out.contains("(uvm)         2 FN               push_1\n(uvm)"));
+        assert!(out.contains("unknown command"));
+    }
+}
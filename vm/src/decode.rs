@@ -0,0 +1,346 @@
+use crate::vm::{Op, MemBlock};
+
+/// Maximum number of bytes that a decoded instruction can span,
+/// including the instructions fused into a superinstruction.
+/// When code is written, the instructions starting this many bytes
+/// before the write need to be decoded again.
+pub const MAX_DECODE_SPAN: usize = 16;
+
+/// Pre-decoded instruction executed by the interpreter
+/// Instructions can be decoded at any byte address, so that jumps
+/// and calls can target any address as they would in the bytecode.
+#[derive(Copy, Clone, Debug)]
+pub struct Insn
+{
+    pub op: Op,
+
+    // Small immediate: local/argument index, argument count,
+    // syscall index, or the byte of an invalid opcode
+    pub idx: u16,
+
+    // Large immediate: constant value, absolute jump or call target,
+    // or the second local index of get_local2
+    pub imm: u64,
+
+    // Address of the next instruction
+    pub next_pc: usize,
+}
+
+impl Insn
+{
+    /// Number of bytecode instructions this instruction stands for
+    /// Each of them consumes one unit of fuel
+    pub fn num_ops(&self) -> u64
+    {
+        match self.op {
+            Op::get_local_add_imm => 3,
+            Op::get_local2 | Op::load_u64_imm => 2,
+            Op::lt_i64_jz | Op::lt_i64_jnz | Op::lt_u64_jz | Op::lt_u64_jnz => 2,
+            _ => 1
+        }
+    }
+}
+
+/// Compute the absolute target of a jump or call
+/// Targets past the end of the code are kept, since the code space can
+/// grow to include them, and are resolved when they are jumped to.
+/// Negative targets wrap around to addresses that are never valid.
+fn jump_target(next_pc: usize, offset: i32) -> u64
+{
+    (next_pc as i64 + offset as i64) as u64
+}
+
+/// Decode a single bytecode instruction at a given address
+/// All the push instructions are decoded as push_u64
+pub fn decode_insn(code: &MemBlock, pc: usize) -> Insn
+{
+    let mut insn = Insn { op: Op::end_of_code, idx: 0, imm: 0, next_pc: pc };
+
+    if pc >= code.len() {
+        return insn;
+    }
+
+    let op_byte = code.read_pc::<u8>(&mut insn.next_pc).unwrap();
+    insn.op = match Op::from_u8(op_byte) {
+        Some(op) => op,
+        None => {
+            insn.op = Op::invalid_op;
+            insn.idx = op_byte as u16;
+            return insn;
+        }
+    };
+
+    if insn.next_pc + insn.op.imm_size() > code.len() {
+        insn.op = Op::truncated;
+        return insn;
+    }
+
+    let next_pc = &mut insn.next_pc;
+
+    match insn.op {
+        Op::getn | Op::get_arg | Op::set_arg | Op::get_local | Op::set_local | Op::call_fp => {
+            insn.idx = code.read_pc::<u8>(next_pc).unwrap() as u16;
+        }
+
+        Op::push_0 | Op::push_1 | Op::push_2 => {
+            insn.imm = (insn.op as u8 - Op::push_0 as u8) as u64;
+            insn.op = Op::push_u64;
+        }
+
+        Op::push_i8 => {
+            insn.imm = code.read_pc::<i8>(next_pc).unwrap() as i64 as u64;
+            insn.op = Op::push_u64;
+        }
+
+        Op::push_u32 => {
+            insn.imm = code.read_pc::<u32>(next_pc).unwrap() as u64;
+            insn.op = Op::push_u64;
+        }
+
+        Op::push_u64 => {
+            insn.imm = code.read_pc::<u64>(next_pc).unwrap();
+        }
+
+        Op::jmp | Op::jz | Op::jnz => {
+            let offset = code.read_pc::<i32>(next_pc).unwrap();
+            insn.imm = jump_target(*next_pc, offset);
+        }
+
+        // call <offset:i32> <num_args:u8>
+        Op::call | Op::tail_call => {
+            let offset = code.read_pc::<i32>(next_pc).unwrap();
+            insn.idx = code.read_pc::<u8>(next_pc).unwrap() as u16;
+            insn.imm = jump_target(*next_pc, offset);
+        }
+
+        Op::syscall => {
+            insn.idx = code.read_pc::<u16>(next_pc).unwrap();
+        }
+
+        _ => {}
+    }
+
+    insn
+}
+
+/// Decode the instruction at a given address, fusing it with the
+/// instructions that follow into a superinstruction where possible
+pub fn decode_fused(code: &MemBlock, pc: usize) -> Insn
+{
+    let insn = decode_insn(code, pc);
+
+    match insn.op {
+        Op::get_local => {
+            let insn2 = decode_insn(code, insn.next_pc);
+
+            if insn2.op == Op::get_local {
+                return Insn { op: Op::get_local2, idx: insn.idx, imm: insn2.idx as u64, next_pc: insn2.next_pc };
+            }
+
+            if insn2.op == Op::push_u64 {
+                let insn3 = decode_insn(code, insn2.next_pc);
+
+                if insn3.op == Op::add_u64 {
+                    return Insn { op: Op::get_local_add_imm, idx: insn.idx, imm: insn2.imm, next_pc: insn3.next_pc };
+                }
+            }
+        }
+
+        Op::push_u64 => {
+            let insn2 = decode_insn(code, insn.next_pc);
+
+            if insn2.op == Op::load_u64 {
+                return Insn { op: Op::load_u64_imm, idx: 0, imm: insn.imm, next_pc: insn2.next_pc };
+            }
+        }
+
+        Op::lt_i64 | Op::lt_u64 => {
+            let insn2 = decode_insn(code, insn.next_pc);
+
+            let op = match (insn.op, insn2.op) {
+                (Op::lt_i64, Op::jz) => Op::lt_i64_jz,
+                (Op::lt_i64, Op::jnz) => Op::lt_i64_jnz,
+                (Op::lt_u64, Op::jz) => Op::lt_u64_jz,
+                (Op::lt_u64, Op::jnz) => Op::lt_u64_jnz,
+                _ => return insn
+            };
+
+            return Insn { op, idx: 0, imm: insn2.imm, next_pc: insn2.next_pc };
+        }
+
+        _ => {}
+    }
+
+    insn
+}
+
+/// Instructions decoded the first time they execute
+/// Each address maps to an index into a dense list of decoded
+/// instructions, so code that never runs only takes 4 bytes per byte
+pub struct InsnCache
+{
+    // Position of the instruction decoded at each address plus one,
+    // or 0 if it isn't decoded. There is one more entry than there
+    // are code bytes, for the end of the code
+    index: Vec<u32>,
+
+    // Decoded instructions, including those invalidated by code writes
+    insns: Vec<Insn>,
+}
+
+impl InsnCache
+{
+    pub fn new(code: &MemBlock) -> Self
+    {
+        Self {
+            index: vec![0; code.len() + 1],
+            insns: Vec::new(),
+        }
+    }
+
+    /// Get the instruction at a given address, decoding it if needed
+    /// Addresses past the end of the code get the end of the code
+    #[inline(always)]
+    pub fn get(&mut self, code: &MemBlock, pc: usize) -> Insn
+    {
+        let pc = pc.min(code.len());
+
+        match self.index[pc] {
+            0 => self.decode(code, pc),
+            idx => self.insns[idx as usize - 1]
+        }
+    }
+
+    #[cold]
+    fn decode(&mut self, code: &MemBlock, pc: usize) -> Insn
+    {
+        // Start over once invalidated instructions could fill the
+        // list, which keeps it no longer than the index
+        if self.insns.len() >= self.index.len().min(u32::MAX as usize) {
+            self.index.fill(0);
+            self.insns.clear();
+        }
+
+        let insn = decode_fused(code, pc);
+        self.insns.push(insn);
+        self.index[pc] = self.insns.len() as u32;
+        insn
+    }
+
+    /// Forget the instructions starting in a range of addresses after
+    /// the code was written to or grown, resizing the index to the code
+    pub fn invalidate(&mut self, code: &MemBlock, start: usize, end: usize)
+    {
+        self.index.resize(code.len() + 1, 0);
+
+        let end = end.min(self.index.len());
+        if start < end {
+            self.index[start..end].fill(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_decode()
+    {
+        let mut code = MemBlock::new();
+        code.push_op(Op::push_i8);
+        code.push_i8(-3);
+        code.push_op(Op::jmp);
+        code.push_i32(-7);
+        code.push_op(Op::jz);
+        code.push_i32(-13);
+
+        let insn = decode_insn(&code, 0);
+        assert_eq!(insn.op, Op::push_u64);
+        assert_eq!(insn.imm, -3i64 as u64);
+        assert_eq!(insn.next_pc, 2);
+
+        let insn = decode_insn(&code, 2);
+        assert_eq!(insn.op, Op::jmp);
+        assert_eq!(insn.imm, 0);
+
+        // Out of bounds targets are resolved when they are jumped to
+        let insn = decode_insn(&code, 7);
+        assert_eq!(insn.op, Op::jz);
+        assert_eq!(insn.imm, u64::MAX);
+
+        // Addresses inside of instructions are also decoded
+        assert_eq!(decode_insn(&code, 1).op, Op::invalid_op);
+        assert_eq!(decode_insn(&code, 1).idx, 0xfd);
+        assert_eq!(decode_insn(&code, 12).op, Op::end_of_code);
+
+        let mut code = MemBlock::new();
+        code.push_op(Op::push_u32);
+        code.push_u16(0);
+        assert_eq!(decode_insn(&code, 0).op, Op::truncated);
+    }
+
+    #[test]
+    fn test_fused()
+    {
+        let mut code = MemBlock::new();
+        code.push_op(Op::get_local);
+        code.push_u8(1);
+        code.push_op(Op::push_1);
+        code.push_op(Op::add_u64);
+        code.push_op(Op::lt_i64);
+        code.push_op(Op::jnz);
+        code.push_i32(-10);
+
+        let insn = decode_fused(&code, 0);
+        assert_eq!(insn.op, Op::get_local_add_imm);
+        assert_eq!((insn.idx, insn.imm, insn.next_pc), (1, 1, 4));
+        assert_eq!(insn.num_ops(), 3);
+
+        let insn = decode_fused(&code, 4);
+        assert_eq!(insn.op, Op::lt_i64_jnz);
+        assert_eq!((insn.imm, insn.next_pc), (0, 10));
+
+        // The jump by itself is not fused
+        assert_eq!(decode_fused(&code, 5).op, Op::jnz);
+    }
+
+    #[test]
+    fn test_insn_cache()
+    {
+        let mut code = MemBlock::new();
+        code.push_op(Op::push_i8);
+        code.push_i8(5);
+        code.push_op(Op::exit);
+
+        let mut insns = InsnCache::new(&code);
+        assert_eq!(insns.get(&code, 0).imm, 5);
+        assert_eq!(insns.get(&code, 2).op, Op::exit);
+        assert_eq!(insns.get(&code, 100).op, Op::end_of_code);
+        assert_eq!(insns.insns.len(), 3);
+
+        // Cached instructions are reused until they are invalidated
+        let mut code = MemBlock::new();
+        code.push_op(Op::push_i8);
+        code.push_i8(7);
+        code.push_op(Op::exit);
+        assert_eq!(insns.get(&code, 0).imm, 5);
+        insns.invalidate(&code, 0, 2);
+        assert_eq!(insns.get(&code, 0).imm, 7);
+        assert_eq!(insns.insns.len(), 4);
+
+        // Growing the code moves the end of the code
+        code.push_op(Op::nop);
+        insns.invalidate(&code, 0, 4);
+        assert_eq!(insns.get(&code, 3).op, Op::nop);
+        assert_eq!(insns.get(&code, 4).op, Op::end_of_code);
+
+        // The list starts over instead of growing past the index
+        for _ in 0..10 {
+            insns.invalidate(&code, 0, 5);
+            assert_eq!(insns.get(&code, 0).imm, 7);
+        }
+        assert!(insns.insns.len() <= 5);
+    }
+}
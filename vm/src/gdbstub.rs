@@ -0,0 +1,511 @@
+//! Stub for the GDB remote serial protocol, so that existing debugger
+//! front ends can debug UVM programs over a TCP connection.
+//!
+//! The code and the heap are separate address spaces in UVM. GDB sees them
+//! as one, with the heap at address 0 and the code at `CODE_BASE`. The pc
+//! is reported as a code address. The registers are pseudo-registers
+//! describing the innermost stack frame, see `REG_NAMES`.
+//!
+//! Interrupting a running program with Ctrl-C is not supported. Programs
+//! stop at breakpoints, after a single step, or when they end.
+
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use crate::vm::{VM, ExitReason};
+use crate::debugger::{Breakpoints, is_insn_start};
+
+/// Address of the code space in the address space seen by GDB
+pub const CODE_BASE: u64 = 1 << 48;
+
+/// Names of the pseudo-registers, in the order GDB reads them
+/// All of them are 64 bits wide
+/// - pc: address of the next instruction, in the code space
+/// - bp: base pointer of the innermost frame, as a position on the stack
+/// - sp: stack depth, in values
+/// - argc: argument count of the innermost frame
+/// - frames: number of frames of the call in progress
+pub const REG_NAMES: [&str; 5] = ["pc", "bp", "sp", "argc", "frames"];
+
+/// Signal reported when the program stops at a breakpoint or after a step
+const SIGTRAP: u8 = 5;
+
+/// Signal reported when the program is stopped by a fault
+const SIGSEGV: u8 = 11;
+
+/// Describe the registers to GDB
+fn target_xml() -> String
+{
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n");
+    xml.push_str("<target version=\"1.0\">\n<feature name=\"org.uvm.core\">\n");
+
+    for (idx, name) in REG_NAMES.iter().enumerate() {
+        let reg_type = if idx == 0 { "code_ptr" } else { "uint64" };
+        xml.push_str(&format!("<reg name=\"{}\" bitsize=\"64\" type=\"{}\" regnum=\"{}\"/>\n", name, reg_type, idx));
+    }
+
+    xml.push_str("</feature>\n</target>\n");
+    xml
+}
+
+fn to_hex(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>>
+{
+    // An odd number of digits leaves a partial byte, which fails
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parse an "addr,len" pair of hex numbers
+fn parse_addr_len(s: &str) -> Option<(u64, usize)>
+{
+    let (addr, len) = s.split_once(',')?;
+    Some((u64::from_str_radix(addr, 16).ok()?, usize::from_str_radix(len, 16).ok()?))
+}
+
+/// Map a GDB address to the code space, if it is in it
+fn code_addr(addr: u64) -> Option<usize>
+{
+    if addr >= CODE_BASE {
+        Some((addr - CODE_BASE) as usize)
+    }
+    else
+    {
+        None
+    }
+}
+
+/// Values of the pseudo-registers of a suspended program
+fn read_regs(vm: &VM) -> [u64; 5]
+{
+    let frames = vm.suspended_frames();
+    let top = &frames[0];
+
+    [
+        CODE_BASE + top.pc as u64,
+        top.bp as u64,
+        vm.stack_values().len() as u64,
+        top.args.len() as u64,
+        frames.len() as u64,
+    ]
+}
+
+/// Read bytes from the code space or the heap
+fn read_mem(vm: &mut VM, addr: u64, len: usize) -> Option<Vec<u8>>
+{
+    match code_addr(addr) {
+        Some(pc) => {
+            if pc.checked_add(len)? > vm.code().len() {
+                return None;
+            }
+
+            let mut pc = pc;
+            (0..len).map(|_| vm.code().read_pc::<u8>(&mut pc).ok()).collect()
+        }
+        None => vm.get_heap_slice::<u8>(addr as usize, len).ok().map(|bytes| bytes.to_vec())
+    }
+}
+
+/// Write bytes into the code space or the heap
+fn write_mem(vm: &mut VM, addr: u64, bytes: &[u8]) -> Option<()>
+{
+    match code_addr(addr) {
+        Some(pc) => vm.write_code(pc, bytes).ok(),
+        None => {
+            let heap_bytes = vm.get_heap_slice::<u8>(addr as usize, bytes.len()).ok()?;
+            heap_bytes.copy_from_slice(bytes);
+            Some(())
+        }
+    }
+}
+
+pub struct GdbStub
+{
+    input: BufReader<TcpStream>,
+    output: TcpStream,
+
+    breakpoints: Breakpoints,
+
+    // GDB is waiting for the program to stop after a continue or step command
+    resumed: bool,
+}
+
+impl GdbStub
+{
+    /// Create a stub talking to GDB over a connected socket
+    pub fn new(stream: TcpStream) -> io::Result<Self>
+    {
+        // Packets are small and each one waits for a reply
+        stream.set_nodelay(true)?;
+
+        Ok(Self {
+            input: BufReader::new(stream.try_clone()?),
+            output: stream,
+            breakpoints: Breakpoints::default(),
+            resumed: false,
+        })
+    }
+
+    /// Take control of a program that stopped at a breakpoint or ran out of
+    /// fuel, and serve GDB until the program ends. Other exit reasons are
+    /// reported to GDB if the program ended. Returns None if GDB kills the
+    /// program or disconnects.
+    pub fn stop(&mut self, vm: &mut VM, exit_reason: ExitReason) -> Option<ExitReason>
+    {
+        let mut exit_reason = exit_reason;
+
+        loop
+        {
+            match exit_reason {
+                // A callback may have stopped with the breakpoints in place
+                ExitReason::Breakpoint | ExitReason::OutOfFuel => self.breakpoints.remove_all(vm),
+
+                // Keep GDB waiting while the event loop runs
+                ExitReason::Return(_) => return Some(exit_reason),
+
+                ExitReason::Exit(val) => {
+                    self.send(&format!("W{:02x}", val.as_u64() as u8)).ok()?;
+                    return Some(exit_reason);
+                }
+
+                ExitReason::Fault { .. } => {
+                    self.send(&format!("X{:02x}", SIGSEGV)).ok()?;
+                    return Some(exit_reason);
+                }
+            }
+
+            if self.resumed {
+                self.resumed = false;
+                self.send(&format!("S{:02x}", SIGTRAP)).ok()?;
+            }
+
+            exit_reason = match self.serve(vm) {
+                Ok(exit_reason) => exit_reason?,
+                Err(_) => return None
+            };
+
+            self.resumed = true;
+        }
+    }
+
+    /// Read a packet, skipping acknowledgments
+    fn recv(&mut self) -> io::Result<String>
+    {
+        let mut byte = [0; 1];
+
+        loop
+        {
+            // Wait for the start of a packet
+            loop
+            {
+                self.input.read_exact(&mut byte)?;
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+
+            let mut data = Vec::new();
+            loop
+            {
+                self.input.read_exact(&mut byte)?;
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+
+            let mut checksum = [0; 2];
+            self.input.read_exact(&mut checksum)?;
+            let expected = from_hex(&String::from_utf8_lossy(&checksum)).map(|bytes| bytes[0]);
+            let actual = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+
+            // Ask for the packet to be sent again if it is corrupt
+            if expected != Some(actual) {
+                self.output.write_all(b"-")?;
+                continue;
+            }
+
+            self.output.write_all(b"+")?;
+            return Ok(String::from_utf8_lossy(&data).into_owned());
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()>
+    {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(self.output, "${}#{:02x}", data, checksum)?;
+        self.output.flush()
+    }
+
+    /// Answer packets until GDB resumes the program
+    /// Returns None if GDB kills the program
+    fn serve(&mut self, vm: &mut VM) -> io::Result<Option<ExitReason>>
+    {
+        loop
+        {
+            let packet = self.recv()?;
+            let (cmd, args) = packet.split_at(packet.chars().next().map_or(0, |ch| ch.len_utf8()));
+
+            let reply = match cmd {
+                "?" => format!("S{:02x}", SIGTRAP),
+
+                "g" => read_regs(vm).iter().map(|reg| to_hex(&reg.to_le_bytes())).collect(),
+
+                "p" => match usize::from_str_radix(args, 16).ok().and_then(|idx| read_regs(vm).get(idx).copied()) {
+                    Some(reg) => to_hex(&reg.to_le_bytes()),
+                    None => "E01".to_string()
+                },
+
+                // The registers describe the VM state and can't be written
+                "G" | "P" => "E01".to_string(),
+
+                "m" => match parse_addr_len(args).and_then(|(addr, len)| read_mem(vm, addr, len)) {
+                    Some(bytes) => to_hex(&bytes),
+                    None => "E01".to_string()
+                },
+
+                "M" => {
+                    let written = args.split_once(':').and_then(|(range, hex)| {
+                        let (addr, len) = parse_addr_len(range)?;
+                        let bytes = from_hex(hex).filter(|bytes| bytes.len() == len)?;
+                        write_mem(vm, addr, &bytes)
+                    });
+
+                    match written {
+                        Some(()) => "OK".to_string(),
+                        None => "E01".to_string()
+                    }
+                }
+
+                // Software breakpoints, Z0,addr,kind and z0,addr,kind
+                // Breakpoints can only be set at the start of an instruction
+                "Z" | "z" if args.starts_with("0,") => {
+                    let pc = args[2..].split(',').next()
+                        .and_then(|addr| u64::from_str_radix(addr, 16).ok())
+                        .and_then(code_addr)
+                        .filter(|pc| is_insn_start(vm, *pc));
+
+                    match pc {
+                        Some(pc) if cmd == "Z" => {
+                            self.breakpoints.add(vm, pc);
+                            "OK".to_string()
+                        }
+                        Some(pc) => {
+                            self.breakpoints.remove(vm, pc);
+                            "OK".to_string()
+                        }
+                        None => "E01".to_string()
+                    }
+                }
+
+                // Resuming at a different address is not supported
+                "s" if args.is_empty() => return Ok(Some(self.breakpoints.step(vm))),
+                "c" if args.is_empty() => return Ok(Some(self.breakpoints.cont(vm))),
+
+                "k" => return Ok(None),
+
+                // Detach and let the program run to completion
+                "D" => {
+                    self.send("OK")?;
+                    self.breakpoints = Breakpoints::default();
+                    return Ok(Some(self.breakpoints.cont(vm)));
+                }
+
+                "H" => "OK".to_string(),
+
+                "q" => {
+                    if args.starts_with("Supported") {
+                        "PacketSize=4000;qXfer:features:read+".to_string()
+                    }
+                    else if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+                        match parse_addr_len(range) {
+                            Some((offset, len)) => {
+                                let xml = target_xml();
+                                let start = (offset as usize).min(xml.len());
+                                let end = start.saturating_add(len).min(xml.len());
+                                let prefix = if end == xml.len() { "l" } else { "m" };
+                                format!("{}{}", prefix, &xml[start..end])
+                            }
+                            None => "E01".to_string()
+                        }
+                    }
+                    else if args == "Attached" {
+                        "1".to_string()
+                    }
+                    else if args == "C" {
+                        "QC1".to_string()
+                    }
+                    else if args == "fThreadInfo" {
+                        "m1".to_string()
+                    }
+                    else if args == "sThreadInfo" {
+                        "l".to_string()
+                    }
+                    else
+                    {
+                        String::new()
+                    }
+                }
+
+                // Unsupported packets get an empty reply
+                _ => String::new()
+            };
+
+            self.send(&reply)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::asm::Assembler;
+
+    /// Scripted GDB client, which sends packets and collects the replies
+    struct Client
+    {
+        stream: TcpStream,
+    }
+
+    impl Client
+    {
+        fn send(&mut self, data: &str)
+        {
+            let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            write!(self.stream, "${}#{:02x}", data, checksum).unwrap();
+        }
+
+        /// Read a reply, checking that the previous packet was acknowledged
+        fn recv(&mut self) -> String
+        {
+            let mut byte = [0; 1];
+
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'+');
+
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'$');
+
+            let mut data = Vec::new();
+            loop
+            {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            self.stream.write_all(b"+").unwrap();
+
+            String::from_utf8(data).unwrap()
+        }
+
+        fn request(&mut self, data: &str) -> String
+        {
+            self.send(data);
+            self.recv()
+        }
+    }
+
+    /// Run a program under the stub, stopping at the first instruction,
+    /// while a scripted client talks to it from another thread
+    fn debug<F>(src: &str, script: F) -> Option<ExitReason>
+    where F: FnOnce(&mut Client) + Send + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut client = Client { stream };
+            script(&mut client);
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut stub = GdbStub::new(stream).unwrap();
+
+        let mut vm = Assembler::new().parse_str(src).unwrap();
+        vm.set_fuel(Some(0));
+        let exit_reason = vm.call(0, &[]);
+        let exit_reason = stub.stop(&mut vm, exit_reason);
+
+        client.join().unwrap();
+        exit_reason
+    }
+
+    fn reg(val: u64) -> String
+    {
+        to_hex(&val.to_le_bytes())
+    }
+
+    #[test]
+    fn test_hex()
+    {
+        assert_eq!(to_hex(&[0, 0xab, 7]), "00ab07");
+        assert_eq!(from_hex("00ab07"), Some(vec![0, 0xab, 7]));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("zz"), None);
+        assert_eq!(parse_addr_len("1000,10"), Some((0x1000, 16)));
+        assert!(target_xml().contains("<reg name=\"frames\" bitsize=\"64\" type=\"uint64\" regnum=\"4\"/>"));
+    }
+
+    #[test]
+    fn test_session()
+    {
+        let src = ".data; .u8 7; .code; push 1; push 2; call ADD, 2; exit; ADD: get_arg 0; get_arg 1; add_u64; ret;";
+
+        let exit_reason = debug(src, |client| {
+            assert_eq!(client.request("qSupported:swbreak+"), "PacketSize=4000;qXfer:features:read+");
+            assert!(client.request("qXfer:features:read:target.xml:0,1000").starts_with("l<?xml"));
+            assert_eq!(client.request("?"), "S05");
+            assert_eq!(client.request("g"), reg(CODE_BASE) + &reg(0) + &reg(0) + &reg(0) + &reg(1));
+            assert_eq!(client.request("vMustReplyEmpty"), "");
+
+            // Memory reads and writes in the heap and the code
+            assert_eq!(client.request("m0,1"), "07");
+            assert_eq!(client.request("M0,1:2a"), "OK");
+            assert_eq!(client.request("m0,1"), "2a");
+            assert_eq!(client.request("m1000000,1"), "E01");
+            assert_eq!(client.request(&format!("m{:x},2", CODE_BASE)), "0405");
+
+            // Single step
+            assert_eq!(client.request("s"), "S05");
+            assert_eq!(client.request("p0"), reg(CODE_BASE + 1));
+            assert_eq!(client.request("p2"), reg(1));
+
+            // Breakpoint in the called function
+            assert_eq!(client.request(&format!("Z0,{:x},1", CODE_BASE + 9)), "OK");
+            assert_eq!(client.request(&format!("m{:x},1", CODE_BASE + 9)), "0e");
+            assert_eq!(client.request("c"), "S05");
+            assert_eq!(client.request("g"), reg(CODE_BASE + 9) + &reg(2) + &reg(2) + &reg(2) + &reg(2));
+            assert_eq!(client.request(&format!("z0,{:x},1", CODE_BASE + 9)), "OK");
+            assert_eq!(client.request("Z0,0,1"), "E01");
+            assert_eq!(client.request(&format!("Z0,{:x},1", CODE_BASE + 5)), "E01");
+
+            // The program exits with the sum
+            assert_eq!(client.request("c"), "W03");
+        });
+
+        assert!(matches!(exit_reason, Some(ExitReason::Exit(val)) if val.as_u64() == 3));
+
+        // Killing the program
+        let exit_reason = debug(src, |client| {
+            assert_eq!(client.request("s"), "S05");
+            client.send("k");
+        });
+        assert!(exit_reason.is_none());
+    }
+}
@@ -0,0 +1,183 @@
+//! Resumable app images, holding the complete state of a suspended program.
+//!
+//! An image starts with a magic number and a format version, followed by
+//! the state of the VM and of its subsystems. All integers are stored as
+//! little-endian u64 values, and byte strings are prefixed by their length.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// Magic bytes at the start of every image
+const MAGIC: &[u8; 4] = b"UVMI";
+
+/// Version of the image format, to be bumped when the layout changes
+const VERSION: u64 = 1;
+
+/// Error that can occur while saving or loading an image
+#[derive(Debug)]
+pub enum ImageError
+{
+    // Error from the underlying reader or writer
+    Io(io::Error),
+
+    // The data doesn't start with the magic bytes of an image
+    BadMagic,
+
+    // The image was saved with an unsupported version of the format
+    BadVersion(u64),
+
+    // The image data is inconsistent
+    Corrupt(String),
+
+    // The program is in a state that can't be saved
+    NotSuspendable(String),
+}
+
+impl fmt::Display for ImageError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            Self::Io(err) => write!(f, "{}", err),
+            Self::BadMagic => write!(f, "not a UVM image"),
+            Self::BadVersion(version) => write!(f, "unsupported image version {}", version),
+            Self::Corrupt(msg) => write!(f, "corrupt image: {}", msg),
+            Self::NotSuspendable(msg) => write!(f, "cannot suspend program: {}", msg),
+        }
+    }
+}
+
+impl From<io::Error> for ImageError
+{
+    fn from(err: io::Error) -> Self
+    {
+        Self::Io(err)
+    }
+}
+
+pub(crate) struct ImageWriter<W: Write>
+{
+    w: W,
+}
+
+impl<W: Write> ImageWriter<W>
+{
+    /// Create a writer and write the image header
+    pub fn new(mut w: W) -> Result<Self, ImageError>
+    {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        Ok(Self { w })
+    }
+
+    pub fn write_u64(&mut self, val: u64) -> Result<(), ImageError>
+    {
+        self.w.write_all(&val.to_le_bytes())?;
+        Ok(())
+    }
+
+    pub fn write_usize(&mut self, val: usize) -> Result<(), ImageError>
+    {
+        self.write_u64(val as u64)
+    }
+
+    pub fn write_bool(&mut self, val: bool) -> Result<(), ImageError>
+    {
+        self.write_u64(val as u64)
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), ImageError>
+    {
+        self.write_usize(bytes.len())?;
+        self.w.write_all(bytes)?;
+        Ok(())
+    }
+
+    pub fn write_str(&mut self, s: &str) -> Result<(), ImageError>
+    {
+        self.write_bytes(s.as_bytes())
+    }
+
+    pub fn flush(&mut self) -> Result<(), ImageError>
+    {
+        self.w.flush()?;
+        Ok(())
+    }
+}
+
+pub(crate) struct ImageReader<R: Read>
+{
+    r: R,
+}
+
+impl<R: Read> ImageReader<R>
+{
+    /// Create a reader and check the image header
+    pub fn new(mut r: R) -> Result<Self, ImageError>
+    {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(ImageError::BadMagic);
+        }
+
+        let mut reader = Self { r };
+        let version = reader.read_u64()?;
+        if version != VERSION {
+            return Err(ImageError::BadVersion(version));
+        }
+
+        Ok(reader)
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, ImageError>
+    {
+        let mut bytes = [0; 8];
+        self.r.read_exact(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn read_usize(&mut self) -> Result<usize, ImageError>
+    {
+        let val = self.read_u64()?;
+        usize::try_from(val).map_err(|_| ImageError::Corrupt(format!("value {} too large", val)))
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, ImageError>
+    {
+        match self.read_u64()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            val => Err(ImageError::Corrupt(format!("invalid boolean {}", val)))
+        }
+    }
+
+    pub fn read_bytes(&mut self) -> Result<Vec<u8>, ImageError>
+    {
+        let len = self.read_u64()?;
+
+        // Don't trust the length to allocate memory up front
+        let mut bytes = Vec::new();
+        (&mut self.r).take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(ImageError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        Ok(bytes)
+    }
+
+    pub fn read_str(&mut self) -> Result<String, ImageError>
+    {
+        String::from_utf8(self.read_bytes()?).map_err(|_| ImageError::Corrupt("invalid UTF-8 string".to_string()))
+    }
+
+    /// Check that all of the image was read
+    pub fn finish(mut self) -> Result<(), ImageError>
+    {
+        let mut byte = [0; 1];
+        match self.r.read(&mut byte)? {
+            0 => Ok(()),
+            _ => Err(ImageError::Corrupt("trailing data".to_string()))
+        }
+    }
+}
@@ -0,0 +1,1526 @@
+// JIT compiler translating basic blocks to x86-64 machine code
+//
+// A block is a straight-line run of decoded instructions, ending at a
+// branch, a syscall, or just before an instruction the JIT doesn't handle.
+// Stack values stay in the VM's value stack, so the interpreter and
+// translated code can hand execution back and forth at any block boundary.
+// Whenever a check fails (e.g. a heap access out of bounds), the block
+// exits to the interpreter at the start of the failing instruction, and
+// the interpreter executes it, producing the exact same fault.
+//
+// Blocks are compiled lazily, the first time execution reaches them, using
+// basic block versioning: a block is compiled for a context holding what is
+// known about the values on top of the stack when it is entered, such as
+// small constants or heap addresses already bounds-checked, which lets it
+// skip redundant checks. A block gets one version per context it is reached
+// with, up to a limit. Exits to other blocks first jump to a stub returning
+// to the VM, which compiles the target version and patches the jump, so
+// that blocks end up chained directly to each other.
+
+pub mod x86;
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::mem::offset_of;
+use crate::vm::{Op, Value, VM, FaultKind, MemBlock};
+use crate::decode::{Insn, MAX_DECODE_SPAN, decode_fused};
+use crate::sys::SysState;
+use x86::*;
+use x86::Reg::*;
+
+/// Maximum number of instructions in a block
+const MAX_BLOCK_INSNS: usize = 256;
+
+/// Maximum number of specialized versions of a block,
+/// past which the generic version gets used instead
+const MAX_VERSIONS: usize = 4;
+
+/// Number of values on top of the stack tracked in a context
+const MAX_CTX_SLOTS: usize = 4;
+
+/// Size of the chunks of executable memory
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Block exit statuses
+pub const EXIT_CONTINUE: u64 = 0;
+pub const EXIT_FAULT: u64 = 1;
+pub const EXIT_INTERP: u64 = 2;
+
+/// What is known about a value on the stack
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Fact
+{
+    #[default]
+    Unknown,
+
+    // Constant, sign-extended to 64 bits
+    Const(i32),
+
+    // Heap address at which an access of this many bytes is in bounds
+    Checked(u8),
+}
+
+impl Fact
+{
+    fn from_imm(imm: u64) -> Self
+    {
+        match i32::try_from(imm as i64) {
+            Ok(val) => Fact::Const(val),
+            Err(_) => Fact::Unknown
+        }
+    }
+}
+
+/// Facts known when entering a block, which it is specialized on
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Context
+{
+    // Facts about the values on top of the stack, top first
+    pub slots: [Fact; MAX_CTX_SLOTS],
+
+    // The heap is known to be at least this many bytes
+    pub heap_min: u64,
+}
+
+impl Context
+{
+    fn is_generic(&self) -> bool
+    {
+        *self == Context::default()
+    }
+}
+
+/// State shared between the VM and translated code
+/// Blocks read their inputs from it, and write their exit state back
+#[repr(C)]
+pub struct JitCtx
+{
+    pub stack: *mut Value,
+    pub stack_len: usize,
+
+    // End of the memory reserved for the stack
+    pub stack_end: *mut Value,
+
+    pub bp: usize,
+    pub argc: usize,
+    pub heap: *mut u8,
+    pub heap_len: usize,
+
+    // Address to continue execution at
+    pub pc: usize,
+
+    // Address of the last instruction executed, used to locate faults
+    pub last_pc: usize,
+
+    // Remaining fuel, decremented as blocks execute
+    pub fuel: u64,
+
+    // Index plus one of the stub the last block exited through, or zero
+    pub stub: usize,
+
+    pub vm: *mut VM,
+}
+
+/// Entry point of a translated block
+pub type BlockFn = unsafe extern "sysv64" fn(ctx: *mut JitCtx) -> u64;
+
+/// Translated block
+#[derive(Copy, Clone)]
+pub struct Block
+{
+    // Range of code addresses the block was translated from
+    pub start: usize,
+    pub end: usize,
+
+    // Context the block was specialized for
+    pub context: Context,
+
+    // Entry point called from the VM
+    pub entry: BlockFn,
+
+    // Entry point jumped to from other blocks
+    chain_entry: *const u8,
+
+    // Number of bytecode instructions in the block,
+    // the most fuel it can consume
+    pub max_ops: u64,
+
+    // Maximum number of values the block pushes past the
+    // stack size it started with, to be reserved before entry
+    pub max_growth: usize,
+
+    // Number of heap bounds checks left in the block
+    pub num_heap_checks: usize,
+
+    // False once the block has been invalidated
+    live: bool,
+}
+
+/// Jump from a block to another, going through a stub
+/// that returns to the VM until the target is compiled
+struct Stub
+{
+    // Address and context of the target block
+    target: usize,
+    context: Context,
+
+    // The jump to patch, which initially targets the stub right after it
+    jump: *mut u8,
+
+    // Block the jump was patched to
+    linked: Option<usize>,
+}
+
+/// Memory for machine code, allocated in chunks
+/// Pages are writable while code is copied in, and executable otherwise
+struct ExecMem
+{
+    chunks: Vec<(*mut u8, usize)>,
+
+    // Bytes used in the last chunk
+    used: usize,
+
+    // True once the host refused to change the protection of a page
+    // The pages may then be in any state, so no code can be run from them
+    failed: bool,
+}
+
+impl ExecMem
+{
+    fn new() -> Self
+    {
+        Self { chunks: Vec::new(), used: 0, failed: false }
+    }
+
+    /// Change the protection of the pages overlapping a range of memory
+    /// Returns false if the host refuses the change, e.g. under SELinux or PaX
+    fn protect(&mut self, ptr: *const u8, len: usize, prot: libc::c_int) -> bool
+    {
+        let ok = unsafe {
+            let page_size = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+            let start = ptr as usize & !(page_size - 1);
+            let end = ptr as usize + len;
+            libc::mprotect(start as *mut libc::c_void, end - start, prot) == 0
+        };
+
+        self.failed |= !ok;
+        ok
+    }
+
+    /// Copy machine code into executable memory
+    /// Returns None if the memory can't be allocated or made writable
+    fn alloc(&mut self, code: &[u8]) -> Option<*const u8>
+    {
+        if self.failed {
+            return None;
+        }
+
+        let fits = match self.chunks.last() {
+            Some((_, size)) => self.used + code.len() <= *size,
+            None => false
+        };
+
+        if !fits {
+            let size = code.len().max(CHUNK_SIZE);
+            let ptr = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    size,
+                    libc::PROT_READ | libc::PROT_EXEC,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                    -1,
+                    0
+                )
+            };
+
+            if ptr == libc::MAP_FAILED {
+                return None;
+            }
+
+            self.chunks.push((ptr as *mut u8, size));
+            self.used = 0;
+        }
+
+        let (chunk, _) = *self.chunks.last().unwrap();
+        let dst = unsafe { chunk.add(self.used) };
+
+        // Only the pages written to are made writable, and only for
+        // as long as the copy takes
+        if !self.protect(dst, code.len(), libc::PROT_READ | libc::PROT_WRITE) {
+            return None;
+        }
+        unsafe { std::ptr::copy_nonoverlapping(code.as_ptr(), dst, code.len()) };
+        if !self.protect(dst, code.len(), libc::PROT_READ | libc::PROT_EXEC) {
+            return None;
+        }
+
+        // Keep entry points aligned
+        self.used = (self.used + code.len() + 15) & !15;
+
+        Some(dst)
+    }
+
+    /// Point a rel32 jump at a new target
+    /// Returns false if the target is out of range, or if the
+    /// jump can't be made writable
+    fn patch_jump(&mut self, jump: *mut u8, target: *const u8) -> bool
+    {
+        let offset = match i32::try_from(target as i64 - (jump as i64 + 5)) {
+            Ok(offset) => offset,
+            Err(_) => return false
+        };
+
+        if !self.protect(jump, 5, libc::PROT_READ | libc::PROT_WRITE) {
+            return false;
+        }
+        unsafe { std::ptr::copy_nonoverlapping(offset.to_le_bytes().as_ptr(), jump.add(1), 4) };
+        self.protect(jump, 5, libc::PROT_READ | libc::PROT_EXEC)
+    }
+}
+
+impl Drop for ExecMem
+{
+    fn drop(&mut self)
+    {
+        for (ptr, size) in &self.chunks {
+            unsafe { libc::munmap(*ptr as *mut libc::c_void, *size); }
+        }
+    }
+}
+
+pub struct Jit
+{
+    mem: ExecMem,
+
+    // All the blocks compiled so far, including invalidated ones
+    // Machine code is never freed while the VM is alive
+    blocks: Vec<Block>,
+
+    // Live versions of the block starting at each code address
+    versions: HashMap<usize, Vec<usize>>,
+
+    // Code addresses where the instruction can't be translated
+    no_block: Vec<bool>,
+
+    stubs: Vec<Stub>,
+
+    // Fault produced by a syscall made from translated code
+    pub fault: Option<FaultKind>,
+
+    // Panic of a syscall made from translated code, which can't unwind
+    // through it, and is resumed once execution leaves it
+    pub panic: Option<Box<dyn Any + Send>>,
+}
+
+impl Jit
+{
+    /// Create a JIT for a code space of a given size
+    /// Returns None if the host isn't supported
+    pub fn new(code_size: usize) -> Option<Self>
+    {
+        if !cfg!(all(target_arch = "x86_64", unix)) {
+            return None;
+        }
+
+        Some(Self {
+            mem: ExecMem::new(),
+            blocks: Vec::new(),
+            versions: HashMap::new(),
+            no_block: vec![false; code_size + 1],
+            stubs: Vec::new(),
+            fault: None,
+            panic: None,
+        })
+    }
+
+    /// Number of blocks compiled so far
+    pub fn num_blocks(&self) -> usize
+    {
+        self.blocks.len()
+    }
+
+    /// Number of jumps between blocks linked so far
+    pub fn num_links(&self) -> usize
+    {
+        self.stubs.iter().filter(|stub| stub.linked.is_some()).count()
+    }
+
+    /// Live versions of the block starting at a given address
+    pub fn versions(&self, pc: usize) -> Vec<Block>
+    {
+        match self.versions.get(&pc) {
+            Some(versions) => versions.iter().map(|idx| self.blocks[*idx]).collect(),
+            None => Vec::new()
+        }
+    }
+
+    /// Get the generic version of the block starting at a given address,
+    /// compiling it the first time
+    pub fn get_block(&mut self, pc: usize, code: &MemBlock, sys_state: &SysState) -> Option<Block>
+    {
+        let idx = self.get_version(pc, Context::default(), code, sys_state)?;
+        Some(self.blocks[idx])
+    }
+
+    /// Get the version of a block for a given context, compiling it if needed
+    fn get_version(&mut self, pc: usize, context: Context, code: &MemBlock, sys_state: &SysState) -> Option<usize>
+    {
+        // Once memory protection fails, translated code can't be run,
+        // and everything is left to the interpreter
+        if self.mem.failed {
+            return None;
+        }
+
+        // Jump targets past the end of the code are left to the interpreter
+        if pc >= self.no_block.len() || self.no_block[pc] {
+            return None;
+        }
+
+        let versions = self.versions.get(&pc).map(|v| v.as_slice()).unwrap_or(&[]);
+        if let Some(idx) = versions.iter().find(|idx| self.blocks[**idx].context == context) {
+            return Some(*idx);
+        }
+
+        // Past a certain number of versions, stop specializing
+        let num_specialized = versions.iter().filter(|idx| !self.blocks[**idx].context.is_generic()).count();
+        if !context.is_generic() && num_specialized >= MAX_VERSIONS {
+            return self.get_version(pc, Context::default(), code, sys_state);
+        }
+
+        match self.compile(pc, context, code, sys_state) {
+            Some(idx) => {
+                self.versions.entry(pc).or_default().push(idx);
+                Some(idx)
+            }
+            None => {
+                self.no_block[pc] = true;
+                None
+            }
+        }
+    }
+
+    fn compile(&mut self, pc: usize, context: Context, code: &MemBlock, sys_state: &SysState) -> Option<usize>
+    {
+        let (machine_code, mut block, chain_offset, stubs) = compile_block(pc, context, code, sys_state, self.stubs.len())?;
+        let base = self.mem.alloc(&machine_code)?;
+
+        unsafe {
+            block.entry = std::mem::transmute::<*const u8, BlockFn>(base);
+            block.chain_entry = base.add(chain_offset);
+        }
+
+        for (target, context, jump) in stubs {
+            let jump = unsafe { base.add(jump) as *mut u8 };
+            self.stubs.push(Stub { target, context, jump, linked: None });
+        }
+
+        self.blocks.push(block);
+        Some(self.blocks.len() - 1)
+    }
+
+    /// Get the target of the stub a block exited through, compiling it
+    /// if needed, and patch the jump to it so the blocks get chained
+    pub fn link_stub(&mut self, stub_idx: usize, code: &MemBlock, sys_state: &SysState) -> Option<Block>
+    {
+        let (target, context) = (self.stubs[stub_idx].target, self.stubs[stub_idx].context);
+        let idx = self.get_version(target, context, code, sys_state)?;
+        let block = self.blocks[idx];
+
+        let stub = &mut self.stubs[stub_idx];
+        if stub.linked.is_none() && self.mem.patch_jump(stub.jump, block.chain_entry) {
+            stub.linked = Some(idx);
+        }
+
+        // The pages may have been left without execute permission
+        if self.mem.failed {
+            return None;
+        }
+
+        Some(block)
+    }
+
+    /// Invalidate the blocks translated from a range of code addresses
+    pub fn invalidate(&mut self, addr: usize, size: usize)
+    {
+        let end = addr + size;
+
+        for (idx, block) in self.blocks.iter_mut().enumerate() {
+            if block.live && block.start < end && addr < block.end {
+                block.live = false;
+                if let Some(versions) = self.versions.get_mut(&block.start) {
+                    versions.retain(|v| *v != idx);
+                }
+            }
+        }
+
+        // Jumps to invalidated blocks go back through their stubs
+        for stub in &mut self.stubs {
+            if let Some(idx) = stub.linked {
+                if !self.blocks[idx].live {
+                    self.mem.patch_jump(stub.jump, unsafe { stub.jump.add(5) });
+                    stub.linked = None;
+                }
+            }
+        }
+
+        // Instructions that couldn't be translated may have changed
+        for flag in &mut self.no_block[addr.saturating_sub(MAX_DECODE_SPAN)..end] {
+            *flag = false;
+        }
+    }
+
+    /// Invalidate the blocks near the old end of the code, after the
+    /// code space was grown, since their last instructions may change
+    pub fn grow(&mut self, code_size: usize)
+    {
+        let old_size = self.no_block.len() - 1;
+        self.no_block.resize(code_size + 1, false);
+        self.invalidate(old_size, code_size + 1 - old_size);
+    }
+}
+
+/// Called from translated code to perform a syscall
+/// Returns EXIT_CONTINUE, or EXIT_FAULT if the syscall faulted
+/// Panics must not unwind out of this function, the VM catches them
+extern "sysv64" fn syscall_helper(ctx: *mut JitCtx, syscall_idx: u64) -> u64
+{
+    let ctx = unsafe { &mut *ctx };
+    let vm = unsafe { &mut *ctx.vm };
+
+    match vm.jit_syscall(syscall_idx as u16, &mut ctx.stack_len, &mut ctx.fuel) {
+        true => EXIT_CONTINUE,
+        false => EXIT_FAULT
+    }
+}
+
+// Registers holding the block state, preserved across chained blocks
+const CTX: Reg = rbx;
+// Address of the first local variable (stack + bp)
+const FRAME: Reg = r12;
+// Address of the stack top when the block was entered
+const STACK_TOP: Reg = r13;
+const HEAP: Reg = r14;
+const HEAP_LEN: Reg = r15;
+
+/// Callee-saved registers used by blocks, in push order
+const SAVED_REGS: [Reg; 5] = [rbx, r12, r13, r14, r15];
+
+fn ctx_field(offset: usize) -> Mem
+{
+    mem(CTX, offset as i32)
+}
+
+/// Execution state at a block exit
+#[derive(Copy, Clone)]
+struct Exit
+{
+    pc: usize,
+    last_pc: Option<usize>,
+    num_ops: u64,
+
+    // Stack size relative to block entry, if it needs to be written back
+    sp: Option<i32>,
+
+    // Status to return, None if it is already in rax
+    status: Option<u64>,
+}
+
+/// Value on the stack, as tracked while compiling
+/// Copies of a value share its id, so facts learned about one apply to all
+#[derive(Copy, Clone)]
+struct Val
+{
+    id: u32,
+    fact: Fact,
+}
+
+/// Id of values which aren't tracked
+const NO_ID: u32 = u32::MAX;
+
+/// Stack effect of an instruction the JIT can translate:
+/// (values read below the stack top, values popped, values pushed)
+fn stack_effect(insn: &Insn, sys_state: &SysState) -> Option<(u32, u32, u32)>
+{
+    let effect = match insn.op {
+        Op::nop | Op::jmp => (0, 0, 0),
+        Op::pop => (1, 1, 0),
+        Op::dup => (1, 0, 1),
+        Op::getn => (insn.idx as u32 + 1, 0, 1),
+        Op::swap => (2, 0, 0),
+        Op::push_u64 | Op::get_argc | Op::get_arg | Op::get_local => (0, 0, 1),
+        Op::get_local_add_imm | Op::load_u64_imm => (0, 0, 1),
+        Op::get_local2 => (0, 0, 2),
+        Op::set_arg | Op::set_local | Op::jz | Op::jnz => (1, 1, 0),
+
+        Op::not_u64 | Op::not_u32 |
+        Op::sx_i8_i32 | Op::sx_i8_i64 | Op::sx_i16_i32 | Op::sx_i16_i64 | Op::sx_i32_i64 |
+        Op::trunc_u8 | Op::trunc_u16 | Op::trunc_u32 |
+        Op::load_u8 | Op::load_u16 | Op::load_u32 | Op::load_u64 => (1, 1, 1),
+
+        Op::and_u64 | Op::or_u64 | Op::xor_u64 | Op::lshift_u64 | Op::rshift_u64 | Op::rshift_i64 |
+        Op::add_u64 | Op::sub_u64 | Op::mul_u64 | Op::div_u64 | Op::mod_u64 | Op::div_i64 | Op::mod_i64 |
+        Op::eq_u64 | Op::ne_u64 | Op::lt_u64 | Op::le_u64 | Op::gt_u64 | Op::ge_u64 |
+        Op::lt_i64 | Op::le_i64 | Op::gt_i64 | Op::ge_i64 |
+        Op::and_u32 | Op::or_u32 | Op::xor_u32 | Op::lshift_u32 | Op::rshift_u32 | Op::rshift_i32 |
+        Op::add_u32 | Op::sub_u32 | Op::mul_u32 | Op::div_u32 | Op::mod_u32 | Op::div_i32 | Op::mod_i32 |
+        Op::eq_u32 | Op::ne_u32 | Op::lt_u32 | Op::le_u32 | Op::gt_u32 | Op::ge_u32 |
+        Op::lt_i32 | Op::le_i32 | Op::gt_i32 | Op::ge_i32 => (2, 2, 1),
+
+        Op::store_u8 | Op::store_u16 | Op::store_u32 | Op::store_u64 => (2, 2, 0),
+        Op::lt_i64_jz | Op::lt_i64_jnz | Op::lt_u64_jz | Op::lt_u64_jnz => (2, 2, 0),
+
+        // The syscall helper pops the arguments and checks the stack itself
+        Op::syscall => {
+            sys_state.lookup_syscall(insn.idx)?;
+            (0, 0, 0)
+        }
+
+        _ => return None
+    };
+
+    Some(effect)
+}
+
+/// Check if an instruction ends a block
+fn is_terminator(op: Op) -> bool
+{
+    matches!(
+        op,
+        Op::jmp | Op::jz | Op::jnz | Op::syscall |
+        Op::lt_i64_jz | Op::lt_i64_jnz | Op::lt_u64_jz | Op::lt_u64_jnz
+    )
+}
+
+/// Address of the last bytecode instruction in a decoded instruction
+fn last_insn_pc(pc: usize, insn: &Insn) -> usize
+{
+    match insn.op {
+        Op::get_local_add_imm | Op::load_u64_imm => insn.next_pc - 1,
+        Op::get_local2 => insn.next_pc - 2,
+        Op::lt_i64_jz | Op::lt_i64_jnz | Op::lt_u64_jz | Op::lt_u64_jnz => insn.next_pc - 5,
+        _ => pc
+    }
+}
+
+/// Stub sites in a compiled block: (target address, target context, offset of the jump)
+type StubSites = Vec<(usize, Context, usize)>;
+
+/// Compile a version of the block starting at a given address
+/// Returns the machine code, the block with a placeholder entry point,
+/// the offset of the chain entry point and the block's stub sites
+fn compile_block(
+    start: usize,
+    context: Context,
+    code: &MemBlock,
+    sys_state: &SysState,
+    first_stub: usize
+) -> Option<(Vec<u8>, Block, usize, StubSites)>
+{
+    // Find the instructions in the block, and its stack bounds
+    let mut block_insns = Vec::new();
+    let mut pc = start;
+    let mut sp: i32 = 0;
+    let mut max_depth: i32 = 0;
+    let mut max_sp: i32 = 0;
+    let mut max_ops = 0;
+
+    while block_insns.len() < MAX_BLOCK_INSNS {
+        let insn = decode_fused(code, pc);
+        let (reads, pops, pushes) = match stack_effect(&insn, sys_state) {
+            Some(effect) => effect,
+            None => break
+        };
+
+        max_depth = max_depth.max(reads as i32 - sp);
+        sp += pushes as i32 - pops as i32;
+        max_sp = max_sp.max(sp);
+        max_ops += insn.num_ops();
+
+        block_insns.push((pc, insn, pops, pushes));
+        pc = insn.next_pc;
+
+        if is_terminator(insn.op) {
+            break;
+        }
+    }
+
+    if block_insns.is_empty() {
+        return None;
+    }
+
+    let mut compiler = BlockCompiler::new(context, first_stub);
+    let chain_offset = compiler.compile(&block_insns, max_depth, max_sp, max_ops);
+
+    let block = Block {
+        start,
+        end: pc,
+        context,
+        entry: placeholder_entry,
+        chain_entry: std::ptr::null(),
+        max_ops,
+        max_growth: max_sp as usize,
+        num_heap_checks: compiler.num_heap_checks,
+        live: true,
+    };
+
+    let stubs = std::mem::take(&mut compiler.stubs);
+    Some((compiler.enc.finish(), block, chain_offset, stubs))
+}
+
+unsafe extern "sysv64" fn placeholder_entry(_ctx: *mut JitCtx) -> u64
+{
+    unreachable!()
+}
+
+struct BlockCompiler
+{
+    enc: Encoder,
+
+    // Stack size relative to block entry
+    sp: i32,
+
+    // Bytecode instructions executed so far
+    num_ops: u64,
+
+    // Last bytecode instruction executed
+    last_pc: Option<usize>,
+
+    // State at the start of the current instruction,
+    // which is where execution resumes if a check fails
+    insn_exit: Exit,
+    insn_bail: Option<Label>,
+
+    // Exits to emit after the block body
+    exits: Vec<(Label, Exit)>,
+
+    epilogue: Label,
+
+    // Values on top of the stack, top last
+    // Values below these are unknown
+    vals: Vec<Val>,
+    next_id: u32,
+
+    // The heap is known to be at least this many bytes
+    heap_min: u64,
+
+    num_heap_checks: usize,
+
+    // Exits to other blocks, and the index of the first one among all stubs
+    stubs: StubSites,
+    first_stub: usize,
+}
+
+impl BlockCompiler
+{
+    fn new(context: Context, first_stub: usize) -> Self
+    {
+        let mut enc = Encoder::new();
+        let epilogue = enc.new_label();
+
+        let mut compiler = Self {
+            enc,
+            sp: 0,
+            num_ops: 0,
+            last_pc: None,
+            insn_exit: Exit { pc: 0, last_pc: None, num_ops: 0, sp: None, status: None },
+            insn_bail: None,
+            exits: Vec::new(),
+            epilogue,
+            vals: Vec::new(),
+            next_id: 0,
+            heap_min: context.heap_min,
+            num_heap_checks: 0,
+            stubs: Vec::new(),
+            first_stub,
+        };
+
+        for fact in context.slots.iter().rev() {
+            let val = compiler.new_val(*fact);
+            compiler.vals.push(val);
+        }
+
+        compiler
+    }
+
+    fn new_val(&mut self, fact: Fact) -> Val
+    {
+        self.next_id += 1;
+        Val { id: self.next_id, fact }
+    }
+
+    /// Tracked nth value from the top of the stack
+    fn val(&self, n: i32) -> Val
+    {
+        let len = self.vals.len() as i32;
+        if n < len {
+            self.vals[(len - 1 - n) as usize]
+        }
+        else
+        {
+            Val { id: NO_ID, fact: Fact::Unknown }
+        }
+    }
+
+    fn pop_vals(&mut self, n: u32)
+    {
+        for _ in 0..n {
+            self.vals.pop();
+        }
+    }
+
+    /// Record that heap accesses of a given size at a value are in bounds
+    fn mark_checked(&mut self, id: u32, size: u8)
+    {
+        for val in &mut self.vals {
+            if val.id == id && id != NO_ID {
+                val.fact = match val.fact {
+                    Fact::Checked(n) => Fact::Checked(n.max(size)),
+                    Fact::Unknown => Fact::Checked(size),
+                    fact => fact
+                };
+            }
+        }
+    }
+
+    /// Update the values tracked on the stack after an instruction
+    fn update_vals(&mut self, insn: &Insn, pops: u32, pushes: u32)
+    {
+        let result = match insn.op {
+            Op::dup => Some(self.val(0)),
+            Op::getn => Some(self.val(insn.idx as i32)),
+            Op::push_u64 => Some(self.new_val(Fact::from_imm(insn.imm))),
+
+            Op::add_u64 | Op::sub_u64 => match (self.val(1).fact, self.val(0).fact) {
+                (Fact::Const(a), Fact::Const(b)) => {
+                    let val = if insn.op == Op::add_u64 { a as i64 + b as i64 } else { a as i64 - b as i64 };
+                    Some(self.new_val(Fact::from_imm(val as u64)))
+                }
+                _ => None
+            }
+
+            Op::swap => {
+                let len = self.vals.len();
+                if len >= 2 {
+                    self.vals.swap(len - 1, len - 2);
+                }
+                else
+                {
+                    self.vals.clear();
+                }
+                return;
+            }
+
+            // Locals and arguments live on the stack too, so writing
+            // them may change any of the values tracked
+            Op::set_local | Op::set_arg => {
+                self.vals.clear();
+                return;
+            }
+
+            _ => None
+        };
+
+        self.pop_vals(pops);
+        for _ in 0..pushes {
+            let val = match result {
+                Some(val) => val,
+                None => self.new_val(Fact::Unknown)
+            };
+            self.vals.push(val);
+        }
+    }
+
+    /// Facts known at the current point, to specialize the next block on
+    fn exit_context(&self) -> Context
+    {
+        let mut context = Context { heap_min: self.heap_min, ..Context::default() };
+        for (n, fact) in context.slots.iter_mut().enumerate() {
+            *fact = self.val(n as i32).fact;
+        }
+        context
+    }
+
+    /// Current exit state, continuing at a given address
+    fn exit_state(&self, pc: usize, status: u64) -> Exit
+    {
+        Exit {
+            pc,
+            last_pc: self.last_pc,
+            num_ops: self.num_ops,
+            sp: Some(self.sp),
+            status: Some(status),
+        }
+    }
+
+    fn add_exit(&mut self, exit: Exit) -> Label
+    {
+        let label = self.enc.new_label();
+        self.exits.push((label, exit));
+        label
+    }
+
+    /// Label to jump to when a check fails in the current instruction
+    /// The interpreter then executes the instruction instead
+    fn bail(&mut self) -> Label
+    {
+        if let Some(label) = self.insn_bail {
+            return label;
+        }
+
+        let label = self.add_exit(self.insn_exit);
+        self.insn_bail = Some(label);
+        label
+    }
+
+    /// Memory operand for the nth value from the top of the stack
+    fn slot(&self, n: i32) -> Mem
+    {
+        mem(STACK_TOP, (self.sp - 1 - n) * 8)
+    }
+
+    fn push_reg(&mut self, reg: Reg)
+    {
+        self.enc.mov(8, mem(STACK_TOP, self.sp * 8), reg);
+        self.sp += 1;
+    }
+
+    /// Write a constant into a context field
+    fn store_ctx(&mut self, offset: usize, val: u64)
+    {
+        match i32::try_from(val) {
+            Ok(imm) => self.enc.store_imm(ctx_field(offset), imm),
+            Err(_) => {
+                self.enc.mov_imm(rcx, val);
+                self.enc.mov(8, ctx_field(offset), rcx);
+            }
+        }
+    }
+
+    /// Check that a local variable index is below the stack size,
+    /// with a given number of values pushed in the instruction so far
+    fn check_local(&mut self, idx: u16, pushed: i32)
+    {
+        let bail = self.bail();
+        self.enc.lea(rax, mem(STACK_TOP, (self.sp + pushed) * 8));
+        self.enc.alu(Alu::sub, 8, rax, FRAME);
+        self.enc.alu_imm(Alu::cmp, 8, rax, idx as i32 * 8);
+        self.enc.jcc(Cond::le, bail);
+    }
+
+    /// Memory operand for a heap access at a constant address, checked
+    /// against the heap size unless a previous check already covers it
+    fn const_heap_addr(&mut self, val: Val, size: u8) -> Option<Mem>
+    {
+        let addr = match val.fact {
+            Fact::Const(addr) if addr >= 0 && addr <= i32::MAX - size as i32 => addr,
+            _ => return None
+        };
+
+        let end = addr as u64 + size as u64;
+        if end > self.heap_min {
+            let bail = self.bail();
+            self.enc.alu_imm(Alu::cmp, 8, HEAP_LEN, end as i32);
+            self.enc.jcc(Cond::b, bail);
+            self.heap_min = end;
+            self.num_heap_checks += 1;
+        }
+
+        Some(mem(HEAP, addr))
+    }
+
+    /// Memory operand for a heap access at the address in rax,
+    /// checked to be in bounds unless the value is known to be
+    fn heap_addr(&mut self, val: Val, size: u8) -> Mem
+    {
+        match val.fact {
+            Fact::Checked(checked) if checked >= size => {}
+            _ => {
+                let bail = self.bail();
+                self.enc.mov(8, rcx, HEAP_LEN);
+                self.enc.alu_imm(Alu::sub, 8, rcx, size as i32);
+                self.enc.jcc(Cond::b, bail);
+                self.enc.alu(Alu::cmp, 8, rax, rcx);
+                self.enc.jcc(Cond::a, bail);
+                self.mark_checked(val.id, size);
+                self.num_heap_checks += 1;
+            }
+        }
+
+        mem_idx(HEAP, rax, 1, 0)
+    }
+
+    /// Load the address of an argument into rax, with a given number
+    /// of values pushed in the instruction so far
+    fn arg_addr(&mut self, idx: u16, pushed: i32) -> Mem
+    {
+        // The interpreter faults if the stack no longer reaches the base pointer
+        let bail = self.bail();
+        self.enc.lea(rax, mem(STACK_TOP, (self.sp + pushed) * 8));
+        self.enc.alu(Alu::cmp, 8, rax, FRAME);
+        self.enc.jcc(Cond::b, bail);
+
+        self.enc.load(8, rax, ctx_field(offset_of!(JitCtx, argc)));
+        self.enc.alu_imm(Alu::cmp, 8, rax, idx as i32);
+        self.enc.jcc(Cond::be, bail);
+
+        // Arguments are just below the base pointer
+        self.enc.neg(8, rax);
+        mem_idx(FRAME, rax, 8, idx as i32 * 8)
+    }
+
+    fn binop(&mut self, op: Alu, size: u8)
+    {
+        self.enc.load(8, rax, self.slot(1));
+        self.enc.alu(op, size, rax, self.slot(0));
+        self.enc.mov(8, self.slot(1), rax);
+        self.sp -= 1;
+    }
+
+    /// 64-bit addition and subtraction, folding known constants
+    fn add_sub(&mut self, op: Op)
+    {
+        let alu = if op == Op::add_u64 { Alu::add } else { Alu::sub };
+
+        let (a, b) = match (self.val(1).fact, self.val(0).fact) {
+            (Fact::Const(a), Fact::Const(b)) => (a as i64, b as i64),
+            _ => return self.binop(alu, 8)
+        };
+
+        let val = if op == Op::add_u64 { a + b } else { a - b };
+        match i32::try_from(val) {
+            Ok(imm) => self.enc.store_imm(self.slot(1), imm),
+            Err(_) => {
+                self.enc.mov_imm(rax, val as u64);
+                self.enc.mov(8, self.slot(1), rax);
+            }
+        }
+        self.sp -= 1;
+    }
+
+    fn compare(&mut self, cond: Cond, size: u8)
+    {
+        self.enc.load(8, rax, self.slot(1));
+        self.enc.alu(Alu::cmp, size, rax, self.slot(0));
+        self.enc.setcc(cond, rax);
+        self.enc.movzx(1, rax, rax);
+        self.enc.mov(8, self.slot(1), rax);
+        self.sp -= 1;
+    }
+
+    fn shift(&mut self, op: Op, size: u8)
+    {
+        self.enc.load(8, rcx, self.slot(0));
+        self.enc.load(8, rax, self.slot(1));
+        match op {
+            Op::lshift_u64 | Op::lshift_u32 => self.enc.shl_cl(size, rax),
+            Op::rshift_u64 | Op::rshift_u32 => self.enc.shr_cl(size, rax),
+            _ => self.enc.sar_cl(size, rax),
+        }
+        self.enc.mov(8, self.slot(1), rax);
+        self.sp -= 1;
+    }
+
+    /// Division and modulo, leaving the faults to the interpreter
+    fn divide(&mut self, size: u8, signed: bool, rem: bool)
+    {
+        self.enc.load(8, rcx, self.slot(0));
+
+        // No checks are needed for known divisors
+        let checked = match self.val(0).fact {
+            Fact::Const(divisor) => divisor != 0 && !(signed && divisor == -1),
+            _ => false
+        };
+
+        if !checked {
+            let bail = self.bail();
+            self.enc.test(size, rcx, rcx);
+            self.enc.jcc(Cond::e, bail);
+
+            // MIN / -1 overflows in hardware, but wraps around in UVM
+            if signed {
+                self.enc.alu_imm(Alu::cmp, size, rcx, -1);
+                self.enc.jcc(Cond::e, bail);
+            }
+        }
+
+        self.enc.load(8, rax, self.slot(1));
+        if signed {
+            self.enc.sign_extend_rdx(size);
+            self.enc.idiv(size, rcx);
+        }
+        else
+        {
+            self.enc.alu(Alu::xor, 4, rdx, rdx);
+            self.enc.div(size, rcx);
+        }
+
+        self.enc.mov(8, self.slot(1), if rem { rdx } else { rax });
+        self.sp -= 1;
+    }
+
+    /// Compile the block, returning the offset of its chain entry point
+    fn compile(&mut self, insns: &[(usize, Insn, u32, u32)], max_depth: i32, max_growth: i32, max_ops: u64) -> usize
+    {
+        let start = insns[0].0;
+
+        // The VM enters the block through a prologue setting up the registers
+        for reg in SAVED_REGS {
+            self.enc.push(reg);
+        }
+        self.enc.mov(8, CTX, rdi);
+        self.enc.load(8, rax, ctx_field(offset_of!(JitCtx, stack_len)));
+        self.enc.load(8, STACK_TOP, ctx_field(offset_of!(JitCtx, stack)));
+        self.enc.lea(STACK_TOP, mem_idx(STACK_TOP, rax, 8, 0));
+        self.enc.load(8, rax, ctx_field(offset_of!(JitCtx, bp)));
+        self.enc.load(8, FRAME, ctx_field(offset_of!(JitCtx, stack)));
+        self.enc.lea(FRAME, mem_idx(FRAME, rax, 8, 0));
+        self.enc.load(8, HEAP, ctx_field(offset_of!(JitCtx, heap)));
+        self.enc.load(8, HEAP_LEN, ctx_field(offset_of!(JitCtx, heap_len)));
+
+        // Other blocks jump straight here, so the checks the VM
+        // does before entering a block are done here as well
+        let chain_offset = self.enc.offset();
+        let entry_exit = Exit { pc: start, last_pc: None, num_ops: 0, sp: Some(0), status: Some(EXIT_CONTINUE) };
+
+        // Check that the stack has enough values for the whole block
+        if max_depth > 0 {
+            let exit = self.add_exit(Exit { status: Some(EXIT_INTERP), ..entry_exit });
+            self.enc.mov(8, rax, STACK_TOP);
+            self.enc.alu(Alu::sub, 8, rax, ctx_field(offset_of!(JitCtx, stack)));
+            self.enc.alu_imm(Alu::cmp, 8, rax, max_depth * 8);
+            self.enc.jcc(Cond::b, exit);
+        }
+
+        // Return to the VM if the stack needs to grow, or if the
+        // block could run out of fuel partway through
+        let exit = self.add_exit(entry_exit);
+        if max_growth > 0 {
+            self.enc.lea(rax, mem(STACK_TOP, max_growth * 8));
+            self.enc.alu(Alu::cmp, 8, rax, ctx_field(offset_of!(JitCtx, stack_end)));
+            self.enc.jcc(Cond::a, exit);
+        }
+        self.enc.alu_imm(Alu::cmp, 8, ctx_field(offset_of!(JitCtx, fuel)), max_ops as i32);
+        self.enc.jcc(Cond::b, exit);
+
+        let mut next_pc = start;
+
+        for &(pc, ref insn, pops, pushes) in insns {
+            next_pc = insn.next_pc;
+
+            self.insn_exit = self.exit_state(pc, EXIT_INTERP);
+            self.insn_bail = None;
+
+            if self.compile_insn(pc, insn) {
+                // The instruction ended the block
+                self.finish();
+                return chain_offset;
+            }
+
+            self.update_vals(insn, pops, pushes);
+            self.num_ops += insn.num_ops();
+            self.last_pc = Some(last_insn_pc(pc, insn));
+        }
+
+        // Continue with the instruction after the block
+        self.chain_exit(next_pc);
+        self.finish();
+        chain_offset
+    }
+
+    /// Compile one instruction, returning true if it ended the block
+    fn compile_insn(&mut self, pc: usize, insn: &Insn) -> bool
+    {
+        match insn.op {
+            Op::nop => {}
+
+            Op::pop => self.sp -= 1,
+
+            Op::dup | Op::getn => {
+                let n = if insn.op == Op::dup { 0 } else { insn.idx as i32 };
+                self.enc.load(8, rax, self.slot(n));
+                self.push_reg(rax);
+            }
+
+            Op::swap => {
+                self.enc.load(8, rax, self.slot(0));
+                self.enc.load(8, rcx, self.slot(1));
+                self.enc.mov(8, self.slot(0), rcx);
+                self.enc.mov(8, self.slot(1), rax);
+            }
+
+            Op::push_u64 => {
+                let imm = insn.imm as i64;
+                if imm as i32 as i64 == imm {
+                    self.enc.store_imm(mem(STACK_TOP, self.sp * 8), imm as i32);
+                    self.sp += 1;
+                }
+                else
+                {
+                    self.enc.mov_imm(rax, insn.imm);
+                    self.push_reg(rax);
+                }
+            }
+
+            Op::get_argc => {
+                self.enc.load(8, rax, ctx_field(offset_of!(JitCtx, argc)));
+                self.push_reg(rax);
+            }
+
+            Op::get_arg => {
+                let arg = self.arg_addr(insn.idx, 0);
+                self.enc.load(8, rax, arg);
+                self.push_reg(rax);
+            }
+
+            Op::set_arg => {
+                let arg = self.arg_addr(insn.idx, -1);
+                self.enc.load(8, rcx, self.slot(0));
+                self.enc.mov(8, arg, rcx);
+                self.sp -= 1;
+            }
+
+            Op::get_local => {
+                self.check_local(insn.idx, 0);
+                self.enc.load(8, rax, mem(FRAME, insn.idx as i32 * 8));
+                self.push_reg(rax);
+            }
+
+            // The value is popped before the index is checked
+            Op::set_local => {
+                self.check_local(insn.idx, -1);
+                self.enc.load(8, rax, self.slot(0));
+                self.enc.mov(8, mem(FRAME, insn.idx as i32 * 8), rax);
+                self.sp -= 1;
+            }
+
+            Op::get_local_add_imm => {
+                self.check_local(insn.idx, 0);
+                self.enc.load(8, rax, mem(FRAME, insn.idx as i32 * 8));
+                let imm = insn.imm as i64;
+                if imm as i32 as i64 == imm {
+                    self.enc.alu_imm(Alu::add, 8, rax, imm as i32);
+                }
+                else
+                {
+                    self.enc.mov_imm(rcx, insn.imm);
+                    self.enc.alu(Alu::add, 8, rax, rcx);
+                }
+                self.push_reg(rax);
+            }
+
+            Op::get_local2 => {
+                self.check_local(insn.idx, 0);
+                self.check_local(insn.imm as u16, 1);
+                self.enc.load(8, rax, mem(FRAME, insn.idx as i32 * 8));
+                self.push_reg(rax);
+                self.enc.load(8, rax, mem(FRAME, insn.imm as i32 * 8));
+                self.push_reg(rax);
+            }
+
+            Op::and_u64 => self.binop(Alu::and, 8),
+            Op::or_u64 => self.binop(Alu::or, 8),
+            Op::xor_u64 => self.binop(Alu::xor, 8),
+            Op::add_u64 | Op::sub_u64 => self.add_sub(insn.op),
+            Op::and_u32 => self.binop(Alu::and, 4),
+            Op::or_u32 => self.binop(Alu::or, 4),
+            Op::xor_u32 => self.binop(Alu::xor, 4),
+            Op::add_u32 => self.binop(Alu::add, 4),
+            Op::sub_u32 => self.binop(Alu::sub, 4),
+
+            Op::mul_u64 | Op::mul_u32 => {
+                let size = if insn.op == Op::mul_u64 { 8 } else { 4 };
+                self.enc.load(8, rax, self.slot(1));
+                self.enc.imul(size, rax, self.slot(0));
+                self.enc.mov(8, self.slot(1), rax);
+                self.sp -= 1;
+            }
+
+            Op::not_u64 | Op::not_u32 => {
+                let size = if insn.op == Op::not_u64 { 8 } else { 4 };
+                self.enc.load(8, rax, self.slot(0));
+                self.enc.not(size, rax);
+                self.enc.mov(8, self.slot(0), rax);
+            }
+
+            Op::lshift_u64 | Op::rshift_u64 | Op::rshift_i64 => self.shift(insn.op, 8),
+            Op::lshift_u32 | Op::rshift_u32 | Op::rshift_i32 => self.shift(insn.op, 4),
+
+            Op::div_u64 => self.divide(8, false, false),
+            Op::mod_u64 => self.divide(8, false, true),
+            Op::div_i64 => self.divide(8, true, false),
+            Op::mod_i64 => self.divide(8, true, true),
+            Op::div_u32 => self.divide(4, false, false),
+            Op::mod_u32 => self.divide(4, false, true),
+            Op::div_i32 => self.divide(4, true, false),
+            Op::mod_i32 => self.divide(4, true, true),
+
+            Op::eq_u64 => self.compare(Cond::e, 8),
+            Op::ne_u64 => self.compare(Cond::ne, 8),
+            Op::lt_u64 => self.compare(Cond::b, 8),
+            Op::le_u64 => self.compare(Cond::be, 8),
+            Op::gt_u64 => self.compare(Cond::a, 8),
+            Op::ge_u64 => self.compare(Cond::ae, 8),
+            Op::lt_i64 => self.compare(Cond::l, 8),
+            Op::le_i64 => self.compare(Cond::le, 8),
+            Op::gt_i64 => self.compare(Cond::g, 8),
+            Op::ge_i64 => self.compare(Cond::ge, 8),
+            Op::eq_u32 => self.compare(Cond::e, 4),
+            Op::ne_u32 => self.compare(Cond::ne, 4),
+            Op::lt_u32 => self.compare(Cond::b, 4),
+            Op::le_u32 => self.compare(Cond::be, 4),
+            Op::gt_u32 => self.compare(Cond::a, 4),
+            Op::ge_u32 => self.compare(Cond::ae, 4),
+            Op::lt_i32 => self.compare(Cond::l, 4),
+            Op::le_i32 => self.compare(Cond::le, 4),
+            Op::gt_i32 => self.compare(Cond::g, 4),
+            Op::ge_i32 => self.compare(Cond::ge, 4),
+
+            Op::sx_i8_i32 | Op::sx_i8_i64 | Op::sx_i16_i32 | Op::sx_i16_i64 | Op::sx_i32_i64 |
+            Op::trunc_u8 | Op::trunc_u16 | Op::trunc_u32 => {
+                let slot = self.slot(0);
+                match insn.op {
+                    Op::sx_i8_i32 => self.enc.movsx(4, 1, rax, slot),
+                    Op::sx_i8_i64 => self.enc.movsx(8, 1, rax, slot),
+                    Op::sx_i16_i32 => self.enc.movsx(4, 2, rax, slot),
+                    Op::sx_i16_i64 => self.enc.movsx(8, 2, rax, slot),
+                    Op::sx_i32_i64 => self.enc.movsx(8, 4, rax, slot),
+                    Op::trunc_u8 => self.enc.movzx(1, rax, slot),
+                    Op::trunc_u16 => self.enc.movzx(2, rax, slot),
+                    _ => self.enc.load(4, rax, slot),
+                }
+                self.enc.mov(8, slot, rax);
+            }
+
+            Op::load_u8 | Op::load_u16 | Op::load_u32 | Op::load_u64 | Op::load_u64_imm => {
+                let size = match insn.op {
+                    Op::load_u8 => 1,
+                    Op::load_u16 => 2,
+                    Op::load_u32 => 4,
+                    _ => 8
+                };
+
+                let is_imm = insn.op == Op::load_u64_imm;
+                let val = if is_imm { Val { id: NO_ID, fact: Fact::from_imm(insn.imm) } } else { self.val(0) };
+
+                let addr = match self.const_heap_addr(val, size) {
+                    Some(addr) => addr,
+                    None => {
+                        if is_imm {
+                            self.enc.mov_imm(rax, insn.imm);
+                        }
+                        else
+                        {
+                            self.enc.load(8, rax, self.slot(0));
+                        }
+                        self.heap_addr(val, size)
+                    }
+                };
+
+                if is_imm {
+                    self.sp += 1;
+                }
+
+                match size {
+                    1 | 2 => self.enc.movzx(size, rax, addr),
+                    _ => self.enc.load(size, rax, addr),
+                }
+                self.enc.mov(8, self.slot(0), rax);
+            }
+
+            Op::store_u8 | Op::store_u16 | Op::store_u32 | Op::store_u64 => {
+                let size = match insn.op {
+                    Op::store_u8 => 1,
+                    Op::store_u16 => 2,
+                    Op::store_u32 => 4,
+                    _ => 8
+                };
+
+                let val = self.val(1);
+                let addr = match self.const_heap_addr(val, size) {
+                    Some(addr) => addr,
+                    None => {
+                        self.enc.load(8, rax, self.slot(1));
+                        self.heap_addr(val, size)
+                    }
+                };
+
+                self.enc.load(8, rcx, self.slot(0));
+                self.enc.mov(size, addr, rcx);
+                self.sp -= 2;
+            }
+
+            Op::jmp => {
+                self.end_insn(pc, insn);
+                self.chain_exit(insn.imm as usize);
+                return true;
+            }
+
+            Op::jz | Op::jnz => {
+                self.enc.load(8, rax, self.slot(0));
+                self.sp -= 1;
+                self.pop_vals(1);
+                self.end_insn(pc, insn);
+
+                let cond = if insn.op == Op::jz { Cond::e } else { Cond::ne };
+                let taken = self.enc.new_label();
+                self.enc.test(8, rax, rax);
+                self.enc.jcc(cond, taken);
+                self.chain_exit(insn.next_pc);
+                self.enc.bind(taken);
+                self.chain_exit(insn.imm as usize);
+                return true;
+            }
+
+            Op::lt_i64_jz | Op::lt_i64_jnz | Op::lt_u64_jz | Op::lt_u64_jnz => {
+                self.enc.load(8, rax, self.slot(1));
+                self.enc.alu(Alu::cmp, 8, rax, self.slot(0));
+                self.sp -= 2;
+                self.pop_vals(2);
+                self.end_insn(pc, insn);
+
+                let cond = match insn.op {
+                    Op::lt_i64_jz => Cond::ge,
+                    Op::lt_i64_jnz => Cond::l,
+                    Op::lt_u64_jz => Cond::ae,
+                    _ => Cond::b,
+                };
+                let taken = self.enc.new_label();
+                self.enc.jcc(cond, taken);
+                self.chain_exit(insn.next_pc);
+                self.enc.bind(taken);
+                self.chain_exit(insn.imm as usize);
+                return true;
+            }
+
+            Op::syscall => {
+                // Write back the stack size and fuel, the helper updates them
+                self.end_insn(pc, insn);
+                self.sub_fuel(self.num_ops);
+                self.write_stack_len(self.sp);
+                self.enc.mov(8, rdi, CTX);
+                self.enc.mov_imm(rsi, insn.idx as u64);
+                self.enc.mov_imm(rax, syscall_helper as *const () as u64);
+                self.enc.call(rax);
+
+                // Faults are located at the syscall, and the block
+                // returns to the VM since the heap may have changed
+                let exit = Exit {
+                    pc: insn.next_pc,
+                    last_pc: self.last_pc,
+                    num_ops: 0,
+                    sp: None,
+                    status: None,
+                };
+                self.emit_exit(exit);
+                return true;
+            }
+
+            _ => unreachable!("untranslatable instruction {:?}", insn.op)
+        }
+
+        false
+    }
+
+    /// Account for an instruction that ends the block
+    fn end_insn(&mut self, pc: usize, insn: &Insn)
+    {
+        self.num_ops += insn.num_ops();
+        self.last_pc = Some(last_insn_pc(pc, insn));
+    }
+
+    /// Write the stack size into the context, from an offset relative to the stack top
+    fn write_stack_len(&mut self, sp: i32)
+    {
+        self.enc.lea(rax, mem(STACK_TOP, sp * 8));
+        self.enc.alu(Alu::sub, 8, rax, ctx_field(offset_of!(JitCtx, stack)));
+        self.enc.shr_imm(8, rax, 3);
+        self.enc.mov(8, ctx_field(offset_of!(JitCtx, stack_len)), rax);
+    }
+
+    fn sub_fuel(&mut self, num_ops: u64)
+    {
+        if num_ops > 0 {
+            self.enc.alu_imm(Alu::sub, 8, ctx_field(offset_of!(JitCtx, fuel)), num_ops as i32);
+        }
+    }
+
+    /// Write the exit state into the context and return
+    fn emit_exit(&mut self, exit: Exit)
+    {
+        self.store_ctx(offset_of!(JitCtx, pc), exit.pc as u64);
+
+        if let Some(last_pc) = exit.last_pc {
+            self.store_ctx(offset_of!(JitCtx, last_pc), last_pc as u64);
+        }
+
+        self.sub_fuel(exit.num_ops);
+
+        if let Some(sp) = exit.sp {
+            self.write_stack_len(sp);
+        }
+
+        if let Some(status) = exit.status {
+            self.enc.mov_imm(rax, status);
+        }
+
+        self.enc.jmp(self.epilogue);
+    }
+
+    /// Continue with the block at a given address, specialized on the
+    /// current context. This jumps to a stub returning to the VM, until
+    /// the VM compiles the block and patches the jump to go there directly
+    fn chain_exit(&mut self, pc: usize)
+    {
+        let context = self.exit_context();
+
+        if let Some(last_pc) = self.last_pc {
+            self.store_ctx(offset_of!(JitCtx, last_pc), last_pc as u64);
+        }
+        self.sub_fuel(self.num_ops);
+        if self.sp != 0 {
+            self.enc.lea(STACK_TOP, mem(STACK_TOP, self.sp * 8));
+        }
+
+        let stub = self.enc.new_label();
+        self.stubs.push((pc, context, self.enc.offset()));
+        self.enc.jmp(stub);
+        self.enc.bind(stub);
+
+        let stub_idx = self.first_stub + self.stubs.len() - 1;
+        self.store_ctx(offset_of!(JitCtx, stub), stub_idx as u64 + 1);
+        self.emit_exit(Exit { pc, last_pc: None, num_ops: 0, sp: Some(0), status: Some(EXIT_CONTINUE) });
+    }
+
+    /// Emit the out-of-line exits and the epilogue
+    fn finish(&mut self)
+    {
+        for (label, exit) in std::mem::take(&mut self.exits) {
+            self.enc.bind(label);
+            self.emit_exit(exit);
+        }
+
+        self.enc.bind(self.epilogue);
+        for reg in SAVED_REGS.iter().rev() {
+            self.enc.pop(*reg);
+        }
+        self.enc.ret();
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn test_exec_mem()
+    {
+        let mut mem = ExecMem::new();
+        assert!(mem.alloc(&[0xC3]).is_some());
+
+        // Once the host refuses a protection change, e.g. on an unmapped
+        // page, no more code is allocated and the interpreter takes over
+        assert!(!mem.protect(std::ptr::null(), 1, libc::PROT_READ | libc::PROT_WRITE));
+        assert!(mem.alloc(&[0xC3]).is_none());
+    }
+}
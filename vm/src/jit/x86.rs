@@ -0,0 +1,485 @@
+// Minimal x86-64 instruction encoder
+// This only encodes the instructions that the JIT needs
+
+/// General-purpose 64-bit registers
+#[allow(non_camel_case_types)]
+#[derive(PartialEq, Copy, Clone, Debug)]
+#[repr(u8)]
+pub enum Reg
+{
+    rax = 0,
+    rcx,
+    rdx,
+    rbx,
+    rsp,
+    rbp,
+    rsi,
+    rdi,
+    r8,
+    r9,
+    r10,
+    r11,
+    r12,
+    r13,
+    r14,
+    r15,
+}
+
+/// Memory operand of the form [base + index * scale + disp]
+#[derive(Copy, Clone, Debug)]
+pub struct Mem
+{
+    pub base: Reg,
+    pub index: Option<(Reg, u8)>,
+    pub disp: i32,
+}
+
+pub fn mem(base: Reg, disp: i32) -> Mem
+{
+    Mem { base, index: None, disp }
+}
+
+pub fn mem_idx(base: Reg, index: Reg, scale: u8, disp: i32) -> Mem
+{
+    Mem { base, index: Some((index, scale)), disp }
+}
+
+/// Register or memory operand (r/m in the Intel manuals)
+#[derive(Copy, Clone, Debug)]
+pub enum Opnd
+{
+    Reg(Reg),
+    Mem(Mem),
+}
+
+impl From<Reg> for Opnd
+{
+    fn from(reg: Reg) -> Self
+    {
+        Opnd::Reg(reg)
+    }
+}
+
+impl From<Mem> for Opnd
+{
+    fn from(mem: Mem) -> Self
+    {
+        Opnd::Mem(mem)
+    }
+}
+
+/// Condition codes, as encoded in jcc and setcc
+#[allow(non_camel_case_types)]
+#[derive(PartialEq, Copy, Clone, Debug)]
+#[repr(u8)]
+pub enum Cond
+{
+    o = 0,
+    no,
+    b,
+    ae,
+    e,
+    ne,
+    be,
+    a,
+    s,
+    ns,
+    p,
+    np,
+    l,
+    ge,
+    le,
+    g,
+}
+
+/// Two-operand integer ALU operations
+/// The value is the opcode of the "reg op= r/m" form,
+/// and the opcode extension of the "r/m op= imm32" form is value / 8
+#[allow(non_camel_case_types)]
+#[derive(PartialEq, Copy, Clone, Debug)]
+#[repr(u8)]
+pub enum Alu
+{
+    add = 0x03,
+    or = 0x0B,
+    and = 0x23,
+    sub = 0x2B,
+    xor = 0x33,
+    cmp = 0x3B,
+}
+
+/// Position in the code that jumps can target
+#[derive(Copy, Clone, Debug)]
+pub struct Label(usize);
+
+pub struct Encoder
+{
+    code: Vec<u8>,
+
+    // Position of each label, once bound
+    labels: Vec<Option<usize>>,
+
+    // Positions of rel32 jump offsets to patch, and their target label
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Encoder
+{
+    pub fn new() -> Self
+    {
+        Self {
+            code: Vec::new(),
+            labels: Vec::new(),
+            fixups: Vec::new(),
+        }
+    }
+
+    fn byte(&mut self, byte: u8)
+    {
+        self.code.push(byte);
+    }
+
+    fn bytes(&mut self, bytes: &[u8])
+    {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// Encode an instruction with a ModRM operand
+    /// The size is the operand size in bytes. Byte operations
+    /// are only supported with the registers al, cl, dl and bl.
+    fn rm_insn(&mut self, size: u8, opcode: &[u8], reg: u8, rm: Opnd)
+    {
+        if size == 2 {
+            self.byte(0x66);
+        }
+
+        let (b, x) = match rm {
+            Opnd::Reg(rm_reg) => (rm_reg as u8 >> 3, 0),
+            Opnd::Mem(mem) => (mem.base as u8 >> 3, mem.index.map_or(0, |(index, _)| index as u8 >> 3)),
+        };
+        let w = (size == 8) as u8;
+        let rex = 0x40 | (w << 3) | ((reg >> 3) << 2) | (x << 1) | b;
+        if rex != 0x40 {
+            self.byte(rex);
+        }
+
+        self.bytes(opcode);
+
+        let reg = (reg & 7) << 3;
+
+        let mem = match rm {
+            Opnd::Reg(rm_reg) => {
+                self.byte(0xC0 | reg | (rm_reg as u8 & 7));
+                return;
+            }
+            Opnd::Mem(mem) => mem
+        };
+
+        // rbp and r13 as base can't be encoded without a displacement
+        let base = mem.base as u8 & 7;
+        let mode: u8 = if mem.disp == 0 && base != 5 {
+            0
+        } else if mem.disp as i8 as i32 == mem.disp {
+            1
+        } else {
+            2
+        };
+
+        match mem.index {
+            Some((index, scale)) => {
+                assert!(index != Reg::rsp, "rsp can't be used as an index");
+                let scale_bits = match scale {
+                    1 => 0,
+                    2 => 1,
+                    4 => 2,
+                    8 => 3,
+                    _ => panic!("invalid scale {}", scale)
+                };
+                self.byte((mode << 6) | reg | 4);
+                self.byte((scale_bits << 6) | ((index as u8 & 7) << 3) | base);
+            }
+
+            // rsp and r12 as base need a SIB byte
+            None if base == 4 => {
+                self.byte((mode << 6) | reg | 4);
+                self.byte(0x24);
+            }
+
+            None => {
+                self.byte((mode << 6) | reg | base);
+            }
+        }
+
+        match mode {
+            1 => self.byte(mem.disp as i8 as u8),
+            2 => self.bytes(&mem.disp.to_le_bytes()),
+            _ => {}
+        }
+    }
+
+    /// mov r/m, reg
+    pub fn mov(&mut self, size: u8, dst: impl Into<Opnd>, src: Reg)
+    {
+        let opcode = if size == 1 { 0x88 } else { 0x89 };
+        self.rm_insn(size, &[opcode], src as u8, dst.into());
+    }
+
+    /// mov reg, r/m
+    /// 32-bit loads zero-extend into the full register
+    pub fn load(&mut self, size: u8, dst: Reg, src: impl Into<Opnd>)
+    {
+        assert!(size == 4 || size == 8);
+        self.rm_insn(size, &[0x8B], dst as u8, src.into());
+    }
+
+    /// mov qword [mem], imm32 (sign-extended)
+    pub fn store_imm(&mut self, dst: Mem, imm: i32)
+    {
+        self.rm_insn(8, &[0xC7], 0, dst.into());
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// Load a 64-bit constant into a register
+    pub fn mov_imm(&mut self, dst: Reg, imm: u64)
+    {
+        let b = dst as u8 >> 3;
+
+        // 32-bit moves zero-extend, and are shorter
+        if imm <= u32::MAX as u64 {
+            if b != 0 {
+                self.byte(0x41);
+            }
+            self.byte(0xB8 + (dst as u8 & 7));
+            self.bytes(&(imm as u32).to_le_bytes());
+        }
+        else
+        {
+            self.byte(0x48 | b);
+            self.byte(0xB8 + (dst as u8 & 7));
+            self.bytes(&imm.to_le_bytes());
+        }
+    }
+
+    pub fn lea(&mut self, dst: Reg, src: Mem)
+    {
+        self.rm_insn(8, &[0x8D], dst as u8, src.into());
+    }
+
+    /// reg op= r/m
+    pub fn alu(&mut self, op: Alu, size: u8, dst: Reg, src: impl Into<Opnd>)
+    {
+        self.rm_insn(size, &[op as u8], dst as u8, src.into());
+    }
+
+    /// r/m op= imm32 (sign-extended)
+    pub fn alu_imm(&mut self, op: Alu, size: u8, dst: impl Into<Opnd>, imm: i32)
+    {
+        self.rm_insn(size, &[0x81], op as u8 / 8, dst.into());
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// reg *= r/m
+    pub fn imul(&mut self, size: u8, dst: Reg, src: impl Into<Opnd>)
+    {
+        self.rm_insn(size, &[0x0F, 0xAF], dst as u8, src.into());
+    }
+
+    pub fn not(&mut self, size: u8, dst: impl Into<Opnd>)
+    {
+        self.rm_insn(size, &[0xF7], 2, dst.into());
+    }
+
+    pub fn neg(&mut self, size: u8, dst: impl Into<Opnd>)
+    {
+        self.rm_insn(size, &[0xF7], 3, dst.into());
+    }
+
+    /// Unsigned division of rdx:rax, quotient in rax and remainder in rdx
+    pub fn div(&mut self, size: u8, src: impl Into<Opnd>)
+    {
+        self.rm_insn(size, &[0xF7], 6, src.into());
+    }
+
+    /// Signed division of rdx:rax, quotient in rax and remainder in rdx
+    pub fn idiv(&mut self, size: u8, src: impl Into<Opnd>)
+    {
+        self.rm_insn(size, &[0xF7], 7, src.into());
+    }
+
+    /// Sign-extend rax into rdx (cqo) or eax into edx (cdq)
+    pub fn sign_extend_rdx(&mut self, size: u8)
+    {
+        if size == 8 {
+            self.byte(0x48);
+        }
+        self.byte(0x99);
+    }
+
+    /// Shift left by cl, masked to the operand size
+    pub fn shl_cl(&mut self, size: u8, dst: impl Into<Opnd>)
+    {
+        self.rm_insn(size, &[0xD3], 4, dst.into());
+    }
+
+    /// Logical shift right by cl
+    pub fn shr_cl(&mut self, size: u8, dst: impl Into<Opnd>)
+    {
+        self.rm_insn(size, &[0xD3], 5, dst.into());
+    }
+
+    /// Logical shift right by a constant
+    pub fn shr_imm(&mut self, size: u8, dst: impl Into<Opnd>, imm: u8)
+    {
+        self.rm_insn(size, &[0xC1], 5, dst.into());
+        self.byte(imm);
+    }
+
+    /// Arithmetic shift right by cl
+    pub fn sar_cl(&mut self, size: u8, dst: impl Into<Opnd>)
+    {
+        self.rm_insn(size, &[0xD3], 7, dst.into());
+    }
+
+    pub fn test(&mut self, size: u8, dst: impl Into<Opnd>, src: Reg)
+    {
+        self.rm_insn(size, &[0x85], src as u8, dst.into());
+    }
+
+    /// Set a byte register to 0 or 1 based on a condition
+    pub fn setcc(&mut self, cond: Cond, dst: Reg)
+    {
+        self.rm_insn(1, &[0x0F, 0x90 + cond as u8], 0, dst.into());
+    }
+
+    /// Zero-extending load of a byte or word
+    pub fn movzx(&mut self, src_size: u8, dst: Reg, src: impl Into<Opnd>)
+    {
+        let opcode = if src_size == 1 { 0xB6 } else { 0xB7 };
+        self.rm_insn(4, &[0x0F, opcode], dst as u8, src.into());
+    }
+
+    /// Sign-extending load of a byte, word or dword
+    pub fn movsx(&mut self, dst_size: u8, src_size: u8, dst: Reg, src: impl Into<Opnd>)
+    {
+        match src_size {
+            1 => self.rm_insn(dst_size, &[0x0F, 0xBE], dst as u8, src.into()),
+            2 => self.rm_insn(dst_size, &[0x0F, 0xBF], dst as u8, src.into()),
+            _ => self.rm_insn(8, &[0x63], dst as u8, src.into()),
+        }
+    }
+
+    pub fn push(&mut self, reg: Reg)
+    {
+        if reg as u8 >= 8 {
+            self.byte(0x41);
+        }
+        self.byte(0x50 + (reg as u8 & 7));
+    }
+
+    pub fn pop(&mut self, reg: Reg)
+    {
+        if reg as u8 >= 8 {
+            self.byte(0x41);
+        }
+        self.byte(0x58 + (reg as u8 & 7));
+    }
+
+    /// Indirect call through a register
+    pub fn call(&mut self, target: Reg)
+    {
+        self.rm_insn(4, &[0xFF], 2, target.into());
+    }
+
+    pub fn ret(&mut self)
+    {
+        self.byte(0xC3);
+    }
+
+    /// Offset of the next instruction from the start of the code
+    pub fn offset(&self) -> usize
+    {
+        self.code.len()
+    }
+
+    pub fn new_label(&mut self) -> Label
+    {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Bind a label to the current position
+    pub fn bind(&mut self, label: Label)
+    {
+        assert!(self.labels[label.0].is_none(), "label bound twice");
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    pub fn jmp(&mut self, target: Label)
+    {
+        self.byte(0xE9);
+        self.fixups.push((self.code.len(), target));
+        self.bytes(&[0; 4]);
+    }
+
+    pub fn jcc(&mut self, cond: Cond, target: Label)
+    {
+        self.bytes(&[0x0F, 0x80 + cond as u8]);
+        self.fixups.push((self.code.len(), target));
+        self.bytes(&[0; 4]);
+    }
+
+    /// Patch the jumps to labels and produce the machine code
+    pub fn finish(mut self) -> Vec<u8>
+    {
+        for (pos, label) in &self.fixups {
+            let target = self.labels[label.0].expect("jump to unbound label");
+            let offset = (target as i64 - (*pos as i64 + 4)) as i32;
+            self.code[*pos..*pos + 4].copy_from_slice(&offset.to_le_bytes());
+        }
+
+        self.code
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use Reg::*;
+
+    fn encode(f: impl FnOnce(&mut Encoder)) -> Vec<u8>
+    {
+        let mut enc = Encoder::new();
+        f(&mut enc);
+        enc.finish()
+    }
+
+    #[test]
+    fn test_encode()
+    {
+        // Expected encodings checked against an assembler
+        assert_eq!(encode(|e| e.load(8, rax, mem(rbx, 8))), [0x48, 0x8B, 0x43, 0x08]);
+        assert_eq!(encode(|e| e.load(8, rax, mem(r12, 0))), [0x49, 0x8B, 0x04, 0x24]);
+        assert_eq!(encode(|e| e.load(8, rcx, mem(r13, -8))), [0x49, 0x8B, 0x4D, 0xF8]);
+        assert_eq!(encode(|e| e.mov(8, mem(r13, 0), rax)), [0x49, 0x89, 0x45, 0x00]);
+        assert_eq!(encode(|e| e.mov(1, mem_idx(r14, rax, 1, 0), rcx)), [0x41, 0x88, 0x0C, 0x06]);
+        assert_eq!(encode(|e| e.mov(2, mem_idx(r14, rax, 1, 0), rcx)), [0x66, 0x41, 0x89, 0x0C, 0x06]);
+        assert_eq!(encode(|e| e.lea(r13, mem_idx(r13, rax, 8, 0))), [0x4D, 0x8D, 0x6C, 0xC5, 0x00]);
+        assert_eq!(encode(|e| e.alu(Alu::add, 4, rax, mem(r13, 0x100))), [0x41, 0x03, 0x85, 0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(encode(|e| e.alu_imm(Alu::cmp, 8, rax, 5)), [0x48, 0x81, 0xF8, 0x05, 0x00, 0x00, 0x00]);
+        assert_eq!(encode(|e| e.imul(8, rax, rcx)), [0x48, 0x0F, 0xAF, 0xC1]);
+        assert_eq!(encode(|e| e.setcc(Cond::l, rax)), [0x0F, 0x9C, 0xC0]);
+        assert_eq!(encode(|e| e.movsx(8, 4, rax, rax)), [0x48, 0x63, 0xC0]);
+        assert_eq!(encode(|e| e.shr_imm(8, rcx, 3)), [0x48, 0xC1, 0xE9, 0x03]);
+        assert_eq!(encode(|e| e.mov_imm(r8, 7)), [0x41, 0xB8, 0x07, 0x00, 0x00, 0x00]);
+        assert_eq!(encode(|e| { e.push(r15); e.pop(rbx); e.call(rax); }), [0x41, 0x57, 0x5B, 0xFF, 0xD0]);
+
+        // Jumps are relative to the end of the jump instruction
+        let code = encode(|e| {
+            let label = e.new_label();
+            e.bind(label);
+            e.jcc(Cond::e, label);
+        });
+        assert_eq!(code, [0x0F, 0x84, 0xFA, 0xFF, 0xFF, 0xFF]);
+    }
+}
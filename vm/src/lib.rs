@@ -0,0 +1,32 @@
+#![allow(dead_code)]
+#![allow(unused_variables)]
+#![allow(unused_mut)]
+#![allow(unused_imports)]
+
+//! UVM as a library, to embed the virtual machine in other applications.
+//!
+//! A VM is created by assembling a program, either with an `Assembler`, or
+//! with a `VMBuilder` to customize the host system state, for instance to
+//! register host syscalls or disable built-in subsystems. Programs built
+//! with a `VMBuilder` are verified, programs assembled directly aren't.
+
+pub mod vm;
+pub mod sys;
+pub mod asm;
+pub mod verify;
+pub mod image;
+pub mod replay;
+pub mod debugger;
+pub mod gdbstub;
+pub mod trace;
+pub mod strace;
+pub mod profile;
+mod decode;
+mod jit;
+#[cfg(test)]
+mod test_util;
+
+pub use crate::vm::{VM, VMBuilder, BuildError, Value, ExitReason, FaultKind, MemBlock};
+pub use crate::asm::{Assembler, ParseError};
+pub use crate::image::ImageError;
+pub use crate::sys::{SysState, SysCallFn, HOST_SYSCALL_BASE};
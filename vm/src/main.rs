@@ -1,36 +1,103 @@
-#![allow(dead_code)]
 #![allow(unused_variables)]
-#![allow(unused_mut)]
-#![allow(unused_imports)]
-
-mod vm;
-mod sys;
-mod asm;
 
 extern crate sdl2;
 use std::env;
-use crate::vm::{VM, Value, MemBlock, ExitReason};
-use crate::asm::{Assembler};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::net::TcpListener;
+use std::time::Instant;
+use uvm::{VM, Value, ExitReason, FaultKind, Assembler, SysState};
+use uvm::replay::{ReplayLog, UNRECORDABLE_SUBSYSTEMS};
+use uvm::debugger::Debugger;
+use uvm::gdbstub::GdbStub;
+use uvm::trace::Tracer;
+use uvm::strace::SysCallTracer;
+use uvm::profile::Profiler;
+use uvm::sys;
+
+/// Exit code used when the program is stopped by a fault
+/// This is EX_SOFTWARE from sysexits.h
+const FAULT_EXIT_CODE: i32 = 70;
+
+/// Exit code used when the program fails verification
+/// This is EX_DATAERR from sysexits.h
+const VERIFY_EXIT_CODE: i32 = 65;
+
+/// Debugger the program runs under
+enum DebugFrontEnd
+{
+    // Interactive command-line debugger
+    Cli(Debugger),
 
-fn run_program(vm: &mut VM) -> Value
+    // Remote debugging with GDB
+    Gdb(GdbStub),
+}
+
+/// Hand the program over to the debugger when it stops at a breakpoint
+/// Exits if the user quits the debugger
+fn debug_stop(debugger: &mut Option<DebugFrontEnd>, vm: &mut VM, exit_reason: ExitReason) -> ExitReason
+{
+    let exit_reason = match debugger {
+        Some(DebugFrontEnd::Cli(debugger)) => debugger.stop(vm, exit_reason),
+        Some(DebugFrontEnd::Gdb(stub)) => stub.stop(vm, exit_reason),
+        None => Some(exit_reason)
+    };
+
+    match exit_reason {
+        Some(exit_reason) => exit_reason,
+        None => {
+            // Stop tracing, which flushes the trace file
+            vm.set_tracer(None);
+            std::process::exit(0)
+        }
+    }
+}
+
+/// Run the program, then its event loop. A resumed program continues
+/// its suspended call if there is one, or else goes straight to the event loop.
+/// If the program is to be saved, it stops before entering the event loop
+fn run_program(vm: &mut VM, resumed: bool, save: bool, debugger: &mut Option<DebugFrontEnd>) -> ExitReason
 {
     use sdl2::event::Event;
     use sdl2::keyboard::Keycode;
 
-    match vm.call(0, &[])
-    {
-        ExitReason::Exit(val) => {
-            //dbg!(vm.stack_size());
-            return val;
+    if !resumed || vm.is_suspended() {
+        let exit_reason = if vm.is_suspended() { vm.resume() } else { vm.call(0, &[]) };
+        let exit_reason = debug_stop(debugger, vm, exit_reason);
+
+        match exit_reason
+        {
+            // Keep processig events
+            ExitReason::Return(val) if !save => {}
+
+            exit_reason => {
+                //dbg!(vm.stack_size());
+                return exit_reason;
+            }
+        }
+    }
+    else if save {
+        return ExitReason::Return(Value::from(0u32));
+    }
+
+    // When replaying, deliver the recorded events instead of polling for new ones
+    if vm.is_replaying() {
+        while let Some((pc, args)) = vm.next_replay_event() {
+            let exit_reason = vm.call_event(pc, &args);
+            match debug_stop(debugger, vm, exit_reason)
+            {
+                ExitReason::Return(val) => {}
+                exit_reason => {
+                    return exit_reason;
+                }
+            }
         }
 
-        // Keep processig events
-        ExitReason::Return(val) => {}
+        return ExitReason::Exit(Value::from(0u32));
     }
 
     let mut event_pump = vm.sys_state.get_sdl_context().event_pump().unwrap();
 
-    let mut i = 0;
     'main_loop: loop
     {
         // Process all pending events
@@ -46,13 +113,28 @@ fn run_program(vm: &mut VM) -> Value
                 // TODO: we need to move mouse event handling to window.rs
                 // tuck the SDL-specifics in there
                 Event::MouseMotion { window_id, x, y, .. } => {
-                    sys::window::window_call_mousemove(vm, window_id, x, y);
+                    if let Some(exit_reason) = sys::window::window_call_mousemove(vm, window_id, x, y) {
+                        let exit_reason = debug_stop(debugger, vm, exit_reason);
+                        if !matches!(exit_reason, ExitReason::Return(_)) {
+                            return exit_reason;
+                        }
+                    }
                 }
                 Event::MouseButtonDown { window_id, which, mouse_btn, .. } => {
-                    sys::window::window_call_mousedown(vm, window_id, which, mouse_btn);
+                    if let Some(exit_reason) = sys::window::window_call_mousedown(vm, window_id, which, mouse_btn) {
+                        let exit_reason = debug_stop(debugger, vm, exit_reason);
+                        if !matches!(exit_reason, ExitReason::Return(_)) {
+                            return exit_reason;
+                        }
+                    }
                 }
                 Event::MouseButtonUp { window_id, which, mouse_btn, .. } => {
-                    sys::window::window_call_mouseup(vm, window_id, which, mouse_btn);
+                    if let Some(exit_reason) = sys::window::window_call_mouseup(vm, window_id, which, mouse_btn) {
+                        let exit_reason = debug_stop(debugger, vm, exit_reason);
+                        if !matches!(exit_reason, ExitReason::Return(_)) {
+                            return exit_reason;
+                        }
+                    }
                 }
 
                 _ => {}
@@ -69,20 +151,29 @@ fn run_program(vm: &mut VM) -> Value
             std::thread::sleep(std::time::Duration::from_millis(10));
         }
 
+        // Deliver the messages received by the channel callbacks
+        if let Some(exit_reason) = sys::thread::chan_call_recv_cbs(vm) {
+            let exit_reason = debug_stop(debugger, vm, exit_reason);
+            if !matches!(exit_reason, ExitReason::Return(_)) {
+                return exit_reason;
+            }
+        }
+
         // For each callback to run
         for pc in sys::time::get_cbs_to_run(vm)
         {
-            match vm.call(pc, &[])
+            let exit_reason = vm.call_event(pc, &[]);
+            match debug_stop(debugger, vm, exit_reason)
             {
-                ExitReason::Exit(val) => {
-                    return val;
-                }
                 ExitReason::Return(val) => {}
+                exit_reason => {
+                    return exit_reason;
+                }
             }
         }
     }
 
-    Value::from(0 as u32)
+    ExitReason::Exit(Value::from(0 as u32))
 }
 
 fn main()
@@ -90,16 +181,382 @@ fn main()
     let args: Vec<String> = env::args().collect();
     //println!("{:?}", args);
 
-    // TODO: command-line argument parsing
+    // TODO: more command-line options
     // --allow <permissions>
-    // --deny <permissions>
     // --allow-all
 
-    if args.len() == 2 {
-        let asm = Assembler::new();
-        let mut vm = asm.parse_file(&args[1]).unwrap();
-        let ret_val = run_program(&mut vm);
-        std::process::exit(ret_val.as_i32());
+    // `uvm resume <image_file>` continues a program saved as an image
+    let resumed = args.len() > 1 && args[1] == "resume";
+    let opts = if resumed { &args[2..] } else { &args[1..] };
+
+    // Report the execution time and instruction count
+    let mut bench = false;
+
+    // Translate code to machine code
+    let mut jit = false;
+
+    // Image file to save the program into when it is suspended
+    let mut save_file = None;
+
+    // Number of instructions to execute before suspending the program
+    let mut suspend_after = None;
+
+    // Number of instructions each event callback can execute before
+    // the program is stopped, to catch callbacks that never return
+    let mut callback_fuel = None;
+
+    // Log to record the nondeterministic inputs into, or to replay them from
+    let mut record_file = None;
+    let mut replay_file = None;
+
+    // Run the program under the interactive debugger
+    let mut debug = false;
+
+    // Port to wait for GDB to connect on, to debug the program remotely
+    let mut gdb_port = None;
+
+    // File to log every executed instruction into, restricted
+    // to some ranges of the code and to a number of lines
+    let mut trace_file = None;
+    let mut trace_filters = Vec::new();
+    let mut trace_limit = None;
+
+    // Log the syscalls and callbacks to stderr
+    let mut strace = false;
+
+    // Permissions whose syscalls fault instead of running
+    let mut denied_perms = Vec::new();
+
+    // File to write the instruction counts of each call stack into
+    let mut profile_file = None;
+
+    let mut file_name = None;
+
+    let mut opts = opts.iter();
+    while let Some(arg) = opts.next() {
+        match arg.as_str() {
+            "--bench" => bench = true,
+            "--jit" => jit = true,
+            "--debug" => debug = true,
+            "--gdb-port" => {
+                gdb_port = opts.next().and_then(|port| port.parse::<u16>().ok());
+                if gdb_port.is_none() {
+                    eprintln!("--gdb-port expects a port number");
+                    std::process::exit(1);
+                }
+            }
+            "--save" => save_file = opts.next().cloned(),
+            "--record" => record_file = opts.next().cloned(),
+            "--replay" => replay_file = opts.next().cloned(),
+            "--strace" => strace = true,
+            "--deny" => {
+                let perm = opts.next().filter(|perm| {
+                    *perm != "default_allowed" &&
+                    sys::constants::SYSCALL_DESCS.iter().any(|desc| desc.permission == *perm)
+                });
+
+                match perm {
+                    Some(perm) => denied_perms.push(perm.clone()),
+                    None => {
+                        eprintln!("--deny expects a permission, such as vm_code_write");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--profile" => profile_file = opts.next().cloned(),
+            "--trace" => trace_file = opts.next().cloned(),
+            "--trace-filter" => {
+                match opts.next() {
+                    Some(spec) => trace_filters.push(spec.clone()),
+                    None => {
+                        eprintln!("--trace-filter expects a label or a range of addresses");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--trace-limit" => {
+                trace_limit = opts.next().and_then(|n| n.replace('_', "").parse::<u64>().ok());
+                if trace_limit.is_none() {
+                    eprintln!("--trace-limit expects a line count");
+                    std::process::exit(1);
+                }
+            }
+            "--suspend-after" => {
+                suspend_after = opts.next().and_then(|n| n.replace('_', "").parse::<u64>().ok());
+                if suspend_after.is_none() {
+                    eprintln!("--suspend-after expects an instruction count");
+                    std::process::exit(1);
+                }
+            }
+            "--callback-fuel" => {
+                callback_fuel = opts.next().and_then(|n| n.replace('_', "").parse::<u64>().ok());
+                if callback_fuel.is_none() {
+                    eprintln!("--callback-fuel expects an instruction count");
+                    std::process::exit(1);
+                }
+            }
+            _ if arg.starts_with("--") => {
+                eprintln!("unknown option {}", arg);
+                std::process::exit(1);
+            }
+            _ => file_name = Some(arg.clone())
+        }
+    }
+
+    if suspend_after.is_some() && (save_file.is_none() || bench) {
+        eprintln!("--suspend-after requires --save and can't be used with --bench");
+        std::process::exit(1);
+    }
+
+    if callback_fuel.is_some() && (bench || debug || gdb_port.is_some()) {
+        eprintln!("--callback-fuel can't be used with --bench, --debug or --gdb-port");
+        std::process::exit(1);
+    }
+
+    if debug && gdb_port.is_some() {
+        eprintln!("--debug and --gdb-port can't be used together");
+        std::process::exit(1);
+    }
+
+    if (debug || gdb_port.is_some()) && (bench || save_file.is_some() || profile_file.is_some()) {
+        eprintln!("--debug and --gdb-port can't be used with --bench, --save or --profile");
+        std::process::exit(1);
+    }
+
+    if trace_file.is_none() && (!trace_filters.is_empty() || trace_limit.is_some()) {
+        eprintln!("--trace-filter and --trace-limit require --trace");
+        std::process::exit(1);
+    }
+
+    if record_file.is_some() && replay_file.is_some() {
+        eprintln!("--record and --replay can't be used together");
+        std::process::exit(1);
+    }
+
+    let replay_log = if let Some(record_file) = &record_file {
+        match File::create(record_file).and_then(|file| ReplayLog::record(BufWriter::new(file))) {
+            Ok(log) => Some(log),
+            Err(err) => {
+                eprintln!("failed to create {}: {}", record_file, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    else if let Some(replay_file) = &replay_file {
+        let log = File::open(replay_file)
+            .map_err(|err| err.to_string())
+            .and_then(|file| ReplayLog::replay(BufReader::new(file)));
+
+        match log {
+            Ok(log) => Some(log),
+            Err(err) => {
+                eprintln!("failed to read {}: {}", replay_file, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    else
+    {
+        None
+    };
+
+    // Subsystems whose behavior can't be reproduced are disabled when recording
+    let mut sys_state = SysState::new();
+    if replay_log.is_some() {
+        for subsystem in UNRECORDABLE_SUBSYSTEMS {
+            sys_state.disable_subsystem(subsystem);
+        }
+    }
+
+    for perm in &denied_perms {
+        sys_state.deny_permission(perm);
+    }
+
+    if let Some(file_name) = file_name {
+        let mut vm = if resumed {
+            let file = match File::open(&file_name) {
+                Ok(file) => file,
+                Err(err) => {
+                    eprintln!("failed to open {}: {}", file_name, err);
+                    std::process::exit(1);
+                }
+            };
+
+            match VM::load_image(sys_state, BufReader::new(file)) {
+                Ok(vm) => vm,
+                Err(err) => {
+                    eprintln!("failed to load {}: {}", file_name, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        else
+        {
+            let asm = Assembler::with_sys_state(sys_state);
+            asm.parse_file(&file_name).unwrap()
+        };
+
+        // Check the program before running any of it, including the
+        // code of resumed images, which may have been tampered with
+        let diags = vm.verify();
+        if !diags.is_empty() {
+            for diag in &diags {
+                eprintln!("verifier: {} ({})", diag, vm.symbols().symbolize(diag.pc));
+            }
+            std::process::exit(VERIFY_EXIT_CODE);
+        }
+
+        if jit && !vm.enable_jit() {
+            eprintln!("the JIT is not supported on this platform");
+            std::process::exit(1);
+        }
+
+        // Instructions are counted using the fuel budget
+        if bench {
+            vm.set_fuel(Some(u64::MAX));
+        }
+
+        // The program is suspended once it runs out of fuel
+        if suspend_after.is_some() {
+            vm.set_fuel(suspend_after);
+        }
+
+        vm.set_callback_fuel(callback_fuel);
+
+        vm.set_replay_log(replay_log);
+
+        if let Some(trace_file) = &trace_file {
+            let mut tracer = match File::create(trace_file) {
+                Ok(file) => Tracer::new(BufWriter::new(file)),
+                Err(err) => {
+                    eprintln!("failed to create {}: {}", trace_file, err);
+                    std::process::exit(1);
+                }
+            };
+
+            for spec in &trace_filters {
+                if let Err(err) = tracer.add_filter(&vm, spec) {
+                    eprintln!("invalid --trace-filter: {}", err);
+                    std::process::exit(1);
+                }
+            }
+
+            tracer.set_max_lines(trace_limit);
+            vm.set_tracer(Some(tracer));
+        }
+
+        if strace {
+            vm.set_syscall_tracer(Some(SysCallTracer::new(std::io::stderr())));
+        }
+
+        if profile_file.is_some() {
+            vm.set_profiler(Some(Profiler::new()));
+        }
+
+        // The debugger takes over before the first instruction runs
+        let mut debugger = None;
+        if debug {
+            debugger = Some(DebugFrontEnd::Cli(Debugger::new()));
+            vm.set_fuel(Some(0));
+        }
+
+        if let Some(port) = gdb_port {
+            let stub = TcpListener::bind(("127.0.0.1", port))
+                .and_then(|listener| {
+                    eprintln!("waiting for gdb to connect on port {}", port);
+                    listener.accept()
+                })
+                .and_then(|(stream, _)| GdbStub::new(stream));
+
+            match stub {
+                Ok(stub) => debugger = Some(DebugFrontEnd::Gdb(stub)),
+                Err(err) => {
+                    eprintln!("failed to accept a gdb connection on port {}: {}", port, err);
+                    std::process::exit(1);
+                }
+            }
+            vm.set_fuel(Some(0));
+        }
+
+        let start_time = Instant::now();
+        let exit_reason = run_program(&mut vm, resumed, save_file.is_some(), &mut debugger);
+
+        if bench {
+            let time_ms = start_time.elapsed().as_secs_f64() * 1000.0;
+            let num_insns = u64::MAX - vm.fuel().unwrap();
+            let mips = (num_insns as f64) / (time_ms * 1000.0);
+            eprintln!("{} instructions in {:.1} ms ({:.1} MIPS)", num_insns, time_ms, mips);
+        }
+
+        // Stop tracing, which flushes the trace file
+        vm.set_tracer(None);
+
+        if let Some(profile_file) = &profile_file {
+            let mut profiler = vm.take_profiler().unwrap();
+            let result = File::create(profile_file)
+                .and_then(|file| profiler.write_folded(&mut BufWriter::new(file)));
+
+            if let Err(err) = result {
+                eprintln!("failed to write {}: {}", profile_file, err);
+                std::process::exit(1);
+            }
+
+            eprintln!("{} instructions executed, by opcode:", profiler.num_insns());
+            profiler.write_op_histogram(&mut std::io::stderr()).unwrap();
+        }
+
+        // A divergence found during execution is reported as a fault below
+        if let Err(kind) = vm.finish_replay(&exit_reason) {
+            if !matches!(exit_reason, ExitReason::Fault { kind: FaultKind::ReplayDiverged(_), .. }) {
+                eprintln!("{}", kind);
+                std::process::exit(FAULT_EXIT_CODE);
+            }
+        }
+
+        // Save the program if it was suspended, or if it is ready to run its event loop
+        if let Some(save_file) = save_file {
+            if matches!(exit_reason, ExitReason::OutOfFuel | ExitReason::Return(_)) {
+                let result = File::create(&save_file)
+                    .map_err(|err| err.into())
+                    .and_then(|file| vm.save_image(BufWriter::new(file)));
+
+                if let Err(err) = result {
+                    eprintln!("failed to save {}: {}", save_file, err);
+                    std::process::exit(1);
+                }
+
+                std::process::exit(0);
+            }
+        }
+
+        match exit_reason
+        {
+            ExitReason::Exit(val) | ExitReason::Return(val) => {
+                std::process::exit(val.as_i32());
+            }
+
+            // A callback ran out of fuel, unless the program was
+            // suspended to be saved, in which case it was saved above
+            ExitReason::OutOfFuel => {
+                let pc = vm.suspended_pc().unwrap();
+                eprintln!("ran out of fuel (pc={})", pc);
+                eprintln!("backtrace: {}", vm.format_backtrace(&vm.backtrace(pc)));
+                std::process::exit(FAULT_EXIT_CODE);
+            }
+
+            ExitReason::Breakpoint => {
+                let pc = vm.suspended_pc().unwrap();
+                eprintln!("breakpoint reached without a debugger (pc={})", pc);
+                eprintln!("backtrace: {}", vm.format_backtrace(&vm.backtrace(pc)));
+                std::process::exit(FAULT_EXIT_CODE);
+            }
+
+            ExitReason::Fault { kind, pc, backtrace } => {
+                eprintln!("fault: {} (pc={})", kind, pc);
+                eprintln!("backtrace: {}", vm.format_backtrace(&backtrace));
+                std::process::exit(FAULT_EXIT_CODE);
+            }
+        }
     }
 
     std::process::exit(0);
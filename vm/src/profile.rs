@@ -0,0 +1,208 @@
+//! Profiler attributing the instructions executed to guest functions.
+//!
+//! Every instruction executed is counted against the call stack it runs
+//! in, with functions named after the assembler labels. The counts are
+//! written in the folded-stack format read by flamegraph tools, with one
+//! line per call stack, outermost function first:
+//!
+//! ```text
+//! [unknown];FACT;FACT 36
+//! ```
+//!
+//! The number of times each opcode is executed is counted as well. Like
+//! tracing, profiling disables the JIT and superinstructions, so that the
+//! counts are those of the bytecode.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use crate::vm::{VM, Op};
+
+/// Name of the function of code which isn't under any label
+const UNKNOWN_FN: &str = "[unknown]";
+
+/// Counts the instructions executed by a VM
+pub struct Profiler
+{
+    // Number of instructions executed in each call stack
+    stack_counts: BTreeMap<String, u64>,
+
+    // Current call stack, in the folded format
+    stack: String,
+
+    // True when the call stack may have changed since it was computed
+    stack_changed: bool,
+
+    // Instructions executed in the current call stack, not yet counted
+    num_pending: u64,
+
+    // Number of times each opcode was executed
+    op_counts: [u64; 256],
+}
+
+impl Default for Profiler
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Profiler
+{
+    pub fn new() -> Self
+    {
+        Self {
+            stack_counts: BTreeMap::new(),
+            stack: String::new(),
+            stack_changed: true,
+            num_pending: 0,
+            op_counts: [0; 256],
+        }
+    }
+
+    /// Count the pending instructions against the current call stack
+    fn flush(&mut self)
+    {
+        if self.num_pending > 0 {
+            *self.stack_counts.entry(self.stack.clone()).or_insert(0) += self.num_pending;
+            self.num_pending = 0;
+        }
+    }
+
+    /// Signal that execution starts over in a different call stack
+    pub(crate) fn stack_changed(&mut self)
+    {
+        self.stack_changed = true;
+    }
+
+    /// Count an instruction about to be executed
+    /// The call stack is only computed again after instructions which can change it
+    pub(crate) fn log(&mut self, vm: &VM, pc: usize, op: Op)
+    {
+        if self.stack_changed {
+            self.flush();
+
+            let names: Vec<&str> = vm.backtrace(pc).iter().rev()
+                .map(|pc| vm.symbols().lookup(*pc).map_or(UNKNOWN_FN, |(name, _)| name))
+                .collect();
+            self.stack = names.join(";");
+            self.stack_changed = false;
+        }
+
+        // Count the opcode of the bytecode, since push instructions
+        // are all decoded as push_u64. There is none past the end of
+        // the code, where the instruction faults.
+        let mut op_pc = pc;
+        if let Ok(op_byte) = vm.code().read_pc::<u8>(&mut op_pc) {
+            self.num_pending += 1;
+            self.op_counts[op_byte as usize] += 1;
+        }
+
+        // Syscalls can call back into guest code
+        if matches!(op, Op::call | Op::call_fp | Op::tail_call | Op::ret | Op::syscall) {
+            self.stack_changed = true;
+        }
+    }
+
+    /// Total number of instructions executed
+    pub fn num_insns(&self) -> u64
+    {
+        self.op_counts.iter().sum()
+    }
+
+    /// Write the instruction counts of each call stack in the folded-stack format
+    pub fn write_folded(&mut self, out: &mut impl Write) -> io::Result<()>
+    {
+        self.flush();
+
+        for (stack, count) in &self.stack_counts {
+            writeln!(out, "{} {}", stack, count)?;
+        }
+
+        out.flush()
+    }
+
+    /// Write the number of times each opcode was executed, most frequent first
+    pub fn write_op_histogram(&self, out: &mut impl Write) -> io::Result<()>
+    {
+        let num_insns = self.num_insns();
+
+        let mut op_counts: Vec<(Op, u64)> = self.op_counts.iter().enumerate()
+            .filter(|(_, count)| **count > 0)
+            .filter_map(|(op, count)| Op::from_u8(op as u8).map(|op| (op, *count)))
+            .collect();
+        op_counts.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+        for (op, count) in op_counts {
+            let percent = 100.0 * count as f64 / num_insns as f64;
+            writeln!(out, "{:<16} {:>12} {:>6.2}%", format!("{:?}", op), count, percent)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::{ExitReason, FaultKind};
+    use crate::asm::Assembler;
+
+    fn profile(src: &str) -> Profiler
+    {
+        let mut vm = Assembler::new().parse_str(src).unwrap();
+        vm.set_profiler(Some(Profiler::new()));
+        assert!(matches!(vm.call(0, &[]), ExitReason::Exit(_)));
+        vm.take_profiler().unwrap()
+    }
+
+    #[test]
+    fn test_folded()
+    {
+        let mut profiler = profile(concat!(
+            "push 3; call COUNT, 1; exit;",
+            "COUNT: get_arg 0; jz DONE; get_arg 0; push 1; sub_u64; call COUNT, 1; ret;",
+            "DONE: push 0; ret;",
+        ));
+
+        let mut out = Vec::new();
+        profiler.write_folded(&mut out).unwrap();
+        let folded = String::from_utf8(out).unwrap();
+
+        assert_eq!(folded, concat!(
+            "[unknown] 3\n",
+            "[unknown];COUNT 7\n",
+            "[unknown];COUNT;COUNT 7\n",
+            "[unknown];COUNT;COUNT;COUNT 7\n",
+            "[unknown];COUNT;COUNT;COUNT;COUNT 4\n",
+        ));
+        assert_eq!(profiler.num_insns(), 28);
+    }
+
+    #[test]
+    fn test_end_of_code()
+    {
+        // Running past the end of the code faults as it does without a profiler
+        let mut vm = Assembler::new().parse_str("push 1;").unwrap();
+        vm.set_profiler(Some(Profiler::new()));
+        assert!(matches!(vm.call(0, &[]), ExitReason::Fault { kind: FaultKind::PcOutOfBounds, .. }));
+        assert_eq!(vm.take_profiler().unwrap().num_insns(), 1);
+    }
+
+    #[test]
+    fn test_op_histogram()
+    {
+        let profiler = profile("push 0; push 1; push 2; add_u64; add_u64; exit;");
+
+        let mut out = Vec::new();
+        profiler.write_op_histogram(&mut out).unwrap();
+        let histogram = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = histogram.lines().collect();
+
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("add_u64"));
+        assert!(lines[0].ends_with("2  33.33%"));
+        assert!(lines.iter().any(|line| line.starts_with("push_2")));
+    }
+}
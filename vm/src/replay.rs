@@ -0,0 +1,327 @@
+//! Recording and replay of the nondeterministic inputs of a program.
+//!
+//! A log holds, in order, the results of the syscalls whose result depends
+//! on the outside world, the events delivered to callbacks, and how the
+//! program ended. Replaying a log feeds the same inputs back to the
+//! program, so that a run can be reproduced exactly. The log is a text
+//! file with one entry per line:
+//!
+//! ```text
+//! syscall time_current_ms 1697040000123
+//! event 210 0 17 42
+//! end exit 0
+//! ```
+
+use std::io::{BufRead, Write};
+use crate::vm::{Value, ExitReason};
+use crate::sys::constants::*;
+
+/// First line of every log, with the version of the format
+const HEADER: &str = "uvm-replay 1";
+
+/// Syscalls whose results are recorded, because they depend on the outside world
+const RECORDED_SYSCALLS: [u16; 2] = [TIME_CURRENT_MS, READ_I64];
+
+/// Subsystems that can't be recorded, because they are nondeterministic
+/// in ways the log can't capture, such as thread scheduling
+pub const UNRECORDABLE_SUBSYSTEMS: [&str; 1] = ["thread"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Entry
+{
+    // Result of a syscall
+    Syscall { idx: u16, val: u64 },
+
+    // Callback called by the event loop, with its arguments
+    Event { pc: u64, args: Vec<u64> },
+
+    // How the program ended, e.g. "exit 0"
+    End(String),
+}
+
+impl Entry
+{
+    fn parse(line: &str) -> Option<Self>
+    {
+        let mut parts = line.split_whitespace();
+
+        match parts.next()? {
+            "syscall" => {
+                let name = parts.next()?;
+                let desc = SYSCALL_DESCS.iter().find(|desc| desc.name == name)?;
+                let val = parts.next()?.parse().ok()?;
+                Some(Self::Syscall { idx: desc.const_idx, val })
+            }
+
+            "event" => {
+                let pc = parts.next()?.parse().ok()?;
+                let args: Result<Vec<u64>, _> = parts.map(|arg| arg.parse()).collect();
+                Some(Self::Event { pc, args: args.ok()? })
+            }
+
+            "end" => {
+                let desc: Vec<&str> = parts.collect();
+                Some(Self::End(desc.join(" ")))
+            }
+
+            _ => None
+        }
+    }
+
+    /// Describe what the program did, without the result of syscalls,
+    /// which is only known when recording
+    fn summary(&self) -> String
+    {
+        match self {
+            Self::Syscall { idx, .. } => format!("syscall {}", SYSCALL_DESCS[*idx as usize].name),
+            _ => self.to_string()
+        }
+    }
+}
+
+impl std::fmt::Display for Entry
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        match self {
+            Self::Syscall { idx, val } => write!(f, "syscall {} {}", SYSCALL_DESCS[*idx as usize].name, val),
+            Self::Event { pc, args } => {
+                write!(f, "event {}", pc)?;
+                for arg in args {
+                    write!(f, " {}", arg)?;
+                }
+                Ok(())
+            }
+            Self::End(desc) => write!(f, "end {}", desc),
+        }
+    }
+}
+
+/// Describe how a program ended, as stored in the log
+fn describe_end(exit_reason: &ExitReason) -> String
+{
+    match exit_reason {
+        ExitReason::Exit(val) => format!("exit {}", val.as_u64()),
+        ExitReason::Return(val) => format!("return {}", val.as_u64()),
+        ExitReason::OutOfFuel => "suspended".to_string(),
+        ExitReason::Breakpoint => "breakpoint".to_string(),
+        ExitReason::Fault { pc, .. } => format!("fault {}", pc),
+    }
+}
+
+enum Mode
+{
+    Record(Box<dyn Write>),
+    Replay(Vec<Entry>),
+}
+
+/// Log being recorded or replayed
+pub struct ReplayLog
+{
+    mode: Mode,
+
+    // Number of entries recorded or replayed so far
+    pos: usize,
+}
+
+impl ReplayLog
+{
+    /// Start recording a log into a writer
+    pub fn record<W: Write + 'static>(mut out: W) -> std::io::Result<Self>
+    {
+        writeln!(out, "{}", HEADER)?;
+
+        Ok(Self {
+            mode: Mode::Record(Box::new(out)),
+            pos: 0,
+        })
+    }
+
+    /// Read a log to replay
+    pub fn replay<R: BufRead>(input: R) -> Result<Self, String>
+    {
+        let mut lines = input.lines();
+
+        match lines.next() {
+            Some(Ok(line)) if line == HEADER => {}
+            _ => return Err("not a replay log".to_string())
+        }
+
+        let mut entries = Vec::new();
+        for (line_idx, line) in lines.enumerate() {
+            let line = line.map_err(|err| err.to_string())?;
+            match Entry::parse(&line) {
+                Some(entry) => entries.push(entry),
+                None => return Err(format!("invalid entry on line {}: {}", line_idx + 2, line))
+            }
+        }
+
+        Ok(Self {
+            mode: Mode::Replay(entries),
+            pos: 0,
+        })
+    }
+
+    pub fn is_replaying(&self) -> bool
+    {
+        matches!(self.mode, Mode::Replay(_))
+    }
+
+    /// Check if the result of a syscall is recorded
+    pub fn is_recorded(syscall_idx: u16) -> bool
+    {
+        RECORDED_SYSCALLS.contains(&syscall_idx)
+    }
+
+    /// Record an entry, or check that it matches the next entry of the log
+    /// Returns the replayed entry, or a message describing the divergence
+    fn step(&mut self, entry: Entry, matches: impl Fn(&Entry) -> bool) -> Result<Entry, String>
+    {
+        self.pos += 1;
+
+        match &mut self.mode {
+            Mode::Record(out) => {
+                writeln!(out, "{}", entry).map_err(|err| err.to_string())?;
+                Ok(entry)
+            }
+
+            Mode::Replay(entries) => {
+                match entries.get(self.pos - 1) {
+                    Some(logged) if matches(logged) => Ok(logged.clone()),
+                    Some(logged) => Err(format!("entry {}: expected \"{}\", program did \"{}\"", self.pos, logged, entry.summary())),
+                    None => Err(format!("entry {}: log ended, program did \"{}\"", self.pos, entry.summary()))
+                }
+            }
+        }
+    }
+
+    /// Record the result of a syscall, or get its result from the log
+    pub fn syscall(&mut self, syscall_idx: u16, val: Value) -> Result<Value, String>
+    {
+        let entry = Entry::Syscall { idx: syscall_idx, val: val.as_u64() };
+
+        match self.step(entry, |logged| matches!(logged, Entry::Syscall { idx, .. } if *idx == syscall_idx))? {
+            Entry::Syscall { val, .. } => Ok(Value::from(val)),
+            _ => unreachable!()
+        }
+    }
+
+    /// Record a callback called by the event loop, or check it against the log
+    pub fn event(&mut self, pc: u64, args: &[Value]) -> Result<(), String>
+    {
+        let entry = Entry::Event { pc, args: args.iter().map(|arg| arg.as_u64()).collect() };
+        self.step(entry.clone(), |logged| *logged == entry)?;
+        Ok(())
+    }
+
+    /// Next event to deliver when replaying, if the log has one
+    pub fn next_event(&self) -> Option<(u64, Vec<Value>)>
+    {
+        match &self.mode {
+            Mode::Replay(entries) => match entries.get(self.pos) {
+                Some(Entry::Event { pc, args }) => Some((*pc, args.iter().map(|arg| Value::from(*arg)).collect())),
+                _ => None
+            },
+            Mode::Record(_) => None
+        }
+    }
+
+    /// Record how the program ended and flush the log, or check that
+    /// the replay ended the same way, after consuming the whole log
+    pub fn finish(&mut self, exit_reason: &ExitReason) -> Result<(), String>
+    {
+        let entry = Entry::End(describe_end(exit_reason));
+        self.step(entry.clone(), |logged| *logged == entry)?;
+
+        match &mut self.mode {
+            Mode::Record(out) => out.flush().map_err(|err| err.to_string()),
+            Mode::Replay(entries) if entries.len() > self.pos => {
+                Err(format!("entry {}: program ended before the end of the log", self.pos + 1))
+            }
+            Mode::Replay(_) => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::{VM, FaultKind};
+    use crate::asm::Assembler;
+    use crate::test_util::SharedBuf;
+
+    fn parse(src: &str) -> VM
+    {
+        Assembler::new().parse_str(src).unwrap()
+    }
+
+    fn replay_log(log: &str) -> ReplayLog
+    {
+        ReplayLog::replay(log.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_record_replay()
+    {
+        let src = "syscall time_current_ms; syscall time_current_ms; add_u64; push 0; ret; CB: get_arg 0; ret;";
+
+        // Record a run, with an event delivered to a callback
+        let buf = SharedBuf::default();
+        let mut vm = parse(src);
+        vm.set_replay_log(Some(ReplayLog::record(buf.clone()).unwrap()));
+        assert!(matches!(vm.call(0, &[]), ExitReason::Return(_)));
+        let exit_reason = vm.call_event(9, &[Value::from(5u64)]);
+        vm.finish_replay(&exit_reason).unwrap();
+
+        let log = buf.contents();
+        assert_eq!(log.lines().count(), 5);
+        assert!(log.ends_with("event 9 5\nend return 5\n"));
+
+        // Replaying produces the same run
+        let mut vm = parse(src);
+        vm.set_replay_log(Some(replay_log(&log)));
+        assert!(vm.is_replaying());
+        assert!(matches!(vm.call(0, &[]), ExitReason::Return(_)));
+        let (pc, args) = vm.next_replay_event().unwrap();
+        let exit_reason = vm.call_event(pc, &args);
+        assert!(vm.next_replay_event().is_none());
+        assert!(vm.finish_replay(&exit_reason).is_ok());
+
+        // Syscall results come from the log
+        let mut vm = parse("syscall time_current_ms; syscall read_i64; sub_u64; exit;");
+        vm.set_replay_log(Some(replay_log("uvm-replay 1\nsyscall time_current_ms 1000\nsyscall read_i64 1\n")));
+        let exit_reason = vm.call(0, &[]);
+        assert!(matches!(exit_reason, ExitReason::Exit(val) if val.as_u64() == 999));
+    }
+
+    #[test]
+    fn test_divergence()
+    {
+        assert!(ReplayLog::replay("uvm-replay 2\n".as_bytes()).is_err());
+        assert!(ReplayLog::replay("uvm-replay 1\nsyscall foo 1\n".as_bytes()).is_err());
+
+        // The program makes a different syscall
+        let mut vm = parse("syscall time_current_ms; exit;");
+        vm.set_replay_log(Some(replay_log("uvm-replay 1\nsyscall read_i64 7\nend exit 7\n")));
+        let exit_reason = vm.call(0, &[]);
+        assert!(matches!(exit_reason, ExitReason::Fault { kind: FaultKind::ReplayDiverged(_), pc: 0, .. }));
+
+        // The program ends differently
+        let mut vm = parse("syscall read_i64; push 1; add_u64; exit;");
+        vm.set_replay_log(Some(replay_log("uvm-replay 1\nsyscall read_i64 7\nend exit 7\n")));
+        let exit_reason = vm.call(0, &[]);
+        assert!(matches!(vm.finish_replay(&exit_reason), Err(FaultKind::ReplayDiverged(_))));
+
+        // The program ends before the log does
+        let mut vm = parse("push 0; exit;");
+        vm.set_replay_log(Some(replay_log("uvm-replay 1\nend exit 0\nevent 0\n")));
+        let exit_reason = vm.call(0, &[]);
+        assert!(vm.finish_replay(&exit_reason).is_err());
+
+        // A different callback is called
+        let mut vm = parse("push 0; ret; CB: push 0; ret;");
+        vm.set_replay_log(Some(replay_log("uvm-replay 1\nevent 3 1\n")));
+        assert!(matches!(vm.call_event(3, &[Value::from(2u64)]), ExitReason::Fault { kind: FaultKind::ReplayDiverged(_), .. }));
+    }
+}
@@ -0,0 +1,203 @@
+//! Syscall tracing, to see what a program asks of the host, in the style of strace.
+//!
+//! Each syscall produces one line with its name, its decoded arguments,
+//! its return value and the time it took in seconds. Callbacks called by
+//! the event loop are shown around the syscalls they make:
+//!
+//! ```text
+//! print_str("Input an integer:\n") <0.000012>
+//! read_i64() = 5 <1.204311>
+//! --- callback ON_TIMER() ---
+//! time_current_ms() = 1697040000123 <0.000002>
+//! --- callback ON_TIMER returned 0 <0.000041> ---
+//! ```
+//!
+//! A syscall which calls back into guest code is logged once it returns,
+//! after the syscalls made by the guest code it called.
+
+use std::io::Write;
+use std::time::Duration;
+use crate::vm::{VM, Value, ExitReason, FaultKind};
+use crate::sys::HOST_SYSCALL_BASE;
+use crate::sys::constants::SYSCALL_DESCS;
+
+/// Maximum number of characters of a string argument shown
+const MAX_STR_LEN: usize = 32;
+
+/// Format a value according to its type in the syscall API
+fn format_value(vm: &mut VM, val_type: &str, val: Value) -> String
+{
+    if val_type.ends_with("char*") {
+        if let Ok(s) = vm.get_heap_str(val.as_usize()) {
+            return match s.char_indices().nth(MAX_STR_LEN) {
+                Some((idx, _)) => format!("{:?}...", &s[..idx]),
+                None => format!("{:?}", s)
+            };
+        }
+    }
+
+    if val_type.ends_with('*') {
+        return format!("0x{:x}", val.as_u64());
+    }
+
+    match val_type {
+        "bool" => (val.as_u64() != 0).to_string(),
+        "i8" | "i16" | "i32" | "i64" => val.as_i64().to_string(),
+        _ => val.as_u64().to_string()
+    }
+}
+
+/// Format a syscall and its arguments, e.g. print_str("hello")
+/// Host syscalls have no type information, so their arguments are shown as numbers
+/// Unknown syscalls are shown by index, e.g. syscall_999()
+pub(crate) fn format_syscall(vm: &mut VM, syscall_idx: u16, args: &[Value]) -> String
+{
+    let (name, arg_types) = match SYSCALL_DESCS.get(syscall_idx as usize) {
+        Some(desc) if syscall_idx < HOST_SYSCALL_BASE => (desc.name.to_string(), desc.arg_types),
+        _ => {
+            let name = vm.sys_state.host_syscall_names()
+                .find(|(_, idx)| *idx == syscall_idx)
+                .map_or(format!("syscall_{}", syscall_idx), |(name, _)| name.to_string());
+            (name, &[][..])
+        }
+    };
+
+    let args: Vec<String> = args.iter().enumerate()
+        .map(|(idx, arg)| format_value(vm, arg_types.get(idx).copied().unwrap_or("u64"), *arg))
+        .collect();
+
+    format!("{}({})", name, args.join(", "))
+}
+
+/// Format the value returned by a syscall
+pub(crate) fn format_ret(vm: &mut VM, syscall_idx: u16, val: Value) -> String
+{
+    match SYSCALL_DESCS.get(syscall_idx as usize) {
+        Some(desc) => format_value(vm, desc.ret_type, val),
+        None => format_value(vm, "u64", val)
+    }
+}
+
+/// Logs the syscalls made and the callbacks run by a VM
+pub struct SysCallTracer
+{
+    out: Box<dyn Write>,
+}
+
+impl SysCallTracer
+{
+    pub fn new<W: Write + 'static>(out: W) -> Self
+    {
+        Self {
+            out: Box::new(out),
+        }
+    }
+
+    /// Log a syscall once it returns, with its return value if it has one
+    /// Write errors are ignored, so that tracing never stops the program
+    pub(crate) fn syscall(&mut self, call: &str, result: Result<Option<String>, &FaultKind>, elapsed: Duration)
+    {
+        let _ = match result {
+            Ok(Some(ret)) => writeln!(self.out, "{} = {} <{:.6}>", call, ret, elapsed.as_secs_f64()),
+            Ok(None) => writeln!(self.out, "{} <{:.6}>", call, elapsed.as_secs_f64()),
+            Err(kind) => writeln!(self.out, "{} = fault: {} <{:.6}>", call, kind, elapsed.as_secs_f64()),
+        };
+    }
+
+    /// Log a callback about to be called by the event loop
+    pub(crate) fn callback_start(&mut self, name: &str, args: &[Value])
+    {
+        let args: Vec<String> = args.iter().map(|arg| arg.as_u64().to_string()).collect();
+        let _ = writeln!(self.out, "--- callback {}({}) ---", name, args.join(", "));
+    }
+
+    /// Log how a callback ended
+    pub(crate) fn callback_end(&mut self, name: &str, exit_reason: &ExitReason, elapsed: Duration)
+    {
+        let outcome = match exit_reason {
+            ExitReason::Return(val) => format!("returned {}", val.as_i64()),
+            ExitReason::Exit(val) => format!("exited with {}", val.as_i64()),
+            ExitReason::OutOfFuel => "ran out of fuel".to_string(),
+            ExitReason::Breakpoint => "stopped at a breakpoint".to_string(),
+            ExitReason::Fault { kind, .. } => format!("faulted: {}", kind),
+        };
+
+        let _ = writeln!(self.out, "--- callback {} {} <{:.6}> ---", name, outcome, elapsed.as_secs_f64());
+    }
+}
+
+impl Drop for SysCallTracer
+{
+    fn drop(&mut self)
+    {
+        let _ = self.out.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::test_util::SharedBuf;
+
+    /// Log lines with the timings removed
+    fn lines(buf: &SharedBuf) -> Vec<String>
+    {
+        let log = buf.contents();
+        log.lines().map(|line| match (line.rfind(" <"), line.rfind('>')) {
+            (Some(start), Some(end)) => format!("{}{}", &line[..start], &line[end + 1..]),
+            _ => line.to_string()
+        }).collect()
+    }
+
+    #[test]
+    fn test_syscalls()
+    {
+        let src = concat!(
+            ".data; STR: .stringz \"a string which is longer than the limit\";",
+            ".code; push STR; syscall print_str; push 0; push 0; push 2; syscall memset;",
+            "push -1; syscall vm_resize_heap; exit;",
+        );
+
+        let buf = SharedBuf::default();
+        let mut vm = Assembler::new().parse_str(src).unwrap();
+        vm.set_syscall_tracer(Some(SysCallTracer::new(buf.clone())));
+        assert!(matches!(vm.call(0, &[]), ExitReason::Exit(_)));
+
+        assert_eq!(lines(&buf), vec![
+            "print_str(\"a string which is longer than th\"...)",
+            "memset(0x0, 0, 2)",
+            "vm_resize_heap(18446744073709551615) = false",
+        ]);
+    }
+
+    #[test]
+    fn test_unknown_syscall()
+    {
+        let buf = SharedBuf::default();
+        let mut vm = Assembler::new().parse_str("push 1; syscall 999; exit;").unwrap();
+        vm.set_syscall_tracer(Some(SysCallTracer::new(buf.clone())));
+        assert!(matches!(vm.call(0, &[]), ExitReason::Fault { kind: FaultKind::UnknownSyscall(999), .. }));
+
+        assert_eq!(lines(&buf), vec![
+            "syscall_999() = fault: unknown syscall 999",
+        ]);
+    }
+
+    #[test]
+    fn test_callback()
+    {
+        let buf = SharedBuf::default();
+        let mut vm = Assembler::new().parse_str("push 0; ret; CB: get_arg 0; syscall print_i64; push 7; ret;").unwrap();
+        vm.set_syscall_tracer(Some(SysCallTracer::new(buf.clone())));
+        let cb = vm.symbols().find("CB").unwrap() as u64;
+        assert!(matches!(vm.call_event(cb, &[Value::from(5u64)]), ExitReason::Return(_)));
+
+        assert_eq!(lines(&buf), vec![
+            "--- callback CB(5) ---",
+            "print_i64(5)",
+            "--- callback CB returned 7 ---",
+        ]);
+    }
+}
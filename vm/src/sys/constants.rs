@@ -4,10 +4,15 @@
 
 #![allow(unused)]
 
-pub const NUM_SYSCALLS: usize = 14;
+pub const NUM_SYSCALLS: usize = 26;
 
 pub const MEMCPY: u16 = 3;
 pub const MEMSET: u16 = 4;
+pub const VM_HEAP_SIZE: u16 = 14;
+pub const VM_RESIZE_HEAP: u16 = 15;
+pub const VM_CODE_SIZE: u16 = 16;
+pub const VM_GROW_CODE: u16 = 17;
+pub const VM_CODE_WRITE: u16 = 18;
 pub const PRINT_I64: u16 = 5;
 pub const PRINT_STR: u16 = 6;
 pub const PRINT_ENDL: u16 = 7;
@@ -20,6 +25,13 @@ pub const WINDOW_DRAW_FRAME: u16 = 10;
 pub const WINDOW_ON_MOUSEMOVE: u16 = 11;
 pub const WINDOW_ON_MOUSEDOWN: u16 = 12;
 pub const WINDOW_ON_MOUSEUP: u16 = 13;
+pub const THREAD_SPAWN: u16 = 19;
+pub const THREAD_ID: u16 = 20;
+pub const THREAD_JOIN: u16 = 21;
+pub const CHAN_CREATE: u16 = 22;
+pub const CHAN_SEND: u16 = 23;
+pub const CHAN_RECV: u16 = 24;
+pub const CHAN_ON_RECV: u16 = 25;
 
 pub struct SysCallDesc
 {
@@ -27,21 +39,37 @@ pub struct SysCallDesc
     pub const_idx: u16,
     pub argc: usize,
     pub has_ret: bool,
+    pub arg_types: &'static [&'static str],
+    pub ret_type: &'static str,
+    pub permission: &'static str,
+    pub subsystem: &'static str,
 }
 
 pub const SYSCALL_DESCS: [SysCallDesc; NUM_SYSCALLS] = [
-    SysCallDesc { name: "time_current_ms", const_idx: 0, argc: 0, has_ret: true },
-    SysCallDesc { name: "window_create", const_idx: 1, argc: 4, has_ret: true },
-    SysCallDesc { name: "time_delay_cb", const_idx: 2, argc: 2, has_ret: false },
-    SysCallDesc { name: "memcpy", const_idx: 3, argc: 3, has_ret: false },
-    SysCallDesc { name: "memset", const_idx: 4, argc: 3, has_ret: false },
-    SysCallDesc { name: "print_i64", const_idx: 5, argc: 1, has_ret: false },
-    SysCallDesc { name: "print_str", const_idx: 6, argc: 1, has_ret: false },
-    SysCallDesc { name: "print_endl", const_idx: 7, argc: 0, has_ret: false },
-    SysCallDesc { name: "read_i64", const_idx: 8, argc: 0, has_ret: true },
-    SysCallDesc { name: "window_show", const_idx: 9, argc: 1, has_ret: false },
-    SysCallDesc { name: "window_draw_frame", const_idx: 10, argc: 2, has_ret: false },
-    SysCallDesc { name: "window_on_mousemove", const_idx: 11, argc: 2, has_ret: false },
-    SysCallDesc { name: "window_on_mousedown", const_idx: 12, argc: 2, has_ret: false },
-    SysCallDesc { name: "window_on_mouseup", const_idx: 13, argc: 2, has_ret: false },
+    SysCallDesc { name: "time_current_ms", const_idx: 0, argc: 0, has_ret: true, arg_types: &[], ret_type: "u64", permission: "time_get_time", subsystem: "time" },
+    SysCallDesc { name: "window_create", const_idx: 1, argc: 4, has_ret: true, arg_types: &["u32", "u32", "const char*", "u64"], ret_type: "u32", permission: "window_display", subsystem: "window" },
+    SysCallDesc { name: "time_delay_cb", const_idx: 2, argc: 2, has_ret: false, arg_types: &["u64", "void*"], ret_type: "void", permission: "default_allowed", subsystem: "time" },
+    SysCallDesc { name: "memcpy", const_idx: 3, argc: 3, has_ret: false, arg_types: &["u8*", "const u8*", "u64"], ret_type: "void", permission: "default_allowed", subsystem: "vm" },
+    SysCallDesc { name: "memset", const_idx: 4, argc: 3, has_ret: false, arg_types: &["u8*", "u8", "u64"], ret_type: "void", permission: "default_allowed", subsystem: "vm" },
+    SysCallDesc { name: "print_i64", const_idx: 5, argc: 1, has_ret: false, arg_types: &["i64"], ret_type: "void", permission: "default_allowed", subsystem: "io" },
+    SysCallDesc { name: "print_str", const_idx: 6, argc: 1, has_ret: false, arg_types: &["const char*"], ret_type: "void", permission: "default_allowed", subsystem: "io" },
+    SysCallDesc { name: "print_endl", const_idx: 7, argc: 0, has_ret: false, arg_types: &[], ret_type: "void", permission: "default_allowed", subsystem: "io" },
+    SysCallDesc { name: "read_i64", const_idx: 8, argc: 0, has_ret: true, arg_types: &[], ret_type: "i64", permission: "default_allowed", subsystem: "io" },
+    SysCallDesc { name: "window_show", const_idx: 9, argc: 1, has_ret: false, arg_types: &["u32"], ret_type: "void", permission: "window_display", subsystem: "window" },
+    SysCallDesc { name: "window_draw_frame", const_idx: 10, argc: 2, has_ret: false, arg_types: &["u32", "const u8*"], ret_type: "void", permission: "window_display", subsystem: "window" },
+    SysCallDesc { name: "window_on_mousemove", const_idx: 11, argc: 2, has_ret: false, arg_types: &["u32", "void*"], ret_type: "void", permission: "window_display", subsystem: "window" },
+    SysCallDesc { name: "window_on_mousedown", const_idx: 12, argc: 2, has_ret: false, arg_types: &["u32", "void*"], ret_type: "void", permission: "window_display", subsystem: "window" },
+    SysCallDesc { name: "window_on_mouseup", const_idx: 13, argc: 2, has_ret: false, arg_types: &["u32", "void*"], ret_type: "void", permission: "window_display", subsystem: "window" },
+    SysCallDesc { name: "vm_heap_size", const_idx: 14, argc: 0, has_ret: true, arg_types: &[], ret_type: "u64", permission: "default_allowed", subsystem: "vm" },
+    SysCallDesc { name: "vm_resize_heap", const_idx: 15, argc: 1, has_ret: true, arg_types: &["u64"], ret_type: "bool", permission: "default_allowed", subsystem: "vm" },
+    SysCallDesc { name: "vm_code_size", const_idx: 16, argc: 0, has_ret: true, arg_types: &[], ret_type: "u64", permission: "default_allowed", subsystem: "vm" },
+    SysCallDesc { name: "vm_grow_code", const_idx: 17, argc: 1, has_ret: true, arg_types: &["u64"], ret_type: "bool", permission: "vm_code_write", subsystem: "vm" },
+    SysCallDesc { name: "vm_code_write", const_idx: 18, argc: 3, has_ret: false, arg_types: &["u64", "const u8*", "u64"], ret_type: "void", permission: "vm_code_write", subsystem: "vm" },
+    SysCallDesc { name: "thread_spawn", const_idx: 19, argc: 2, has_ret: true, arg_types: &["void*", "u64"], ret_type: "u64", permission: "default_allowed", subsystem: "thread" },
+    SysCallDesc { name: "thread_id", const_idx: 20, argc: 0, has_ret: true, arg_types: &[], ret_type: "u64", permission: "default_allowed", subsystem: "thread" },
+    SysCallDesc { name: "thread_join", const_idx: 21, argc: 1, has_ret: true, arg_types: &["u64"], ret_type: "u64", permission: "default_allowed", subsystem: "thread" },
+    SysCallDesc { name: "chan_create", const_idx: 22, argc: 0, has_ret: true, arg_types: &[], ret_type: "u64", permission: "default_allowed", subsystem: "thread" },
+    SysCallDesc { name: "chan_send", const_idx: 23, argc: 3, has_ret: false, arg_types: &["u64", "const u8*", "u64"], ret_type: "void", permission: "default_allowed", subsystem: "thread" },
+    SysCallDesc { name: "chan_recv", const_idx: 24, argc: 3, has_ret: true, arg_types: &["u64", "u8*", "u64"], ret_type: "u64", permission: "default_allowed", subsystem: "thread" },
+    SysCallDesc { name: "chan_on_recv", const_idx: 25, argc: 4, has_ret: false, arg_types: &["u64", "u8*", "u64", "void*"], ret_type: "void", permission: "default_allowed", subsystem: "thread" },
 ];
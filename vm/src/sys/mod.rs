@@ -1,56 +1,73 @@
 pub mod window;
 pub mod audio;
 pub mod time;
+pub mod thread;
 pub mod constants;
 
 extern crate sdl2;
-use std::collections::HashMap;
-use std::io::Write;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
 use std::io::{stdout, stdin};
-use crate::vm::{Value, VM};
+use crate::vm::{Value, VM, FaultKind};
+use crate::image::{ImageWriter, ImageReader, ImageError};
 use window::*;
 use audio::*;
 use time::*;
+use thread::*;
 use constants::*;
 
+/// Syscalls registered by the application embedding UVM are
+/// numbered from this index up, so they never clash with built-in ones
+pub const HOST_SYSCALL_BASE: u16 = 0x8000;
+
 /// System call function signature
 /// Note: the in/out arg count should be fixed so
 ///       that we can JIT syscalls efficiently
+/// Syscalls can fail by returning a fault, which stops execution
 #[derive(Copy, Clone)]
 pub enum SysCallFn
 {
-    Fn0_0(fn(&mut VM)),
-    Fn0_1(fn(&mut VM) -> Value),
-    Fn1_0(fn(&mut VM, a0: Value)),
-    Fn2_0(fn(&mut VM, a0: Value, a1: Value)),
-    Fn3_0(fn(&mut VM, a0: Value, a1: Value, a2: Value)),
-    Fn4_0(fn(&mut VM, a0: Value, a1: Value, a2: Value, a3: Value)),
-    Fn4_1(fn(&mut VM, a0: Value, a1: Value, a2: Value, a3: Value) -> Value),
+    Fn0_0(fn(&mut VM) -> Result<(), FaultKind>),
+    Fn0_1(fn(&mut VM) -> Result<Value, FaultKind>),
+    Fn1_0(fn(&mut VM, a0: Value) -> Result<(), FaultKind>),
+    Fn1_1(fn(&mut VM, a0: Value) -> Result<Value, FaultKind>),
+    Fn2_0(fn(&mut VM, a0: Value, a1: Value) -> Result<(), FaultKind>),
+    Fn2_1(fn(&mut VM, a0: Value, a1: Value) -> Result<Value, FaultKind>),
+    Fn3_0(fn(&mut VM, a0: Value, a1: Value, a2: Value) -> Result<(), FaultKind>),
+    Fn3_1(fn(&mut VM, a0: Value, a1: Value, a2: Value) -> Result<Value, FaultKind>),
+    Fn4_0(fn(&mut VM, a0: Value, a1: Value, a2: Value, a3: Value) -> Result<(), FaultKind>),
+    Fn4_1(fn(&mut VM, a0: Value, a1: Value, a2: Value, a3: Value) -> Result<Value, FaultKind>),
 }
 
 impl SysCallFn
 {
-    fn argc(&self) -> usize
+    pub fn argc(&self) -> usize
     {
         match self {
             Self::Fn0_0(_) => 0,
             Self::Fn0_1(_) => 0,
             Self::Fn1_0(_) => 1,
+            Self::Fn1_1(_) => 1,
             Self::Fn2_0(_) => 2,
+            Self::Fn2_1(_) => 2,
             Self::Fn3_0(_) => 3,
+            Self::Fn3_1(_) => 3,
             Self::Fn4_0(_) => 4,
             Self::Fn4_1(_) => 4,
         }
     }
 
-    fn has_ret(&self) -> bool
+    pub fn has_ret(&self) -> bool
     {
         match self {
             Self::Fn0_0(_) => false,
             Self::Fn0_1(_) => true,
             Self::Fn1_0(_) => false,
+            Self::Fn1_1(_) => true,
             Self::Fn2_0(_) => false,
+            Self::Fn2_1(_) => true,
             Self::Fn3_0(_) => false,
+            Self::Fn3_1(_) => true,
             Self::Fn4_0(_) => false,
             Self::Fn4_1(_) => true,
         }
@@ -62,6 +79,9 @@ pub struct SysState
     /// Map of indices to syscall functions
     syscalls: [Option<SysCallFn>; NUM_SYSCALLS],
 
+    /// Syscalls registered by the host, by index
+    host_syscalls: HashMap<u16, (String, SysCallFn)>,
+
     /// SDL context (used for UI and audio)
     sdl: Option<sdl2::Sdl>,
 
@@ -70,6 +90,20 @@ pub struct SysState
 
     // Time module state
     pub time_state: TimeState,
+
+    // Thread module state
+    pub thread_state: ThreadState,
+
+    /// Permissions denied by the host
+    denied_perms: HashSet<String>,
+}
+
+impl Default for SysState
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
 }
 
 impl SysState
@@ -78,9 +112,12 @@ impl SysState
     {
         let mut sys_state = Self {
             syscalls: [None; NUM_SYSCALLS],
+            host_syscalls: HashMap::new(),
             sdl: None,
             window_state: None,
             time_state: TimeState::new(),
+            thread_state: ThreadState::new(),
+            denied_perms: HashSet::new(),
         };
 
         sys_state.init_syscalls();
@@ -115,25 +152,129 @@ impl SysState
         self.syscalls[const_idx as usize] = Some(fun);
     }
 
-    /// Get the syscall with a given index
-    pub fn get_syscall(&self, const_idx: u16) -> SysCallFn
+    /// Register a syscall defined by the host, with an index
+    /// at or above HOST_SYSCALL_BASE. Host syscalls are always allowed
+    pub fn reg_host_syscall(&mut self, const_idx: u16, name: &str, fun: SysCallFn)
+    {
+        assert!(
+            const_idx >= HOST_SYSCALL_BASE,
+            "host syscall {} should have an index of at least {}",
+            name,
+            HOST_SYSCALL_BASE
+        );
+
+        assert!(
+            self.syscall_idx(name).is_none(),
+            "a syscall named {} is already registered",
+            name
+        );
+
+        self.host_syscalls.insert(const_idx, (name.to_string(), fun));
+    }
+
+    /// Remove the syscalls of a built-in subsystem, such as "window"
+    /// Programs using them then fault as if they didn't exist
+    pub fn disable_subsystem(&mut self, subsystem: &str)
+    {
+        for desc in &SYSCALL_DESCS {
+            if desc.subsystem == subsystem {
+                self.syscalls[desc.const_idx as usize] = None;
+            }
+        }
+    }
+
+    /// Get the index of a syscall from its name
+    /// Built-in syscalls can be looked up even if their subsystem is disabled
+    pub fn syscall_idx(&self, name: &str) -> Option<u16>
+    {
+        if let Some(desc) = SYSCALL_DESCS.iter().find(|desc| desc.name == name) {
+            return Some(desc.const_idx);
+        }
+
+        self.host_syscalls.iter().find(|(_, (host_name, _))| host_name == name).map(|(idx, _)| *idx)
+    }
+
+    /// Names and indices of the syscalls registered by the host
+    pub fn host_syscall_names(&self) -> impl Iterator<Item = (&str, u16)>
+    {
+        self.host_syscalls.iter().map(|(idx, (name, _))| (name.as_str(), *idx))
+    }
+
+    /// Deny a permission, so that the syscalls requiring it will fault
+    /// Syscalls with the default_allowed permission can't be denied
+    pub fn deny_permission(&mut self, perm: &str)
+    {
+        self.denied_perms.insert(perm.to_string());
+    }
+
+    /// Allow a permission that was previously denied
+    pub fn allow_permission(&mut self, perm: &str)
+    {
+        self.denied_perms.remove(perm);
+    }
+
+    /// Check if a given permission is allowed
+    pub fn is_allowed(&self, perm: &str) -> bool
     {
+        perm == "default_allowed" || !self.denied_perms.contains(perm)
+    }
 
-        if let Some(syscall_fn) = self.syscalls[const_idx as usize] {
-            return syscall_fn;
+    /// Look up a syscall by index, without checking permissions
+    pub fn lookup_syscall(&self, const_idx: u16) -> Option<SysCallFn>
+    {
+        if const_idx >= HOST_SYSCALL_BASE {
+            return self.host_syscalls.get(&const_idx).map(|(_, fun)| *fun);
         }
-        else
-        {
-            panic!("unknown syscall \"{}\"", const_idx);
+
+        match self.syscalls.get(const_idx as usize) {
+            Some(Some(syscall_fn)) => Some(*syscall_fn),
+            _ => None
         }
     }
 
+    /// Get the syscall with a given index
+    pub fn get_syscall(&self, const_idx: u16) -> Result<SysCallFn, FaultKind>
+    {
+        let syscall_fn = match self.lookup_syscall(const_idx) {
+            Some(syscall_fn) => syscall_fn,
+            None => return Err(FaultKind::UnknownSyscall(const_idx))
+        };
+
+        if const_idx < HOST_SYSCALL_BASE && !self.is_allowed(SYSCALL_DESCS[const_idx as usize].permission) {
+            return Err(FaultKind::PermissionDenied(const_idx));
+        }
+
+        Ok(syscall_fn)
+    }
+
+    /// Save the subsystem state belonging to the program, such as pending
+    /// callbacks. The host configuration, such as host syscalls and
+    /// denied permissions, is not part of images
+    pub(crate) fn save_image<W: Write>(&self, w: &mut ImageWriter<W>) -> Result<(), ImageError>
+    {
+        self.time_state.save_image(w)?;
+        self.thread_state.save_image(w)?;
+        save_window(w)
+    }
+
+    pub(crate) fn load_image<R: Read>(&mut self, r: &mut ImageReader<R>) -> Result<(), ImageError>
+    {
+        self.time_state.load_image(r)?;
+        self.thread_state.load_image(r)?;
+        load_window(self, r)
+    }
+
     fn init_syscalls(&mut self)
     {
         let mut syscalls = HashMap::<String, SysCallFn>::new();
 
         self.reg_syscall(MEMSET, SysCallFn::Fn3_0(memset));
         self.reg_syscall(MEMCPY, SysCallFn::Fn3_0(memcpy));
+        self.reg_syscall(VM_HEAP_SIZE, SysCallFn::Fn0_1(vm_heap_size));
+        self.reg_syscall(VM_RESIZE_HEAP, SysCallFn::Fn1_1(vm_resize_heap));
+        self.reg_syscall(VM_CODE_SIZE, SysCallFn::Fn0_1(vm_code_size));
+        self.reg_syscall(VM_GROW_CODE, SysCallFn::Fn1_1(vm_grow_code));
+        self.reg_syscall(VM_CODE_WRITE, SysCallFn::Fn3_0(vm_code_write));
 
         self.reg_syscall(PRINT_I64, SysCallFn::Fn1_0(print_i64));
         self.reg_syscall(PRINT_STR, SysCallFn::Fn1_0(print_str));
@@ -143,6 +284,14 @@ impl SysState
         self.reg_syscall(TIME_CURRENT_MS, SysCallFn::Fn0_1(time_current_ms));
         self.reg_syscall(TIME_DELAY_CB, SysCallFn::Fn2_0(time_delay_cb));
 
+        self.reg_syscall(THREAD_SPAWN, SysCallFn::Fn2_1(thread_spawn));
+        self.reg_syscall(THREAD_ID, SysCallFn::Fn0_1(thread_id));
+        self.reg_syscall(THREAD_JOIN, SysCallFn::Fn1_1(thread_join));
+        self.reg_syscall(CHAN_CREATE, SysCallFn::Fn0_1(chan_create));
+        self.reg_syscall(CHAN_SEND, SysCallFn::Fn3_0(chan_send));
+        self.reg_syscall(CHAN_RECV, SysCallFn::Fn3_1(chan_recv));
+        self.reg_syscall(CHAN_ON_RECV, SysCallFn::Fn4_0(chan_on_recv));
+
         self.reg_syscall(WINDOW_CREATE, SysCallFn::Fn4_1(window_create));
         self.reg_syscall(WINDOW_SHOW, SysCallFn::Fn1_0(window_show));
         self.reg_syscall(WINDOW_DRAW_FRAME, SysCallFn::Fn2_0(window_draw_frame));
@@ -152,17 +301,18 @@ impl SysState
     }
 }
 
-fn memset(vm: &mut VM, dst_ptr: Value, val: Value, num_bytes: Value)
+fn memset(vm: &mut VM, dst_ptr: Value, val: Value, num_bytes: Value) -> Result<(), FaultKind>
 {
     let dst_ptr = dst_ptr.as_usize();
     let val = val.as_u8();
     let num_bytes = num_bytes.as_usize();
 
-    let mem_slice: &mut [u8] = vm.get_heap_slice(dst_ptr, num_bytes);
+    let mem_slice: &mut [u8] = vm.get_heap_slice(dst_ptr, num_bytes)?;
     mem_slice.fill(val);
+    Ok(())
 }
 
-fn memcpy(vm: &mut VM, dst_ptr: Value, src_ptr: Value, num_bytes: Value)
+fn memcpy(vm: &mut VM, dst_ptr: Value, src_ptr: Value, num_bytes: Value) -> Result<(), FaultKind>
 {
     let dst_ptr = dst_ptr.as_usize();
     let src_ptr = src_ptr.as_usize();
@@ -170,43 +320,94 @@ fn memcpy(vm: &mut VM, dst_ptr: Value, src_ptr: Value, num_bytes: Value)
 
     // TODO: panic if slices are overlapping
 
-    let dst_ptr: *mut u8 = vm.get_heap_ptr(dst_ptr);
-    let src_ptr: *mut u8 = vm.get_heap_ptr(src_ptr);
+    // Check that both memory regions are within the heap
+    vm.get_heap_slice::<u8>(dst_ptr, num_bytes)?;
+    vm.get_heap_slice::<u8>(src_ptr, num_bytes)?;
+
+    let dst_ptr: *mut u8 = vm.get_heap_ptr(dst_ptr)?;
+    let src_ptr: *mut u8 = vm.get_heap_ptr(src_ptr)?;
 
     unsafe {
         std::ptr::copy_nonoverlapping(src_ptr, dst_ptr, num_bytes);
     }
+
+    Ok(())
+}
+
+fn vm_heap_size(vm: &mut VM) -> Result<Value, FaultKind>
+{
+    Ok(Value::from(vm.heap_size() as u64))
 }
 
-fn print_i64(vm: &mut VM, v: Value)
+fn vm_resize_heap(vm: &mut VM, num_bytes: Value) -> Result<Value, FaultKind>
+{
+    let success = match usize::try_from(num_bytes.as_u64()) {
+        Ok(num_bytes) => vm.resize_heap(num_bytes),
+        Err(_) => false
+    };
+
+    Ok(Value::from(success as u64))
+}
+
+fn vm_code_size(vm: &mut VM) -> Result<Value, FaultKind>
+{
+    Ok(Value::from(vm.code_size() as u64))
+}
+
+fn vm_grow_code(vm: &mut VM, num_bytes: Value) -> Result<Value, FaultKind>
+{
+    let success = match usize::try_from(num_bytes.as_u64()) {
+        Ok(num_bytes) => vm.grow_code(num_bytes),
+        Err(_) => false
+    };
+
+    Ok(Value::from(success as u64))
+}
+
+fn vm_code_write(vm: &mut VM, dst_pc: Value, src_ptr: Value, num_bytes: Value) -> Result<(), FaultKind>
+{
+    let dst_pc = dst_pc.as_usize();
+    let src_ptr = src_ptr.as_usize();
+    let num_bytes = num_bytes.as_usize();
+
+    let bytes = vm.get_heap_slice::<u8>(src_ptr, num_bytes)?.to_vec();
+    vm.write_code(dst_pc, &bytes)
+}
+
+fn print_i64(vm: &mut VM, v: Value) -> Result<(), FaultKind>
 {
     let v = v.as_i64();
     print!("{}", v);
     stdout().flush().unwrap();
+    Ok(())
 }
 
 /// Print a null-terminated UTF-8 string to stdout
-fn print_str(vm: &mut VM, str_ptr: Value)
+fn print_str(vm: &mut VM, str_ptr: Value) -> Result<(), FaultKind>
 {
-    let rust_str = vm.get_heap_str(str_ptr.as_usize());
+    let rust_str = vm.get_heap_str(str_ptr.as_usize())?;
     print!("{}", rust_str);
     stdout().flush().unwrap();
+    Ok(())
 }
 
 /// Print a newline characted to stdout
-fn print_endl(vm: &mut VM)
+fn print_endl(vm: &mut VM) -> Result<(), FaultKind>
 {
     println!();
     stdout().flush().unwrap();
+    Ok(())
 }
 
-fn read_i64(vm: &mut VM) -> Value
+fn read_i64(vm: &mut VM) -> Result<Value, FaultKind>
 {
     let mut line_buf = String::new();
-    stdin()
-        .read_line(&mut line_buf)
-        .expect("failed to read input line");
-    let val: i64 = line_buf.trim().parse().expect("expected i64 input");
+    if stdin().read_line(&mut line_buf).is_err() {
+        return Err(FaultKind::SyscallError("failed to read input line".to_string()));
+    }
 
-    return Value::from(val);
+    match line_buf.trim().parse::<i64>() {
+        Ok(val) => Ok(Value::from(val)),
+        Err(_) => Err(FaultKind::SyscallError("expected i64 input".to_string()))
+    }
 }
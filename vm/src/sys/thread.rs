@@ -0,0 +1,527 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use crate::vm::{VM, Value, FaultKind, ExitReason};
+use crate::image::{ImageWriter, ImageReader, ImageError};
+use super::SysState;
+use super::constants::*;
+
+/// Queue of messages sent to a channel
+#[derive(Default)]
+struct Channel
+{
+    // Messages not yet received, oldest first
+    queue: Mutex<VecDeque<Vec<u8>>>,
+
+    // Signaled when a message is sent
+    ready: Condvar,
+}
+
+impl Channel
+{
+    fn send(&self, msg: Vec<u8>)
+    {
+        self.queue.lock().unwrap().push_back(msg);
+        self.ready.notify_one();
+    }
+
+    /// Wait until a message is available and take it
+    fn recv(&self) -> Vec<u8>
+    {
+        let mut queue = self.queue.lock().unwrap();
+
+        loop {
+            if let Some(msg) = queue.pop_front() {
+                return msg;
+            }
+
+            queue = self.ready.wait(queue).unwrap();
+        }
+    }
+
+    /// Take a message if one is available, without waiting
+    fn try_recv(&self) -> Option<Vec<u8>>
+    {
+        self.queue.lock().unwrap().pop_front()
+    }
+}
+
+/// Result of a thread, either its return value or a fault message
+type ThreadResult = Result<Value, String>;
+
+/// State shared by all the threads of a program
+struct Shared
+{
+    // Id of the next thread to be spawned
+    next_tid: AtomicU64,
+
+    // Channels, indexed by channel id
+    channels: Mutex<Vec<Arc<Channel>>>,
+
+    // Threads that haven't been joined yet, by thread id
+    threads: Mutex<HashMap<u64, JoinHandle<ThreadResult>>>,
+}
+
+// Callback to call when a message is received on a channel
+#[derive(Debug, Copy, Clone)]
+struct RecvCb
+{
+    chan_id: u64,
+    buf_ptr: usize,
+    buf_size: usize,
+    pc: u64,
+}
+
+pub struct ThreadState
+{
+    // Id of the thread this VM runs on, 0 for the main thread
+    tid: u64,
+
+    // State shared with the other threads
+    shared: Arc<Shared>,
+
+    // Receive callbacks registered by this thread
+    recv_cbs: Vec<RecvCb>,
+}
+
+impl Default for ThreadState
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl ThreadState
+{
+    pub fn new() -> Self
+    {
+        let shared = Shared {
+            next_tid: AtomicU64::new(1),
+            channels: Mutex::new(Vec::default()),
+            threads: Mutex::new(HashMap::default()),
+        };
+
+        Self {
+            tid: 0,
+            shared: Arc::new(shared),
+            recv_cbs: Vec::default(),
+        }
+    }
+
+    /// Save the channels with their pending messages, and the receive callbacks
+    /// Running threads can't be saved, since each one has its own VM
+    pub(crate) fn save_image<W: Write>(&self, w: &mut ImageWriter<W>) -> Result<(), ImageError>
+    {
+        if self.tid != 0 {
+            return Err(ImageError::NotSuspendable("only the main thread can be saved".to_string()));
+        }
+
+        if !self.shared.threads.lock().unwrap().is_empty() {
+            return Err(ImageError::NotSuspendable("threads must be joined first".to_string()));
+        }
+
+        w.write_u64(self.shared.next_tid.load(Ordering::Relaxed))?;
+
+        let channels = self.shared.channels.lock().unwrap();
+        w.write_usize(channels.len())?;
+        for chan in channels.iter() {
+            let queue = chan.queue.lock().unwrap();
+            w.write_usize(queue.len())?;
+            for msg in queue.iter() {
+                w.write_bytes(msg)?;
+            }
+        }
+
+        w.write_usize(self.recv_cbs.len())?;
+        for cb in &self.recv_cbs {
+            w.write_u64(cb.chan_id)?;
+            w.write_usize(cb.buf_ptr)?;
+            w.write_usize(cb.buf_size)?;
+            w.write_u64(cb.pc)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn load_image<R: Read>(&mut self, r: &mut ImageReader<R>) -> Result<(), ImageError>
+    {
+        self.shared.next_tid.store(r.read_u64()?, Ordering::Relaxed);
+
+        let mut channels = self.shared.channels.lock().unwrap();
+        for _ in 0..r.read_u64()? {
+            let chan = Channel::default();
+            for _ in 0..r.read_u64()? {
+                chan.queue.lock().unwrap().push_back(r.read_bytes()?);
+            }
+            channels.push(Arc::new(chan));
+        }
+
+        for _ in 0..r.read_u64()? {
+            let cb = RecvCb {
+                chan_id: r.read_u64()?,
+                buf_ptr: r.read_usize()?,
+                buf_size: r.read_usize()?,
+                pc: r.read_u64()?,
+            };
+
+            if cb.chan_id >= channels.len() as u64 {
+                return Err(ImageError::Corrupt(format!("unknown channel {}", cb.chan_id)));
+            }
+
+            self.recv_cbs.push(cb);
+        }
+
+        Ok(())
+    }
+
+    fn get_chan(&self, chan_id: Value) -> Result<Arc<Channel>, FaultKind>
+    {
+        let channels = self.shared.channels.lock().unwrap();
+
+        match channels.get(chan_id.as_usize()) {
+            Some(chan) => Ok(chan.clone()),
+            None => Err(FaultKind::SyscallError(format!("unknown channel {}", chan_id.as_u64())))
+        }
+    }
+}
+
+/// Copy a message into a buffer in the heap, truncating it if needed
+fn copy_msg(vm: &mut VM, msg: &[u8], buf_ptr: usize, buf_size: usize) -> Result<Value, FaultKind>
+{
+    let num_bytes = msg.len().min(buf_size);
+    let buf = vm.get_heap_slice::<u8>(buf_ptr, num_bytes)?;
+    buf.copy_from_slice(&msg[..num_bytes]);
+    Ok(Value::from(msg.len() as u64))
+}
+
+/// Spawn a thread running a function in a new VM with a copy of the heap
+pub fn thread_spawn(vm: &mut VM, fun: Value, arg: Value) -> Result<Value, FaultKind>
+{
+    let sys_state = &vm.sys_state;
+
+    // Only the parts of the system state that can be sent to
+    // another thread are copied, the rest is created anew
+    let mut syscalls = sys_state.syscalls;
+    let host_syscalls = sys_state.host_syscalls.clone();
+    let denied_perms = sys_state.denied_perms.clone();
+    let shared = sys_state.thread_state.shared.clone();
+
+    // Windows can only be used from the main thread
+    for desc in &SYSCALL_DESCS {
+        if desc.subsystem == "window" {
+            syscalls[desc.const_idx as usize] = None;
+        }
+    }
+
+    let parts = vm.copy_parts();
+    let tid = shared.next_tid.fetch_add(1, Ordering::Relaxed);
+
+    // Hold the lock until the thread is registered, so it can't be joined before
+    let mut threads = shared.threads.lock().unwrap();
+    let thread_shared = shared.clone();
+
+    let handle = std::thread::spawn(move || {
+        let mut sys_state = SysState::new();
+        sys_state.syscalls = syscalls;
+        sys_state.host_syscalls = host_syscalls;
+        sys_state.denied_perms = denied_perms;
+        sys_state.thread_state.tid = tid;
+        sys_state.thread_state.shared = thread_shared;
+
+        let mut vm = VM::from_parts(sys_state, parts);
+
+        match vm.call(fun.as_u64(), &[arg]) {
+            ExitReason::Exit(val) | ExitReason::Return(val) => Ok(val),
+            ExitReason::Fault { kind, pc, .. } => {
+                Err(format!("{} at {}", kind, vm.symbols().symbolize(pc)))
+            }
+            ExitReason::Breakpoint => {
+                Err(format!("breakpoint at {}", vm.symbols().symbolize(vm.suspended_pc().unwrap())))
+            }
+            ExitReason::OutOfFuel => unreachable!(),
+        }
+    });
+
+    threads.insert(tid, handle);
+
+    Ok(Value::from(tid))
+}
+
+/// Get the id of the current thread
+pub fn thread_id(vm: &mut VM) -> Result<Value, FaultKind>
+{
+    Ok(Value::from(vm.sys_state.thread_state.tid))
+}
+
+/// Wait for a thread to finish and get its return value
+pub fn thread_join(vm: &mut VM, tid: Value) -> Result<Value, FaultKind>
+{
+    let tid = tid.as_u64();
+
+    // Release the lock before waiting, so other threads can spawn and join
+    let handle = vm.sys_state.thread_state.shared.threads.lock().unwrap().remove(&tid);
+
+    let handle = match handle {
+        Some(handle) => handle,
+        None => return Err(FaultKind::SyscallError(format!("unknown thread {}", tid)))
+    };
+
+    match handle.join() {
+        Ok(Ok(val)) => Ok(val),
+        Ok(Err(msg)) => Err(FaultKind::SyscallError(format!("thread {} faulted: {}", tid, msg))),
+        Err(_) => Err(FaultKind::SyscallError(format!("thread {} panicked", tid)))
+    }
+}
+
+/// Create a new channel
+pub fn chan_create(vm: &mut VM) -> Result<Value, FaultKind>
+{
+    let mut channels = vm.sys_state.thread_state.shared.channels.lock().unwrap();
+    channels.push(Arc::new(Channel::default()));
+    Ok(Value::from((channels.len() - 1) as u64))
+}
+
+/// Send a copy of a block of bytes to a channel
+pub fn chan_send(vm: &mut VM, chan_id: Value, buf_ptr: Value, num_bytes: Value) -> Result<(), FaultKind>
+{
+    let chan = vm.sys_state.thread_state.get_chan(chan_id)?;
+    let msg = vm.get_heap_slice::<u8>(buf_ptr.as_usize(), num_bytes.as_usize())?.to_vec();
+    chan.send(msg);
+    Ok(())
+}
+
+/// Wait for a message on a channel and copy it into a buffer
+pub fn chan_recv(vm: &mut VM, chan_id: Value, buf_ptr: Value, buf_size: Value) -> Result<Value, FaultKind>
+{
+    let chan = vm.sys_state.thread_state.get_chan(chan_id)?;
+    let (buf_ptr, buf_size) = (buf_ptr.as_usize(), buf_size.as_usize());
+
+    // Check the buffer before waiting, so that the message isn't lost
+    vm.get_heap_slice::<u8>(buf_ptr, buf_size)?;
+
+    let msg = chan.recv();
+    copy_msg(vm, &msg, buf_ptr, buf_size)
+}
+
+/// Register a callback for messages received on a channel
+pub fn chan_on_recv(vm: &mut VM, chan_id: Value, buf_ptr: Value, buf_size: Value, callback_pc: Value) -> Result<(), FaultKind>
+{
+    vm.sys_state.thread_state.get_chan(chan_id)?;
+    vm.get_heap_slice::<u8>(buf_ptr.as_usize(), buf_size.as_usize())?;
+
+    let cb = RecvCb {
+        chan_id: chan_id.as_u64(),
+        buf_ptr: buf_ptr.as_usize(),
+        buf_size: buf_size.as_usize(),
+        pc: callback_pc.as_u64(),
+    };
+
+    let recv_cbs = &mut vm.sys_state.thread_state.recv_cbs;
+    recv_cbs.retain(|cb| cb.chan_id != chan_id.as_u64());
+    recv_cbs.push(cb);
+
+    Ok(())
+}
+
+/// Call the receive callbacks for all the messages pending on their channel
+/// Returns the exit reason of the first callback that doesn't return normally,
+/// or None if all the callbacks returned
+pub fn chan_call_recv_cbs(vm: &mut VM) -> Option<ExitReason>
+{
+    let recv_cbs = vm.sys_state.thread_state.recv_cbs.clone();
+
+    for cb in recv_cbs {
+        let chan = vm.sys_state.thread_state.get_chan(Value::from(cb.chan_id)).unwrap();
+
+        while let Some(msg) = chan.try_recv() {
+            // The heap may have been shrunk since the callback was registered
+            let msg_size = match copy_msg(vm, &msg, cb.buf_ptr, cb.buf_size) {
+                Ok(msg_size) => msg_size,
+                Err(kind) => {
+                    let pc = cb.pc as usize;
+                    return Some(ExitReason::Fault { kind, pc, backtrace: vec![pc] });
+                }
+            };
+
+            match vm.call_event(cb.pc, &[msg_size]) {
+                ExitReason::Return(_) => {}
+                exit_reason => return Some(exit_reason)
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::asm::Assembler;
+
+    fn parse(src: &str, jit: bool) -> VM
+    {
+        let mut vm = Assembler::new().parse_str(src).unwrap();
+        assert_eq!(vm.verify(), vec![]);
+        if jit {
+            assert!(vm.enable_jit());
+        }
+        vm
+    }
+
+    #[test]
+    fn test_spawn_recv_join()
+    {
+        // The worker gets a copy of the heap, so its write to FLAG is
+        // not visible to the main thread, only the message it sends is
+        let src = "
+            .data;
+            CHAN: .u64 0;
+            BUF: .u64 0;
+            FLAG: .u64 0;
+            .code;
+            push CHAN;
+            syscall chan_create;
+            store_u64;
+            push_p32 WORKER;
+            push 5;
+            syscall thread_spawn;
+            push CHAN;
+            load_u64;
+            push BUF;
+            push 8;
+            syscall chan_recv;
+            pop;
+            get_local 0;
+            syscall thread_join;
+            push BUF;
+            load_u64;
+            add_u64;
+            push FLAG;
+            load_u64;
+            add_u64;
+            exit;
+            WORKER:
+            push FLAG;
+            push 1000;
+            store_u64;
+            push BUF;
+            get_arg 0;
+            push 10;
+            mul_u64;
+            store_u64;
+            push CHAN;
+            load_u64;
+            push BUF;
+            push 8;
+            syscall chan_send;
+            syscall thread_id;
+            ret;
+        ";
+
+        for jit in [false, true] {
+            let mut vm = parse(src, jit);
+            assert!(matches!(vm.call(0, &[]), ExitReason::Exit(val) if val.as_u64() == 51));
+        }
+    }
+
+    #[test]
+    fn test_recv_cbs()
+    {
+        // Each message is copied into BUF and added to SUM by the callback
+        let src = "
+            .data;
+            CHAN: .u64 0;
+            BUF: .u64 0;
+            SUM: .u64 0;
+            .code;
+            push CHAN;
+            syscall chan_create;
+            store_u64;
+            push CHAN;
+            load_u64;
+            push BUF;
+            push 8;
+            push_p32 ON_MSG;
+            syscall chan_on_recv;
+            push_p32 WORKER;
+            push 3;
+            syscall thread_spawn;
+            syscall thread_join;
+            ret;
+            ON_MSG:
+            push SUM;
+            push SUM;
+            load_u64;
+            push BUF;
+            load_u64;
+            add_u64;
+            store_u64;
+            push 0;
+            ret;
+            WORKER:
+            push BUF;
+            get_arg 0;
+            store_u64;
+            push CHAN;
+            load_u64;
+            push BUF;
+            push 8;
+            syscall chan_send;
+            push BUF;
+            push 4;
+            store_u64;
+            push CHAN;
+            load_u64;
+            push BUF;
+            push 8;
+            syscall chan_send;
+            push 0;
+            ret;
+        ";
+
+        let mut vm = parse(src, false);
+        assert!(matches!(vm.call(0, &[]), ExitReason::Return(_)));
+        assert!(chan_call_recv_cbs(&mut vm).is_none());
+        assert_eq!(vm.get_heap_slice::<u64>(16, 1).unwrap()[0], 7);
+
+        // No more messages to deliver
+        assert!(chan_call_recv_cbs(&mut vm).is_none());
+        assert_eq!(vm.get_heap_slice::<u64>(16, 1).unwrap()[0], 7);
+    }
+
+    #[test]
+    fn test_join_fault()
+    {
+        // Spawned threads can't create windows
+        let src = "
+            push_p32 WORKER;
+            push 0;
+            syscall thread_spawn;
+            syscall thread_join;
+            exit;
+            WORKER:
+            push 0;
+            push 0;
+            push 0;
+            push 0;
+            syscall window_create;
+            ret;
+        ";
+
+        let mut vm = parse(src, false);
+        match vm.call(0, &[]) {
+            ExitReason::Fault { kind: FaultKind::SyscallError(msg), .. } => {
+                assert!(msg.starts_with("thread 1 faulted"), "{}", msg);
+            }
+            _ => panic!()
+        }
+
+        // Threads can only be joined once
+        let mut vm = parse("push 1; syscall thread_join; exit;", false);
+        assert!(matches!(vm.call(0, &[]), ExitReason::Fault { kind: FaultKind::SyscallError(_), .. }));
+    }
+}
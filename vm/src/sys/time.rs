@@ -1,5 +1,7 @@
+use std::io::{Read, Write};
 use std::time::{SystemTime, UNIX_EPOCH};
-use crate::vm::{VM, Value};
+use crate::vm::{VM, Value, FaultKind};
+use crate::image::{ImageWriter, ImageReader, ImageError};
 
 // Callback function to be run at a given time stamp
 #[derive(Debug, Copy, Clone)]
@@ -9,6 +11,7 @@ struct DelayCb
     pc: u64,
 }
 
+#[derive(Default)]
 pub struct TimeState
 {
     // List of delay callbacks
@@ -23,6 +26,46 @@ impl TimeState
             delay_cbs: Vec::default(),
         }
     }
+
+    /// Callbacks are saved with their remaining delay, so
+    /// that they run relative to when the image is resumed
+    /// Callbacks which never expire are saved with a delay of u64::MAX
+    pub(crate) fn save_image<W: Write>(&self, w: &mut ImageWriter<W>) -> Result<(), ImageError>
+    {
+        let cur_time_ms = get_time_ms();
+
+        w.write_usize(self.delay_cbs.len())?;
+        for cb in &self.delay_cbs {
+            let delay_ms = match cb.time_ms {
+                u64::MAX => u64::MAX,
+                time_ms => time_ms.saturating_sub(cur_time_ms),
+            };
+            w.write_u64(delay_ms)?;
+            w.write_u64(cb.pc)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn load_image<R: Read>(&mut self, r: &mut ImageReader<R>) -> Result<(), ImageError>
+    {
+        let cur_time_ms = get_time_ms();
+
+        for _ in 0..r.read_u64()? {
+            let delay_ms = r.read_u64()?;
+            let pc = r.read_u64()?;
+
+            let time_ms = match delay_ms {
+                u64::MAX => u64::MAX,
+                delay_ms => cur_time_ms.checked_add(delay_ms).ok_or_else(|| {
+                    ImageError::Corrupt("callback delay out of range".to_string())
+                })?,
+            };
+            self.delay_cbs.push(DelayCb { time_ms, pc });
+        }
+
+        Ok(())
+    }
 }
 
 /// Get the current time stamp in milliseconds
@@ -32,21 +75,22 @@ pub fn get_time_ms() -> u64
 }
 
 /// Get the current time stamp in milliseconds since the unix epoch
-pub fn time_current_ms(vm: &mut VM) -> Value
+pub fn time_current_ms(vm: &mut VM) -> Result<Value, FaultKind>
 {
-    Value::from(get_time_ms())
+    Ok(Value::from(get_time_ms()))
 }
 
 /// Call a callback function after a given delay in milliseconds
-pub fn time_delay_cb(vm: &mut VM, delay_ms: Value, callback_pc: Value)
+pub fn time_delay_cb(vm: &mut VM, delay_ms: Value, callback_pc: Value) -> Result<(), FaultKind>
 {
     let delay_ms = delay_ms.as_u64();
     let callback_pc = callback_pc.as_u64();
 
     let time_ms = get_time_ms();
 
+    // Delays too long to be represented never expire
     let cb_entry = DelayCb {
-        time_ms: time_ms + delay_ms,
+        time_ms: time_ms.saturating_add(delay_ms),
         pc: callback_pc
     };
 
@@ -56,6 +100,8 @@ pub fn time_delay_cb(vm: &mut VM, delay_ms: Value, callback_pc: Value)
 
     // Sort the callbacks by decreasing trigger time
     //time_state.delay_cbs.sort_by(|a, b| b.time_ms.cmp(&a.time_ms));
+
+    Ok(())
 }
 
 /// Compute the time untl the next delay callback needs to run
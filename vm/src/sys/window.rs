@@ -11,9 +11,11 @@ use sdl2::render::TextureAccess;
 use sdl2::pixels::PixelFormatEnum;
 
 use std::time::Duration;
+use std::io::{Read, Write};
 
 use crate::sys::{SysState};
-use crate::vm::{VM, Value, ExitReason};
+use crate::vm::{VM, Value, ExitReason, FaultKind};
+use crate::image::{ImageWriter, ImageReader, ImageError};
 
 /// Mutable state for the window syscalls
 pub struct WindowState
@@ -45,6 +47,10 @@ struct Window<'a>
     width: u32,
     height: u32,
 
+    // Window title and visibility, so the window can be recreated
+    title: String,
+    shown: bool,
+
     // TODO: we should support multiple windows
     //window_id
 
@@ -64,38 +70,49 @@ struct Window<'a>
 // TODO: eventually we will likely want to allow multiple windows
 static mut WINDOW: Option<Window> = None;
 
-fn get_window(window_id: u32) -> &'static mut Window<'static>
+fn get_window(window_id: u32) -> Result<&'static mut Window<'static>, FaultKind>
 {
-    if window_id != 0 {
-        panic!("for now, only one window supported");
-    }
-
+    // For now, only one window supported
     unsafe {
-        WINDOW.as_mut().unwrap()
+        match WINDOW.as_mut() {
+            Some(window) if window_id == 0 => Ok(window),
+            _ => Err(FaultKind::SyscallError(format!("invalid window id {}", window_id)))
+        }
     }
 }
 
-pub fn window_create(vm: &mut VM, width: Value, height: Value, title: Value, flags: Value) -> Value
+pub fn window_create(vm: &mut VM, width: Value, height: Value, title: Value, flags: Value) -> Result<Value, FaultKind>
 {
     unsafe {
         if WINDOW.is_some() {
-            panic!("for now, only one window supported");
+            return Err(FaultKind::SyscallError("for now, only one window supported".to_string()));
         }
     }
 
-    let width: u32 = width.as_usize().try_into().unwrap();
-    let height: u32 = height.as_usize().try_into().unwrap();
-    let title_str = vm.get_heap_str(title.as_usize()).to_owned();
+    let width: u32 = width.as_u32();
+    let height: u32 = height.as_u32();
+    let title_str = vm.get_heap_str(title.as_usize())?.to_owned();
 
-    let video_subsystem = &mut vm.sys_state.get_window_state().sdl_video;
+    create_window(&mut vm.sys_state, width, height, title_str)?;
 
-    let window = video_subsystem.window(&title_str, width, height)
+    // TODO: return unique window id
+    Ok(Value::from(0))
+}
+
+/// Create the window, failing if SDL rejects its parameters,
+/// e.g. a width or height of zero or too large
+fn create_window(sys_state: &mut SysState, width: u32, height: u32, title: String) -> Result<(), FaultKind>
+{
+    let video_subsystem = &mut sys_state.get_window_state().sdl_video;
+
+    let window = video_subsystem.window(&title, width, height)
         .hidden()
         .position_centered()
         .build()
-        .unwrap();
+        .map_err(|err| FaultKind::SyscallError(format!("failed to create window: {}", err)))?;
 
-    let mut canvas = window.into_canvas().build().unwrap();
+    let mut canvas = window.into_canvas().build()
+        .map_err(|err| FaultKind::SyscallError(format!("failed to create window canvas: {}", err)))?;
 
     canvas.set_draw_color(Color::RGB(0, 0, 0));
     canvas.clear();
@@ -106,6 +123,8 @@ pub fn window_create(vm: &mut VM, width: Value, height: Value, title: Value, fla
     let window = Window {
         width,
         height,
+        title,
+        shown: false,
         canvas,
         texture_creator,
         texture: None,
@@ -122,92 +141,153 @@ pub fn window_create(vm: &mut VM, width: Value, height: Value, title: Value, fla
         let window = WINDOW.as_mut().unwrap();
 
         // Pixels use the BGRA byte order (0xAA_RR_GG_BB on a little-endian machine)
-        window.texture = Some(window.texture_creator.create_texture(
+        let texture = window.texture_creator.create_texture(
             PixelFormatEnum::BGRA32,
             TextureAccess::Streaming,
             width,
             height
-        ).unwrap());
+        );
+
+        match texture {
+            Ok(texture) => window.texture = Some(texture),
+            Err(err) => {
+                WINDOW = None;
+                return Err(FaultKind::SyscallError(format!("failed to create window texture: {}", err)));
+            }
+        }
     }
 
-    // TODO: return unique window id
-    Value::from(0)
+    Ok(())
 }
 
-pub fn window_show(vm: &mut VM, window_id: Value)
+pub fn window_show(vm: &mut VM, window_id: Value) -> Result<(), FaultKind>
 {
-    let window = get_window(window_id.as_u32());
+    let window = get_window(window_id.as_u32())?;
     window.canvas.window_mut().show();
     window.canvas.window_mut().raise();
+    window.shown = true;
+    Ok(())
 }
 
-pub fn window_draw_frame(vm: &mut VM, window_id: Value, src_addr: Value)
+pub fn window_draw_frame(vm: &mut VM, window_id: Value, src_addr: Value) -> Result<(), FaultKind>
 {
-    // Get the address to copy pixel data from
-    let data_ptr = vm.get_heap_ptr(src_addr.as_usize());
+    let window = get_window(window_id.as_u32())?;
 
-    unsafe {
-        let mut window = WINDOW.as_mut().unwrap();
-
-        // Update the texture
-        let pitch = 4 * window.width as usize;
-        let data_len = (4 * window.width * window.height) as usize;
-        let pixel_slice = std::slice::from_raw_parts(data_ptr, data_len);
-        window.texture.as_mut().unwrap().update(None, pixel_slice, pitch).unwrap();
-
-        // Copy the texture into the canvas
-        window.canvas.copy(
-            &window.texture.as_ref().unwrap(),
-            None,
-            None
-        ).unwrap();
-
-        // Update the screen with any rendering performed since the previous call
-        window.canvas.present();
-    }
+    // Get the pixel data to copy, checking that it is within the heap
+    let data_len = (4 * window.width as usize).checked_mul(window.height as usize)
+        .ok_or_else(|| FaultKind::SyscallError("window frame too large".to_string()))?;
+    let pixel_slice: &mut [u8] = vm.get_heap_slice(src_addr.as_usize(), data_len)?;
+
+    // Update the texture
+    let pitch = 4 * window.width as usize;
+    window.texture.as_mut().unwrap().update(None, pixel_slice, pitch).unwrap();
+
+    // Copy the texture into the canvas
+    window.canvas.copy(
+        &window.texture.as_ref().unwrap(),
+        None,
+        None
+    ).unwrap();
+
+    // Update the screen with any rendering performed since the previous call
+    window.canvas.present();
+
+    Ok(())
 }
 
-pub fn window_on_mousemove(vm: &mut VM, window_id: Value, cb: Value)
+pub fn window_on_mousemove(vm: &mut VM, window_id: Value, cb: Value) -> Result<(), FaultKind>
 {
-    let window = get_window(window_id.as_u32());
+    let window = get_window(window_id.as_u32())?;
     window.cb_mousemove = cb.as_u64();
+    Ok(())
 }
 
-pub fn window_on_mousedown(vm: &mut VM, window_id: Value, cb: Value)
+pub fn window_on_mousedown(vm: &mut VM, window_id: Value, cb: Value) -> Result<(), FaultKind>
 {
-    let window = get_window(window_id.as_u32());
+    let window = get_window(window_id.as_u32())?;
     window.cb_mousedown = cb.as_u64();
+    Ok(())
 }
 
-pub fn window_on_mouseup(vm: &mut VM, window_id: Value, cb: Value)
+pub fn window_on_mouseup(vm: &mut VM, window_id: Value, cb: Value) -> Result<(), FaultKind>
 {
-    let window = get_window(window_id.as_u32());
+    let window = get_window(window_id.as_u32())?;
     window.cb_mouseup = cb.as_u64();
+    Ok(())
+}
+
+/// Save the window parameters and callbacks, if a window was created
+/// The contents of the window are not saved, they appear once the
+/// program draws its next frame
+pub(crate) fn save_window<W: Write>(w: &mut ImageWriter<W>) -> Result<(), ImageError>
+{
+    let window = match get_window(0) {
+        Ok(window) => window,
+        Err(_) => return w.write_bool(false)
+    };
+
+    w.write_bool(true)?;
+    w.write_u64(window.width as u64)?;
+    w.write_u64(window.height as u64)?;
+    w.write_str(&window.title)?;
+    w.write_bool(window.shown)?;
+    w.write_u64(window.cb_mousemove)?;
+    w.write_u64(window.cb_mousedown)?;
+    w.write_u64(window.cb_mouseup)
+}
+
+/// Recreate the window saved in an image
+pub(crate) fn load_window<R: Read>(sys_state: &mut SysState, r: &mut ImageReader<R>) -> Result<(), ImageError>
+{
+    if !r.read_bool()? {
+        return Ok(());
+    }
+
+    let width = u32::try_from(r.read_u64()?).map_err(|_| ImageError::Corrupt("invalid window width".to_string()))?;
+    let height = u32::try_from(r.read_u64()?).map_err(|_| ImageError::Corrupt("invalid window height".to_string()))?;
+    let title = r.read_str()?;
+    let shown = r.read_bool()?;
+
+    if get_window(0).is_ok() {
+        return Err(ImageError::Corrupt("a window already exists".to_string()));
+    }
+
+    create_window(sys_state, width, height, title)
+        .map_err(|err| ImageError::Corrupt(err.to_string()))?;
+    let window = get_window(0).unwrap();
+    window.cb_mousemove = r.read_u64()?;
+    window.cb_mousedown = r.read_u64()?;
+    window.cb_mouseup = r.read_u64()?;
+
+    if shown {
+        window.canvas.window_mut().show();
+        window.shown = true;
+    }
+
+    Ok(())
 }
 
 // TODO: functions to process window-related events
-// TODO: we should return the exit reason?
+// The callback functions return the exit reason of the callback,
+// or None if no callback is registered
 // this is gonna be awkward if we have audio processing threads/processes and such?
 // though I suppose exit would just end those processes
 
 // TODO: this is just for testing
 // we should handle window-related events here instead
-pub fn window_call_mousemove(vm: &mut VM, window_id: u32, x: i32, y: i32)
+pub fn window_call_mousemove(vm: &mut VM, window_id: u32, x: i32, y: i32) -> Option<ExitReason>
 {
-    let window = get_window(0);
-    let cb = window.cb_mousemove;
+    let cb = match get_window(0) {
+        Ok(window) => window.cb_mousemove,
+        Err(_) => return None
+    };
 
     if cb == 0 {
-        return;
+        return None;
     }
 
     // TODO: pass window id
-    match vm.call(cb, &[Value::from(0), Value::from(x), Value::from(y)])
-    {
-        // TODO: we should return the exit reason?
-        ExitReason::Exit(val) => {}
-        ExitReason::Return(val) => {}
-    }
+    Some(vm.call_event(cb, &[Value::from(0), Value::from(x), Value::from(y)]))
 }
 
 /*
@@ -220,13 +300,15 @@ MouseButtonDown {
     y: i32,
 },
 */
-pub fn window_call_mousedown(vm: &mut VM, window_id: u32, mouse_id: u32, mouse_btn: MouseButton)
+pub fn window_call_mousedown(vm: &mut VM, window_id: u32, mouse_id: u32, mouse_btn: MouseButton) -> Option<ExitReason>
 {
-    let window = get_window(0);
-    let cb = window.cb_mousedown;
+    let cb = match get_window(0) {
+        Ok(window) => window.cb_mousedown,
+        Err(_) => return None
+    };
 
     if cb == 0 {
-        return;
+        return None;
     }
 
     // TODO: ignore SDL_TOUCH_MOUSEID
@@ -245,21 +327,18 @@ pub fn window_call_mousedown(vm: &mut VM, window_id: u32, mouse_id: u32, mouse_b
     };
 
     // TODO: pass window id
-    match vm.call(cb, &[Value::from(0), Value::from(btn_id)])
-    {
-        // TODO: we should return the exit reason?
-        ExitReason::Exit(val) => {}
-        ExitReason::Return(val) => {}
-    }
+    Some(vm.call_event(cb, &[Value::from(0), Value::from(btn_id)]))
 }
 
-pub fn window_call_mouseup(vm: &mut VM, window_id: u32, mouse_id: u32, mouse_btn: MouseButton)
+pub fn window_call_mouseup(vm: &mut VM, window_id: u32, mouse_id: u32, mouse_btn: MouseButton) -> Option<ExitReason>
 {
-    let window = get_window(0);
-    let cb = window.cb_mouseup;
+    let cb = match get_window(0) {
+        Ok(window) => window.cb_mouseup,
+        Err(_) => return None
+    };
 
     if cb == 0 {
-        return;
+        return None;
     }
 
     // TODO: ignore SDL_TOUCH_MOUSEID
@@ -278,10 +357,5 @@ pub fn window_call_mouseup(vm: &mut VM, window_id: u32, mouse_id: u32, mouse_btn
     };
 
     // TODO: pass window id
-    match vm.call(cb, &[Value::from(0), Value::from(btn_id)])
-    {
-        // TODO: we should return the exit reason?
-        ExitReason::Exit(val) => {}
-        ExitReason::Return(val) => {}
-    }
+    Some(vm.call_event(cb, &[Value::from(0), Value::from(btn_id)]))
 }
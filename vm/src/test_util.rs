@@ -0,0 +1,32 @@
+//! Helpers shared by the tests of the different modules.
+
+use std::io::Write;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+/// Buffer which can be read while a debugger, tracer or log writes into it
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuf
+{
+    /// Text written into the buffer so far
+    pub fn contents(&self) -> String
+    {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+impl Write for SharedBuf
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+    {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()>
+    {
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+//! Instruction tracing, to follow what a program does one instruction at a time.
+//!
+//! Each executed instruction produces one line with its address, the
+//! symbolic name of that address, the disassembled instruction, the stack
+//! depth, and the values at the top of the stack before it executes, with
+//! the top of the stack last. Only the program's state is logged, not
+//! timings, so that the traces of two runs can be compared with diff:
+//!
+//! ```text
+//!     14 ADD+0x4                  add_u64                  depth=4 top=[2, 3, 2, 3]
+//! ```
+//!
+//! Tracing disables the JIT and superinstructions, so that every
+//! instruction of the bytecode is executed and logged by itself.
+
+use std::io::Write;
+use std::ops::Range;
+use crate::vm::VM;
+use crate::debugger::{disasm, parse_num};
+
+/// Number of values at the top of the stack shown on each line
+const NUM_TOP_VALUES: usize = 4;
+
+/// Logs the instructions executed by a VM
+pub struct Tracer
+{
+    out: Box<dyn Write>,
+
+    // Ranges of code addresses to trace, everything if empty
+    ranges: Vec<Range<usize>>,
+
+    // Maximum number of lines to write, if any
+    max_lines: Option<u64>,
+
+    // Number of lines written so far
+    num_lines: u64,
+}
+
+impl Tracer
+{
+    pub fn new<W: Write + 'static>(out: W) -> Self
+    {
+        Self {
+            out: Box::new(out),
+            ranges: Vec::new(),
+            max_lines: None,
+            num_lines: 0,
+        }
+    }
+
+    /// Only trace instructions within a range of the code
+    /// The range is given as `start..end`, where both ends are labels or
+    /// addresses, or as a single label, which covers the code from that
+    /// label up to the next function
+    pub fn add_filter(&mut self, vm: &VM, spec: &str) -> Result<(), String>
+    {
+        let resolve = |s: &str| {
+            parse_num(s)
+                .or_else(|| vm.symbols().find(s))
+                .ok_or_else(|| format!("unknown label or address \"{}\"", s))
+        };
+
+        let range = match spec.split_once("..") {
+            Some((start, end)) => resolve(start)?..resolve(end)?,
+            None if parse_num(spec).is_some() => {
+                let pc = resolve(spec)?;
+                pc..pc + 1
+            }
+            None => {
+                let start = resolve(spec)?;
+                let end = vm.symbols().fn_addrs()
+                    .find(|addr| *addr > start)
+                    .unwrap_or(vm.code_size());
+                start..end
+            }
+        };
+
+        if range.is_empty() {
+            return Err(format!("empty trace range \"{}\"", spec));
+        }
+
+        self.ranges.push(range);
+        Ok(())
+    }
+
+    /// Stop tracing after a given number of lines
+    pub fn set_max_lines(&mut self, max_lines: Option<u64>)
+    {
+        self.max_lines = max_lines;
+    }
+
+    /// Log the instruction at a given address, about to be executed
+    /// Write errors are ignored, so that tracing never stops the program
+    pub(crate) fn log(&mut self, vm: &VM, pc: usize)
+    {
+        if !self.ranges.is_empty() && !self.ranges.iter().any(|range| range.contains(&pc)) {
+            return;
+        }
+
+        if let Some(max_lines) = self.max_lines {
+            if self.num_lines >= max_lines {
+                if self.num_lines == max_lines {
+                    let _ = writeln!(self.out, "trace limit of {} lines reached", max_lines);
+                    self.num_lines += 1;
+                }
+                return;
+            }
+        }
+
+        let (text, _) = disasm(vm.code(), vm.symbols(), &vm.sys_state, pc);
+        let stack = vm.stack_values();
+        let top: Vec<String> = stack[stack.len().saturating_sub(NUM_TOP_VALUES)..]
+            .iter()
+            .map(|val| val.as_i64().to_string())
+            .collect();
+
+        let _ = writeln!(
+            self.out,
+            "{:>6} {:<24} {:<24} depth={} top=[{}]",
+            pc,
+            vm.symbols().symbolize(pc),
+            text,
+            stack.len(),
+            top.join(", ")
+        );
+        self.num_lines += 1;
+    }
+}
+
+impl Drop for Tracer
+{
+    fn drop(&mut self)
+    {
+        let _ = self.out.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::vm::{Value, ExitReason};
+    use crate::asm::Assembler;
+    use crate::test_util::SharedBuf;
+
+    const SRC: &str = "push 2; push 3; call ADD, 2; exit; ADD: get_arg 0; get_arg 1; add_u64; ret;";
+
+    /// Run the program with a tracer, and return the trace lines
+    fn trace(setup: impl Fn(&VM, &mut Tracer)) -> Vec<String>
+    {
+        let buf = SharedBuf::default();
+        let mut vm = Assembler::new().parse_str(SRC).unwrap();
+        let mut tracer = Tracer::new(buf.clone());
+        setup(&vm, &mut tracer);
+        vm.set_tracer(Some(tracer));
+
+        let exit_reason = vm.call(0, &[]);
+        assert!(matches!(exit_reason, ExitReason::Exit(val) if val == Value::from(5u64)));
+
+        let trace = buf.contents();
+        trace.lines().map(|line| line.split_whitespace().collect::<Vec<_>>().join(" ")).collect()
+    }
+
+    #[test]
+    fn test_trace()
+    {
+        let lines = trace(|_, _| {});
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0], "0 0x0 push_2 depth=0 top=[]");
+        assert_eq!(lines[2], "3 0x3 call ADD, 2 depth=2 top=[2, 3]");
+        assert_eq!(lines[5], "14 ADD+0x4 add_u64 depth=4 top=[2, 3, 2, 3]");
+        assert_eq!(lines[7], "9 0x9 exit depth=1 top=[5]");
+
+        // Tracing the same program twice gives the same trace
+        assert_eq!(lines, trace(|_, _| {}));
+    }
+
+    #[test]
+    fn test_filters()
+    {
+        // Only the function called
+        let lines = trace(|vm, tracer| tracer.add_filter(vm, "ADD").unwrap());
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("10 ADD get_arg 0"));
+
+        // A range of addresses, with a cap on the number of lines
+        let lines = trace(|vm, tracer| {
+            tracer.add_filter(vm, "0..ADD").unwrap();
+            tracer.set_max_lines(Some(2));
+        });
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[2], "trace limit of 2 lines reached");
+
+        let vm = Assembler::new().parse_str(SRC).unwrap();
+        let mut tracer = Tracer::new(SharedBuf::default());
+        assert!(tracer.add_filter(&vm, "FOO").is_err());
+        assert!(tracer.add_filter(&vm, "ADD..0").is_err());
+    }
+}
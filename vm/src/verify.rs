@@ -0,0 +1,366 @@
+use std::collections::HashSet;
+use std::fmt;
+use crate::vm::{Op, MemBlock, SymbolTable};
+use crate::decode::{Insn, decode_insn};
+use crate::sys::SysState;
+
+/// Problem found by the bytecode verifier
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic
+{
+    /// Address of the instruction the problem was found at
+    pub pc: usize,
+
+    pub msg: String,
+}
+
+impl fmt::Display for Diagnostic
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "pc={}: {}", self.pc, self.msg)
+    }
+}
+
+/// Number of values popped and pushed by instructions that
+/// don't have an immediate operand affecting the stack
+fn stack_effect(op: Op) -> (usize, usize)
+{
+    use Op::*;
+
+    match op {
+        panic | nop | breakpoint | jmp => (0, 0),
+        push_0 | push_1 | push_2 | push_i8 | push_u32 | push_u64 => (0, 1),
+        get_argc | get_arg | get_local | getn => (0, 1),
+        pop | set_arg | set_local | jz | jnz => (1, 0),
+        dup => (1, 2),
+        swap => (2, 2),
+
+        not_u64 | not_u32 |
+        sx_i8_i32 | sx_i8_i64 | sx_i16_i32 | sx_i16_i64 | sx_i32_i64 |
+        trunc_u8 | trunc_u16 | trunc_u32 |
+        sqrt_f32 | sqrt_f64 |
+        i32_to_f32 | i64_to_f64 | f32_to_i32 | f64_to_i64 | f32_to_f64 | f64_to_f32 |
+        load_u8 | load_u16 | load_u32 | load_u64 => (1, 1),
+
+        add_u64_ovf | sub_u64_ovf | mul_u64_ovf |
+        add_i64_ovf | sub_i64_ovf | mul_i64_ovf |
+        mul_u64_wide | mul_i64_wide => (2, 2),
+
+        store_u8 | store_u16 | store_u32 | store_u64 => (2, 0),
+
+        ret | exit => (1, 0),
+
+        // Calls and syscalls are handled by insn_effect
+        call | tail_call | call_fp | syscall => (0, 0),
+
+        // Other instructions are binary operators
+        _ => (2, 1),
+    }
+}
+
+/// Decode the instruction at a given address
+fn decode(code: &MemBlock, pc: usize) -> Result<Insn, String>
+{
+    let insn = decode_insn(code, pc);
+
+    match insn.op {
+        Op::invalid_op => Err(format!("invalid opcode {}", insn.idx)),
+        Op::truncated => {
+            let mut op_pc = pc;
+            let op = Op::from_u8(code.read_pc::<u8>(&mut op_pc).unwrap()).unwrap();
+            Err(format!("truncated immediate operand for {:?}", op))
+        }
+        _ => Ok(insn)
+    }
+}
+
+/// Jump or call target of an instruction, which may be out of bounds
+fn jump_target(insn: &Insn) -> Option<i64>
+{
+    match insn.op {
+        Op::jmp | Op::jz | Op::jnz | Op::call | Op::tail_call => Some(insn.imm as i64),
+        _ => None
+    }
+}
+
+/// Number of values popped and pushed by an instruction, if known
+fn insn_effect(insn: &Insn, sys_state: &SysState) -> Option<(usize, usize)>
+{
+    let argc = insn.idx as usize;
+
+    match insn.op {
+        // The callee pops the arguments and pushes the return value
+        Op::call => Some((argc, 1)),
+
+        // Control doesn't come back, like for ret
+        Op::tail_call => Some((argc, 0)),
+
+        // The function pointer is on top of the arguments
+        Op::call_fp => Some((argc + 1, 1)),
+
+        // The effect is unknown if the syscall doesn't exist
+        Op::syscall => {
+            sys_state
+                .lookup_syscall(insn.idx)
+                .map(|syscall_fn| (syscall_fn.argc(), syscall_fn.has_ret() as usize))
+        }
+
+        op => Some(stack_effect(op)),
+    }
+}
+
+/// Verify the code space. This decodes every instruction, checks that
+/// jump and call targets land on instruction boundaries, and follows the
+/// control flow from the function entry points to check stack depths.
+pub fn verify_code(code: &MemBlock, sys_state: &SysState, symbols: &SymbolTable) -> Vec<Diagnostic>
+{
+    let mut diags = Vec::new();
+
+    // Decode every instruction, indexed by address
+    let mut insns: Vec<Option<Insn>> = (0..code.len()).map(|_| None).collect();
+    let mut pc = 0;
+    while pc < code.len() {
+        match decode(code, pc) {
+            Ok(insn) => {
+                if insn.op == Op::syscall && insn_effect(&insn, sys_state).is_none() {
+                    diags.push(Diagnostic { pc, msg: format!("unknown syscall {}", insn.idx) });
+                }
+
+                let next_pc = insn.next_pc;
+                insns[pc] = Some(insn);
+                pc = next_pc;
+            }
+
+            // Skip one byte and keep decoding
+            Err(msg) => {
+                diags.push(Diagnostic { pc, msg });
+                pc += 1;
+            }
+        }
+    }
+
+    // Check that jump and call targets are valid
+    let mut entries = Vec::new();
+    for (pc, insn) in insns.iter().enumerate() {
+        let insn = match insn {
+            Some(insn) => insn,
+            None => continue
+        };
+
+        let target = match jump_target(insn) {
+            Some(target) => target,
+            None => continue
+        };
+
+        let is_call = insn.op == Op::call || insn.op == Op::tail_call;
+        let kind = if is_call { "call" } else { "jump" };
+
+        if target < 0 || target >= code.len() as i64 {
+            diags.push(Diagnostic { pc, msg: format!("{} target {} is out of bounds", kind, target) });
+        }
+        else if insns[target as usize].is_none() {
+            diags.push(Diagnostic { pc, msg: format!("{} target {} is not an instruction boundary", kind, target) });
+        }
+        else if is_call {
+            entries.push(target as usize);
+        }
+    }
+
+    // Execution normally starts at address 0
+    entries.push(0);
+    entries.extend(symbols.fn_addrs());
+
+    // Stack depth relative to the base pointer, at each reachable instruction
+    let mut depths: Vec<Option<usize>> = vec![None; code.len()];
+    let mut mismatched = HashSet::new();
+    let mut worklist = Vec::new();
+
+    // Record the stack depth flowing into an instruction
+    let mut flow_to = |pc: usize, depth: usize, depths: &mut Vec<Option<usize>>, worklist: &mut Vec<usize>, diags: &mut Vec<Diagnostic>| {
+        match depths[pc] {
+            None => {
+                depths[pc] = Some(depth);
+                worklist.push(pc);
+            }
+            // Only report the first mismatch at a given address
+            Some(prev_depth) if prev_depth != depth && !mismatched.contains(&pc) => {
+                mismatched.insert(pc);
+                diags.push(Diagnostic {
+                    pc,
+                    msg: format!("inconsistent stack depth at merge point ({} vs {})", prev_depth, depth)
+                });
+            }
+            _ => {}
+        }
+    };
+
+    // Functions start with an empty frame
+    for entry in entries {
+        if entry < code.len() && insns[entry].is_some() {
+            flow_to(entry, 0, &mut depths, &mut worklist, &mut diags);
+        }
+    }
+
+    while let Some(pc) = worklist.pop() {
+        let insn = insns[pc].as_ref().unwrap();
+        let depth = depths[pc].unwrap();
+
+        let (num_pops, num_pushes) = match insn_effect(insn, sys_state) {
+            Some(effect) => effect,
+            None => continue
+        };
+
+        if depth < num_pops {
+            diags.push(Diagnostic {
+                pc,
+                msg: format!("{:?} needs {} values but the stack depth is {}", insn.op, num_pops, depth)
+            });
+            continue;
+        }
+
+        match insn.op {
+            Op::get_local if insn.idx as usize >= depth => {
+                diags.push(Diagnostic { pc, msg: format!("get_local {} is past the frame (depth {})", insn.idx, depth) });
+            }
+
+            // The value is popped before the local is written
+            Op::set_local if insn.idx as usize >= depth - 1 => {
+                diags.push(Diagnostic { pc, msg: format!("set_local {} is past the frame (depth {})", insn.idx, depth - 1) });
+            }
+
+            _ => {}
+        }
+
+        let new_depth = depth - num_pops + num_pushes;
+
+        // Jump targets were checked above, invalid ones aren't followed
+        let target = jump_target(insn)
+            .map(|t| t as usize)
+            .filter(|t| *t < code.len() && insns[*t].is_some());
+
+        let falls_through = match insn.op {
+            Op::panic | Op::ret | Op::tail_call | Op::exit => false,
+
+            Op::jmp => {
+                if let Some(target) = target {
+                    flow_to(target, new_depth, &mut depths, &mut worklist, &mut diags);
+                }
+                false
+            }
+
+            Op::jz | Op::jnz => {
+                if let Some(target) = target {
+                    flow_to(target, new_depth, &mut depths, &mut worklist, &mut diags);
+                }
+                true
+            }
+
+            _ => true
+        };
+
+        if falls_through {
+            if insn.next_pc >= code.len() {
+                diags.push(Diagnostic { pc, msg: "execution falls off the end of the code".to_string() });
+            }
+            else if insns[insn.next_pc].is_some() {
+                flow_to(insn.next_pc, new_depth, &mut depths, &mut worklist, &mut diags);
+            }
+        }
+    }
+
+    diags.sort_by_key(|diag| diag.pc);
+    diags
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::asm::*;
+
+    fn verify_src(src: &str) -> Vec<Diagnostic>
+    {
+        dbg!(src);
+        let asm = Assembler::new();
+        let vm = asm.parse_str(src).unwrap();
+        vm.verify()
+    }
+
+    fn verify_bytes(code: MemBlock) -> Vec<Diagnostic>
+    {
+        verify_code(&code, &SysState::new(), &SymbolTable::new())
+    }
+
+    /// Check that verification fails with a single diagnostic
+    fn expect_diag(diags: Vec<Diagnostic>, pc: usize, msg: &str)
+    {
+        dbg!(&diags);
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].pc, pc);
+        assert!(diags[0].msg.contains(msg));
+    }
+
+    #[test]
+    fn test_valid()
+    {
+        assert_eq!(verify_src(""), vec![]);
+        assert_eq!(verify_src("push 1; exit;"), vec![]);
+        assert_eq!(verify_src("push 0; L: get_local 0; push 1; add_u64; dup; set_local 0; push 10; lt_u64; jnz L; get_local 0; exit;"), vec![]);
+        assert_eq!(verify_src("push 3; push 5; call FN, 2; exit; FN: get_arg 0; get_arg 1; sub_u64; ret;"), vec![]);
+        assert_eq!(verify_src("push 7; push FN; call_fp 1; exit; FN: get_arg 0; ret;"), vec![]);
+        assert_eq!(verify_src("push 7; call FN, 1; exit; FN: push 0; get_arg 0; tail_call G, 1; G: get_arg 0; ret;"), vec![]);
+        assert_eq!(verify_src("push 0; push 0; push 0; syscall memset; push 0; exit;"), vec![]);
+    }
+
+    #[test]
+    fn test_decode()
+    {
+        // Truncated immediate at the end of the code
+        let mut code = MemBlock::new();
+        code.push_op(Op::push_u32);
+        code.push_u16(0);
+        expect_diag(verify_bytes(code), 0, "truncated");
+
+        let mut code = MemBlock::new();
+        code.push_u8(250);
+        expect_diag(verify_bytes(code), 0, "invalid opcode");
+
+        let mut code = MemBlock::new();
+        code.push_op(Op::syscall);
+        code.push_u16(9999);
+        expect_diag(verify_bytes(code), 0, "unknown syscall 9999");
+    }
+
+    #[test]
+    fn test_targets()
+    {
+        // Jump into the middle of the push_u32 instruction
+        let mut code = MemBlock::new();
+        code.push_op(Op::jmp);
+        code.push_i32(1);
+        code.push_op(Op::push_u32);
+        code.push_u32(0);
+        code.push_op(Op::exit);
+        expect_diag(verify_bytes(code), 0, "not an instruction boundary");
+
+        let mut code = MemBlock::new();
+        code.push_op(Op::push_0);
+        code.push_op(Op::call);
+        code.push_i32(-100);
+        code.push_u8(0);
+        code.push_op(Op::exit);
+        expect_diag(verify_bytes(code), 1, "call target -93 is out of bounds");
+    }
+
+    #[test]
+    fn test_stack_depth()
+    {
+        expect_diag(verify_src("push 1; push 2; add_u64; add_u64; exit;"), 3, "stack depth is 1");
+        expect_diag(verify_src("get_local 0; exit;"), 0, "get_local 0 is past the frame");
+        expect_diag(verify_src("push 0; push 1; set_local 1; exit;"), 2, "set_local 1 is past the frame");
+        expect_diag(verify_src("push 1; call FN, 2; exit; FN: push 0; ret;"), 1, "call needs 2 values");
+        expect_diag(verify_src("L: push 1; jmp L;"), 0, "inconsistent stack depth");
+        expect_diag(verify_src("push 1;"), 0, "falls off the end");
+    }
+}
@@ -1,7 +1,20 @@
 use std::mem::{transmute, size_of};
-use std::collections::HashSet;
+use std::collections::{HashSet, BTreeMap};
 use std::ffi::CStr;
+use std::fmt;
+use std::io::{Read, Write};
+use std::time::Instant;
+use std::panic::{self, AssertUnwindSafe};
 use crate::sys::*;
+use crate::asm::{Assembler, ParseError};
+use crate::verify::{Diagnostic, verify_code};
+use crate::decode::*;
+use crate::jit::{Jit, JitCtx, EXIT_CONTINUE, EXIT_FAULT};
+use crate::image::{ImageWriter, ImageReader, ImageError};
+use crate::replay::ReplayLog;
+use crate::trace::Tracer;
+use crate::profile::Profiler;
+use crate::strace::{SysCallTracer, format_syscall, format_ret};
 
 /// Instruction opcodes
 /// Note: commonly used upcodes should be in the [0, 127] range (one byte)
@@ -83,12 +96,23 @@ pub enum Op
     div_i64,
     mod_i64,
 
-    // TODO: arithmetic with overflow
-    // I think these instructions shouldn't jump directly?
-    // depends. We don't need to worry about compactness.
-    // add_u64_ovf,
-    // sub_u64_ovf,
-    // mul_i64_ovf, // produces two 64-bit words of output
+    // 64-bit arithmetic with overflow detection
+    // These push the wrapped-around result, followed by an overflow
+    // flag (0 or 1) on top, so that they can be directly followed
+    // by a jnz to an overflow handler.
+    // add_u64_ovf (a) (b) -> (a+b) (ovf)
+    add_u64_ovf,
+    sub_u64_ovf,
+    mul_u64_ovf,
+    add_i64_ovf,
+    sub_i64_ovf,
+    mul_i64_ovf,
+
+    // Full 64x64 -> 128-bit multiplication
+    // These push the low word of the product, followed by the high word
+    // mul_u64_wide (a) (b) -> (lo) (hi)
+    mul_u64_wide,
+    mul_i64_wide,
 
     // 64-bit integer comparisons
     eq_u64,
@@ -102,9 +126,40 @@ pub enum Op
     gt_i64,
     ge_i64,
 
-    //
-    // TODO: 32-bit integer bitwise, arithmetic & comparison operations
-    //
+    // 32-bit integer operations
+    // These only read the lower 32 bits of their inputs, and their
+    // output is zero-extended to 64 bits, like a trunc_u32 was applied.
+    // Arithmetic wraps around modulo 2^32.
+
+    // 32-bit bitwise operations
+    and_u32,
+    or_u32,
+    xor_u32,
+    not_u32,
+    lshift_u32,
+    rshift_u32,
+    rshift_i32,
+
+    // 32-bit integer arithmetic
+    add_u32,
+    sub_u32,
+    mul_u32,
+    div_u32,
+    mod_u32,
+    div_i32,
+    mod_i32,
+
+    // 32-bit integer comparisons
+    eq_u32,
+    ne_u32,
+    lt_u32,
+    le_u32,
+    gt_u32,
+    ge_u32,
+    lt_i32,
+    le_i32,
+    gt_i32,
+    ge_i32,
 
     // Integer sign extension
     sx_i8_i32,
@@ -129,19 +184,55 @@ pub enum Op
     // test_bit_nz <bit_idx:u8>
 
     // 32-bit floating-point arithmetic
+    // f32 values are stored in the lower 32 bits of a stack slot
     add_f32,
     sub_f32,
     mul_f32,
     div_f32,
 
-    // TODO:
-    // Do we want to provide sin, cos, sqrt as built-ins or not?
-    // sin_f32, cos_f32, sqrt_f32?
-
-    // TODO:
-    // int/float conversion instructions
-    //f32_to_i32,
-    //i32_to_f32,
+    // sqrt is provided as a built-in because it maps to a single
+    // hardware instruction and is correctly rounded under IEEE 754.
+    // sin, cos and other transcendental functions are not, because their
+    // results differ across implementations. They belong in a library.
+    sqrt_f32,
+
+    // 32-bit floating-point comparisons
+    // Comparisons involving NaN are false, except for ne which is true
+    eq_f32,
+    ne_f32,
+    lt_f32,
+    le_f32,
+    gt_f32,
+    ge_f32,
+
+    // 64-bit floating-point arithmetic
+    add_f64,
+    sub_f64,
+    mul_f64,
+    div_f64,
+    sqrt_f64,
+
+    // 64-bit floating-point comparisons
+    eq_f64,
+    ne_f64,
+    lt_f64,
+    le_f64,
+    gt_f64,
+    ge_f64,
+
+    // Int/float conversion instructions
+    // Int to float conversions round to the nearest representable value.
+    // Float to int conversions truncate towards zero, and saturate to the
+    // min/max value of the output type. NaN converts to 0.
+    i32_to_f32,
+    i64_to_f64,
+    f32_to_i32,
+    f64_to_i64,
+
+    // Float/float conversion instructions
+    // f64 to f32 rounds to the nearest representable value
+    f32_to_f64,
+    f64_to_f32,
 
     // Load a value at a given adress
     // store (addr)
@@ -186,8 +277,15 @@ pub enum Op
     call,
 
     // Call a function pointer passed as argument
-    // call <num_args:u8> (f_ptr, arg0, arg1, ..., argN)
-    //call_fp,
+    // The function pointer is an absolute code address, which
+    // gets pushed on top of the arguments
+    // call_fp <num_args:u8> (arg0, arg1, ..., argN, f_ptr)
+    call_fp,
+
+    // Call a function in place of the current one, reusing its stack frame
+    // The callee returns directly to the caller of the current function
+    // tail_call <offset:i32> <num_args:u8> (arg0, arg1, ..., argN)
+    tail_call,
 
     // Call into a host function
     // For example, to set up a device or to allocate more memory
@@ -202,6 +300,33 @@ pub enum Op
     // exit (value)
     exit,
 
+    // Internal opcodes produced by the instruction decoder
+    // These can't appear in bytecode, see decode.rs
+
+    // Superinstruction: get_local <idx>; push <val>; add_u64
+    get_local_add_imm,
+
+    // Superinstruction: get_local <idx0>; get_local <idx1>
+    get_local2,
+
+    // Superinstruction: push <addr>; load_u64
+    load_u64_imm,
+
+    // Superinstructions: lt_i64 or lt_u64 followed by jz or jnz
+    lt_i64_jz,
+    lt_i64_jnz,
+    lt_u64_jz,
+    lt_u64_jnz,
+
+    // Byte that is not a valid opcode
+    invalid_op,
+
+    // Instruction with immediates past the end of the code
+    truncated,
+
+    // Address just past the end of the code
+    end_of_code,
+
     // NOTE: last opcode must have value < 255
     // Currently, every opcode is just one byte long,
     // and we hope to keep it that way, but the value
@@ -209,7 +334,40 @@ pub enum Op
     OP_EXT = 255,
 }
 
+impl Op
+{
+    /// Decode an opcode byte, returning None if it isn't a valid opcode
+    pub fn from_u8(byte: u8) -> Option<Op>
+    {
+        // exit is the last opcode in the enum
+        if byte > Op::exit as u8 {
+            return None;
+        }
+
+        Some(unsafe { transmute::<u8, Op>(byte) })
+    }
+
+    /// Size in bytes of the immediate operands following the opcode
+    pub fn imm_size(&self) -> usize
+    {
+        match self {
+            Op::getn | Op::get_arg | Op::set_arg | Op::get_local | Op::set_local => 1,
+            Op::push_i8 => 1,
+            Op::push_u32 => 4,
+            Op::push_u64 => 8,
+            Op::jmp | Op::jz | Op::jnz => 4,
+
+            // call <offset:i32> <num_args:u8>
+            Op::call | Op::tail_call => 5,
+            Op::call_fp => 1,
+            Op::syscall => 2,
+            _ => 0
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(transparent)]
 pub struct Value(u64);
 
 impl Value
@@ -263,6 +421,16 @@ impl Value
         let Value(val) = *self;
         val as i64
     }
+
+    pub fn as_f32(&self) -> f32 {
+        let Value(val) = *self;
+        f32::from_bits(val as u32)
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        let Value(val) = *self;
+        f64::from_bits(val)
+    }
 }
 
 impl From<u8> for Value {
@@ -307,6 +475,20 @@ impl From<i64> for Value {
     }
 }
 
+// f32 values are stored in the lower 32 bits of a stack slot
+impl From<f32> for Value {
+    fn from(val: f32) -> Self {
+        Value(val.to_bits() as u64)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(val: f64) -> Self {
+        Value(val.to_bits())
+    }
+}
+
+#[derive(Default, Clone)]
 pub struct MemBlock
 {
     data: Vec<u8>
@@ -327,6 +509,17 @@ impl MemBlock
         self.data.len()
     }
 
+    pub fn is_empty(&self) -> bool
+    {
+        self.data.is_empty()
+    }
+
+    /// Resize the memory block, zero-filling any new bytes
+    pub fn resize(&mut self, num_bytes: usize)
+    {
+        self.data.resize(num_bytes, 0);
+    }
+
     pub fn push_op(&mut self, op: Op)
     {
         self.data.push(op as u8);
@@ -381,13 +574,19 @@ impl MemBlock
     }
 
     /// Read a value at the current PC and then increment the PC
-    pub fn read_pc<T>(&self, pc: &mut usize) -> T where T: Copy
+    /// Fails if the value would extend past the end of the block
+    pub fn read_pc<T>(&self, pc: &mut usize) -> Result<T, FaultKind> where T: Copy
     {
+        match pc.checked_add(size_of::<T>()) {
+            Some(end) if end <= self.data.len() => {}
+            _ => return Err(FaultKind::PcOutOfBounds)
+        }
+
         unsafe {
             let buf_ptr = self.data.as_ptr();
             let val_ptr = transmute::<*const u8 , *const T>(buf_ptr.add(*pc));
             *pc += size_of::<T>();
-            std::ptr::read_unaligned(val_ptr)
+            Ok(std::ptr::read_unaligned(val_ptr))
         }
     }
 }
@@ -404,11 +603,254 @@ struct StackFrame
     argc: usize,
 }
 
+/// Stack frame of a suspended call, as shown by a debugger
+pub struct FrameInfo
+{
+    // Address of the next instruction to execute in this frame
+    pub pc: usize,
+
+    // Base pointer, the position of the first local on the stack
+    pub bp: usize,
+
+    pub args: Vec<Value>,
+
+    // Local variables and temporary values, starting at the base pointer
+    pub locals: Vec<Value>,
+}
+
+/// Stack and frame counts when a call started, restored when it completes
+/// Calls can be nested, when a syscall calls back into guest code, or when
+/// the host starts a call while another one is suspended
+#[derive(Copy, Clone)]
+struct CallCtx
+{
+    stack_len: usize,
+    num_frames: usize,
+
+    // Saved (pc, bp) if the call ran out of fuel or hit a breakpoint
+    suspended: Option<(usize, usize)>,
+}
+
+/// Symbol table mapping code addresses to label names
+/// This is used to produce readable backtraces
+#[derive(Default, Clone)]
+pub struct SymbolTable
+{
+    // All code labels, by address
+    labels: BTreeMap<usize, String>,
+
+    // Labels that are function entry points, by address
+    // These are labels that are the target of a call or have their address taken
+    fn_labels: BTreeMap<usize, String>,
+}
+
+impl SymbolTable
+{
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    pub fn add_label(&mut self, name: &str, pos: usize, is_fn: bool)
+    {
+        // If multiple labels have the same address, keep the first one
+        self.labels.entry(pos).or_insert(name.to_string());
+
+        if is_fn {
+            self.fn_labels.entry(pos).or_insert(name.to_string());
+        }
+    }
+
+    /// Find the label closest before a given address, along with the offset
+    /// of the address from that label. Function labels are preferred.
+    pub fn lookup(&self, pc: usize) -> Option<(&str, usize)>
+    {
+        let entry = match self.fn_labels.range(..=pc).next_back() {
+            Some(entry) => Some(entry),
+            None => self.labels.range(..=pc).next_back()
+        };
+
+        entry.map(|(pos, name)| (name.as_str(), pc - pos))
+    }
+
+    /// Find the address of a label by name
+    pub fn find(&self, name: &str) -> Option<usize>
+    {
+        self.labels.iter().find(|(_, label)| *label == name).map(|(pos, _)| *pos)
+    }
+
+    /// Addresses of the function entry points
+    pub fn fn_addrs(&self) -> impl Iterator<Item = usize> + '_
+    {
+        self.fn_labels.keys().copied()
+    }
+
+    fn save_image<W: Write>(&self, w: &mut ImageWriter<W>) -> Result<(), ImageError>
+    {
+        w.write_usize(self.labels.len())?;
+        for (pos, name) in &self.labels {
+            w.write_usize(*pos)?;
+            w.write_str(name)?;
+        }
+
+        w.write_usize(self.fn_labels.len())?;
+        for (pos, name) in &self.fn_labels {
+            w.write_usize(*pos)?;
+            w.write_str(name)?;
+        }
+
+        Ok(())
+    }
+
+    fn load_image<R: Read>(r: &mut ImageReader<R>) -> Result<Self, ImageError>
+    {
+        let mut symbols = Self::new();
+
+        for _ in 0..r.read_u64()? {
+            let pos = r.read_usize()?;
+            symbols.labels.insert(pos, r.read_str()?);
+        }
+
+        for _ in 0..r.read_u64()? {
+            let pos = r.read_usize()?;
+            symbols.fn_labels.insert(pos, r.read_str()?);
+        }
+
+        Ok(symbols)
+    }
+
+    /// Produce a symbolic name for a code address, e.g. fib+0x12
+    pub fn symbolize(&self, pc: usize) -> String
+    {
+        match self.lookup(pc) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+0x{:x}", name, offset),
+            None => format!("0x{:x}", pc)
+        }
+    }
+}
+
+/// Kind of error that can occur while executing guest code
+#[derive(Debug, Clone, PartialEq)]
+pub enum FaultKind
+{
+    // The panic instruction was executed
+    Panic,
+
+    // Invalid or unimplemented opcode
+    InvalidOpcode(u8),
+
+    // The pc went outside of the bounds of the code space
+    PcOutOfBounds,
+
+    // Tried to pop or read past the bottom of the stack
+    StackUnderflow,
+
+    // Invalid argument index in get_arg/set_arg
+    InvalidArg { idx: usize, argc: usize },
+
+    // Invalid local variable index in get_local/set_local
+    InvalidLocal { idx: usize },
+
+    // Memory access past the end of the heap
+    HeapOutOfBounds { addr: usize, size: usize },
+
+    // Write past the end of the code space
+    CodeOutOfBounds { addr: usize, size: usize },
+
+    // Indirect call to an address outside the code space
+    InvalidCallTarget(usize),
+
+    // Integer division or modulo by zero
+    DivByZero,
+
+    // Syscall index that is not implemented
+    UnknownSyscall(u16),
+
+    // Syscall requiring a permission that was denied by the host
+    PermissionDenied(u16),
+
+    // String in the heap that is not null-terminated or not valid UTF-8
+    InvalidString(usize),
+
+    // Error reported by a syscall implementation
+    SyscallError(String),
+
+    // The program did something other than what the replay log recorded
+    ReplayDiverged(String),
+
+    // VM::resume() was called without a suspended call to continue
+    NotSuspended,
+}
+
+impl fmt::Display for FaultKind
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            Self::Panic => write!(f, "panic instruction executed"),
+            Self::InvalidOpcode(op) => write!(f, "invalid opcode {}", op),
+            Self::PcOutOfBounds => write!(f, "pc outside bounds of code space"),
+            Self::StackUnderflow => write!(f, "stack underflow"),
+            Self::InvalidArg { idx, argc } => write!(f, "invalid argument index {}, argc={}", idx, argc),
+            Self::InvalidLocal { idx } => write!(f, "invalid local variable index {}", idx),
+            Self::HeapOutOfBounds { addr, size } => write!(f, "access of {} bytes at address {} past end of heap", size, addr),
+            Self::CodeOutOfBounds { addr, size } => write!(f, "write of {} bytes at address {} past end of code space", size, addr),
+            Self::InvalidCallTarget(addr) => write!(f, "call to invalid code address {}", addr),
+            Self::DivByZero => write!(f, "division by zero"),
+            Self::UnknownSyscall(idx) => write!(f, "unknown syscall {}", idx),
+            Self::PermissionDenied(idx) => {
+                let desc = &constants::SYSCALL_DESCS[*idx as usize];
+                write!(f, "syscall {} requires denied permission {}", desc.name, desc.permission)
+            }
+            Self::InvalidString(addr) => write!(f, "invalid string at address {}", addr),
+            Self::SyscallError(msg) => write!(f, "syscall error: {}", msg),
+            Self::ReplayDiverged(msg) => write!(f, "replay diverged: {}", msg),
+            Self::NotSuspended => write!(f, "no suspended call to resume"),
+        }
+    }
+}
+
 pub enum ExitReason
 {
     Return(Value),
     Exit(Value),
-    //Panic,
+
+    // The instruction budget ran out before execution completed
+    // Execution can be continued with VM::resume()
+    OutOfFuel,
+
+    // A breakpoint instruction was reached, and execution stopped before it
+    // Execution can be continued with VM::resume() once it is removed
+    Breakpoint,
+
+    // Execution was stopped because of an error
+    // The pc is the address of the faulting instruction, and the
+    // backtrace lists the code addresses on the call stack, innermost first
+    Fault { kind: FaultKind, pc: usize, backtrace: Vec<usize> },
+}
+
+/// Default limit on the heap size, 1GiB
+pub const DEFAULT_MAX_HEAP_SIZE: usize = 1 << 30;
+
+/// Default limit on the code space size, 256MiB
+pub const DEFAULT_MAX_CODE_SIZE: usize = 1 << 28;
+
+/// Function called after the code space is written to, with the
+/// address and size of the region that was written.
+/// A JIT can use this to invalidate translated code.
+pub type CodeWriteHook = fn(vm: &mut VM, addr: usize, size: usize);
+
+/// Copy of the parts of a VM needed to start a new thread
+/// Unlike the VM itself, this can be sent to another thread
+pub(crate) struct VMParts
+{
+    code: MemBlock,
+    heap: MemBlock,
+    symbols: SymbolTable,
+    max_heap_size: usize,
+    max_code_size: usize,
+    jit: bool,
 }
 
 pub struct VM
@@ -422,499 +864,1898 @@ pub struct VM
     // Code memory space
     code: MemBlock,
 
+    // Code decoded as it runs, which is what the interpreter executes
+    insns: InsnCache,
+
     // Value stack
     stack: Vec<Value>,
 
     // List of stack frames (activation records)
     frames: Vec<StackFrame>,
+
+    // Code labels from the assembler, used for backtraces
+    symbols: SymbolTable,
+
+    // Maximum size the heap can be resized to, in bytes
+    max_heap_size: usize,
+
+    // Maximum size the code space can grow to, in bytes
+    max_code_size: usize,
+
+    // Hook called when the code space is written to
+    code_write_hook: Option<CodeWriteHook>,
+
+    // Remaining instruction budget, if any
+    fuel: Option<u64>,
+
+    // Budget given to each event callback, if any
+    callback_fuel: Option<u64>,
+
+    // Calls in progress, innermost last
+    calls: Vec<CallCtx>,
+
+    // JIT compiler state, if the JIT is enabled
+    jit: Option<Box<Jit>>,
+
+    // Log of nondeterministic inputs being recorded or replayed, if any
+    replay: Option<ReplayLog>,
+
+    // Tracer logging each instruction executed, if tracing is enabled
+    trace: Option<Tracer>,
+
+    // Tracer logging each syscall and callback, if syscall tracing is enabled
+    strace: Option<SysCallTracer>,
+
+    // Profiler counting the instructions executed, if profiling is enabled
+    profiler: Option<Profiler>,
 }
 
 impl VM
 {
-    pub fn new(code: MemBlock, heap: MemBlock, syscalls: HashSet<u16>) -> Self
+    pub fn new(code: MemBlock, heap: MemBlock, syscalls: HashSet<u16>, symbols: SymbolTable) -> Self
     {
-        // Initialize the system state
-        let sys_state = SysState::new();
+        Self::with_sys_state(SysState::new(), code, heap, symbols)
+    }
+
+    /// Create a VM with a given host system state
+    pub fn with_sys_state(sys_state: SysState, code: MemBlock, heap: MemBlock, symbols: SymbolTable) -> Self
+    {
+        let insns = InsnCache::new(&code);
 
         Self {
             sys_state,
             //syscalls: syscall_fns,
             code,
+            insns,
             heap,
             stack: Vec::default(),
             frames: Vec::default(),
+            symbols,
+            max_heap_size: DEFAULT_MAX_HEAP_SIZE,
+            max_code_size: DEFAULT_MAX_CODE_SIZE,
+            code_write_hook: None,
+            fuel: None,
+            callback_fuel: None,
+            calls: Vec::new(),
+            jit: None,
+            replay: None,
+            trace: None,
+            strace: None,
+            profiler: None,
         }
     }
 
-    pub fn stack_size(&self) -> usize
+    /// Copy the code and heap, along with the settings of this VM
+    pub(crate) fn copy_parts(&self) -> VMParts
     {
-        self.stack.len()
+        VMParts {
+            code: self.code.clone(),
+            heap: self.heap.clone(),
+            symbols: self.symbols.clone(),
+            max_heap_size: self.max_heap_size,
+            max_code_size: self.max_code_size,
+            jit: self.jit.is_some(),
+        }
     }
 
-    pub fn push(&mut self, val: Value)
+    /// Create a VM from parts copied out of another VM
+    pub(crate) fn from_parts(sys_state: SysState, parts: VMParts) -> Self
     {
-        self.stack.push(val);
+        let mut vm = Self::with_sys_state(sys_state, parts.code, parts.heap, parts.symbols);
+        vm.max_heap_size = parts.max_heap_size;
+        vm.max_code_size = parts.max_code_size;
+        if parts.jit {
+            vm.enable_jit();
+        }
+        vm
     }
 
-    pub fn push_bool(&mut self, val: bool)
+    /// Get the current heap size in bytes
+    pub fn heap_size(&self) -> usize
     {
-        let int_val: i64 = if val { 1 } else { 0 };
-        self.stack.push(int_val.into());
+        self.heap.len()
     }
 
-    pub fn pop(&mut self) -> Value
+    /// Set the maximum size the heap can be resized to
+    /// This does not shrink the heap if it is already larger
+    pub fn set_max_heap_size(&mut self, num_bytes: usize)
     {
-        match self.stack.pop() {
-            Some(val) => val,
-            None => panic!("tried to pop when the stack is empty")
-        }
+        self.max_heap_size = num_bytes;
     }
 
-    /// Get a pointer to an address/offset in the heap
-    pub fn get_heap_ptr<T>(&mut self, addr: usize) -> *mut T
+    /// Grow or shrink the heap, zero-filling any new memory
+    /// Returns false, leaving the heap unchanged, if the new
+    /// size is above the maximum heap size
+    pub fn resize_heap(&mut self, num_bytes: usize) -> bool
     {
-        if addr + std::mem::size_of::<T>() > self.heap.len() {
-            panic!(
-                "attempting to access data of type {} past end of heap",
-                std::any::type_name::<T>()
-            );
+        if num_bytes > self.max_heap_size {
+            return false;
         }
 
-        unsafe {
-            let heap_ptr: *mut u8 = self.heap.data.as_mut_ptr().add(addr);
-            transmute::<*mut u8 , *mut T>(heap_ptr)
-        }
+        self.heap.resize(num_bytes);
+        true
     }
 
-    /// Get a mutable slice to access a memory region in the heap
-    pub fn get_heap_slice<T>(&mut self, addr: usize, num_elems: usize) -> &mut [T]
+    /// Get the current code space size in bytes
+    pub fn code_size(&self) -> usize
     {
-        if addr + std::mem::size_of::<T>() * num_elems > self.heap.len() {
-            panic!("attempting to access memory slice past end of heap");
-        }
-
-        unsafe {
-            let heap_ptr: *mut u8 = self.heap.data.as_mut_ptr().add(addr);
+        self.code.len()
+    }
 
-            let start_ptr = transmute::<*mut u8 , *mut T>(heap_ptr);
+    /// Set the maximum size the code space can grow to
+    pub fn set_max_code_size(&mut self, num_bytes: usize)
+    {
+        self.max_code_size = num_bytes;
+    }
 
-            std::slice::from_raw_parts_mut(start_ptr, num_elems)
-        }
+    /// Set a hook to be called whenever the code space is written to
+    pub fn set_code_write_hook(&mut self, hook: Option<CodeWriteHook>)
+    {
+        self.code_write_hook = hook;
     }
 
-    /// Copy an UTF-8 string at a given address in the heap
-    pub fn get_heap_str(&mut self, str_ptr: usize) -> &str
+    /// Grow the code space, filling it with zeros (panic instructions)
+    /// Returns false, leaving the code unchanged, if the new size
+    /// is above the maximum code size
+    pub fn grow_code(&mut self, num_bytes: usize) -> bool
     {
-        // Verify that there is a null-terminator for this string
-        // within the bounds of the heap
-        let mut str_len = 0;
-        loop
-        {
-            let char_idx = str_ptr + str_len;
-            if char_idx >= self.heap.len() {
-                panic!("string is not properly null-terminated");
-            }
+        let new_size = match self.code.len().checked_add(num_bytes) {
+            Some(new_size) if new_size <= self.max_code_size => new_size,
+            _ => return false
+        };
 
-            if self.heap.data[char_idx] == 0 {
-                break;
-            }
+        self.code.resize(new_size);
 
-            str_len += 1;
+        // Only the instructions running into the old end of the code
+        // change, jump targets past the end are resolved at run time
+        let start = (new_size - num_bytes).saturating_sub(MAX_DECODE_SPAN);
+        self.insns.invalidate(&self.code, start, new_size + 1);
+
+        if let Some(jit) = self.jit.as_mut() {
+            jit.grow(new_size);
         }
 
-        // Convert the string to a Rust string
-        let char_ptr = self.get_heap_ptr(str_ptr);
-        let c_str = unsafe { CStr::from_ptr(char_ptr as *const i8) };
-        let rust_str = c_str.to_str().unwrap();
-        rust_str
+        true
     }
 
-    /// Call a function at a given address
-    pub fn call(&mut self, callee_pc: u64, args: &[Value]) -> ExitReason
+    /// Overwrite bytes in the code space
+    /// The code space can only be grown through grow_code
+    /// The new code isn't verified, invalid code faults when it runs
+    pub fn write_code(&mut self, addr: usize, bytes: &[u8]) -> Result<(), FaultKind>
     {
-        assert!(self.stack.len() == 0);
-        assert!(self.frames.len() == 0);
+        match addr.checked_add(bytes.len()) {
+            Some(end) if end <= self.code.len() => {},
+            _ => return Err(FaultKind::CodeOutOfBounds { addr, size: bytes.len() })
+        }
 
-        // Push a new stack frame
-        self.frames.push(StackFrame {
-            prev_bp: usize::MAX,
-            ret_addr: usize::MAX,
-            argc: args.len(),
-        });
+        self.code.data[addr..addr + bytes.len()].copy_from_slice(bytes);
 
-        // Push the arguments on the stack
-        for arg in args {
-            self.stack.push(*arg);
+        // Forget the instructions overlapping with the bytes written
+        let start = addr.saturating_sub(MAX_DECODE_SPAN);
+        self.insns.invalidate(&self.code, start, addr + bytes.len());
+
+        if let Some(jit) = self.jit.as_mut() {
+            jit.invalidate(addr, bytes.len());
         }
 
-        // The base pointer will point at the first local
-        let mut bp = self.stack.len();
-        let mut pc = callee_pc as usize;
+        // Notify the hook so that stale translated code can be invalidated
+        if let Some(hook) = self.code_write_hook {
+            hook(self, addr, bytes.len());
+        }
 
-        // For each instruction to execute
-        loop
-        {
-            if pc >= self.code.len() {
-                panic!("pc outside bounds of code space")
-            }
+        Ok(())
+    }
 
-            let op = self.code.read_pc::<Op>(&mut pc);
-            //dbg!(op);
+    /// Set the instruction budget for subsequent calls
+    /// Each instruction executed consumes one unit of fuel
+    /// None means that there is no limit
+    pub fn set_fuel(&mut self, fuel: Option<u64>)
+    {
+        self.fuel = fuel;
+    }
 
-            match op
-            {
-                Op::panic => panic!("execution error, encountered panic opcode"),
+    /// Give each event callback its own instruction budget, so that a
+    /// callback that never returns stops with ExitReason::OutOfFuel
+    /// This replaces the fuel budget whenever a callback starts
+    pub fn set_callback_fuel(&mut self, fuel: Option<u64>)
+    {
+        self.callback_fuel = fuel;
+    }
 
-                Op::nop => continue,
+    /// Get the remaining instruction budget
+    pub fn fuel(&self) -> Option<u64>
+    {
+        self.fuel
+    }
 
-                Op::pop => {
-                    self.pop();
-                }
+    /// Enable the JIT compiler, which translates blocks of code
+    /// to machine code the first time they are reached
+    /// Returns false if the JIT doesn't support the host platform
+    pub fn enable_jit(&mut self) -> bool
+    {
+        self.jit = Jit::new(self.code.len()).map(Box::new);
+        self.jit.is_some()
+    }
 
-                Op::getn => {
-                    let n = self.code.read_pc::<u8>(&mut pc) as usize;
-                    let val = self.stack[self.stack.len() - (1 + n)];
-                    self.push(val);
-                }
+    /// Record the nondeterministic inputs of the program into a log,
+    /// or replay them from a log
+    pub fn set_replay_log(&mut self, log: Option<ReplayLog>)
+    {
+        self.replay = log;
+    }
 
-                Op::dup => {
-                    let val = self.pop();
-                    self.push(val);
-                    self.push(val);
-                }
+    /// Log every instruction executed, or stop tracing
+    /// The JIT isn't used while tracing
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>)
+    {
+        self.trace = tracer;
+    }
 
-                Op::swap => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    self.push(a);
-                    self.push(b);
-                }
+    /// Count the instructions executed, or stop profiling
+    /// The JIT isn't used while profiling
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>)
+    {
+        self.profiler = profiler;
+    }
 
-                Op::get_arg => {
-                    let idx = self.code.read_pc::<u8>(&mut pc) as usize;
+    /// Stop profiling and get the profiler back, with its counts
+    pub fn take_profiler(&mut self) -> Option<Profiler>
+    {
+        self.profiler.take()
+    }
 
-                    let argc = self.frames[self.frames.len() - 1].argc;
-                    if idx >= argc {
-                        panic!("invalid index in get_arg, idx={}, argc={}", idx, argc);
-                    }
+    /// Log every syscall and callback, or stop tracing them
+    pub fn set_syscall_tracer(&mut self, tracer: Option<SysCallTracer>)
+    {
+        self.strace = tracer;
+    }
 
-                    // Last argument is at bp - 1 (if there are arguments)
-                    let stack_idx = (bp - argc) + idx;
-                    self.push(self.stack[stack_idx]);
-                }
+    pub fn is_replaying(&self) -> bool
+    {
+        self.replay.as_ref().is_some_and(|log| log.is_replaying())
+    }
 
-                Op::set_arg => {
-                    let idx = self.code.read_pc::<u8>(&mut pc) as usize;
+    /// Next event to deliver from the replay log, if any
+    pub fn next_replay_event(&self) -> Option<(u64, Vec<Value>)>
+    {
+        self.replay.as_ref().and_then(|log| log.next_event())
+    }
 
-                    let argc = self.frames[self.frames.len() - 1].argc;
-                    if idx >= argc {
-                        panic!("invalid index in set_arg, idx={}, argc={}", idx, argc);
+    /// Call a callback in response to an event from the event loop
+    /// The event is recorded, or checked against the replay log
+    pub fn call_event(&mut self, callee_pc: u64, args: &[Value]) -> ExitReason
+    {
+        if self.callback_fuel.is_some() {
+            self.fuel = self.callback_fuel;
+        }
+
+        if let Some(log) = self.replay.as_mut() {
+            if let Err(msg) = log.event(callee_pc, args) {
+                let pc = callee_pc as usize;
+                return ExitReason::Fault { kind: FaultKind::ReplayDiverged(msg), pc, backtrace: vec![pc] };
+            }
+        }
+
+        if self.strace.is_none() {
+            return self.call(callee_pc, args);
+        }
+
+        let name = self.symbols.symbolize(callee_pc as usize);
+        self.strace.as_mut().unwrap().callback_start(&name, args);
+        let start_time = Instant::now();
+        let exit_reason = self.call(callee_pc, args);
+        self.strace.as_mut().unwrap().callback_end(&name, &exit_reason, start_time.elapsed());
+
+        exit_reason
+    }
+
+    /// Record how the program ended and flush the log, or check that
+    /// the replay ended the same way as the recorded run
+    pub fn finish_replay(&mut self, exit_reason: &ExitReason) -> Result<(), FaultKind>
+    {
+        match self.replay.as_mut() {
+            Some(log) => log.finish(exit_reason).map_err(FaultKind::ReplayDiverged),
+            None => Ok(())
+        }
+    }
+
+    /// Saved (pc, bp) of the innermost call, if it is suspended
+    fn suspended(&self) -> Option<(usize, usize)>
+    {
+        self.calls.last().and_then(|call| call.suspended)
+    }
+
+    /// Check if the innermost call ran out of fuel and can be resumed
+    pub fn is_suspended(&self) -> bool
+    {
+        self.suspended().is_some()
+    }
+
+    /// Address of the next instruction of the suspended call, if any
+    pub fn suspended_pc(&self) -> Option<usize>
+    {
+        self.suspended().map(|(pc, _)| pc)
+    }
+
+    /// Stack frames of the suspended call, innermost first
+    pub fn suspended_frames(&self) -> Vec<FrameInfo>
+    {
+        let mut infos = Vec::new();
+
+        let (mut pc, mut bp) = match self.suspended() {
+            Some(suspended) => suspended,
+            None => return infos
+        };
+
+        // Values above the frame being visited
+        let mut end = self.stack.len();
+
+        for frame in self.frames.iter().rev() {
+            infos.push(FrameInfo {
+                pc,
+                bp,
+                args: self.stack[bp - frame.argc..bp].to_vec(),
+                locals: self.stack[bp..end].to_vec(),
+            });
+
+            // Stop at the frame of the function called by the host
+            if frame.ret_addr == usize::MAX {
+                break;
+            }
+
+            end = bp - frame.argc;
+            pc = frame.ret_addr;
+            bp = frame.prev_bp;
+        }
+
+        infos
+    }
+
+    pub fn code(&self) -> &MemBlock
+    {
+        &self.code
+    }
+
+    /// Values on the stack, bottom first
+    pub fn stack_values(&self) -> &[Value]
+    {
+        &self.stack
+    }
+
+    pub fn symbols(&self) -> &SymbolTable
+    {
+        &self.symbols
+    }
+
+    /// Get the list of code addresses on the call stack, starting with
+    /// the current pc, followed by the return address of each frame
+    pub fn backtrace(&self, pc: usize) -> Vec<usize>
+    {
+        let mut pcs = vec![pc];
+
+        // The bottom frame of each call has no return address
+        for frame in self.frames.iter().rev() {
+            if frame.ret_addr != usize::MAX {
+                pcs.push(frame.ret_addr);
+            }
+        }
+
+        pcs
+    }
+
+    /// Format a backtrace with symbols, e.g. fib+0x12 <- main+0x40
+    pub fn format_backtrace(&self, pcs: &[usize]) -> String
+    {
+        let syms: Vec<String> = pcs.iter().map(|pc| self.symbols.symbolize(*pc)).collect();
+        syms.join(" <- ")
+    }
+
+    /// Statically check the code space before running it
+    /// Returns the list of problems found, sorted by pc
+    pub fn verify(&self) -> Vec<Diagnostic>
+    {
+        verify_code(&self.code, &self.sys_state, &self.symbols)
+    }
+
+    /// Save the complete state of the program as a resumable image
+    /// This is possible when no call is running, or when the only
+    /// call in progress ran out of fuel and can be resumed
+    pub fn save_image<W: Write>(&self, w: W) -> Result<(), ImageError>
+    {
+        let suspended = match self.calls.as_slice() {
+            [] => None,
+            [call] if call.suspended.is_some() => call.suspended,
+            _ => return Err(ImageError::NotSuspendable("a call is in progress".to_string())),
+        };
+
+        let mut w = ImageWriter::new(w)?;
+
+        w.write_bytes(&self.code.data)?;
+        w.write_bytes(&self.heap.data)?;
+        w.write_usize(self.max_heap_size)?;
+        w.write_usize(self.max_code_size)?;
+        self.symbols.save_image(&mut w)?;
+
+        w.write_usize(self.stack.len())?;
+        for val in &self.stack {
+            w.write_u64(val.as_u64())?;
+        }
+
+        w.write_usize(self.frames.len())?;
+        for frame in &self.frames {
+            w.write_usize(frame.prev_bp)?;
+            w.write_usize(frame.ret_addr)?;
+            w.write_usize(frame.argc)?;
+        }
+
+        w.write_bool(suspended.is_some())?;
+        if let Some((pc, bp)) = suspended {
+            w.write_usize(pc)?;
+            w.write_usize(bp)?;
+        }
+
+        self.sys_state.save_image(&mut w)?;
+
+        w.flush()
+    }
+
+    /// Load a program saved with save_image, to be resumed with the
+    /// given host system state. If a call was suspended, it can be
+    /// continued with VM::resume()
+    pub fn load_image<R: Read>(sys_state: SysState, r: R) -> Result<VM, ImageError>
+    {
+        let mut r = ImageReader::new(r)?;
+
+        let code = MemBlock { data: r.read_bytes()? };
+        let heap = MemBlock { data: r.read_bytes()? };
+        let max_heap_size = r.read_usize()?;
+        let max_code_size = r.read_usize()?;
+        let symbols = SymbolTable::load_image(&mut r)?;
+
+        let mut vm = VM::with_sys_state(sys_state, code, heap, symbols);
+        vm.max_heap_size = max_heap_size;
+        vm.max_code_size = max_code_size;
+
+        for _ in 0..r.read_u64()? {
+            vm.stack.push(Value(r.read_u64()?));
+        }
+
+        for _ in 0..r.read_u64()? {
+            let prev_bp = r.read_usize()?;
+            let ret_addr = r.read_usize()?;
+            let argc = r.read_usize()?;
+            vm.frames.push(StackFrame { prev_bp, ret_addr, argc });
+        }
+
+        if r.read_bool()? {
+            let pc = r.read_usize()?;
+            let bp = r.read_usize()?;
+            if pc >= vm.code.len() || bp > vm.stack.len() || vm.frames.is_empty() {
+                return Err(ImageError::Corrupt("invalid suspended call".to_string()));
+            }
+
+            if !vm.check_frames(bp) {
+                return Err(ImageError::Corrupt("invalid stack frames".to_string()));
+            }
+
+            // The suspended call started with an empty stack
+            vm.calls.push(CallCtx { stack_len: 0, num_frames: 0, suspended: Some((pc, bp)) });
+        }
+        else if !vm.stack.is_empty() || !vm.frames.is_empty() {
+            return Err(ImageError::Corrupt("stack is not empty".to_string()));
+        }
+
+        vm.sys_state.load_image(&mut r)?;
+        r.finish()?;
+
+        Ok(vm)
+    }
+
+    /// Check that the frames of a suspended call are consistent with
+    /// the stack and the code, given the base pointer of the innermost frame
+    fn check_frames(&self, bp: usize) -> bool
+    {
+        let mut bp = bp;
+
+        // Values above the arguments of the frame checked
+        let mut stack_top = self.stack.len();
+
+        for (idx, frame) in self.frames.iter().enumerate().rev() {
+            if bp > stack_top || frame.argc > bp {
+                return false;
+            }
+
+            // The bottom frame returns to the host
+            if idx > 0 && frame.ret_addr >= self.code.len() {
+                return false;
+            }
+
+            stack_top = bp - frame.argc;
+            bp = frame.prev_bp;
+        }
+
+        true
+    }
+
+    pub fn stack_size(&self) -> usize
+    {
+        self.stack.len()
+    }
+
+    pub fn push(&mut self, val: Value)
+    {
+        self.stack.push(val);
+    }
+
+    pub fn push_bool(&mut self, val: bool)
+    {
+        let int_val: i64 = if val { 1 } else { 0 };
+        self.stack.push(int_val.into());
+    }
+
+    pub fn pop(&mut self) -> Result<Value, FaultKind>
+    {
+        match self.stack.pop() {
+            Some(val) => Ok(val),
+            None => Err(FaultKind::StackUnderflow)
+        }
+    }
+
+    /// Number of values on the stack above a base pointer
+    /// Faults if the current function popped values below its base pointer
+    fn frame_len(&self, bp: usize) -> Result<usize, FaultKind>
+    {
+        self.stack.len().checked_sub(bp).ok_or(FaultKind::StackUnderflow)
+    }
+
+    /// Position of the first argument of the current function on the stack
+    /// The arguments are just below the base pointer
+    fn args_start(&self, bp: usize) -> Result<usize, FaultKind>
+    {
+        let argc = self.frames[self.frames.len() - 1].argc;
+        bp.checked_sub(argc).ok_or(FaultKind::StackUnderflow)
+    }
+
+    /// Check that a memory region is within the bounds of the heap
+    fn check_heap_range(&self, addr: usize, size: usize) -> Result<(), FaultKind>
+    {
+        match addr.checked_add(size) {
+            Some(end) if end <= self.heap.len() => Ok(()),
+            _ => Err(FaultKind::HeapOutOfBounds { addr, size })
+        }
+    }
+
+    /// Get a pointer to an address/offset in the heap
+    /// Note that the pointer is not necessarily aligned
+    pub fn get_heap_ptr<T>(&mut self, addr: usize) -> Result<*mut T, FaultKind>
+    {
+        self.check_heap_range(addr, std::mem::size_of::<T>())?;
+
+        unsafe {
+            let heap_ptr: *mut u8 = self.heap.data.as_mut_ptr().add(addr);
+            Ok(transmute::<*mut u8 , *mut T>(heap_ptr))
+        }
+    }
+
+    /// Get a mutable slice to access a memory region in the heap
+    pub fn get_heap_slice<T>(&mut self, addr: usize, num_elems: usize) -> Result<&mut [T], FaultKind>
+    {
+        let num_bytes = std::mem::size_of::<T>().saturating_mul(num_elems);
+        self.check_heap_range(addr, num_bytes)?;
+
+        unsafe {
+            let heap_ptr: *mut u8 = self.heap.data.as_mut_ptr().add(addr);
+
+            let start_ptr = transmute::<*mut u8 , *mut T>(heap_ptr);
+
+            Ok(std::slice::from_raw_parts_mut(start_ptr, num_elems))
+        }
+    }
+
+    /// Read a value from the heap at a possibly unaligned address
+    pub fn heap_read<T>(&mut self, addr: usize) -> Result<T, FaultKind> where T: Copy
+    {
+        let heap_ptr: *mut T = self.get_heap_ptr(addr)?;
+        Ok(unsafe { std::ptr::read_unaligned(heap_ptr) })
+    }
+
+    /// Write a value to the heap at a possibly unaligned address
+    pub fn heap_write<T>(&mut self, addr: usize, val: T) -> Result<(), FaultKind> where T: Copy
+    {
+        let heap_ptr: *mut T = self.get_heap_ptr(addr)?;
+        unsafe { std::ptr::write_unaligned(heap_ptr, val) };
+        Ok(())
+    }
+
+    /// Copy an UTF-8 string at a given address in the heap
+    pub fn get_heap_str(&mut self, str_ptr: usize) -> Result<&str, FaultKind>
+    {
+        // Verify that there is a null-terminator for this string
+        // within the bounds of the heap
+        let mut str_len = 0;
+        loop
+        {
+            let char_idx = str_ptr.saturating_add(str_len);
+            if char_idx >= self.heap.len() {
+                return Err(FaultKind::InvalidString(str_ptr));
+            }
+
+            if self.heap.data[char_idx] == 0 {
+                break;
+            }
+
+            str_len += 1;
+        }
+
+        // Convert the string to a Rust string
+        let char_ptr: *mut u8 = self.get_heap_ptr(str_ptr)?;
+        let c_str = unsafe { CStr::from_ptr(char_ptr as *const i8) };
+
+        match c_str.to_str() {
+            Ok(rust_str) => Ok(rust_str),
+            Err(_) => Err(FaultKind::InvalidString(str_ptr))
+        }
+    }
+
+    /// Call a function at a given address
+    /// Syscalls can use this to call back into guest code while another
+    /// call is running. Such a call has to complete, or be resumed until
+    /// it completes if it runs out of fuel, before the syscall returns.
+    /// The host can also make a call while another one is suspended, which
+    /// then can't be resumed until the new call completes
+    pub fn call(&mut self, callee_pc: u64, args: &[Value]) -> ExitReason
+    {
+        // Save the current context, which is restored when the call completes
+        self.calls.push(CallCtx {
+            stack_len: self.stack.len(),
+            num_frames: self.frames.len(),
+            suspended: None,
+        });
+
+        // Push a new stack frame
+        self.frames.push(StackFrame {
+            prev_bp: usize::MAX,
+            ret_addr: usize::MAX,
+            argc: args.len(),
+        });
+
+        // Push the arguments on the stack
+        for arg in args {
+            self.stack.push(*arg);
+        }
+
+        // The base pointer will point at the first local
+        let bp = self.stack.len();
+        let pc = callee_pc as usize;
+
+        self.run(pc, bp)
+    }
+
+    /// Resume the innermost call if it ran out of fuel or stopped at a
+    /// breakpoint, using the current fuel budget
+    pub fn resume(&mut self) -> ExitReason
+    {
+        match self.calls.last_mut().and_then(|call| call.suspended.take()) {
+            Some((pc, bp)) => self.run(pc, bp),
+            None => ExitReason::Fault { kind: FaultKind::NotSuspended, pc: 0, backtrace: Vec::new() }
+        }
+    }
+
+    /// Execute from a given pc and bp, and clean up once the call completes
+    fn run(&mut self, pc: usize, bp: usize) -> ExitReason
+    {
+        // Number of calls in progress, including this one
+        let num_calls = self.calls.len();
+
+        let mut insn_pc = pc;
+        let exit_reason = match self.exec(pc, bp, &mut insn_pc)
+        {
+            Ok(exit_reason) => exit_reason,
+            Err(kind) => {
+                let backtrace = self.backtrace(insn_pc);
+                ExitReason::Fault { kind, pc: insn_pc, backtrace }
+            }
+        };
+
+        // Unless the call can be resumed, restore the context from before it
+        if !matches!(exit_reason, ExitReason::OutOfFuel | ExitReason::Breakpoint) {
+            // A nested call made by a syscall which was suspended, and then
+            // abandoned by the syscall returning an error, is discarded
+            self.calls.truncate(num_calls);
+
+            let call = self.calls.pop().unwrap();
+            self.stack.truncate(call.stack_len);
+            self.frames.truncate(call.num_frames);
+        }
+
+        exit_reason
+    }
+
+    /// Pop the arguments of a syscall, call it and push its return value
+    /// The syscall is logged if syscalls are being traced
+    fn exec_syscall(&mut self, syscall_idx: u16) -> Result<(), FaultKind>
+    {
+        if self.strace.is_none() {
+            return self.dispatch_syscall(syscall_idx);
+        }
+
+        // The arguments are formatted before the syscall can change them
+        let syscall_fn = self.sys_state.lookup_syscall(syscall_idx);
+        let argc = syscall_fn.map_or(0, |syscall_fn| syscall_fn.argc()).min(self.stack.len());
+        let args = self.stack[self.stack.len() - argc..].to_vec();
+        let call = format_syscall(self, syscall_idx, &args);
+
+        let start_time = Instant::now();
+        let result = self.dispatch_syscall(syscall_idx);
+        let elapsed = start_time.elapsed();
+
+        let ret = match &result {
+            Ok(()) if syscall_fn.is_some_and(|syscall_fn| syscall_fn.has_ret()) => {
+                let val = *self.stack.last().unwrap();
+                Ok(Some(format_ret(self, syscall_idx, val)))
+            }
+            Ok(()) => Ok(None),
+            Err(kind) => Err(kind),
+        };
+        self.strace.as_mut().unwrap().syscall(&call, ret, elapsed);
+
+        result
+    }
+
+    /// Call a syscall, or get its result from the replay log
+    fn dispatch_syscall(&mut self, syscall_idx: u16) -> Result<(), FaultKind>
+    {
+        let syscall_fn = self.sys_state.get_syscall(syscall_idx)?;
+        let recorded = self.replay.is_some() && ReplayLog::is_recorded(syscall_idx);
+
+        // When replaying, the result comes from the log instead of the outside world
+        if recorded && self.is_replaying() {
+            for _ in 0..syscall_fn.argc() {
+                self.pop()?;
+            }
+
+            let val = self.replay.as_mut().unwrap().syscall(syscall_idx, Value(0));
+            self.push(val.map_err(FaultKind::ReplayDiverged)?);
+            return Ok(());
+        }
+
+        let num_calls = self.calls.len();
+        self.call_syscall_fn(syscall_fn)?;
+
+        // Calls back into guest code can't be left suspended, since
+        // their stack values would be mistaken for the syscall's
+        if self.calls.len() != num_calls {
+            return Err(FaultKind::SyscallError("syscall returned while a call it made is suspended".to_string()));
+        }
+
+        if recorded {
+            let val = *self.stack.last().unwrap();
+            self.replay.as_mut().unwrap().syscall(syscall_idx, val).map_err(FaultKind::ReplayDiverged)?;
+        }
+
+        Ok(())
+    }
+
+    /// Pop the arguments of a syscall function, call it and push its return value
+    fn call_syscall_fn(&mut self, syscall_fn: SysCallFn) -> Result<(), FaultKind>
+    {
+        match syscall_fn
+        {
+            SysCallFn::Fn0_0(fun) => {
+                fun(self)?
+            }
+
+            SysCallFn::Fn0_1(fun) => {
+                let v = fun(self)?;
+                self.push(v);
+            }
+
+            SysCallFn::Fn1_0(fun) => {
+                let a0 = self.pop()?;
+                fun(self, a0)?
+            }
+
+            SysCallFn::Fn1_1(fun) => {
+                let a0 = self.pop()?;
+                let v = fun(self, a0)?;
+                self.push(v);
+            }
+
+            SysCallFn::Fn2_0(fun) => {
+                let a1 = self.pop()?;
+                let a0 = self.pop()?;
+                fun(self, a0, a1)?
+            }
+
+            SysCallFn::Fn2_1(fun) => {
+                let a1 = self.pop()?;
+                let a0 = self.pop()?;
+                let v = fun(self, a0, a1)?;
+                self.push(v);
+            }
+
+            SysCallFn::Fn3_0(fun) => {
+                let a2 = self.pop()?;
+                let a1 = self.pop()?;
+                let a0 = self.pop()?;
+                fun(self, a0, a1, a2)?
+            }
+
+            SysCallFn::Fn3_1(fun) => {
+                let a2 = self.pop()?;
+                let a1 = self.pop()?;
+                let a0 = self.pop()?;
+                let v = fun(self, a0, a1, a2)?;
+                self.push(v);
+            }
+
+            SysCallFn::Fn4_0(fun) => {
+                let a3 = self.pop()?;
+                let a2 = self.pop()?;
+                let a1 = self.pop()?;
+                let a0 = self.pop()?;
+                fun(self, a0, a1, a2, a3)?
+            }
+
+            SysCallFn::Fn4_1(fun) => {
+                let a3 = self.pop()?;
+                let a2 = self.pop()?;
+                let a1 = self.pop()?;
+                let a0 = self.pop()?;
+                let v = fun(self, a0, a1, a2, a3)?;
+                self.push(v);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Perform a syscall for translated code, which passes in the stack
+    /// size and fuel it computed, and gets them back after the syscall
+    /// Both can change if the syscall calls back into guest code
+    /// Returns false if the syscall faulted
+    pub(crate) fn jit_syscall(&mut self, syscall_idx: u16, stack_len: &mut usize, fuel: &mut u64) -> bool
+    {
+        unsafe { self.stack.set_len(*stack_len) };
+        if let Some(vm_fuel) = self.fuel.as_mut() {
+            *vm_fuel = *fuel;
+        }
+
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| self.exec_syscall(syscall_idx))) {
+            Ok(result) => result,
+            Err(payload) => {
+                self.jit.as_mut().unwrap().panic = Some(payload);
+                Err(FaultKind::SyscallError("syscall panicked".to_string()))
+            }
+        };
+
+        *stack_len = self.stack.len();
+        if let Some(vm_fuel) = self.fuel {
+            *fuel = vm_fuel;
+        }
+
+        match result {
+            Ok(()) => true,
+            Err(kind) => {
+                self.jit.as_mut().unwrap().fault = Some(kind);
+                false
+            }
+        }
+    }
+
+    /// Run translated blocks for as long as possible, starting at pc
+    /// This returns when the instruction at pc has to be interpreted
+    fn exec_jit(&mut self, pc: &mut usize, bp: usize, insn_pc: &mut usize) -> Result<(), FaultKind>
+    {
+        // Stub the last block exited through, plus one
+        let mut stub = 0;
+
+        loop
+        {
+            // Link the block exited from to the next one, so that
+            // next time, execution goes from one to the other directly
+            let jit = self.jit.as_mut().unwrap();
+            let block = if stub != 0 {
+                jit.link_stub(stub - 1, &self.code, &self.sys_state)
+            }
+            else
+            {
+                jit.get_block(*pc, &self.code, &self.sys_state)
+            };
+
+            let block = match block {
+                Some(block) => block,
+                None => return Ok(())
+            };
+
+            // The block must not run out of fuel partway through
+            let fuel = self.fuel.unwrap_or(u64::MAX);
+            if fuel < block.max_ops {
+                return Ok(());
+            }
+
+            self.stack.reserve(block.max_growth);
+            let stack = self.stack.as_mut_ptr();
+
+            let mut ctx = JitCtx {
+                stack,
+                stack_len: self.stack.len(),
+                stack_end: unsafe { stack.add(self.stack.capacity()) },
+                bp,
+                argc: self.frames[self.frames.len() - 1].argc,
+                heap: self.heap.data.as_mut_ptr(),
+                heap_len: self.heap.len(),
+                pc: *pc,
+                last_pc: *insn_pc,
+                fuel,
+                stub: 0,
+                vm: self as *mut VM,
+            };
+
+            let status = unsafe { (block.entry)(&mut ctx) };
+
+            unsafe { self.stack.set_len(ctx.stack_len) };
+            if let Some(fuel) = self.fuel.as_mut() {
+                *fuel = ctx.fuel;
+            }
+            *pc = ctx.pc;
+            *insn_pc = ctx.last_pc;
+            stub = ctx.stub;
+
+            // A syscall that panicked panics the same way as in the interpreter
+            if let Some(payload) = self.jit.as_mut().unwrap().panic.take() {
+                panic::resume_unwind(payload);
+            }
+
+            match status {
+                EXIT_CONTINUE => continue,
+                EXIT_FAULT => return Err(self.jit.as_mut().unwrap().fault.take().unwrap()),
+                _ => return Ok(())
+            }
+        }
+    }
+
+    /// Execute instructions until exit, a top-level return or running out of fuel.
+    /// The address of each instruction is written into insn_pc
+    /// before it executes, so that faults can be located.
+    fn exec(&mut self, mut pc: usize, mut bp: usize, insn_pc: &mut usize) -> Result<ExitReason, FaultKind>
+    {
+        // Number of frames below the function called by the host
+        let base_frames = self.calls[self.calls.len() - 1].num_frames;
+
+        // Tracing and profiling observe each instruction executed
+        let instrumented = self.trace.is_some() || self.profiler.is_some();
+
+        // Execution starts in a call stack the profiler hasn't seen
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.stack_changed();
+        }
+
+        // For each instruction to execute
+        loop
+        {
+            if self.jit.is_some() && !instrumented {
+                self.exec_jit(&mut pc, bp, insn_pc)?;
+            }
+
+            // When instrumented, each instruction is executed by itself
+            // Jump targets past the end of the code run into its end
+            let mut insn = match instrumented {
+                true => decode_insn(&self.code, pc),
+                false => self.insns.get(&self.code, pc)
+            };
+
+            // If we are out of fuel, save the state so that we can resume
+            // The stack and frames are left as they are
+            if let Some(fuel) = self.fuel.as_mut() {
+                if *fuel == 0 {
+                    self.calls.last_mut().unwrap().suspended = Some((pc, bp));
+                    return Ok(ExitReason::OutOfFuel);
+                }
+
+                // If there isn't enough fuel for a whole superinstruction,
+                // execute its first instruction by itself
+                if insn.num_ops() > *fuel {
+                    insn = decode_insn(&self.code, pc);
+                }
+                *fuel -= insn.num_ops();
+            }
+
+            // Breakpoints are left out of the trace, since the
+            // instruction they stop at is logged when it executes
+            if self.trace.is_some() && insn.op != Op::breakpoint {
+                let mut tracer = self.trace.take().unwrap();
+                tracer.log(self, pc);
+                self.trace = Some(tracer);
+            }
+
+            if self.profiler.is_some() && insn.op != Op::breakpoint {
+                let mut profiler = self.profiler.take().unwrap();
+                profiler.log(self, pc, insn.op);
+                self.profiler = Some(profiler);
+            }
+
+            // The previous instruction is blamed for going out of bounds
+            let prev_pc = *insn_pc;
+            *insn_pc = pc;
+            pc = insn.next_pc;
+            //dbg!(insn.op);
+
+            match insn.op
+            {
+                Op::panic => return Err(FaultKind::Panic),
+
+                Op::nop => continue,
+
+                // Stop before the breakpoint, so that a debugger can
+                // put back the instruction it replaced and resume there
+                Op::breakpoint => {
+                    if let Some(fuel) = self.fuel.as_mut() {
+                        *fuel += 1;
+                    }
+                    self.calls.last_mut().unwrap().suspended = Some((*insn_pc, bp));
+                    return Ok(ExitReason::Breakpoint);
+                }
+
+                Op::pop => {
+                    self.pop()?;
+                }
+
+                Op::getn => {
+                    let n = insn.idx as usize;
+                    if n >= self.stack.len() {
+                        return Err(FaultKind::StackUnderflow);
+                    }
+                    let val = self.stack[self.stack.len() - (1 + n)];
+                    self.push(val);
+                }
+
+                Op::dup => {
+                    let val = self.pop()?;
+                    self.push(val);
+                    self.push(val);
+                }
+
+                Op::swap => {
+                    let a = self.pop()?;
+                    let b = self.pop()?;
+                    self.push(a);
+                    self.push(b);
+                }
+
+                Op::get_argc => {
+                    let argc = self.frames[self.frames.len() - 1].argc;
+                    self.push(Value::from(argc as u64));
+                }
+
+                Op::get_arg => {
+                    let idx = insn.idx as usize;
+
+                    let argc = self.frames[self.frames.len() - 1].argc;
+                    if idx >= argc {
+                        return Err(FaultKind::InvalidArg { idx, argc });
                     }
 
                     // Last argument is at bp - 1 (if there are arguments)
-                    let stack_idx = (bp - argc) + idx;
-                    let val = self.pop();
+                    self.frame_len(bp)?;
+                    let stack_idx = self.args_start(bp)? + idx;
+                    self.push(self.stack[stack_idx]);
+                }
+
+                Op::set_arg => {
+                    let idx = insn.idx as usize;
+
+                    let argc = self.frames[self.frames.len() - 1].argc;
+                    if idx >= argc {
+                        return Err(FaultKind::InvalidArg { idx, argc });
+                    }
+
+                    // Last argument is at bp - 1 (if there are arguments)
+                    let val = self.pop()?;
+                    self.frame_len(bp)?;
+                    let stack_idx = self.args_start(bp)? + idx;
                     self.stack[stack_idx] = val;
                 }
 
                 Op::get_local => {
-                    let idx = self.code.read_pc::<u8>(&mut pc) as usize;
+                    let idx = insn.idx as usize;
+
+                    if bp + idx >= self.stack.len() {
+                        return Err(FaultKind::InvalidLocal { idx });
+                    }
+
+                    self.push(self.stack[bp + idx]);
+                }
+
+                Op::set_local => {
+                    let idx = insn.idx as usize;
+                    let val = self.pop()?;
 
                     if bp + idx >= self.stack.len() {
-                        panic!("invalid index {} in get_local", idx);
+                        return Err(FaultKind::InvalidLocal { idx });
+                    }
+
+                    self.stack[bp + idx] = val;
+                }
+
+                // All push instructions are decoded as push_u64
+                Op::push_u64 => {
+                    self.push(Value::from(insn.imm));
+                }
+
+                Op::and_u64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_u64() & v1.as_u64()
+                    ));
+                }
+
+                Op::or_u64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_u64() | v1.as_u64()
+                    ));
+                }
+
+                Op::xor_u64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_u64() ^ v1.as_u64()
+                    ));
+                }
+
+                Op::not_u64 => {
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        !v0.as_u64()
+                    ));
+                }
+
+                Op::lshift_u64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_u64().wrapping_shl(v1.as_u32())
+                    ));
+                }
+
+                Op::rshift_u64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_u64().wrapping_shr(v1.as_u32())
+                    ));
+                }
+
+                Op::rshift_i64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_i64().wrapping_shr(v1.as_u32())
+                    ));
+                }
+
+                Op::add_u64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_u64().wrapping_add(v1.as_u64())
+                    ));
+                }
+
+                Op::sub_u64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_u64().wrapping_sub(v1.as_u64())
+                    ));
+                }
+
+                Op::mul_u64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_u64().wrapping_mul(v1.as_u64())
+                    ));
+                }
+
+                Op::div_u64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    if v1.as_u64() == 0 {
+                        return Err(FaultKind::DivByZero);
+                    }
+                    self.push(Value::from(
+                        v0.as_u64() / v1.as_u64()
+                    ));
+                }
+
+                Op::mod_u64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    if v1.as_u64() == 0 {
+                        return Err(FaultKind::DivByZero);
+                    }
+                    self.push(Value::from(
+                        v0.as_u64() % v1.as_u64()
+                    ));
+                }
+
+                // i64::MIN / -1 wraps around to i64::MIN
+                Op::div_i64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    if v1.as_i64() == 0 {
+                        return Err(FaultKind::DivByZero);
+                    }
+                    self.push(Value::from(
+                        v0.as_i64().wrapping_div(v1.as_i64())
+                    ));
+                }
+
+                // i64::MIN % -1 wraps around to 0
+                Op::mod_i64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    if v1.as_i64() == 0 {
+                        return Err(FaultKind::DivByZero);
                     }
+                    self.push(Value::from(
+                        v0.as_i64().wrapping_rem(v1.as_i64())
+                    ));
+                }
+
+                Op::add_u64_ovf => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    let (r, ovf) = v0.as_u64().overflowing_add(v1.as_u64());
+                    self.push(Value::from(r));
+                    self.push_bool(ovf);
+                }
+
+                Op::sub_u64_ovf => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    let (r, ovf) = v0.as_u64().overflowing_sub(v1.as_u64());
+                    self.push(Value::from(r));
+                    self.push_bool(ovf);
+                }
+
+                Op::mul_u64_ovf => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    let (r, ovf) = v0.as_u64().overflowing_mul(v1.as_u64());
+                    self.push(Value::from(r));
+                    self.push_bool(ovf);
+                }
+
+                Op::add_i64_ovf => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    let (r, ovf) = v0.as_i64().overflowing_add(v1.as_i64());
+                    self.push(Value::from(r));
+                    self.push_bool(ovf);
+                }
+
+                Op::sub_i64_ovf => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    let (r, ovf) = v0.as_i64().overflowing_sub(v1.as_i64());
+                    self.push(Value::from(r));
+                    self.push_bool(ovf);
+                }
+
+                Op::mul_i64_ovf => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    let (r, ovf) = v0.as_i64().overflowing_mul(v1.as_i64());
+                    self.push(Value::from(r));
+                    self.push_bool(ovf);
+                }
+
+                Op::mul_u64_wide => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    let r = (v0.as_u64() as u128) * (v1.as_u64() as u128);
+                    self.push(Value::from(r as u64));
+                    self.push(Value::from((r >> 64) as u64));
+                }
+
+                Op::mul_i64_wide => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    let r = (v0.as_i64() as i128) * (v1.as_i64() as i128);
+                    self.push(Value::from(r as u64));
+                    self.push(Value::from((r >> 64) as u64));
+                }
+
+                Op::eq_u64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_u64() == v1.as_u64());
+                }
+
+                Op::ne_u64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_u64() != v1.as_u64());
+                }
+
+                Op::lt_u64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_u64() < v1.as_u64());
+                }
 
-                    self.push(self.stack[bp + idx]);
+                Op::le_u64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_u64() <= v1.as_u64());
                 }
 
-                Op::set_local => {
-                    let idx = self.code.read_pc::<u8>(&mut pc) as usize;
-                    let val = self.pop();
+                Op::gt_u64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_u64() > v1.as_u64());
+                }
 
-                    if bp + idx >= self.stack.len() {
-                        panic!("invalid index in set_local");
-                    }
+                Op::ge_u64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_u64() >= v1.as_u64());
+                }
 
-                    self.stack[bp + idx] = val;
+                Op::lt_i64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_i64() < v1.as_i64());
                 }
 
-                Op::push_0 => {
-                    self.push(Value::from(0 as u64));
+                Op::le_i64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_i64() <= v1.as_i64());
                 }
-                Op::push_1 => {
-                    self.push(Value::from(1 as u64));
+
+                Op::gt_i64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_i64() > v1.as_i64());
                 }
-                Op::push_2 => {
-                    self.push(Value::from(2 as u64));
+
+                Op::ge_i64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_i64() >= v1.as_i64());
                 }
 
-                Op::push_i8 => {
-                    let val = self.code.read_pc::<i8>(&mut pc);
-                    self.push(val.into());
+                Op::and_u32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_u32() & v1.as_u32()
+                    ));
                 }
 
-                Op::push_u32 => {
-                    let val = self.code.read_pc::<u32>(&mut pc);
-                    self.push(Value::from(val));
+                Op::or_u32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_u32() | v1.as_u32()
+                    ));
                 }
 
-                Op::push_u64 => {
-                    let val = self.code.read_pc::<u64>(&mut pc);
-                    self.push(Value::from(val));
+                Op::xor_u32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_u32() ^ v1.as_u32()
+                    ));
                 }
 
-                Op::and_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
+                Op::not_u32 => {
+                    let v0 = self.pop()?;
                     self.push(Value::from(
-                        v0.as_u64() & v1.as_u64()
+                        !v0.as_u32()
                     ));
                 }
 
-                Op::or_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
+                Op::lshift_u32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
                     self.push(Value::from(
-                        v0.as_u64() | v1.as_u64()
+                        v0.as_u32().wrapping_shl(v1.as_u32())
                     ));
                 }
 
-                Op::xor_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
+                Op::rshift_u32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
                     self.push(Value::from(
-                        v0.as_u64() ^ v1.as_u64()
+                        v0.as_u32().wrapping_shr(v1.as_u32())
                     ));
                 }
 
-                Op::not_u64 => {
-                    let v0 = self.pop();
+                Op::rshift_i32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
                     self.push(Value::from(
-                        !v0.as_u64()
+                        v0.as_i32().wrapping_shr(v1.as_u32()) as u32
                     ));
                 }
 
-                Op::lshift_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
+                Op::add_u32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
                     self.push(Value::from(
-                        v0.as_u64().wrapping_shl(v1.as_u32())
+                        v0.as_u32().wrapping_add(v1.as_u32())
                     ));
                 }
 
-                Op::rshift_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
+                Op::sub_u32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
                     self.push(Value::from(
-                        v0.as_u64().wrapping_shr(v1.as_u32())
+                        v0.as_u32().wrapping_sub(v1.as_u32())
                     ));
                 }
 
-                Op::add_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
+                Op::mul_u32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
                     self.push(Value::from(
-                        v0.as_u64().wrapping_add(v1.as_u64())
+                        v0.as_u32().wrapping_mul(v1.as_u32())
                     ));
                 }
 
-                Op::sub_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
+                Op::div_u32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    if v1.as_u32() == 0 {
+                        return Err(FaultKind::DivByZero);
+                    }
                     self.push(Value::from(
-                        v0.as_u64().wrapping_sub(v1.as_u64())
+                        v0.as_u32() / v1.as_u32()
                     ));
                 }
 
-                Op::mul_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
+                Op::mod_u32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    if v1.as_u32() == 0 {
+                        return Err(FaultKind::DivByZero);
+                    }
                     self.push(Value::from(
-                        v0.as_u64().wrapping_mul(v1.as_u64())
+                        v0.as_u32() % v1.as_u32()
                     ));
                 }
 
-                // TODO: should we make sure that div panics on division by zero?
-                Op::div_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
+                // i32::MIN / -1 wraps around to i32::MIN
+                Op::div_i32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    if v1.as_i32() == 0 {
+                        return Err(FaultKind::DivByZero);
+                    }
                     self.push(Value::from(
-                        v0.as_i64() / v1.as_i64()
+                        v0.as_i32().wrapping_div(v1.as_i32()) as u32
                     ));
                 }
 
-                // TODO: should we make sure that mod panics on division by zero?
-                Op::mod_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
+                // i32::MIN % -1 wraps around to 0
+                Op::mod_i32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    if v1.as_i32() == 0 {
+                        return Err(FaultKind::DivByZero);
+                    }
                     self.push(Value::from(
-                        v0.as_i64() % v1.as_i64()
+                        v0.as_i32().wrapping_rem(v1.as_i32()) as u32
                     ));
                 }
 
-                Op::eq_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push_bool(v0.as_u64() == v1.as_u64());
+                Op::eq_u32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_u32() == v1.as_u32());
                 }
 
-                Op::ne_u64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push_bool(v0.as_u64() != v1.as_u64());
+                Op::ne_u32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_u32() != v1.as_u32());
                 }
 
-                Op::lt_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push_bool(v0.as_i64() < v1.as_i64());
+                Op::lt_u32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_u32() < v1.as_u32());
                 }
 
-                Op::le_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push_bool(v0.as_i64() <= v1.as_i64());
+                Op::le_u32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_u32() <= v1.as_u32());
                 }
 
-                Op::gt_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push_bool(v0.as_i64() > v1.as_i64());
+                Op::gt_u32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_u32() > v1.as_u32());
                 }
 
-                Op::ge_i64 => {
-                    let v1 = self.pop();
-                    let v0 = self.pop();
-                    self.push_bool(v0.as_i64() >= v1.as_i64());
+                Op::ge_u32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_u32() >= v1.as_u32());
+                }
+
+                Op::lt_i32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_i32() < v1.as_i32());
+                }
+
+                Op::le_i32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_i32() <= v1.as_i32());
+                }
+
+                Op::gt_i32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_i32() > v1.as_i32());
+                }
+
+                Op::ge_i32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_i32() >= v1.as_i32());
                 }
 
                 Op::sx_i8_i32 => {
-                    let v = self.pop();
-                    self.push(Value::from(v.as_i8() as i32));
+                    let v = self.pop()?;
+                    self.push(Value::from(v.as_i8() as i32 as u32));
                 }
 
                 Op::sx_i8_i64 => {
-                    let v = self.pop();
+                    let v = self.pop()?;
                     self.push(Value::from(v.as_i8() as i64));
                 }
 
                 Op::sx_i16_i32 => {
-                    let v = self.pop();
-                    self.push(Value::from(v.as_i16() as i32));
+                    let v = self.pop()?;
+                    self.push(Value::from(v.as_i16() as i32 as u32));
                 }
 
                 Op::sx_i16_i64 => {
-                    let v = self.pop();
+                    let v = self.pop()?;
                     self.push(Value::from(v.as_i16() as i64));
                 }
 
                 Op::sx_i32_i64 => {
-                    let v = self.pop();
+                    let v = self.pop()?;
                     self.push(Value::from(v.as_i32() as i64));
                 }
 
                 Op::trunc_u8 => {
-                    let v = self.pop();
+                    let v = self.pop()?;
                     self.push(Value::from(v.as_u8()));
                 }
 
                 Op::trunc_u16 => {
-                    let v = self.pop();
+                    let v = self.pop()?;
                     self.push(Value::from(v.as_u16()));
                 }
 
                 Op::trunc_u32 => {
-                    let v = self.pop();
+                    let v = self.pop()?;
                     self.push(Value::from(v.as_u32()));
                 }
 
+                Op::add_f32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_f32() + v1.as_f32()
+                    ));
+                }
+
+                Op::sub_f32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_f32() - v1.as_f32()
+                    ));
+                }
+
+                Op::mul_f32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_f32() * v1.as_f32()
+                    ));
+                }
+
+                Op::div_f32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_f32() / v1.as_f32()
+                    ));
+                }
+
+                Op::sqrt_f32 => {
+                    let v = self.pop()?;
+                    self.push(Value::from(v.as_f32().sqrt()));
+                }
+
+                Op::eq_f32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_f32() == v1.as_f32());
+                }
+
+                Op::ne_f32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_f32() != v1.as_f32());
+                }
+
+                Op::lt_f32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_f32() < v1.as_f32());
+                }
+
+                Op::le_f32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_f32() <= v1.as_f32());
+                }
+
+                Op::gt_f32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_f32() > v1.as_f32());
+                }
+
+                Op::ge_f32 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_f32() >= v1.as_f32());
+                }
+
+                Op::add_f64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_f64() + v1.as_f64()
+                    ));
+                }
+
+                Op::sub_f64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_f64() - v1.as_f64()
+                    ));
+                }
+
+                Op::mul_f64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_f64() * v1.as_f64()
+                    ));
+                }
+
+                Op::div_f64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push(Value::from(
+                        v0.as_f64() / v1.as_f64()
+                    ));
+                }
+
+                Op::sqrt_f64 => {
+                    let v = self.pop()?;
+                    self.push(Value::from(v.as_f64().sqrt()));
+                }
+
+                Op::eq_f64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_f64() == v1.as_f64());
+                }
+
+                Op::ne_f64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_f64() != v1.as_f64());
+                }
+
+                Op::lt_f64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_f64() < v1.as_f64());
+                }
+
+                Op::le_f64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_f64() <= v1.as_f64());
+                }
+
+                Op::gt_f64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_f64() > v1.as_f64());
+                }
+
+                Op::ge_f64 => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+                    self.push_bool(v0.as_f64() >= v1.as_f64());
+                }
+
+                // Rust's `as` casts have exactly the rounding, saturation
+                // and NaN semantics that we document for these instructions
+                Op::i32_to_f32 => {
+                    let v = self.pop()?;
+                    self.push(Value::from(v.as_i32() as f32));
+                }
+
+                Op::i64_to_f64 => {
+                    let v = self.pop()?;
+                    self.push(Value::from(v.as_i64() as f64));
+                }
+
+                Op::f32_to_i32 => {
+                    let v = self.pop()?;
+                    self.push(Value::from(v.as_f32() as i32 as u32));
+                }
+
+                Op::f64_to_i64 => {
+                    let v = self.pop()?;
+                    self.push(Value::from(v.as_f64() as i64));
+                }
+
+                Op::f32_to_f64 => {
+                    let v = self.pop()?;
+                    self.push(Value::from(v.as_f32() as f64));
+                }
+
+                Op::f64_to_f32 => {
+                    let v = self.pop()?;
+                    self.push(Value::from(v.as_f64() as f32));
+                }
+
                 Op::load_u8 => {
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr(addr);
-                    let val: u8 = unsafe { *heap_ptr };
+                    let addr = self.pop()?.as_usize();
+                    let val: u8 = self.heap_read(addr)?;
                     self.push(Value::from(val));
                 }
 
                 Op::load_u16 => {
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr(addr);
-                    let val: u16 = unsafe { *heap_ptr };
+                    let addr = self.pop()?.as_usize();
+                    let val: u16 = self.heap_read(addr)?;
                     self.push(Value::from(val));
                 }
 
                 Op::load_u32 => {
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr(addr);
-                    let val: u32 = unsafe { *heap_ptr };
+                    let addr = self.pop()?.as_usize();
+                    let val: u32 = self.heap_read(addr)?;
                     self.push(Value::from(val));
                 }
 
                 Op::load_u64 => {
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr(addr);
-                    let val: u64 = unsafe { *heap_ptr };
+                    let addr = self.pop()?.as_usize();
+                    let val: u64 = self.heap_read(addr)?;
                     self.push(Value::from(val));
                 }
 
                 Op::store_u8 => {
-                    let val = self.pop().as_u8();
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr(addr);
-                    unsafe { *heap_ptr = val; }
+                    let val = self.pop()?.as_u8();
+                    let addr = self.pop()?.as_usize();
+                    self.heap_write(addr, val)?;
                 }
 
                 Op::store_u16 => {
-                    let val = self.pop().as_u16();
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr(addr);
-                    unsafe { *heap_ptr = val; }
+                    let val = self.pop()?.as_u16();
+                    let addr = self.pop()?.as_usize();
+                    self.heap_write(addr, val)?;
                 }
 
                 Op::store_u32 => {
-                    let val = self.pop().as_u32();
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr(addr);
-                    unsafe { *heap_ptr = val; }
+                    let val = self.pop()?.as_u32();
+                    let addr = self.pop()?.as_usize();
+                    self.heap_write(addr, val)?;
                 }
 
                 Op::store_u64 => {
-                    let val = self.pop().as_u64();
-                    let addr = self.pop().as_usize();
-                    let heap_ptr = self.get_heap_ptr(addr);
-                    unsafe { *heap_ptr = val; }
+                    let val = self.pop()?.as_u64();
+                    let addr = self.pop()?.as_usize();
+                    self.heap_write(addr, val)?;
                 }
 
+                // Jump targets are resolved when decoding
                 Op::jmp => {
-                    let offset = self.code.read_pc::<i32>(&mut pc) as isize;
-                    pc = ((pc as isize) + offset) as usize;
+                    pc = insn.imm as usize;
                 }
 
                 Op::jz => {
-                    let offset = self.code.read_pc::<i32>(&mut pc) as isize;
-                    let v0 = self.pop();
+                    let v0 = self.pop()?;
 
                     if v0.as_i64() == 0 {
-                        pc = ((pc as isize) + offset) as usize;
+                        pc = insn.imm as usize;
                     }
                 }
 
                 Op::jnz => {
-                    let offset = self.code.read_pc::<i32>(&mut pc) as isize;
-                    let v0 = self.pop();
+                    let v0 = self.pop()?;
 
                     if v0.as_i64() != 0 {
-                        pc = ((pc as isize) + offset) as usize;
+                        pc = insn.imm as usize;
                     }
                 }
 
                 // call <num_args:u8> <offset:i32> (arg0, arg1, ..., argN)
                 Op::call => {
-                    // Offset of the function to call
-                    let offset = self.code.read_pc::<i32>(&mut pc) as isize;
+                    // Argument count
+                    let num_args = insn.idx as usize;
+                    if num_args > self.frame_len(bp)? {
+                        return Err(FaultKind::StackUnderflow);
+                    }
 
+                    self.frames.push(StackFrame {
+                        prev_bp: bp,
+                        ret_addr: pc,
+                        argc: num_args,
+                    });
+
+                    // The base pointer will point at the first local
+                    bp = self.stack.len();
+                    pc = insn.imm as usize;
+                }
+
+                Op::call_fp => {
                     // Argument count
-                    let num_args = self.code.read_pc::<u8>(&mut pc) as usize;
-                    assert!(num_args <= self.stack.len() - bp);
+                    let num_args = insn.idx as usize;
+
+                    // Absolute address of the function to call
+                    let f_ptr = self.pop()?.as_usize();
+                    if f_ptr >= self.code.len() {
+                        return Err(FaultKind::InvalidCallTarget(f_ptr));
+                    }
+
+                    if num_args > self.frame_len(bp)? {
+                        return Err(FaultKind::StackUnderflow);
+                    }
 
                     self.frames.push(StackFrame {
                         prev_bp: bp,
@@ -924,103 +2765,278 @@ impl VM
 
                     // The base pointer will point at the first local
                     bp = self.stack.len();
-                    pc = ((pc as isize) + offset) as usize;
+                    pc = f_ptr;
                 }
 
-                Op::syscall => {
-                    let syscall_idx = self.code.read_pc::<u16>(&mut pc);
-                    let syscall_fn = self.sys_state.get_syscall(syscall_idx);
-
-                    match syscall_fn
-                    {
-                        SysCallFn::Fn0_0(fun) => {
-                            fun(self)
-                        }
-
-                        SysCallFn::Fn0_1(fun) => {
-                            let v = fun(self);
-                            self.push(v);
-                        }
-
-                        SysCallFn::Fn1_0(fun) => {
-                            let a0 = self.pop();
-                            fun(self, a0)
-                        }
-
-                        SysCallFn::Fn2_0(fun) => {
-                            let a1 = self.pop();
-                            let a0 = self.pop();
-                            fun(self, a0, a1)
-                        }
-
-                        SysCallFn::Fn3_0(fun) => {
-                            let a2 = self.pop();
-                            let a1 = self.pop();
-                            let a0 = self.pop();
-                            fun(self, a0, a1, a2)
-                        }
-
-                        SysCallFn::Fn4_0(fun) => {
-                            let a3 = self.pop();
-                            let a2 = self.pop();
-                            let a1 = self.pop();
-                            let a0 = self.pop();
-                            fun(self, a0, a1, a2, a3)
-                        }
-
-                        SysCallFn::Fn4_1(fun) => {
-                            let a3 = self.pop();
-                            let a2 = self.pop();
-                            let a1 = self.pop();
-                            let a0 = self.pop();
-                            let v = fun(self, a0, a1, a2, a3);
-                            self.push(v);
-                        }
+                // tail_call <num_args:u8> <offset:i32> (arg0, arg1, ..., argN)
+                Op::tail_call => {
+                    // Argument count
+                    let num_args = insn.idx as usize;
+                    if num_args > self.frame_len(bp)? {
+                        return Err(FaultKind::StackUnderflow);
                     }
+
+                    // Replace the locals and arguments of the current
+                    // function by the arguments of the callee
+                    let caller_args_start = self.args_start(bp)?;
+                    let args_start = self.stack.len() - num_args;
+                    self.stack.drain(caller_args_start..args_start);
+                    self.frames.last_mut().unwrap().argc = num_args;
+
+                    // The base pointer will point at the first local
+                    bp = self.stack.len();
+                    pc = insn.imm as usize;
+                }
+
+                Op::syscall => {
+                    self.exec_syscall(insn.idx)?;
                 }
 
                 Op::exit => {
                     if self.stack.len() <= bp {
-                        panic!("exit with no return value on stack");
+                        return Err(FaultKind::StackUnderflow);
+                    }
+
+                    let val = self.pop()?;
+                    return Ok(ExitReason::Exit(val));
+                }
+
+                Op::ret => {
+                    if self.stack.len() <= bp {
+                        return Err(FaultKind::StackUnderflow);
+                    }
+
+                    let ret_val = self.pop()?;
+
+                    // If this returns from the function called by the host
+                    if self.frames.len() == base_frames + 1 {
+                        return Ok(ExitReason::Return(ret_val));
+                    }
+
+                    // Pop all local variables and arguments
+                    // We pop arguments in the callee so we can support tail calls
+                    let args_start = self.args_start(bp)?;
+                    if self.stack.len() < args_start {
+                        return Err(FaultKind::StackUnderflow);
+                    }
+                    self.stack.truncate(args_start);
+                    let top_frame = self.frames.pop().unwrap();
+
+                    pc = top_frame.ret_addr;
+                    bp = top_frame.prev_bp;
+
+                    self.push(ret_val);
+                }
+
+                Op::get_local_add_imm => {
+                    let idx = insn.idx as usize;
+
+                    if bp + idx >= self.stack.len() {
+                        return Err(FaultKind::InvalidLocal { idx });
+                    }
+
+                    let val = self.stack[bp + idx].as_u64().wrapping_add(insn.imm);
+                    self.push(Value::from(val));
+
+                    // Locate the last instruction executed, add_u64
+                    *insn_pc = pc - 1;
+                }
+
+                Op::get_local2 => {
+                    let idx0 = insn.idx as usize;
+                    let idx1 = insn.imm as usize;
+
+                    if bp + idx0 >= self.stack.len() {
+                        return Err(FaultKind::InvalidLocal { idx: idx0 });
+                    }
+                    self.push(self.stack[bp + idx0]);
+
+                    // Each get_local is two bytes long
+                    *insn_pc = pc - 2;
+                    if bp + idx1 >= self.stack.len() {
+                        return Err(FaultKind::InvalidLocal { idx: idx1 });
+                    }
+                    self.push(self.stack[bp + idx1]);
+                }
+
+                Op::load_u64_imm => {
+                    // Faults are located at the load_u64 instruction
+                    *insn_pc = pc - 1;
+                    let val: u64 = self.heap_read(insn.imm as usize)?;
+                    self.push(Value::from(val));
+                }
+
+                Op::lt_i64_jz | Op::lt_i64_jnz => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+
+                    // Locate the last instruction executed, jz or jnz
+                    *insn_pc = pc - 5;
+
+                    if (v0.as_i64() < v1.as_i64()) == (insn.op == Op::lt_i64_jnz) {
+                        pc = insn.imm as usize;
                     }
+                }
+
+                Op::lt_u64_jz | Op::lt_u64_jnz => {
+                    let v1 = self.pop()?;
+                    let v0 = self.pop()?;
+
+                    // Locate the last instruction executed, jz or jnz
+                    *insn_pc = pc - 5;
+
+                    if (v0.as_u64() < v1.as_u64()) == (insn.op == Op::lt_u64_jnz) {
+                        pc = insn.imm as usize;
+                    }
+                }
+
+                Op::invalid_op => return Err(FaultKind::InvalidOpcode(insn.idx as u8)),
+
+                Op::truncated => return Err(FaultKind::PcOutOfBounds),
 
-                    let val = self.pop();
-                    self.stack.clear();
-                    self.frames.clear();
-                    return ExitReason::Exit(val);
+                Op::end_of_code => {
+                    *insn_pc = prev_pc;
+                    return Err(FaultKind::PcOutOfBounds);
                 }
 
-                Op::ret => {
-                    if self.stack.len() <= bp {
-                        panic!("ret with no return value on stack");
-                    }
+                // The push instructions are all decoded as push_u64,
+                // and opcode extensions are reserved for future use
+                Op::push_0 | Op::push_1 | Op::push_2 | Op::push_i8 | Op::push_u32 | Op::OP_EXT => {
+                    return Err(FaultKind::InvalidOpcode(insn.op as u8));
+                }
+            }
+        }
+    }
+}
+
+/// Error that can occur while building a VM from a program
+#[derive(Debug)]
+pub enum BuildError
+{
+    // The program couldn't be assembled
+    Parse(ParseError),
+
+    // The verifier found problems in the program, sorted by pc
+    Verify(Vec<Diagnostic>),
+}
+
+impl fmt::Display for BuildError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            Self::Parse(err) => write!(f, "{}", err),
+            Self::Verify(diags) => {
+                let diags: Vec<String> = diags.iter().map(|diag| diag.to_string()).collect();
+                write!(f, "verifier: {}", diags.join("; "))
+            }
+        }
+    }
+}
+
+impl From<ParseError> for BuildError
+{
+    fn from(err: ParseError) -> Self
+    {
+        Self::Parse(err)
+    }
+}
+
+/// Builder to create a VM with a customized host system state,
+/// used by applications embedding UVM
+/// The program is verified, so that the VM built can run it safely
+pub struct VMBuilder
+{
+    sys_state: SysState,
+    max_heap_size: usize,
+    max_code_size: usize,
+}
+
+impl Default for VMBuilder
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl VMBuilder
+{
+    pub fn new() -> Self
+    {
+        Self {
+            sys_state: SysState::new(),
+            max_heap_size: DEFAULT_MAX_HEAP_SIZE,
+            max_code_size: DEFAULT_MAX_CODE_SIZE,
+        }
+    }
+
+    /// Use a system state set up by the host
+    pub fn sys_state(mut self, sys_state: SysState) -> Self
+    {
+        self.sys_state = sys_state;
+        self
+    }
+
+    /// Register a host syscall, see SysState::reg_host_syscall
+    pub fn host_syscall(mut self, const_idx: u16, name: &str, fun: SysCallFn) -> Self
+    {
+        self.sys_state.reg_host_syscall(const_idx, name, fun);
+        self
+    }
 
-                    let ret_val = self.pop();
+    /// Remove the syscalls of a built-in subsystem
+    pub fn disable_subsystem(mut self, subsystem: &str) -> Self
+    {
+        self.sys_state.disable_subsystem(subsystem);
+        self
+    }
 
-                    // If this is a top-level return
-                    if self.frames.len() == 1 {
-                        self.stack.clear();
-                        self.frames.clear();
-                        return ExitReason::Return(ret_val);
-                    }
+    pub fn deny_permission(mut self, perm: &str) -> Self
+    {
+        self.sys_state.deny_permission(perm);
+        self
+    }
 
-                    assert!(self.frames.len() > 0);
-                    let top_frame = self.frames.pop().unwrap();
+    pub fn max_heap_size(mut self, num_bytes: usize) -> Self
+    {
+        self.max_heap_size = num_bytes;
+        self
+    }
 
-                    // Pop all local variables and arguments
-                    // We pop arguments in the callee so we can support tail calls
-                    assert!(self.stack.len() >= bp - top_frame.argc);
-                    self.stack.truncate(bp - top_frame.argc);
+    pub fn max_code_size(mut self, num_bytes: usize) -> Self
+    {
+        self.max_code_size = num_bytes;
+        self
+    }
 
-                    pc = top_frame.ret_addr;
-                    bp = top_frame.prev_bp;
+    /// Assemble and verify a program from source code into a VM
+    pub fn parse_str(self, src: &str) -> Result<VM, BuildError>
+    {
+        let (max_heap_size, max_code_size) = (self.max_heap_size, self.max_code_size);
+        let vm = Assembler::with_sys_state(self.sys_state).parse_str(src)?;
+        Self::finish(vm, max_heap_size, max_code_size)
+    }
 
-                    self.push(ret_val);
-                }
+    /// Assemble and verify a program from a source file into a VM
+    pub fn parse_file(self, file_name: &str) -> Result<VM, BuildError>
+    {
+        let (max_heap_size, max_code_size) = (self.max_heap_size, self.max_code_size);
+        let vm = Assembler::with_sys_state(self.sys_state).parse_file(file_name)?;
+        Self::finish(vm, max_heap_size, max_code_size)
+    }
 
-                _ => panic!("unknown opcode {:?}", op),
-            }
+    /// Verify an assembled program and apply the limits
+    fn finish(mut vm: VM, max_heap_size: usize, max_code_size: usize) -> Result<VM, BuildError>
+    {
+        let diags = vm.verify();
+        if !diags.is_empty() {
+            return Err(BuildError::Verify(diags));
         }
+
+        vm.set_max_heap_size(max_heap_size);
+        vm.set_max_code_size(max_code_size);
+        Ok(vm)
     }
 }
 
@@ -1029,20 +3045,64 @@ mod tests
 {
     use super::*;
     use crate::asm::*;
+    use crate::jit::{Context, Fact};
 
-    fn eval_src(src: &str) -> Value
+    /// Run a program with the interpreter, or with the JIT compiling every block
+    fn run_src(src: &str, jit: bool) -> ExitReason
     {
-        dbg!(src);
         let asm = Assembler::new();
         let mut vm = asm.parse_str(src).unwrap();
+        if jit {
+            assert!(vm.enable_jit());
+        }
         let result = vm.call(0, &[]);
-        assert!(vm.stack.len() == 0 && vm.frames.len() == 0);
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+        result
+    }
 
-        match result
-        {
-            ExitReason::Exit(value) => value,
-            ExitReason::Return(value) => value,
+    fn eval_src(src: &str) -> Value
+    {
+        dbg!(src);
+        let asm = Assembler::new();
+        let vm = asm.parse_str(src).unwrap();
+        assert_eq!(vm.verify(), vec![]);
+
+        // The JIT must produce the same result as the interpreter
+        let mut value = None;
+        for jit in [false, true] {
+            match run_src(src, jit)
+            {
+                ExitReason::Exit(val) | ExitReason::Return(val) => {
+                    assert!(value.is_none() || value == Some(val), "jit={}", jit);
+                    value = Some(val);
+                }
+                ExitReason::Fault { kind, pc, .. } => panic!("fault: {} (pc={}, jit={})", kind, pc, jit),
+                ExitReason::OutOfFuel => panic!("out of fuel"),
+                ExitReason::Breakpoint => panic!("breakpoint"),
+            }
+        }
+
+        value.unwrap()
+    }
+
+    fn eval_fault(src: &str) -> (FaultKind, usize)
+    {
+        dbg!(src);
+
+        // The JIT must produce the same fault as the interpreter
+        let mut fault = None;
+        for jit in [false, true] {
+            match run_src(src, jit)
+            {
+                ExitReason::Fault { kind, pc, .. } => {
+                    assert!(fault.is_none() || fault == Some((kind.clone(), pc)), "jit={}", jit);
+                    fault = Some((kind, pc));
+                }
+                _ => panic!("expected fault, jit={}", jit)
+            }
         }
+
+        fault.unwrap()
     }
 
     fn eval_i64(src: &str, expected: i64)
@@ -1059,7 +3119,7 @@ mod tests
 
         // Keep track of how many short opcodes we have so far
         dbg!(Op::exit as usize);
-        assert!(Op::exit as usize <= 80);
+        assert!(Op::exit as usize <= 130);
     }
 
     #[test]
@@ -1095,6 +3155,237 @@ mod tests
         eval_i64("push_i8 11; push_i8 1; lt_i64; exit;", 0);
     }
 
+    #[test]
+    fn test_argc()
+    {
+        eval_i64("call FN, 0; exit; FN: get_argc; ret;", 0);
+        eval_i64("push 1; push 2; push 3; call FN, 3; exit; FN: get_argc; ret;", 3);
+    }
+
+    #[test]
+    fn test_shifts()
+    {
+        eval_i64("push 8; push 2; rshift_u64; exit;", 2);
+        eval_i64("push -8; push 1; rshift_u64; exit;", (-8i64 as u64 >> 1) as i64);
+        eval_i64("push -8; push 1; rshift_i64; exit;", -4);
+        eval_i64("push -1; push 63; rshift_i64; exit;", -1);
+        eval_i64("push 8; push 2; rshift_i64; exit;", 2);
+    }
+
+    #[test]
+    fn test_div_mod_u64()
+    {
+        eval_i64("push 7; push 2; div_u64; exit;", 3);
+        eval_i64("push 7; push 2; mod_u64; exit;", 1);
+        eval_i64("push -1; push 2; div_u64; exit;", (u64::MAX / 2) as i64);
+        eval_i64("push -1; push 10; mod_u64; exit;", (u64::MAX % 10) as i64);
+
+        // Signed division truncates towards zero
+        eval_i64("push -7; push 2; div_i64; exit;", -3);
+        eval_i64("push -7; push 2; mod_i64; exit;", -1);
+    }
+
+    #[test]
+    fn test_ovf()
+    {
+        // The overflow flag is on top of the stack, the result under it
+        eval_i64("push 3; push 4; add_u64_ovf; exit;", 0);
+        eval_i64("push 3; push 4; add_u64_ovf; pop; exit;", 7);
+        eval_i64("push -1; push 1; add_u64_ovf; exit;", 1);
+        eval_i64("push -1; push 1; add_u64_ovf; pop; exit;", 0);
+        eval_i64("push 3; push 4; sub_u64_ovf; exit;", 1);
+        eval_i64("push 3; push 4; sub_u64_ovf; pop; exit;", -1);
+        eval_i64("push 0x100000000; push 0x100000000; mul_u64_ovf; exit;", 1);
+        eval_i64("push 0xFFFFFFFF; push 0x100000001; mul_u64_ovf; exit;", 0);
+
+        // Signed overflow
+        eval_i64("push -1; push 1; add_i64_ovf; exit;", 0);
+        eval_i64("push 0x7FFFFFFFFFFFFFFF; push 1; add_i64_ovf; exit;", 1);
+        eval_i64("push 0x7FFFFFFFFFFFFFFF; push 1; add_i64_ovf; pop; exit;", i64::MIN);
+        eval_i64("push 3; push 4; sub_i64_ovf; exit;", 0);
+        eval_i64("push 0x8000000000000000; push 1; sub_i64_ovf; exit;", 1);
+        eval_i64("push -3; push 4; mul_i64_ovf; pop; exit;", -12);
+        eval_i64("push 0x8000000000000000; push -1; mul_i64_ovf; exit;", 1);
+
+        // Branching on overflow
+        eval_i64("push 0x7FFFFFFFFFFFFFFF; push 1; add_i64_ovf; jnz OVF; exit; OVF: pop; push 7; exit;", 7);
+        eval_i64("push 5; push 1; add_i64_ovf; jnz OVF; exit; OVF: pop; push 7; exit;", 6);
+    }
+
+    #[test]
+    fn test_mul_wide()
+    {
+        // The high word is on top of the stack, the low word under it
+        eval_i64("push 3; push 4; mul_u64_wide; exit;", 0);
+        eval_i64("push 3; push 4; mul_u64_wide; pop; exit;", 12);
+        eval_i64("push -1; push -1; mul_u64_wide; exit;", -2);
+        eval_i64("push -1; push -1; mul_u64_wide; pop; exit;", 1);
+        eval_i64("push 0x100000000; push 0x100000000; mul_u64_wide; exit;", 1);
+        eval_i64("push 0x100000000; push 0x100000000; mul_u64_wide; pop; exit;", 0);
+
+        // Signed
+        eval_i64("push -1; push -1; mul_i64_wide; exit;", 0);
+        eval_i64("push -1; push -1; mul_i64_wide; pop; exit;", 1);
+        eval_i64("push -3; push 4; mul_i64_wide; exit;", -1);
+        eval_i64("push -3; push 4; mul_i64_wide; pop; exit;", -12);
+        eval_i64("push 0x8000000000000000; push 2; mul_i64_wide; exit;", -1);
+        eval_i64("push 0x8000000000000000; push 2; mul_i64_wide; pop; exit;", 0);
+    }
+
+    #[test]
+    fn test_cmp_u64()
+    {
+        eval_i64("push 1; push 2; lt_u64; exit;", 1);
+        eval_i64("push 2; push 2; lt_u64; exit;", 0);
+        eval_i64("push 2; push 2; le_u64; exit;", 1);
+        eval_i64("push 3; push 2; le_u64; exit;", 0);
+        eval_i64("push 3; push 2; gt_u64; exit;", 1);
+        eval_i64("push 2; push 2; gt_u64; exit;", 0);
+        eval_i64("push 2; push 2; ge_u64; exit;", 1);
+        eval_i64("push 1; push 2; ge_u64; exit;", 0);
+
+        // -1 is the largest unsigned value, but the smallest signed one here
+        eval_i64("push -1; push 1; lt_u64; exit;", 0);
+        eval_i64("push -1; push 1; gt_u64; exit;", 1);
+        eval_i64("push -1; push 1; lt_i64; exit;", 1);
+        eval_i64("push -1; push 1; gt_i64; exit;", 0);
+    }
+
+    #[test]
+    fn test_int32()
+    {
+        // Results are zero-extended and wrap around modulo 2^32
+        eval_i64("push 0xFFFFFFFF; push 1; add_u32; exit;", 0);
+        eval_i64("push 0; push 1; sub_u32; exit;", 0xFFFF_FFFF);
+        eval_i64("push 0x10000; push 0x10000; mul_u32; exit;", 0);
+        eval_i64("push -5; push 3; add_u32; exit;", 0xFFFF_FFFE);
+
+        // Upper 32 bits of the inputs are ignored
+        eval_i64("push 0x1_0000_0005; push 3; add_u32; exit;", 8);
+        eval_i64("push 0x1_0000_0005; push 5; eq_u32; exit;", 1);
+
+        // Bitwise ops
+        eval_i64("push 0xF0F0; push 0xFF00; and_u32; exit;", 0xF000);
+        eval_i64("push 0xF0F0; push 0xFF00; or_u32; exit;", 0xFFF0);
+        eval_i64("push 0xF0F0; push 0xFF00; xor_u32; exit;", 0x0FF0);
+        eval_i64("push 0; not_u32; exit;", 0xFFFF_FFFF);
+        eval_i64("push 1; push 31; lshift_u32; exit;", 0x8000_0000);
+        eval_i64("push 1; push 32; lshift_u32; exit;", 1);
+        eval_i64("push 0x80000000; push 4; rshift_u32; exit;", 0x0800_0000);
+        eval_i64("push 0x80000000; push 4; rshift_i32; exit;", 0xF800_0000);
+
+        // Division
+        eval_i64("push 7; push 2; div_u32; exit;", 3);
+        eval_i64("push 7; push 2; mod_u32; exit;", 1);
+        eval_i64("push -7; push 2; div_u32; exit;", 0x7FFF_FFFC);
+        eval_i64("push -7; push 2; div_i32; exit;", -3i32 as u32 as i64);
+        eval_i64("push -7; push 2; mod_i32; exit;", -1i32 as u32 as i64);
+        eval_i64("push 0x80000000; push -1; div_i32; exit;", 0x8000_0000);
+        eval_i64("push 0x80000000; push -1; mod_i32; exit;", 0);
+
+        // Comparisons
+        eval_i64("push 3; push 3; eq_u32; exit;", 1);
+        eval_i64("push 3; push 3; ne_u32; exit;", 0);
+        eval_i64("push 1; push 2; lt_u32; exit;", 1);
+        eval_i64("push 2; push 2; le_u32; exit;", 1);
+        eval_i64("push 2; push 1; gt_u32; exit;", 1);
+        eval_i64("push 1; push 2; ge_u32; exit;", 0);
+        eval_i64("push -1; push 1; lt_u32; exit;", 0);
+        eval_i64("push -1; push 1; lt_i32; exit;", 1);
+        eval_i64("push -1; push -1; le_i32; exit;", 1);
+        eval_i64("push -1; push 1; gt_i32; exit;", 0);
+        eval_i64("push 0xFFFFFFFF; push 0; ge_i32; exit;", 0);
+
+        // 32-bit sign extension produces a zero-extended 32-bit value
+        eval_i64("push 0xFF; sx_i8_i32; exit;", 0xFFFF_FFFF);
+        eval_i64("push 0xFFFF; sx_i16_i32; exit;", 0xFFFF_FFFF);
+        eval_i64("push 0xFF; sx_i8_i64; exit;", -1);
+        eval_i64("push 0xFFFFFFFF; sx_i32_i64; exit;", -1);
+    }
+
+    #[test]
+    fn test_f32()
+    {
+        fn eval_f32(src: &str, expected: f32)
+        {
+            let result = eval_src(src);
+            assert_eq!(result.as_f32(), expected);
+        }
+
+        // 1.5f32 = 0x3FC00000, 2.0f32 = 0x40000000
+        eval_f32("push_u32 0x3FC00000; push_u32 0x40000000; add_f32; exit;", 3.5);
+        eval_f32("push_u32 0x3FC00000; push_u32 0x40000000; sub_f32; exit;", -0.5);
+        eval_f32("push_u32 0x3FC00000; push_u32 0x40000000; mul_f32; exit;", 3.0);
+        eval_f32("push_u32 0x3FC00000; push_u32 0x40000000; div_f32; exit;", 0.75);
+
+        // Float literals
+        eval_f32("push_f32 1.5; push_f32 2; add_f32; exit;", 3.5);
+        eval_f32("push_f32 -2.25e1; exit;", -22.5);
+        eval_f32("push_f32 6.25; sqrt_f32; exit;", 2.5);
+
+        // Comparisons
+        eval_i64("push_f32 1.5; push_f32 2.0; lt_f32; exit;", 1);
+        eval_i64("push_f32 1.5; push_f32 2.0; ge_f32; exit;", 0);
+        eval_i64("push_f32 2.0; push_f32 2.0; eq_f32; exit;", 1);
+        eval_i64("push_f32 nan; push_f32 nan; eq_f32; exit;", 0);
+        eval_i64("push_f32 nan; push_f32 nan; ne_f32; exit;", 1);
+        eval_i64("push_f32 nan; push_f32 1.0; le_f32; exit;", 0);
+    }
+
+    #[test]
+    fn test_f64()
+    {
+        fn eval_f64(src: &str, expected: f64)
+        {
+            let result = eval_src(src);
+            assert_eq!(result.as_f64(), expected);
+        }
+
+        eval_f64("push_f64 1.5; push_f64 2.0; add_f64; exit;", 3.5);
+        eval_f64("push_f64 1.5; push_f64 2.0; sub_f64; exit;", -0.5);
+        eval_f64("push_f64 1.5; push_f64 2.0; mul_f64; exit;", 3.0);
+        eval_f64("push_f64 1.5; push_f64 2.0; div_f64; exit;", 0.75);
+        eval_f64("push_f64 2.0; sqrt_f64; exit;", 2.0f64.sqrt());
+        eval_f64("push_f64 1.0; push_f64 0.0; div_f64; exit;", f64::INFINITY);
+        eval_f64("push_f64 -inf; exit;", f64::NEG_INFINITY);
+        assert!(eval_src("push_f64 -1.0; sqrt_f64; exit;").as_f64().is_nan());
+
+        eval_i64("push_f64 1.5; push_f64 2.0; lt_f64; exit;", 1);
+        eval_i64("push_f64 1.5; push_f64 2.0; le_f64; exit;", 1);
+        eval_i64("push_f64 1.5; push_f64 2.0; gt_f64; exit;", 0);
+        eval_i64("push_f64 2.0; push_f64 2.0; ge_f64; exit;", 1);
+        eval_i64("push_f64 2.0; push_f64 2.0; ne_f64; exit;", 0);
+        eval_i64("push_f64 nan; push_f64 nan; eq_f64; exit;", 0);
+        eval_i64("push_f64 nan; push_f64 1.0; gt_f64; exit;", 0);
+    }
+
+    #[test]
+    fn test_float_conv()
+    {
+        // Int to float
+        assert_eq!(eval_src("push -3; i32_to_f32; exit;").as_f32(), -3.0);
+        assert_eq!(eval_src("push -3; i64_to_f64; exit;").as_f64(), -3.0);
+        assert_eq!(eval_src("push 0xFFFFFFFF; i32_to_f32; exit;").as_f32(), -1.0);
+
+        // Float to int truncates towards zero
+        eval_i64("push_f32 2.75; f32_to_i32; exit;", 2);
+        eval_i64("push_f32 -2.75; f32_to_i32; exit;", 0xFFFF_FFFE);
+        eval_i64("push_f64 -2.75; f64_to_i64; exit;", -2);
+
+        // Saturation and NaN
+        eval_i64("push_f32 1e20; f32_to_i32; exit;", i32::MAX as i64);
+        eval_i64("push_f32 -1e20; f32_to_i32; exit;", i32::MIN as u32 as i64);
+        eval_i64("push_f64 inf; f64_to_i64; exit;", i64::MAX);
+        eval_i64("push_f64 -inf; f64_to_i64; exit;", i64::MIN);
+        eval_i64("push_f32 nan; f32_to_i32; exit;", 0);
+        eval_i64("push_f64 nan; f64_to_i64; exit;", 0);
+
+        // Float to float
+        assert_eq!(eval_src("push_f32 1.5; f32_to_f64; exit;").as_f64(), 1.5);
+        assert_eq!(eval_src("push_f64 0.1; f64_to_f32; exit;").as_f32(), 0.1f32);
+        assert_eq!(eval_src("push_f64 1e300; f64_to_f32; exit;").as_f32(), f32::INFINITY);
+    }
+
     #[test]
     fn test_loop()
     {
@@ -1128,6 +3419,46 @@ mod tests
         eval_i64("push 5; call foo, 0; pop; exit; foo: push 2; push 0; ret;", 5);
     }
 
+    #[test]
+    fn test_call_fp()
+    {
+        eval_i64("push_p32 FN; call_fp 0; exit; FN: push_i8 33; ret;", 33);
+        eval_i64("push FN; call_fp 0; exit; FN: push_i8 33; ret;", 33);
+
+        // Arguments are pushed below the function pointer
+        eval_i64("push_i8 7; push 5; push FN; call_fp 2; exit; FN: get_arg 0; get_arg 1; sub_u64; get_argc; add_u64; ret;", 4);
+
+        // Function pointer passed as an argument to another function
+        eval_i64("push 3; push INC; call APPLY, 2; exit; APPLY: get_arg 0; get_arg 1; call_fp 1; ret; INC: get_arg 0; push 1; add_u64; ret;", 4);
+    }
+
+    #[test]
+    fn test_tail_call()
+    {
+        eval_i64("call FN, 0; exit; FN: tail_call G, 0; G: push_i8 33; ret;", 33);
+
+        // The arguments and locals of the caller are replaced by the new arguments
+        eval_i64("push 7; push 5; call FN, 2; exit; FN: push 0; get_arg 0; get_arg 1; tail_call SUB, 2; SUB: get_arg 0; get_arg 1; sub_u64; get_argc; add_u64; ret;", 4);
+        eval_i64("push 1; push 2; call FN, 0; add_u64; exit; FN: push 5; tail_call G, 1; G: get_arg 0; ret;", 7);
+
+        // Mutual recursion
+        eval_i64("push 11; call ODD, 1; exit; ODD: get_arg 0; jz NO; get_arg 0; push 1; sub_u64; tail_call EVEN, 1; NO: push 0; ret; EVEN: get_arg 0; jz YES; get_arg 0; push 1; sub_u64; tail_call ODD, 1; YES: push 1; ret;", 1);
+
+        assert_eq!(eval_fault("push 1; call FN, 0; exit; FN: tail_call G, 1; G: push 0; ret;").0, FaultKind::StackUnderflow);
+        assert_eq!(eval_fault("push 5; push 1; call FN, 1; exit; FN: pop; pop; push 3; tail_call G, 1; G: push 0; ret;").0, FaultKind::StackUnderflow);
+
+        // Deep tail recursion runs in a single frame
+        let src = "push 1000000; push 0; call SUM, 2; exit; SUM: get_arg 0; jz DONE; get_arg 0; push 1; sub_u64; get_arg 1; get_arg 0; add_u64; tail_call SUM, 2; DONE: get_arg 1; ret;";
+        eval_i64(src, 500000500000);
+        let mut vm = Assembler::new().parse_str(src).unwrap();
+        vm.set_fuel(Some(100000));
+        assert!(matches!(vm.call(0, &[]), ExitReason::OutOfFuel));
+        assert_eq!(vm.frames.len(), 2);
+        assert!(vm.stack.len() < 8);
+        vm.set_fuel(None);
+        assert!(matches!(vm.resume(), ExitReason::Exit(Value(500000500000))));
+    }
+
     #[test]
     fn test_syscalls()
     {
@@ -1135,30 +3466,753 @@ mod tests
     }
 
     #[test]
-    #[should_panic]
-    fn test_ret_none()
+    fn test_faults()
     {
-        eval_src("call FN, 0; exit; FN: ret;");
+        // The pc is the address of the faulting instruction
+        assert_eq!(eval_fault("panic;"), (FaultKind::Panic, 0));
+        assert_eq!(eval_fault("push 1; pop; pop; exit;"), (FaultKind::StackUnderflow, 2));
+        assert_eq!(eval_fault("push 1; getn 1; exit;"), (FaultKind::StackUnderflow, 1));
+        assert_eq!(eval_fault("call FN, 0; exit; FN: ret;").0, FaultKind::StackUnderflow);
+        assert_eq!(eval_fault("push 1; call FN, 2; exit; FN: push 0; ret;").0, FaultKind::StackUnderflow);
+        assert_eq!(eval_fault("call FN, 0; exit; FN: get_arg 0; push 0; ret;").0, FaultKind::InvalidArg { idx: 0, argc: 0 });
+
+        // Functions which pop values below their base pointer
+        assert_eq!(eval_fault("push 1; call FN, 1; exit; FN: pop; get_arg 0; ret;"), (FaultKind::StackUnderflow, 9));
+        assert_eq!(eval_fault("push 1; call FN, 1; exit; FN: pop; push 2; set_arg 0; push 0; ret;").0, FaultKind::StackUnderflow);
+        assert_eq!(eval_fault("push 1; call FN, 1; exit; FN: pop; call G, 0; ret; G: push 0; ret;").0, FaultKind::StackUnderflow);
+        assert_eq!(eval_fault("get_local 2; exit;").0, FaultKind::InvalidLocal { idx: 2 });
+        assert_eq!(eval_fault("push 1000; call_fp 0; exit;").0, FaultKind::InvalidCallTarget(1000));
+        assert_eq!(eval_fault("push 1; call FN, 1; exit; FN: pop; push 0; call_fp 0; ret;").0, FaultKind::StackUnderflow);
+        assert_eq!(eval_fault("jmp END; END:").0, FaultKind::PcOutOfBounds);
+
+        // Immediate operand truncated by the end of the code
+        let mut code = MemBlock::new();
+        code.push_op(Op::push_u64);
+        code.push_u32(0);
+        let mut vm = VM::new(code, MemBlock::new(), HashSet::new(), SymbolTable::new());
+        assert!(matches!(vm.call(0, &[]), ExitReason::Fault { kind: FaultKind::PcOutOfBounds, pc: 0, .. }));
+
+        // Division by zero
+        assert_eq!(eval_fault("push 1; push 0; div_u64; exit;").0, FaultKind::DivByZero);
+        assert_eq!(eval_fault("push 1; push 0; mod_i64; exit;").0, FaultKind::DivByZero);
+        assert_eq!(eval_fault("push 1; push 0x100000000; div_u32; exit;").0, FaultKind::DivByZero);
+        assert_eq!(eval_fault("push 1; push 0; mod_i32; exit;").0, FaultKind::DivByZero);
+        eval_i64("push 0x8000000000000000; push -1; div_i64; exit;", i64::MIN);
+        eval_i64("push 0x8000000000000000; push -1; mod_i64; exit;", 0);
+
+        // Heap accesses
+        assert_eq!(eval_fault(".data; .fill 1000, 0; .code; push 1000; load_u64; exit;").0, FaultKind::HeapOutOfBounds { addr: 1000, size: 8 });
+        assert_eq!(eval_fault(".data; .fill 1000, 0; .code; push 996; push 0; store_u64; push 0; exit;").0, FaultKind::HeapOutOfBounds { addr: 996, size: 8 });
+        assert_eq!(eval_fault("push -1; load_u8; exit;").0, FaultKind::HeapOutOfBounds { addr: usize::MAX, size: 1 });
+
+        // Unaligned heap accesses are allowed
+        eval_i64(".data; .fill 16, 0; .code; push 3; push 77; store_u64; push 3; load_u64; exit;", 77);
     }
 
     #[test]
-    #[should_panic]
-    fn test_get_arg_none()
+    fn test_backtrace()
+    {
+        let asm = Assembler::new();
+        let mut vm = asm.parse_str("
+            call main, 0;
+            exit;
+            foo:
+            push 1;
+            push 0;
+            div_u64;
+            ret;
+            main:
+            push 7;
+            call foo, 0;
+            ret;
+        ").unwrap();
+
+        match vm.call(0, &[])
+        {
+            ExitReason::Fault { kind, pc, backtrace } => {
+                assert_eq!(kind, FaultKind::DivByZero);
+                assert_eq!(pc, 9);
+                assert_eq!(backtrace, vec![9, 19, 6]);
+                assert_eq!(vm.format_backtrace(&backtrace), "foo+0x2 <- main+0x8 <- 0x6");
+            }
+            _ => panic!("expected fault")
+        }
+
+        // Local labels are only used when there is no function label before them
+        let asm = Assembler::new();
+        let vm = asm.parse_str("LOOP: push 0; jz END; call F, 0; jmp LOOP; END: exit; F: push 1; JOIN: ret;").unwrap();
+        assert_eq!(vm.symbols().symbolize(1), "LOOP+0x1");
+        assert_eq!(vm.symbols().symbolize(17), "END");
+        assert_eq!(vm.symbols().symbolize(18), "F");
+        assert_eq!(vm.symbols().symbolize(19), "F+0x1");
+    }
+
+    #[test]
+    fn test_fuel()
     {
-        eval_src("call FN, 0; exit; FN: get_arg 0; push 0; ret;");
+        let asm = Assembler::new();
+        let mut vm = asm.parse_str("push 0; LOOP: push 1; add_u64; dup; push 10; lt_u64; jnz LOOP; exit;").unwrap();
+
+        // Without a budget, the call runs to completion
+        assert!(matches!(vm.call(0, &[]), ExitReason::Exit(Value(10))));
+        assert_eq!(vm.fuel(), None);
+
+        // Exactly enough fuel: 1 + 10 * 6 + 1 instructions
+        vm.set_fuel(Some(62));
+        assert!(matches!(vm.call(0, &[]), ExitReason::Exit(Value(10))));
+        assert_eq!(vm.fuel(), Some(0));
+
+        // Run in slices of 5 instructions, resuming each time
+        vm.set_fuel(Some(5));
+        let mut exit_reason = vm.call(0, &[]);
+        let mut num_slices = 1;
+        while let ExitReason::OutOfFuel = exit_reason {
+            assert!(vm.is_suspended());
+            vm.set_fuel(Some(5));
+            exit_reason = vm.resume();
+            num_slices += 1;
+        }
+        assert!(matches!(exit_reason, ExitReason::Exit(Value(10))));
+        assert!(!vm.is_suspended());
+        assert_eq!(num_slices, 13);
+        assert_eq!(vm.fuel(), Some(3));
     }
 
     #[test]
-    #[should_panic]
-    fn test_load_oob()
+    fn test_superinsns()
     {
-        eval_src(".data; .fill 1000, 0; .code; push 1000; load_u64; exit;");
+        eval_i64("push 7; get_local 0; push 3; add_u64; exit;", 10);
+        eval_i64("push 2; push 3; get_local 0; get_local 1; mul_u64; exit;", 6);
+        eval_i64(".data; .u64 77; .code; push 0; load_u64; exit;", 77);
+        eval_i64("push 1; push 2; lt_i64; jnz A; push 0; exit; A: push 1; exit;", 1);
+        eval_i64("push -1; push 2; lt_u64; jz A; push 0; exit; A: push 1; exit;", 1);
+
+        // Jump into the middle of a fused sequence
+        eval_i64("push 5; push 5; jmp MID; get_local 0; MID: push 10; add_u64; exit;", 15);
+
+        // Faults are located at the instruction that caused them
+        assert_eq!(eval_fault("push 0; get_local 0; get_local 3; exit;"), (FaultKind::InvalidLocal { idx: 3 }, 3));
+        assert_eq!(eval_fault("push 8; load_u64; exit;"), (FaultKind::HeapOutOfBounds { addr: 8, size: 8 }, 2));
+        assert_eq!(eval_fault("push 0; push 1; lt_u64; jz END; END:"), (FaultKind::PcOutOfBounds, 3));
+
+        // Writing code inside of a fused sequence
+        let asm = Assembler::new();
+        let mut vm = asm.parse_str("push 1; get_local 0; push_i8 2; add_u64; exit;").unwrap();
+        assert!(matches!(vm.call(0, &[]), ExitReason::Exit(Value(3))));
+        vm.write_code(4, &[7]).unwrap();
+        assert!(matches!(vm.call(0, &[]), ExitReason::Exit(Value(8))));
+    }
+
+    #[test]
+    fn test_breakpoint()
+    {
+        for jit in [false, true] {
+            let mut vm = Assembler::new().parse_str("push 1; call FN, 1; exit; FN: get_arg 0; breakpoint; push 2; add_u64; ret;").unwrap();
+            if jit {
+                vm.enable_jit();
+            }
+
+            // Execution stops before the breakpoint, keeping the frames
+            vm.set_fuel(Some(100));
+            assert!(matches!(vm.call(0, &[]), ExitReason::Breakpoint));
+            assert_eq!(vm.suspended_pc(), Some(10));
+            assert_eq!(vm.fuel(), Some(97));
+
+            let frames = vm.suspended_frames();
+            assert_eq!(frames.len(), 2);
+            assert_eq!((frames[0].pc, frames[0].args.len(), frames[0].locals.len()), (10, 1, 1));
+            assert_eq!((frames[1].pc, frames[1].args.len(), frames[1].locals.len()), (7, 0, 0));
+
+            // Removing the breakpoint lets execution continue
+            vm.write_code(10, &[Op::nop as u8]).unwrap();
+            assert!(matches!(vm.resume(), ExitReason::Exit(Value(3))));
+            assert!(vm.suspended_frames().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_fuel_calls()
+    {
+        // Running out of fuel inside nested calls keeps the frames
+        let asm = Assembler::new();
+        let mut vm = asm.parse_str("push 10; call DEC, 1; exit; DEC: get_arg 0; dup; jz ZERO; push 1; sub_u64; call DEC, 1; ret; ZERO: ret;").unwrap();
+        vm.set_fuel(Some(20));
+        assert!(matches!(vm.call(0, &[]), ExitReason::OutOfFuel));
+        assert!(vm.frames.len() > 1);
+        vm.set_fuel(None);
+        assert!(matches!(vm.resume(), ExitReason::Exit(Value(0))));
+
+        // Infinite loop used as a watchdog test
+        let asm = Assembler::new();
+        let mut vm = asm.parse_str("LOOP: jmp LOOP;").unwrap();
+        vm.set_fuel(Some(1000));
+        assert!(matches!(vm.call(0, &[]), ExitReason::OutOfFuel));
+
+        // Resuming when nothing is suspended is a fault, not a panic
+        let mut vm = Assembler::new().parse_str("push 0; exit;").unwrap();
+        assert!(matches!(vm.resume(), ExitReason::Fault { kind: FaultKind::NotSuspended, .. }));
+    }
+
+    #[test]
+    fn test_fuel_time_slicing()
+    {
+        // Callback A counts down from its argument, and B adds 1 to its argument
+        let src = "
+            push 0; ret;
+            A: get_arg 0; LOOP: push 1; sub_u64; dup; jnz LOOP; push 7; ret;
+            B: get_arg 0; push 1; add_u64; ret;
+        ";
+        for jit in [false, true] {
+            let mut vm = Assembler::new().parse_str(src).unwrap();
+            if jit {
+                vm.enable_jit();
+            }
+            let a_pc = vm.symbols().find("A").unwrap() as u64;
+            let b_pc = vm.symbols().find("B").unwrap() as u64;
+
+            // B runs, and can itself run out of fuel, while A is suspended
+            vm.set_fuel(Some(50));
+            assert!(matches!(vm.call(a_pc, &[Value::from(1000u64)]), ExitReason::OutOfFuel));
+            let a_suspended_pc = vm.suspended_pc();
+            vm.set_fuel(Some(2));
+            assert!(matches!(vm.call(b_pc, &[Value::from(5u64)]), ExitReason::OutOfFuel));
+            vm.set_fuel(Some(10));
+            assert!(matches!(vm.resume(), ExitReason::Return(Value(6))));
+            assert!(matches!(vm.call(b_pc, &[Value::from(1u64)]), ExitReason::Return(Value(2))));
+
+            // A is resumed where it stopped once B is done
+            assert_eq!(vm.suspended_pc(), a_suspended_pc);
+            assert!(vm.save_image(Vec::new()).is_ok());
+            vm.set_fuel(None);
+            assert!(matches!(vm.resume(), ExitReason::Return(Value(7))));
+            assert!(vm.stack.is_empty() && vm.frames.is_empty());
+        }
+
+        // Each callback gets its own budget when there is a watchdog
+        let mut vm = Assembler::new().parse_str(src).unwrap();
+        let a_pc = vm.symbols().find("A").unwrap() as u64;
+        vm.set_callback_fuel(Some(100));
+        assert!(matches!(vm.call_event(a_pc, &[Value::from(1000u64)]), ExitReason::OutOfFuel));
+        assert!(matches!(vm.call_event(a_pc, &[Value::from(3u64)]), ExitReason::Return(Value(7))));
+    }
+
+    /// Save a VM image and load it back, with a fresh system state
+    fn reload_image(vm: &VM) -> VM
+    {
+        let mut bytes = Vec::new();
+        vm.save_image(&mut bytes).unwrap();
+        VM::load_image(SysState::new(), bytes.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn test_image()
+    {
+        // Suspend inside nested calls, and resume from the image,
+        // with or without the JIT
+        let src = "
+            .data;
+            COUNT: .u64 0;
+            .code;
+            push 10;
+            call DEC, 1;
+            push COUNT;
+            load_u64;
+            add_u64;
+            exit;
+            DEC:
+            push COUNT;
+            push COUNT;
+            load_u64;
+            push 1;
+            add_u64;
+            store_u64;
+            get_arg 0;
+            dup;
+            jz ZERO;
+            push 1;
+            sub_u64;
+            call DEC, 1;
+            ret;
+            ZERO:
+            ret;
+        ";
+        for jit in [false, true] {
+            let asm = Assembler::new();
+            let mut vm = asm.parse_str(src).unwrap();
+            vm.set_fuel(Some(50));
+            assert!(matches!(vm.call(0, &[]), ExitReason::OutOfFuel));
+            assert!(vm.frames.len() > 1);
+
+            let mut vm = reload_image(&vm);
+            assert!(vm.is_suspended());
+            assert!(vm.symbols().symbolize(vm.suspended_pc().unwrap()).starts_with("DEC"));
+            if jit {
+                assert!(vm.enable_jit());
+            }
+            assert!(matches!(vm.resume(), ExitReason::Exit(Value(11))));
+            assert!(vm.stack.is_empty() && vm.frames.is_empty());
+        }
+
+        // An image saved between calls keeps the heap and pending callbacks
+        let asm = Assembler::new();
+        let mut vm = asm.parse_str(".data; .u64 0; .code; push 0; push 7; store_u64; push 60000; push_p32 CB; syscall time_delay_cb; push 0; ret; CB: push 0; ret;").unwrap();
+        assert!(matches!(vm.call(0, &[]), ExitReason::Return(_)));
+        let mut vm = reload_image(&vm);
+        assert!(!vm.is_suspended());
+        assert_eq!(vm.get_heap_slice::<u64>(0, 1).unwrap()[0], 7);
+        assert!(time::time_until_next_cb(&vm).is_some());
+        assert!(time::get_cbs_to_run(&mut vm).is_empty());
+    }
+
+    #[test]
+    fn test_image_errors()
+    {
+        let asm = Assembler::new();
+        let vm = asm.parse_str("push 0; exit;").unwrap();
+        let mut bytes = Vec::new();
+        vm.save_image(&mut bytes).unwrap();
+
+        let load = |bytes: &[u8]| VM::load_image(SysState::new(), bytes);
+        assert!(load(&bytes).is_ok());
+        assert!(matches!(load(b"ELF"), Err(ImageError::Io(_))));
+        assert!(matches!(load(b"UVMX\x01\0\0\0\0\0\0\0"), Err(ImageError::BadMagic)));
+        assert!(matches!(load(b"UVMI\x07\0\0\0\0\0\0\0"), Err(ImageError::BadVersion(7))));
+        assert!(matches!(load(&bytes[..bytes.len() - 1]), Err(ImageError::Io(_))));
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(matches!(load(&trailing), Err(ImageError::Corrupt(_))));
+
+        // Frames which don't match the stack or the code
+        let mut vm = Assembler::new().parse_str("push 1; call FN, 1; exit; FN: get_arg 0; get_arg 0; add_u64; ret;").unwrap();
+        vm.set_fuel(Some(3));
+        assert!(matches!(vm.call(0, &[]), ExitReason::OutOfFuel));
+        let corrupt_frame = |vm: &VM, edit: fn(&mut StackFrame)| {
+            let mut vm = reload_image(vm);
+            edit(vm.frames.last_mut().unwrap());
+            let mut bytes = Vec::new();
+            vm.save_image(&mut bytes).unwrap();
+            load(&bytes)
+        };
+        assert!(corrupt_frame(&vm, |_| {}).is_ok());
+        assert!(matches!(corrupt_frame(&vm, |frame| frame.argc = 200), Err(ImageError::Corrupt(_))));
+        assert!(matches!(corrupt_frame(&vm, |frame| frame.ret_addr = 1000), Err(ImageError::Corrupt(_))));
+        assert!(matches!(corrupt_frame(&vm, |frame| frame.prev_bp = 5), Err(ImageError::Corrupt(_))));
+
+        // Callback delays too long to be represented, unless the callback never expires
+        let src = "push 0x123456789abc; push 0; syscall time_delay_cb; push -1; push 0; syscall time_delay_cb; push 0; ret;";
+        let mut vm = Assembler::new().parse_str(src).unwrap();
+        assert!(matches!(vm.call(0, &[]), ExitReason::Return(_)));
+        let mut bytes = Vec::new();
+        vm.save_image(&mut bytes).unwrap();
+        assert!(load(&bytes).is_ok());
+        let pos = bytes.windows(7).rposition(|w| w == [0x9a, 0x78, 0x56, 0x34, 0x12, 0, 0]).unwrap() - 1;
+        bytes[pos..pos + 8].copy_from_slice(&(u64::MAX - 1).to_le_bytes());
+        assert!(matches!(load(&bytes), Err(ImageError::Corrupt(_))));
+
+        // A VM can't be saved from a syscall, since the syscall's
+        // own state would be lost
+        let mut vm = VMBuilder::new()
+            .host_syscall(HOST_SYSCALL_BASE, "host_save", SysCallFn::Fn0_1(host_save))
+            .parse_str("syscall host_save; exit;")
+            .unwrap();
+        assert!(matches!(vm.call(0, &[]), ExitReason::Exit(Value(1))));
+    }
+
+    /// Try to save the VM while a call is running
+    fn host_save(vm: &mut VM) -> Result<Value, FaultKind>
+    {
+        let result = vm.save_image(std::io::sink());
+        Ok(Value::from(matches!(result, Err(ImageError::NotSuspendable(_))) as u64))
+    }
+
+    #[test]
+    fn test_resize_heap()
+    {
+        eval_i64(".data; .zero 10; .code; syscall vm_heap_size; exit;", 10);
+        eval_i64("push 100; syscall vm_resize_heap; exit;", 1);
+        eval_i64("push 100; syscall vm_resize_heap; pop; syscall vm_heap_size; exit;", 100);
+
+        // New memory is accessible and zero-filled
+        eval_i64(".data; .fill 8, 0xFF; .code; push 16; syscall vm_resize_heap; pop; push 8; load_u64; exit;", 0);
+        eval_i64("push 16; syscall vm_resize_heap; pop; push 8; push 7; store_u64; push 8; load_u64; exit;", 7);
+
+        // Shrinking the heap, then growing it back zero-fills
+        eval_i64(".data; .fill 8, 0xFF; .code; push 4; syscall vm_resize_heap; pop; push 8; syscall vm_resize_heap; pop; push 4; load_u32; exit;", 0);
+        assert_eq!(
+            eval_fault(".data; .fill 8, 0xFF; .code; push 4; syscall vm_resize_heap; pop; push 4; load_u8; exit;").0,
+            FaultKind::HeapOutOfBounds { addr: 4, size: 1 }
+        );
+
+        // The maximum heap size is configurable by the host
+        let asm = Assembler::new();
+        let mut vm = asm.parse_str(".data; .zero 8; .code; get_arg 0; syscall vm_resize_heap; ret;").unwrap();
+        vm.set_max_heap_size(1024);
+        assert!(matches!(vm.call(0, &[Value::from(1024u64)]), ExitReason::Return(Value(1))));
+        assert!(matches!(vm.call(0, &[Value::from(1025u64)]), ExitReason::Return(Value(0))));
+        assert_eq!(vm.heap_size(), 1024);
+        assert!(matches!(vm.call(0, &[Value::from(u64::MAX)]), ExitReason::Return(Value(0))));
+    }
+
+    #[test]
+    fn test_code_write()
+    {
+        // Code to write: push_i8 42; ret;
+        let code_bytes = format!(".u8 {}; .u8 42; .u8 {};", Op::push_i8 as u8, Op::ret as u8);
+
+        // Patch a function in place
+        eval_i64(&format!("
+            .data;
+            CODE: {}
+            .code;
+            call FN, 0;
+            push FN; push CODE; push 3; syscall vm_code_write;
+            call FN, 0;
+            add_u64;
+            exit;
+            FN: push 1; ret; nop;
+        ", code_bytes), 43);
+
+        // Grow the code space and append a new function at the end
+        eval_i64(&format!("
+            .data;
+            CODE: {}
+            .code;
+            syscall vm_code_size;
+            push 3; syscall vm_grow_code; pop;
+            dup; push CODE; push 3; syscall vm_code_write;
+            call_fp 0;
+            exit;
+        ", code_bytes), 42);
+
+        // New code space is filled with panic instructions
+        assert_eq!(eval_fault("syscall vm_code_size; push 8; syscall vm_grow_code; pop; call_fp 0; exit;").0, FaultKind::Panic);
+
+        // Jumps past the end of the code fault, until the code grows to include their target
+        for jit in [false, true] {
+            let mut vm = Assembler::new().parse_str("call FN, 0; exit; FN: jmp FN; ret;").unwrap();
+            if jit {
+                assert!(vm.enable_jit());
+            }
+
+            let fn_pc = vm.symbols().find("FN").unwrap();
+            let target = vm.code_size() + 4;
+            let mut jmp = vec![Op::jmp as u8];
+            jmp.extend_from_slice(&((target - (fn_pc + 5)) as i32).to_le_bytes());
+            vm.write_code(fn_pc, &jmp).unwrap();
+            assert!(matches!(vm.call(0, &[]), ExitReason::Fault { kind: FaultKind::PcOutOfBounds, pc, .. } if pc == fn_pc));
+
+            assert!(vm.grow_code(8));
+            vm.write_code(target, &[Op::push_i8 as u8, 42, Op::ret as u8]).unwrap();
+            assert!(matches!(vm.call(0, &[]), ExitReason::Exit(Value(42))));
+        }
+
+        // Code written at runtime isn't verified, so invalid code faults
+        // when it runs, without affecting the host
+        for (code_bytes, kind) in [
+            (format!(".u8 {}; .u8 {}; .u8 0; .u8 {};", Op::pop as u8, Op::get_arg as u8, Op::ret as u8), FaultKind::StackUnderflow),
+            (format!(".u8 {}; .u8 {}; .u8 {}; .u8 0;", Op::pop as u8, Op::push_0 as u8, Op::call_fp as u8), FaultKind::StackUnderflow),
+            (".u8 255; .u8 255; .u8 255; .u8 255;".to_string(), FaultKind::InvalidOpcode(255)),
+        ] {
+            let src = format!("
+                .data;
+                CODE: {}
+                .code;
+                push FN; push CODE; push 4; syscall vm_code_write;
+                push 7; call FN, 1;
+                exit;
+                FN: get_arg 0; ret; nop;
+            ", code_bytes);
+            assert_eq!(Assembler::new().parse_str(&src).unwrap().verify(), vec![]);
+            assert_eq!(eval_fault(&src).0, kind);
+        }
+
+        // Writes past the end of the code space fault
+        assert_eq!(
+            eval_fault(".data; .zero 8; .code; syscall vm_code_size; push 0; push 8; syscall vm_code_write; push 0; exit;").0,
+            FaultKind::CodeOutOfBounds { addr: 11, size: 8 }
+        );
+    }
+
+    #[test]
+    fn test_code_write_hook()
+    {
+        fn hook(vm: &mut VM, addr: usize, size: usize)
+        {
+            // Record the written range on the stack
+            vm.push(Value::from((addr * 100 + size) as u64));
+        }
+
+        let asm = Assembler::new();
+        let mut vm = asm.parse_str(".data; .u8 0; .u8 0; .code; push 1; push 0; push 2; syscall vm_code_write; ret;").unwrap();
+        vm.set_code_write_hook(Some(hook));
+        assert!(matches!(vm.call(0, &[]), ExitReason::Return(Value(102))));
+
+        // The host can set a maximum code size
+        let asm = Assembler::new();
+        let mut vm = asm.parse_str("get_arg 0; syscall vm_grow_code; ret;").unwrap();
+        vm.set_max_code_size(100);
+        assert!(matches!(vm.call(0, &[Value::from(94u64)]), ExitReason::Return(Value(1))));
+        assert!(matches!(vm.call(0, &[Value::from(1u64)]), ExitReason::Return(Value(0))));
+        assert_eq!(vm.code_size(), 100);
+
+        // Code writes can be disabled by denying the permission
+        let asm = Assembler::new();
+        let mut vm = asm.parse_str("push 1; syscall vm_grow_code; ret;").unwrap();
+        vm.sys_state.deny_permission("vm_code_write");
+        assert!(matches!(vm.call(0, &[]), ExitReason::Fault { kind: FaultKind::PermissionDenied(constants::VM_GROW_CODE), .. }));
+        vm.sys_state.allow_permission("vm_code_write");
+        assert!(matches!(vm.call(0, &[]), ExitReason::Return(Value(1))));
+    }
+
+    #[test]
+    fn test_syscall_faults()
+    {
+        assert_eq!(
+            eval_fault(".data; LABEL: .zero 1; .code; push LABEL; push 255; push 256; syscall memset; push 0; exit;").0,
+            FaultKind::HeapOutOfBounds { addr: 0, size: 256 }
+        );
+        assert_eq!(
+            eval_fault(".data; LABEL: .zero 8; .code; push LABEL; push 4; push 8; syscall memcpy; push 0; exit;").0,
+            FaultKind::HeapOutOfBounds { addr: 4, size: 8 }
+        );
+        assert_eq!(
+            eval_fault(".data; LABEL: .fill 4, 65; .code; push LABEL; syscall print_str; push 0; exit;").0,
+            FaultKind::InvalidString(0)
+        );
+        assert_eq!(
+            eval_fault(".data; LABEL: .fill 1, 0xFF; .u8 0; .code; push LABEL; syscall print_str; push 0; exit;").0,
+            FaultKind::InvalidString(0)
+        );
+
+        // Delays too long to be represented never expire, instead of overflowing
+        let mut vm = Assembler::new().parse_str("push -1; push 0; syscall time_delay_cb; push 0; exit;").unwrap();
+        assert!(matches!(vm.call(0, &[]), ExitReason::Exit(Value(0))));
+        assert!(time::get_cbs_to_run(&mut vm).is_empty());
+    }
+
+    #[test]
+    fn test_jit()
+    {
+        let src = "push 0; LOOP: push 1; add_u64; dup; push 10; lt_u64; jnz LOOP; exit;";
+
+        // Blocks are compiled when first reached, with versions specialized
+        // on the counter value until there are too many, and the generic
+        // version of the loop body ends up linked to itself
+        let asm = Assembler::new();
+        let mut vm = asm.parse_str(src).unwrap();
+        assert!(vm.enable_jit());
+        assert!(matches!(vm.call(0, &[]), ExitReason::Exit(Value(10))));
+        let jit = vm.jit.as_ref().unwrap();
+        let versions = jit.versions(1);
+        assert_eq!(jit.num_blocks(), 6);
+        assert_eq!(versions.len(), 5);
+        assert_eq!(versions[0].context.slots[0], Fact::Const(1));
+        assert!(versions[4].context == Context::default());
+        assert_eq!(jit.num_links(), 6);
+
+        // Fuel is consumed exactly as in the interpreter
+        let asm = Assembler::new();
+        let mut vm = asm.parse_str(src).unwrap();
+        assert!(vm.enable_jit());
+        vm.set_fuel(Some(5));
+        let mut exit_reason = vm.call(0, &[]);
+        let mut num_slices = 1;
+        while let ExitReason::OutOfFuel = exit_reason {
+            vm.set_fuel(Some(5));
+            exit_reason = vm.resume();
+            num_slices += 1;
+        }
+        assert!(matches!(exit_reason, ExitReason::Exit(Value(10))));
+        assert_eq!(num_slices, 13);
+        assert_eq!(vm.fuel(), Some(3));
+
+        // Heap accesses at constant addresses covered by an earlier
+        // check, or at a copy of an address already checked, are not checked
+        let src = "
+            .data;
+            .u64 8;
+            .u64 42;
+            .code;
+            push 8;
+            load_u64;
+            push 0;
+            load_u64;
+            dup;
+            load_u64;
+            swap;
+            load_u32;
+            add_u64;
+            add_u64;
+            exit;
+        ";
+        eval_i64(src, 126);
+        let asm = Assembler::new();
+        let mut vm = asm.parse_str(src).unwrap();
+        assert!(vm.enable_jit());
+        assert!(matches!(vm.call(0, &[]), ExitReason::Exit(Value(126))));
+        assert_eq!(vm.jit.as_ref().unwrap().versions(0)[0].num_heap_checks, 2);
+
+        // Writing code invalidates the blocks translated from it
+        let asm = Assembler::new();
+        let mut vm = asm.parse_str("push 1; get_local 0; push_i8 2; add_u64; exit;").unwrap();
+        assert!(vm.enable_jit());
+        assert!(matches!(vm.call(0, &[]), ExitReason::Exit(Value(3))));
+        vm.write_code(4, &[7]).unwrap();
+        assert!(matches!(vm.call(0, &[]), ExitReason::Exit(Value(8))));
+
+        // Values pushed by a block can exceed the stack capacity
+        eval_i64(&format!("{} exit;", "push 1; ".repeat(200)), 1);
+        eval_i64(&format!("{} {} exit;", "push 1; ".repeat(100), "add_u64; ".repeat(99)), 100);
+    }
+
+    fn host_double(vm: &mut VM, val: Value) -> Result<Value, FaultKind>
+    {
+        Ok(Value::from(val.as_u64() * 2))
+    }
+
+    #[test]
+    fn test_builder()
+    {
+        // Host syscalls can be called by name, including from translated code
+        for jit in [false, true] {
+            let mut vm = VMBuilder::new()
+                .host_syscall(HOST_SYSCALL_BASE, "host_double", SysCallFn::Fn1_1(host_double))
+                .parse_str("push 21; syscall host_double; exit;")
+                .unwrap();
+            assert_eq!(vm.verify(), vec![]);
+            if jit {
+                assert!(vm.enable_jit());
+            }
+            assert!(matches!(vm.call(0, &[]), ExitReason::Exit(Value(42))));
+        }
+
+        // The syscalls of disabled subsystems don't exist
+        let src = "push 1; syscall print_i64; push 0; exit;";
+        let result = VMBuilder::new().disable_subsystem("io").parse_str(src);
+        assert!(matches!(result, Err(BuildError::Verify(diags)) if diags.len() == 1));
+        let mut sys_state = SysState::new();
+        sys_state.disable_subsystem("io");
+        let mut vm = Assembler::with_sys_state(sys_state).parse_str(src).unwrap();
+        assert!(matches!(vm.call(0, &[]), ExitReason::Fault { kind: FaultKind::UnknownSyscall(constants::PRINT_I64), .. }));
+
+        // Programs that fail to assemble or to verify are rejected
+        assert!(matches!(VMBuilder::new().parse_str("push;"), Err(BuildError::Parse(_))));
+        assert!(matches!(VMBuilder::new().parse_str("pop; push 0; exit;"), Err(BuildError::Verify(_))));
+
+        let mut vm = VMBuilder::new()
+            .max_heap_size(64)
+            .parse_str("push 65; syscall vm_resize_heap; exit;")
+            .unwrap();
+        assert!(matches!(vm.call(0, &[]), ExitReason::Exit(Value(0))));
+    }
+
+    fn host_panic(vm: &mut VM) -> Result<Value, FaultKind>
+    {
+        panic!("host syscall panicked");
+    }
+
+    #[test]
+    fn test_host_syscall_panic()
+    {
+        // A panicking syscall unwinds into the host, even from translated code
+        for jit in [false, true] {
+            let mut vm = VMBuilder::new()
+                .host_syscall(HOST_SYSCALL_BASE, "host_panic", SysCallFn::Fn0_1(host_panic))
+                .parse_str("push 1; syscall host_panic; exit;")
+                .unwrap();
+            if jit {
+                assert!(vm.enable_jit());
+            }
+            let result = panic::catch_unwind(AssertUnwindSafe(|| vm.call(0, &[])));
+            assert!(result.is_err());
+        }
     }
 
     #[test]
     #[should_panic]
-    fn test_memset_oob()
+    fn test_host_syscall_idx()
+    {
+        // Host syscalls can't take the indices of built-in ones
+        VMBuilder::new().host_syscall(3, "host_double", SysCallFn::Fn1_1(host_double));
+    }
+
+    /// Call a guest function from a syscall, and return its result
+    fn host_call_add(vm: &mut VM, fun: Value) -> Result<Value, FaultKind>
     {
-        eval_src(".data; LABEL: .zero 1; .code; push LABEL; push 255; push 256; syscall memset; push 0; exit;");
+        match vm.call(fun.as_u64(), &[Value::from(20u64), Value::from(22u64)]) {
+            ExitReason::Return(val) => Ok(val),
+            _ => Err(FaultKind::SyscallError("guest call failed".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_reentrant_call()
+    {
+        let build = |src: &str, jit: bool| {
+            let mut vm = VMBuilder::new()
+                .host_syscall(HOST_SYSCALL_BASE, "host_call_add", SysCallFn::Fn1_1(host_call_add))
+                .parse_str(src)
+                .unwrap();
+            if jit {
+                assert!(vm.enable_jit());
+            }
+            vm
+        };
+
+        // The caller's stack and frames are left intact by the nested call,
+        // and fuel is consumed the same way with the JIT
+        let src = "
+            push 100;
+            push_p32 ADD;
+            syscall host_call_add;
+            add_u64;
+            exit;
+            ADD:
+            get_arg 0;
+            get_arg 1;
+            add_u64;
+            ret;
+        ";
+        for jit in [false, true] {
+            let mut vm = build(src, jit);
+            vm.set_fuel(Some(100));
+            assert!(matches!(vm.call(0, &[]), ExitReason::Exit(Value(142))));
+            assert!(vm.stack.is_empty() && vm.frames.is_empty());
+            assert_eq!(vm.fuel(), Some(91));
+        }
+
+        // A fault in the nested call is reported to the syscall
+        let src = "push 0; push_p32 BAD; syscall host_call_add; exit; BAD: panic;";
+        for jit in [false, true] {
+            let mut vm = build(src, jit);
+            assert!(matches!(vm.call(0, &[]), ExitReason::Fault { kind: FaultKind::SyscallError(_), pc: 6, .. }));
+            assert!(vm.stack.is_empty() && vm.frames.is_empty());
+        }
+
+        // A nested call which runs out of fuel and is abandoned by
+        // the syscall doesn't prevent further calls
+        let src = "push 0; push_p32 LOOP; syscall host_call_add; exit; LOOP: jmp LOOP; OK: push 5; ret;";
+        for jit in [false, true] {
+            let mut vm = build(src, jit);
+            vm.set_fuel(Some(1000));
+            assert!(matches!(vm.call(0, &[]), ExitReason::Fault { kind: FaultKind::SyscallError(_), .. }));
+            assert!(vm.stack.is_empty() && vm.frames.is_empty() && vm.calls.is_empty());
+            assert!(!vm.is_suspended());
+
+            vm.set_fuel(None);
+            let ok = vm.symbols().find("OK").unwrap() as u64;
+            assert!(matches!(vm.call(ok, &[]), ExitReason::Return(Value(5))));
+        }
+    }
+
+    #[test]
+    fn test_call_after_fault()
+    {
+        // The VM can still be used after a fault
+        let asm = Assembler::new();
+        let mut vm = asm.parse_str("FAULT: panic; OK: push 5; ret;").unwrap();
+        assert!(matches!(vm.call(0, &[]), ExitReason::Fault { kind: FaultKind::Panic, pc: 0, .. }));
+        assert!(matches!(vm.call(1, &[]), ExitReason::Return(Value(5))));
     }
 }